@@ -1,18 +1,27 @@
 #!/usr/bin/perl
 
+# Check that the boot block fits the sectors reserved for it, given as
+# the second argument, and pad it to them. bootasm.S ends the first
+# sector with the signature.
+
 open(SIG, $ARGV[0]) || die "open $ARGV[0]: $!";
 
-$n = sysread(SIG, $buf, 1000);
+$max = 512 * $ARGV[1];
+$n = sysread(SIG, $buf, $max + 1);
+
+if($n > $max){
+  print STDERR "boot block too large: $n bytes (max $max)\n";
+  exit 1;
+}
 
-if($n > 510){
-  print STDERR "boot block too large: $n bytes (max 510)\n";
+if(substr($buf, 510, 2) ne "\x55\xAA"){
+  print STDERR "boot block has no signature\n";
   exit 1;
 }
 
-print STDERR "boot block is $n bytes (max 510)\n";
+print STDERR "boot block is $n bytes (max $max)\n";
 
-$buf .= "\0" x (510-$n);
-$buf .= "\x55\xAA";
+$buf .= "\0" x ($max-$n);
 
 open(SIG, ">$ARGV[0]") || die "open >$ARGV[0]: $!";
 print SIG $buf;
@@ -2,51 +2,157 @@
 //
 // Part of the boot block, along with bootasm.S, which calls bootmain().
 // bootasm.S has put the processor into protected 32-bit mode.
-// bootmain() loads an ELF kernel image from the disk starting at
-// sector 1 and then jumps to the kernel entry routine.
+// bootmain() loads the ELF kernel image, the file KERNEL in the root
+// directory of the file system following the boot block, and then jumps
+// to the kernel entry routine, the way a Multiboot loader does: with the
+// magic in %eax and the information block, holding the BIOS memory map
+// and boot drive, in %ebx.
 
 #include "types.h"
 #include "elf64.h"
 #include "x86.h"
 #include "memlayout.h"
+#include "multiboot.h"
+#include "fs.h"
 
 #define SECTSIZE  512
+#define KERNEL    "ros"
+
+// Scratch space
+#define ELFHDR    0x10000           // The first page of the kernel
+#define INOBUF    0x11000           // The inode block of the file at hand
+#define BLKBUF    0x11400           // Super, directory and indirect blocks
 
 __asm__(".code32\n\t");
 
-void readseg(uchar*, uint, uint);
+void readblock(void*, uint);
+void readseg(struct dinode*, uchar*, uint, uint);
+
+// Print s on the first line of the VGA text screen and stop.
+static void
+fail(char *s)
+{
+  ushort *crt = (ushort*)0xb8000;
+
+  while(*s)
+    *crt++ = 0x0700 | *s++;  // Light gray on black
+  for(;;)
+    ;
+}
+
+// Read inode inum, it stays valid until the next call.
+static struct dinode*
+iget(struct superblock *sb, uint inum)
+{
+  readblock((void*)INOBUF, sb->inodestart + inum / IPB);
+  return (struct dinode*)INOBUF + inum % IPB;
+}
+
+// Return the disk block address of the nth block of ip.
+static uint
+bmap(struct dinode *ip, uint bn)
+{
+  uint *a = (uint*)BLKBUF;
+
+  if(bn < NDIRECT)
+    return ip->addrs[bn];
+  bn -= NDIRECT;
+  if(bn < NINDIRECT){
+    readblock(a, ip->addrs[NDIRECT]);
+    return a[bn];
+  }
+  bn -= NINDIRECT;
+  readblock(a, ip->addrs[NDIRECT+1]);
+  readblock(a, a[bn / NINDIRECT]);
+  return a[bn % NINDIRECT];
+}
+
+// Does the directory entry name, padded with 0s, equal s?
+static int
+namematch(char *name, char *s)
+{
+  int i;
+
+  for(i = 0; i < DIRSIZ && s[i]; i++)
+    if(name[i] != s[i])
+      return 0;
+  return i == DIRSIZ || name[i] == 0;
+}
+
+// Look up the kernel in the root directory.
+static struct dinode*
+findkernel(void)
+{
+  struct superblock sb;
+  struct dinode *dp;
+  struct dirent *de, *ede;
+  uint off;
+
+  readblock((void*)BLKBUF, 1);
+  sb = *(struct superblock*)BLKBUF;
+  if(sb.magic != FSMAGIC)
+    fail("boot: no file system");
+
+  dp = iget(&sb, ROOTINO);
+  for(off = 0; off < dp->size; off += BSIZE){
+    readblock((void*)BLKBUF, bmap(dp, off / BSIZE));
+    de = (struct dirent*)BLKBUF;
+    ede = de + BSIZE / sizeof(*de);
+    for(; de < ede; de++)
+      if(de->inum != 0 && namematch(de->name, KERNEL))
+        return iget(&sb, de->inum);
+  }
+  fail("boot: no " KERNEL " in the root directory");
+  return 0;
+}
 
 void
-bootmain(void)
+bootmain(uint mmapend, uint drive)
 {
   struct elf64hdr *elf;
   struct prog64hdr *ph, *eph;
-  void (*entry)(void);
+  struct dinode *ip;
+  struct mbinfo *mbi;
   uchar* pa;
 
-  elf = (struct elf64hdr*)0x10000;  // scratch space
+  ip = findkernel();
+  elf = (struct elf64hdr*)ELFHDR;
 
   // Read 1st page off disk
-  readseg((uchar*)elf, 4096, 0);
+  readseg(ip, (uchar*)elf, 4096, 0);
 
   // Is this an ELF executable?
   if(elf->magic != ELF_MAGIC)
-    return;  // let bootasm.S handle error
+    fail("boot: kernel is not ELF");
 
-  // Load each program segment (ignores ph flags).
+  // Load each program segment. There is no paging yet to enforce the
+  // flags, but the kernel later maps its code read-only, so refuse an
+  // image that wants code writable.
   ph = (struct prog64hdr*)((uchar*)elf + elf->phoff);
   eph = ph + elf->phnum;
   for(; ph < eph; ph++){
+    if(ph->type != ELF_PROG_LOAD)
+      continue;
+    if((ph->flags & ELF_PROG_FLAG_WRITE) && (ph->flags & ELF_PROG_FLAG_EXEC))
+      fail("boot: writable and executable segment");
+    if(ph->off + ph->filesz > ip->size)
+      fail("boot: kernel file truncated");
     pa = (uchar*)ph->paddr;
-    readseg(pa, ph->filesz, ph->off);
+    readseg(ip, pa, ph->filesz, ph->off);
     if(ph->memsz > ph->filesz)
       stosb(pa + ph->filesz, 0, ph->memsz - ph->filesz);
   }
 
-  // Call the entry point from the ELF header.
+  mbi = (struct mbinfo*)BOOTINFO;
+  stosb(mbi, 0, sizeof(*mbi));
+  mbi->flags = MB_INFO_BOOTDEV | MB_INFO_MEM_MAP;
+  mbi->boot_device = (drive << 24) | 0xffffff;  // No partition
+  mbi->mmap_length = mmapend - E820MAP;
+  mbi->mmap_addr = E820MAP;
+
+  // Jump to the entry point from the ELF header.
   // Does not return!
-  entry = (void(*)(void))(elf->entry);
-  entry();
+  asm volatile("jmp *%0" : : "r" (elf->entry), "a" (MULTIBOOT_BOOTLOADER_MAGIC), "b" (mbi));
 }
 
 void
@@ -75,24 +181,35 @@ readsect(void *dst, uint offset)
   insl(0x1F0, dst, SECTSIZE/4);
 }
 
-// Read 'count' bytes at 'offset' from kernel into physical address 'pa'.
+// Read block bno of the file system, which follows the boot block.
+void
+readblock(void *dst, uint bno)
+{
+  uint sect;
+
+  sect = BOOTSECTS + bno * (BSIZE / SECTSIZE);
+  readsect(dst, sect);
+  readsect((uchar*)dst + SECTSIZE, sect + 1);
+}
+
+// Read 'count' bytes at 'offset' of file ip into physical address 'pa'.
 // Might copy more than asked.
 void
-readseg(uchar* pa, uint count, uint offset)
+readseg(struct dinode *ip, uchar* pa, uint count, uint offset)
 {
   uchar* epa;
 
   epa = pa + count;
 
-  // Round down to sector boundary.
-  pa -= offset % SECTSIZE;
+  // Round down to block boundary.
+  pa -= offset % BSIZE;
 
-  // Translate from bytes to sectors; kernel starts at sector 1.
-  offset = (offset / SECTSIZE) + 1;
+  // Translate from bytes to blocks of the file.
+  offset = offset / BSIZE;
 
   // If this is too slow, we could read lots of sectors at a time.
   // We'd write more to memory than asked, but it doesn't matter --
   // we load in increasing order.
-  for(; pa < epa; pa += SECTSIZE, offset++)
-    readsect(pa, offset);
+  for(; pa < epa; pa += BSIZE, offset++)
+    readblock(pa, bmap(ip, offset));
 }
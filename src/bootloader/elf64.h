@@ -40,3 +40,11 @@ struct prog64hdr {
   uint align;
   uint align_pad;
 };
+
+// Values for prog64hdr type
+#define ELF_PROG_LOAD           1
+
+// Flag bits for prog64hdr flags
+#define ELF_PROG_FLAG_EXEC      1
+#define ELF_PROG_FLAG_WRITE     2
+#define ELF_PROG_FLAG_READ      4
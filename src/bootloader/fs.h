@@ -0,0 +1,42 @@
+// On-disk file system format, as far as the boot loader reads it.
+// See kern/fs.rs for the kernel side and tools/mkfs for the layout:
+// [ boot block | super block | log | inode blocks | free bit map | data blocks ]
+
+#define BSIZE 1024          // Block size
+#define FSMAGIC 0x10203040
+#define ROOTINO 1           // Root i-number
+
+struct superblock {
+  uint magic;               // Must be FSMAGIC
+  uint size;                // Size of file system image (blocks)
+  uint nblocks;             // Number of data blocks
+  uint ninodes;             // Number of inodes
+  uint nlog;                // Number of log blocks
+  uint logstart;            // Block number of first log block
+  uint inodestart;          // Block number of first inode block
+  uint bmapstart;           // Block number of first free map block
+};
+
+#define NDIRECT 11
+#define NINDIRECT (BSIZE / sizeof(uint))
+
+// On-disk inode structure
+struct dinode {
+  short type;               // File type
+  short major;              // Major device number (T_DEV only)
+  short minor;              // Minor device number (T_DEV only)
+  short nlink;              // Number of links to inode in file system
+  uint size;                // Size of file (bytes)
+  uint addrs[NDIRECT+2];    // Data block addresses
+};
+
+// Inodes per block.
+#define IPB (BSIZE / sizeof(struct dinode))
+
+// Directory is a file containing a sequence of dirent structures.
+#define DIRSIZ 14
+
+struct dirent {
+  ushort inum;
+  char name[DIRSIZ];
+};
@@ -4,6 +4,13 @@
 #define PHYSTOP 0xE000000           // Top physical memory
 #define DEVSPACE 0xFE000000         // Other devices are at high addresses
 
+// Sectors of the boot block, the file system holding the kernel follows
+#define BOOTSECTS 4
+
+// Multiboot information the boot loader builds, see multiboot.h
+#define BOOTINFO 0x9000
+#define E820MAP  0x9100             // The BIOS memory map it points to
+
 // Key addresses for address space layout (see kmap in vm.c for layout)
 #define KERNBASE 0x80000000         // First kernel virtual address
 #define KERNLINK (KERNBASE+EXTMEM)  // Address where kernel is linked
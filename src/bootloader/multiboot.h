@@ -0,0 +1,20 @@
+// The Multiboot information block, as far as the boot loader fills it.
+// See kern/multiboot.rs for the kernel side.
+
+#define MULTIBOOT_BOOTLOADER_MAGIC 0x2BADB002
+
+#define MB_INFO_BOOTDEV  (1 << 1)   // boot_device is valid
+#define MB_INFO_MEM_MAP  (1 << 6)   // mmap_length and mmap_addr are valid
+
+struct mbinfo {
+  uint flags;
+  uint mem_lower;
+  uint mem_upper;
+  uint boot_device;   // BIOS drive in the top byte, partitions below
+  uint cmdline;
+  uint mods_count;
+  uint mods_addr;
+  uint syms[4];
+  uint mmap_length;
+  uint mmap_addr;
+};
@@ -0,0 +1,103 @@
+// Just enough ACPI to find tables by signature, e.g. MCFG for PCI ECAM.
+// Tables are read through the linear map, so they must lie below PHYSTOP
+// (QEMU puts them at the top of RAM). The RSDP comes from the boot loader
+// if it passed one, else from a search of the BIOS areas.
+
+use crate::*;
+use crate::kern::multiboot;
+
+#[repr(C, packed)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oemid: [u8; 6],
+    revision: u8,
+    rsdt: u32,
+    // ACPI 2.0 and later
+    length: u32,
+    xsdt: u64,
+    ext_checksum: u8,
+    reserved: [u8; 3],
+}
+
+#[repr(C, packed)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oemid: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+fn sum(a: *const u8, len: usize) -> u8 {
+    let mut s: u8 = 0;
+    for i in 0..len as isize {
+        s = s.wrapping_add(unsafe { *a.offset(i) });
+    }
+    s
+}
+
+// The RSDP is in the first KB of the EBDA or in the BIOS ROM between
+// 0xe0000 and 0xfffff, on a 16 byte boundary.
+unsafe fn search_rsdp() -> Option<&'static Rsdp> {
+    unsafe fn search_1(a: u64, len: u64) -> Option<&'static Rsdp> {
+        let mut p = p2v!(a);
+        let e = p + len;
+        while p < e {
+            if memcmp(p as *const u8, "RSD PTR ".as_ptr(), 8) && sum(p as *const u8, 20) == 0 {
+                return Some(&*(p as *const Rsdp));
+            }
+            p += 16;
+        }
+        None
+    }
+
+    let bda = p2v!(0x400) as *const u8;
+    let ebda = (((*bda.offset(0x0f) as u64) << 8) | (*bda.offset(0x0e) as u64)) << 4;
+    let r = if ebda != 0 { search_1(ebda, 1024) } else { None };
+    r.or_else(|| search_1(0xe0000, 0x20000))
+}
+
+unsafe fn table(pa: u64) -> Option<&'static SdtHeader> {
+    if pa == 0 || pa >= PHYSTOP { return None }
+    let h = &*(p2v!(pa) as *const SdtHeader);
+    if pa + h.length as u64 > PHYSTOP || sum(h as *const SdtHeader as *const u8, h.length as usize) != 0 {
+        return None;
+    }
+    Some(h)
+}
+
+// Find the table with the given signature through the XSDT, or the RSDT
+// on ACPI 1.0.
+pub fn find_table(sig: &[u8; 4]) -> Option<&'static SdtHeader> {
+    unsafe {
+        let rsdp = match multiboot::rsdp() {
+            Some(r) => &*(r.as_ptr() as *const Rsdp),
+            None => search_rsdp()?,
+        };
+        let (root, entsz) = if rsdp.revision >= 2 && rsdp.xsdt != 0 {
+            (table(rsdp.xsdt)?, 8)
+        } else {
+            (table(rsdp.rsdt as u64)?, 4)
+        };
+        let first = root as *const SdtHeader as u64 + size_of::<SdtHeader>() as u64;
+        let n = (root.length as u64 - size_of::<SdtHeader>() as u64) / entsz;
+        for i in 0..n {
+            let ent = first + i * entsz;
+            let pa = if entsz == 8 {
+                core::ptr::read_unaligned(ent as *const u64)
+            } else {
+                core::ptr::read_unaligned(ent as *const u32) as u64
+            };
+            match table(pa) {
+                Some(h) if &h.signature == sig => return Some(h),
+                _ => {}
+            }
+        }
+        None
+    }
+}
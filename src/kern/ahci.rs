@@ -0,0 +1,519 @@
+// AHCI SATA driver.
+//
+// Every port has a command list of up to 32 slots, so up to 32 requests
+// can be outstanding on a disk. Disks supporting native command queuing
+// get FPDMA QUEUED commands which the drive may reorder, the others get
+// READ/WRITE DMA EXT which the controller issues one after another.
+// Commands are completed from the interrupt handler.
+// The controller's registers (ABAR) are mapped by KMapper::setup_vm, which
+// is why ahci_reserve must run before kvm_alloc.
+// Test with QEMU's `-M q35`, see `make qemu-ahci`.
+
+use crate::*;
+use crate::kern::blk::{BlockDevice, register_blkdev, check_range, SECTSIZE};
+use crate::kern::pci::{PciDev, PciDriver, PciId, Bar, pci_scan, pci_register_driver};
+use crate::kern::pci::{PCI_COMMAND_MEMORY, PCI_COMMAND_MASTER};
+use crate::kern::spinlock::SpinLock;
+use crate::kern::proc::{sleep, wakeup, has_proc};
+use crate::kern::vm::{kva2pa, dev_reserve, mmio2v};
+use crate::kern::idt::irq_register;
+use crate::kern::msi::msi_enable;
+use core::cell::UnsafeCell;
+use core::ptr::{read_volatile, write_volatile};
+use x86_64::instructions::interrupts::without_interrupts;
+
+const PCI_CLASS_AHCI: u32 = 0x010601;   // Mass storage, SATA, AHCI 1.0
+const ABAR: u8 = 5;
+const ABAR_SIZE: u64 = 0x1100;
+
+// HBA registers
+const HBA_CAP: u64 = 0x00;
+const HBA_GHC: u64 = 0x04;
+const HBA_IS: u64 = 0x08;
+const HBA_PI: u64 = 0x0C;
+
+const CAP_NCS_SHIFT: u32 = 8;
+const CAP_SNCQ: u32 = 1 << 30;
+const CAP_S64A: u32 = 1 << 31;
+const GHC_IE: u32 = 1 << 1;
+const GHC_AE: u32 = 1 << 31;
+
+// Port registers, relative to the port
+const PORT_BASE: u64 = 0x100;
+const PORT_SIZE: u64 = 0x80;
+const PX_CLB: u64 = 0x00;
+const PX_CLBU: u64 = 0x04;
+const PX_FB: u64 = 0x08;
+const PX_FBU: u64 = 0x0C;
+const PX_IS: u64 = 0x10;
+const PX_IE: u64 = 0x14;
+const PX_CMD: u64 = 0x18;
+const PX_TFD: u64 = 0x20;
+const PX_SIG: u64 = 0x24;
+const PX_SSTS: u64 = 0x28;
+const PX_SERR: u64 = 0x30;
+const PX_SACT: u64 = 0x34;
+const PX_CI: u64 = 0x38;
+
+const PXCMD_ST: u32 = 1 << 0;
+const PXCMD_SUD: u32 = 1 << 1;
+const PXCMD_POD: u32 = 1 << 2;
+const PXCMD_FRE: u32 = 1 << 4;
+const PXCMD_FR: u32 = 1 << 14;
+const PXCMD_CR: u32 = 1 << 15;
+
+const PXIS_DHRS: u32 = 1 << 0;      // Device to host register FIS
+const PXIS_PSS: u32 = 1 << 1;       // PIO setup FIS
+const PXIS_DSS: u32 = 1 << 2;       // DMA setup FIS
+const PXIS_SDBS: u32 = 1 << 3;      // Set device bits FIS, NCQ completion
+const PXIS_IFS: u32 = 1 << 27;
+const PXIS_HBDS: u32 = 1 << 28;
+const PXIS_HBFS: u32 = 1 << 29;
+const PXIS_TFES: u32 = 1 << 30;
+const PXIS_ERR: u32 = PXIS_IFS | PXIS_HBDS | PXIS_HBFS | PXIS_TFES;
+
+const TFD_BSY: u32 = 0x80;
+const TFD_DRQ: u32 = 0x08;
+
+const SSTS_DET_PRESENT: u32 = 3;
+const SIG_ATA: u32 = 0x00000101;
+
+// ATA commands
+const FIS_TYPE_REG_H2D: u8 = 0x27;
+const ATA_CMD_READ_DMA_EXT: u8 = 0x25;
+const ATA_CMD_WRITE_DMA_EXT: u8 = 0x35;
+const ATA_CMD_READ_FPDMA: u8 = 0x60;
+const ATA_CMD_WRITE_FPDMA: u8 = 0x61;
+const ATA_CMD_IDENTIFY: u8 = 0xEC;
+
+const NSLOTS: usize = 32;
+const MAX_PRDS: usize = 8;          // Data pages per command
+const NDISK: usize = 4;
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct CmdHeader {
+    flags: u16,         // FIS length in dwords, W (bit 6)
+    prdtl: u16,         // Entries in the PRD table
+    prdbc: u32,         // Bytes transferred
+    ctba: u64,          // Command table, 128 byte aligned
+    reserved: [u32; 4],
+}
+
+const CMDH_WRITE: u16 = 1 << 6;
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct Prd {
+    dba: u64,
+    reserved: u32,
+    dbc: u32,           // Byte count - 1
+}
+
+#[repr(C, align(128))]
+#[derive(Copy, Clone)]
+struct CmdTable {
+    cfis: [u8; 64],
+    acmd: [u8; 16],
+    reserved: [u8; 48],
+    prdt: [Prd; MAX_PRDS],
+}
+
+// Memory the controller reads commands from and posts FISes to.
+// Nothing in it crosses a page, so it need not be physically contiguous.
+#[repr(C, align(4096))]
+pub struct PortMem {
+    cl: [CmdHeader; NSLOTS],
+    fis: [u8; 256],
+    tables: [CmdTable; NSLOTS],
+}
+
+impl PortMem {
+    const fn new() -> Self {
+        PortMem {
+            cl: [CmdHeader { flags: 0, prdtl: 0, prdbc: 0, ctba: 0, reserved: [0; 4] }; NSLOTS],
+            fis: [0; 256],
+            tables: [CmdTable {
+                cfis: [0; 64],
+                acmd: [0; 16],
+                reserved: [0; 48],
+                prdt: [Prd { dba: 0, reserved: 0, dbc: 0 }; MAX_PRDS],
+            }; NSLOTS],
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+struct SlotInfo {
+    done: bool,
+    error: bool,
+}
+
+// Command slot bookkeeping, protected by AhciDisk::lock.
+struct Slots {
+    busy: u32,          // Slots owned by a request
+    issued: u32,        // Slots handed to the controller
+    info: [SlotInfo; NSLOTS],
+}
+
+pub struct AhciDisk {
+    lock: SpinLock,
+    name: &'static str,
+    hba: u64,           // Virtual address of ABAR
+    port: u32,
+    mem: *mut PortMem,
+    nslots: u32,
+    slots: UnsafeCell<Slots>,
+    ncq: bool,
+    nsectors: u64,
+}
+
+// The lock serializes all access to the port.
+unsafe impl Sync for AhciDisk {}
+
+static mut SDISKS: [AhciDisk; NDISK] = [
+    AhciDisk::new("sda"), AhciDisk::new("sdb"), AhciDisk::new("sdc"), AhciDisk::new("sdd"),
+];
+static mut PORTMEM: [PortMem; NDISK] = [PortMem::new(), PortMem::new(), PortMem::new(), PortMem::new()];
+static mut NSDISK: usize = 0;
+
+fn phys(p: *const u8) -> u64 {
+    unsafe { kva2pa(VA::from_ptr(p)).expect("ahci: buffer not mapped").as_u64() }
+}
+
+// Fill in a register host to device FIS.
+fn build_fis(cfis: &mut [u8; 64], cmd: u8, lba: u64, count: u16, tag: u8, ncq: bool) -> () {
+    for b in cfis.iter_mut() { *b = 0; }
+    cfis[0] = FIS_TYPE_REG_H2D;
+    cfis[1] = 0x80;                 // Command, not control
+    cfis[2] = cmd;
+    cfis[4] = lba as u8;
+    cfis[5] = (lba >> 8) as u8;
+    cfis[6] = (lba >> 16) as u8;
+    cfis[7] = 0x40;                 // LBA mode
+    cfis[8] = (lba >> 24) as u8;
+    cfis[9] = (lba >> 32) as u8;
+    cfis[10] = (lba >> 40) as u8;
+    if ncq {
+        // The count moves to the features field, the tag into count.
+        cfis[3] = count as u8;
+        cfis[11] = (count >> 8) as u8;
+        cfis[12] = tag << 3;
+    } else {
+        cfis[12] = count as u8;
+        cfis[13] = (count >> 8) as u8;
+    }
+}
+
+impl AhciDisk {
+    const fn new(name: &'static str) -> Self {
+        AhciDisk {
+            lock: SpinLock::new(),
+            name: name,
+            hba: 0,
+            port: 0,
+            mem: null_mut(),
+            nslots: 0,
+            slots: UnsafeCell::new(Slots {
+                busy: 0,
+                issued: 0,
+                info: [SlotInfo { done: false, error: false }; NSLOTS],
+            }),
+            ncq: false,
+            nsectors: 0,
+        }
+    }
+
+    // The caller holds lock.
+    unsafe fn slots(&self) -> &mut Slots { &mut *self.slots.get() }
+
+    fn reg(&self, reg: u64) -> u32 {
+        unsafe { read_volatile((self.hba + PORT_BASE + self.port as u64 * PORT_SIZE + reg) as *const u32) }
+    }
+
+    fn set_reg(&self, reg: u64, v: u32) -> () {
+        unsafe { write_volatile((self.hba + PORT_BASE + self.port as u64 * PORT_SIZE + reg) as *mut u32, v) }
+    }
+
+    fn stop(&self) -> () {
+        self.set_reg(PX_CMD, self.reg(PX_CMD) & !PXCMD_ST);
+        while self.reg(PX_CMD) & PXCMD_CR != 0 { }
+        self.set_reg(PX_CMD, self.reg(PX_CMD) & !PXCMD_FRE);
+        while self.reg(PX_CMD) & PXCMD_FR != 0 { }
+    }
+
+    fn start(&self) -> () {
+        while self.reg(PX_TFD) & (TFD_BSY | TFD_DRQ) != 0 { }
+        self.set_reg(PX_CMD, self.reg(PX_CMD) | PXCMD_FRE | PXCMD_POD | PXCMD_SUD);
+        self.set_reg(PX_CMD, self.reg(PX_CMD) | PXCMD_ST);
+    }
+
+    unsafe fn init(&mut self, hba: u64, port: u32, mem: *mut PortMem, nslots: u32) -> Result<(), &'static str> {
+        self.hba = hba;
+        self.port = port;
+        self.mem = mem;
+        self.nslots = nslots;
+
+        self.stop();
+        let m = &mut *mem;
+        let clb = phys(m.cl.as_ptr() as *const u8);
+        let fb = phys(m.fis.as_ptr());
+        if read_volatile((hba + HBA_CAP) as *const u32) & CAP_S64A == 0 && (clb | fb) >> 32 != 0 {
+            return Err("ahci: controller cannot reach memory above 4GB");
+        }
+        for (h, t) in m.cl.iter_mut().zip(m.tables.iter()) {
+            *h = CmdHeader { flags: 0, prdtl: 0, prdbc: 0, ctba: phys(t as *const CmdTable as *const u8), reserved: [0; 4] };
+        }
+        self.set_reg(PX_CLB, clb as u32);
+        self.set_reg(PX_CLBU, (clb >> 32) as u32);
+        self.set_reg(PX_FB, fb as u32);
+        self.set_reg(PX_FBU, (fb >> 32) as u32);
+        self.set_reg(PX_SERR, 0xFFFFFFFF);
+        self.set_reg(PX_IS, 0xFFFFFFFF);
+        self.set_reg(PX_IE, PXIS_DHRS | PXIS_PSS | PXIS_DSS | PXIS_SDBS | PXIS_ERR);
+        self.start();
+
+        let mut id = [0u16; 256];
+        self.rw_chunk(ATA_CMD_IDENTIFY, 0, id.as_mut_ptr() as *mut u8, SECTSIZE, false)?;
+        // Words 83 bit 10: LBA48, 100-103: LBA48 capacity,
+        // 76 bit 8: NCQ, 75: queue depth - 1
+        if id[83] & (1 << 10) == 0 { return Err("ahci: disk without LBA48") }
+        self.nsectors = (id[103] as u64) << 48 | (id[102] as u64) << 32 | (id[101] as u64) << 16 | id[100] as u64;
+        if read_volatile((hba + HBA_CAP) as *const u32) & CAP_SNCQ != 0 && id[76] & (1 << 8) != 0 {
+            self.ncq = true;
+            let depth = (id[75] & 0x1F) as u32 + 1;
+            if depth < self.nslots { self.nslots = depth; }
+        }
+        Ok(())
+    }
+
+    // Complete every command the disk has finished, on an error fail all
+    // of them and restart the port. Caller holds the lock.
+    unsafe fn intr_locked(&self) -> () {
+        let is = self.reg(PX_IS);
+        self.set_reg(PX_IS, is);
+        write_volatile((self.hba + HBA_IS) as *mut u32, 1 << self.port);
+
+        let (done, error) = if is & PXIS_ERR != 0 {
+            self.stop();
+            self.set_reg(PX_SERR, 0xFFFFFFFF);
+            self.set_reg(PX_IS, 0xFFFFFFFF);
+            self.start();
+            (self.slots().issued, true)
+        } else {
+            (self.slots().issued & !(self.reg(PX_CI) | self.reg(PX_SACT)), false)
+        };
+
+        let slots = self.slots();
+        for slot in 0..NSLOTS {
+            if done & (1 << slot) == 0 { continue }
+            let info = &mut slots.info[slot];
+            info.error = error;
+            info.done = true;
+            wakeup(VA::from_ptr(info as *const SlotInfo));
+        }
+        slots.issued &= !done;
+    }
+
+    // Wait on chan, or poll the port if there is no process to put to sleep.
+    // Caller holds the lock, interrupts are off.
+    unsafe fn wait(&self, chan: VA) -> () {
+        if has_proc() {
+            sleep(chan, &self.lock);
+        } else {
+            self.intr_locked();
+        }
+    }
+
+    // Issue one command moving at most MAX_PRDS pages, returns the number
+    // of bytes moved. Reads and writes are turned into their NCQ variants
+    // if the disk supports them.
+    unsafe fn rw_chunk(&self, cmd: u8, sector: u64, data: *mut u8, len: usize, write: bool)
+        -> Result<usize, &'static str> {
+        let mut prds = [Prd { dba: 0, reserved: 0, dbc: 0 }; MAX_PRDS];
+        let mut nprd = 0;
+        let mut done = 0;
+        while done < len && nprd < MAX_PRDS {
+            let va = data as u64 + done as u64;
+            let n = core::cmp::min(len - done, (PGSIZE - va % PGSIZE) as usize);
+            prds[nprd] = Prd { dba: phys(va as *const u8), reserved: 0, dbc: n as u32 - 1 };
+            nprd += 1;
+            done += n;
+        }
+        // Keep whole sectors only, the rest goes with the next chunk.
+        // The controller wants even byte counts, which sectors are.
+        let len = done - done % SECTSIZE;
+        while done > len {
+            let last = &mut prds[nprd - 1];
+            let cut = core::cmp::min(done - len, last.dbc as usize + 1);
+            done -= cut;
+            if cut == last.dbc as usize + 1 { nprd -= 1; } else { last.dbc -= cut as u32; }
+        }
+        if len == 0 { return Ok(0) }
+
+        let mut result = Ok(len);
+        without_interrupts(|| {
+            self.lock.acquire();
+
+            let slots_chan = VA::from_ptr(&self.slots().busy as *const u32);
+            let slot = loop {
+                let free = !self.slots().busy & (!0u32 >> (32 - self.nslots));
+                if free != 0 { break free.trailing_zeros() as usize }
+                self.wait(slots_chan);
+            };
+            self.slots().busy |= 1 << slot;
+
+            let ncq = self.ncq && cmd != ATA_CMD_IDENTIFY;
+            let cmd = match cmd {
+                ATA_CMD_READ_DMA_EXT if ncq => ATA_CMD_READ_FPDMA,
+                ATA_CMD_WRITE_DMA_EXT if ncq => ATA_CMD_WRITE_FPDMA,
+                c => c,
+            };
+            let m = &mut *self.mem;
+            let t = &mut m.tables[slot];
+            build_fis(&mut t.cfis, cmd, sector, (len / SECTSIZE) as u16, slot as u8, ncq);
+            t.prdt[..nprd].copy_from_slice(&prds[..nprd]);
+            let h = &mut m.cl[slot];
+            h.flags = 5 | if write { CMDH_WRITE } else { 0 };   // 5 dwords FIS
+            h.prdtl = nprd as u16;
+            h.prdbc = 0;
+
+            self.slots().info[slot] = SlotInfo { done: false, error: false };
+            self.slots().issued |= 1 << slot;
+            if ncq { self.set_reg(PX_SACT, 1 << slot); }
+            self.set_reg(PX_CI, 1 << slot);
+
+            let info_chan = VA::from_ptr(&self.slots().info[slot] as *const SlotInfo);
+            while !read_volatile(&self.slots().info[slot].done) {
+                self.wait(info_chan);
+            }
+
+            if self.slots().info[slot].error { result = Err("ahci: I/O error"); }
+            self.slots().busy &= !(1 << slot);
+            wakeup(slots_chan);
+
+            self.lock.release();
+        });
+        result
+    }
+
+    unsafe fn rw(&self, sector: u64, data: *mut u8, len: usize, write: bool) -> Result<(), &'static str> {
+        check_range(self, sector, len)?;
+        let mut done = 0;
+        while done < len {
+            let cmd = if write { ATA_CMD_WRITE_DMA_EXT } else { ATA_CMD_READ_DMA_EXT };
+            let n = self.rw_chunk(cmd, sector + (done / SECTSIZE) as u64,
+                                  data.offset(done as isize), len - done, write)?;
+            if n == 0 { return Err("ahci: buffer not sector aligned") }
+            done += n;
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for AhciDisk {
+    fn name(&self) -> &'static str { self.name }
+
+    fn nsectors(&self) -> u64 { self.nsectors }
+
+    fn read(&self, sector: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        unsafe { self.rw(sector, buf.as_mut_ptr(), buf.len(), false) }
+    }
+
+    fn write(&self, sector: u64, buf: &[u8]) -> Result<(), &'static str> {
+        unsafe { self.rw(sector, buf.as_ptr() as *mut u8, buf.len(), true) }
+    }
+}
+
+// Interrupt handler, shared by all AHCI disks.
+fn ahci_intr() -> () {
+    unsafe {
+        for disk in SDISKS[..NSDISK].iter() {
+            if disk.reg(PX_IS) == 0 { continue }
+            disk.lock.acquire();
+            disk.intr_locked();
+            disk.lock.release();
+        }
+    }
+}
+
+fn ahci_msi(_: usize) -> () { ahci_intr() }
+
+// Have the controllers' registers mapped, must run before kvm_alloc.
+pub fn ahci_reserve() -> () {
+    pci_scan(|pci| {
+        if pci.class() != PCI_CLASS_AHCI { return }
+        if let Err(e) = unsafe { dev_reserve(pci.mem_bar(ABAR), ABAR_SIZE) } {
+            println!("{}", e);
+        }
+    });
+}
+
+static AHCI_IDS: [PciId; 1] = [PciId::class(PCI_CLASS_AHCI, 0xFFFFFF)];
+
+static AHCI_DRIVER: PciDriver = PciDriver {
+    name: "ahci",
+    ids: &AHCI_IDS,
+    probe: ahci_probe,
+};
+
+// Interrupt lines ahci_intr is registered on.
+static mut AHCI_IRQS: u32 = 0;
+
+fn ahci_probe(dev: &PciDev) -> Result<(), &'static str> {
+    let hba = match dev.bars[ABAR as usize] {
+        Bar::Mem { addr, .. } => mmio2v(addr).ok_or("ahci: ABAR not mapped")?,
+        _ => return Err("ahci: no ABAR"),
+    };
+    dev.addr.enable(PCI_COMMAND_MEMORY | PCI_COMMAND_MASTER);
+
+    unsafe {
+        let ghc = (hba + HBA_GHC) as *mut u32;
+        write_volatile(ghc, read_volatile(ghc) | GHC_AE);
+        let cap = read_volatile((hba + HBA_CAP) as *const u32);
+        let nslots = ((cap >> CAP_NCS_SHIFT) & 0x1F) + 1;
+        let pi = read_volatile((hba + HBA_PI) as *const u32);
+
+        for port in 0..32 {
+            if pi & (1 << port) == 0 { continue }
+            let p = hba + PORT_BASE + port as u64 * PORT_SIZE;
+            if read_volatile((p + PX_SSTS) as *const u32) & 0xF != SSTS_DET_PRESENT { continue }
+            if read_volatile((p + PX_SIG) as *const u32) != SIG_ATA { continue }
+            if NSDISK == NDISK {
+                println!("ahci: too many disks");
+                break;
+            }
+            let disk = &mut SDISKS[NSDISK];
+            if let Err(e) = disk.init(hba, port, &mut PORTMEM[NSDISK], nslots) {
+                println!("{}", e);
+                write_volatile((p + PX_IE) as *mut u32, 0);
+                continue;
+            }
+            NSDISK += 1;
+            println!("ahci: {} on port {}, {}", disk.name, port,
+                     if disk.ncq { "NCQ" } else { "no NCQ" });
+            if let Err(e) = register_blkdev(&*(disk as *const AhciDisk)) {
+                println!("{}", e);
+            }
+        }
+
+        // A vector of its own if the controller can do MSI, the shared
+        // legacy line otherwise.
+        let irq = dev.irq_line as u32;
+        if msi_enable(dev, 0, ahci_msi, 0).is_err() && irq < 32 && AHCI_IRQS & (1 << irq) == 0 {
+            match irq_register(irq, ahci_intr) {
+                Ok(_) => AHCI_IRQS |= 1 << irq,
+                Err(e) => println!("ahci: irq {}: {}", irq, e),
+            }
+        }
+        write_volatile((hba + HBA_IS) as *mut u32, 0xFFFFFFFF);
+        write_volatile(ghc, read_volatile(ghc) | GHC_IE);
+    }
+    Ok(())
+}
+
+pub fn ahci_init() -> () {
+    if let Err(e) = pci_register_driver(&AHCI_DRIVER) {
+        println!("{}", e);
+    }
+}
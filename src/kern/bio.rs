@@ -0,0 +1,228 @@
+// Buffer cache.
+//
+// The buffer cache holds cached copies of disk blocks. Caching disk
+// blocks in memory reduces the number of disk reads and also provides a
+// synchronization point for disk blocks used by multiple processes.
+//
+// Interface:
+// * To get a buffer for a particular disk block, call bread.
+// * After changing buffer data, call bwrite to write it to disk now, or
+//   bdirty to have it written back later by bsync.
+// * When done with the buffer, call brelse.
+// * Do not use the buffer after calling brelse.
+// * Only one process at a time can use a buffer, so do not keep them
+//   longer than necessary.
+//
+// Unused buffers are recycled least recently used first. Dirty buffers
+// are written back by bsync, which brelse runs every SYNC_TICKS ticks,
+// and before a dirty buffer is recycled.
+
+use crate::*;
+use crate::kern::blk::{blkdev, SECTSIZE};
+use crate::kern::sleeplock::SleepLock;
+use crate::kern::spinlock::SpinLock;
+use crate::kern::idt::get_ticks;
+
+pub const BSIZE: usize = 1024;      // Block size
+const NBUF: usize = 30;             // Size of the buffer cache
+const SYNC_TICKS: u64 = 300;        // Write back dirty buffers this often
+
+pub struct Buf {
+    valid: bool,                    // Has data been read from disk?
+    dirty: bool,                    // Changed since read from or written to disk?
+    pub dev: usize,
+    pub blockno: u32,
+    lock: SleepLock,
+    refcnt: u32,
+    lastuse: u64,                   // For LRU, from BCACHE.clock
+    pub data: [u8; BSIZE],
+}
+
+struct BCache {
+    bufs: [Buf; NBUF],
+    clock: u64,
+    last_sync: u64,                 // Ticks at the last periodic sync
+}
+
+// BCACHE_LOCK protects the identity (dev, blockno), refcnt and lastuse
+// of every buffer, the lock of a buffer protects its data, valid and dirty.
+static BCACHE_LOCK: SpinLock = SpinLock::new();
+static mut BCACHE: BCache = BCache {
+    bufs: [
+        Buf::new(), Buf::new(), Buf::new(), Buf::new(), Buf::new(), Buf::new(),
+        Buf::new(), Buf::new(), Buf::new(), Buf::new(), Buf::new(), Buf::new(),
+        Buf::new(), Buf::new(), Buf::new(), Buf::new(), Buf::new(), Buf::new(),
+        Buf::new(), Buf::new(), Buf::new(), Buf::new(), Buf::new(), Buf::new(),
+        Buf::new(), Buf::new(), Buf::new(), Buf::new(), Buf::new(), Buf::new(),
+    ],
+    clock: 0,
+    last_sync: 0,
+};
+
+impl Buf {
+    const fn new() -> Self {
+        Buf {
+            valid: false,
+            dirty: false,
+            dev: 0,
+            blockno: 0,
+            lock: SleepLock::new("buffer"),
+            refcnt: 0,
+            lastuse: 0,
+            data: [0; BSIZE],
+        }
+    }
+
+    fn sector(&self) -> u64 { self.blockno as u64 * (BSIZE / SECTSIZE) as u64 }
+
+    fn read(&mut self) -> Result<(), &'static str> {
+        let dev = blkdev(self.dev).ok_or("bio: no such device")?;
+        dev.read(self.sector(), &mut self.data)
+    }
+
+    fn write(&mut self) -> Result<(), &'static str> {
+        let dev = blkdev(self.dev).ok_or("bio: no such device")?;
+        dev.write(self.sector(), &self.data)?;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+// Write back dirty buffers nobody uses. With wait unset, buffers somebody
+// else has locked are skipped, so this never sleeps on a buffer lock the
+// caller might hold itself. Returns the number of buffers written.
+unsafe fn flush_unused(wait: bool) -> usize {
+    let mut n = 0;
+    for b in BCACHE.bufs.iter_mut() {
+        BCACHE_LOCK.acquire();
+        if !b.dirty || (b.refcnt != 0 && !wait) {
+            BCACHE_LOCK.release();
+            continue;
+        }
+        b.refcnt += 1;
+        BCACHE_LOCK.release();
+
+        if wait { b.lock.acquire(); } else if !b.lock.try_acquire() {
+            BCACHE_LOCK.acquire();
+            b.refcnt -= 1;
+            BCACHE_LOCK.release();
+            continue;
+        }
+        if b.dirty {
+            match b.write() {
+                Ok(_) => n += 1,
+                Err(e) => println!("bio: write back dev {} block {}: {}", b.dev, b.blockno, e),
+            }
+        }
+        b.lock.release();
+
+        BCACHE_LOCK.acquire();
+        b.refcnt -= 1;
+        BCACHE_LOCK.release();
+    }
+    n
+}
+
+// Look through buffer cache for block on device dev.
+// If not found, recycle the least recently used clean buffer.
+// In either case, return locked buffer.
+unsafe fn bget(dev: usize, blockno: u32) -> &'static mut Buf {
+    loop {
+        BCACHE_LOCK.acquire();
+        BCACHE.clock += 1;
+
+        // Is the block already cached?
+        if let Some(b) = BCACHE.bufs.iter_mut().find(|b| b.dev == dev && b.blockno == blockno) {
+            b.refcnt += 1;
+            b.lastuse = BCACHE.clock;
+            BCACHE_LOCK.release();
+            b.lock.acquire();
+            return b;
+        }
+
+        // Not cached; recycle an unused clean buffer.
+        let victim = BCACHE.bufs.iter_mut()
+            .filter(|b| b.refcnt == 0 && !b.dirty)
+            .min_by_key(|b| b.lastuse);
+        if let Some(b) = victim {
+            b.dev = dev;
+            b.blockno = blockno;
+            b.valid = false;
+            b.refcnt = 1;
+            b.lastuse = BCACHE.clock;
+            BCACHE_LOCK.release();
+            b.lock.acquire();
+            return b;
+        }
+        BCACHE_LOCK.release();
+
+        // Every unused buffer is dirty, clean some and try again.
+        if flush_unused(false) == 0 { panic!("bget: no buffers"); }
+    }
+}
+
+// Return a locked buf with the contents of the indicated block.
+pub fn bread(dev: usize, blockno: u32) -> Result<&'static mut Buf, &'static str> {
+    let b = unsafe { bget(dev, blockno) };
+    if !b.valid {
+        if let Err(e) = b.read() {
+            brelse(b);
+            return Err(e);
+        }
+        b.valid = true;
+    }
+    Ok(b)
+}
+
+// Write b's contents to disk now. Must be locked.
+pub fn bwrite(b: &mut Buf) -> Result<(), &'static str> {
+    if !b.lock.holding() { panic!("bwrite"); }
+    b.write()
+}
+
+// Mark b as changed, it is written back by the next bsync. Must be locked.
+pub fn bdirty(b: &mut Buf) -> () {
+    if !b.lock.holding() { panic!("bdirty"); }
+    b.dirty = true;
+}
+
+// Release a locked buffer.
+pub fn brelse(b: &mut Buf) -> () {
+    if !b.lock.holding() { panic!("brelse"); }
+    b.lock.release();
+
+    BCACHE_LOCK.acquire();
+    b.refcnt -= 1;
+    let sync = unsafe {
+        let now = get_ticks();
+        if now - BCACHE.last_sync >= SYNC_TICKS {
+            BCACHE.last_sync = now;
+            true
+        } else { false }
+    };
+    BCACHE_LOCK.release();
+
+    // Periodic write back. Buffers locked by others, or by the caller,
+    // wait for the next round.
+    if sync { unsafe { flush_unused(false); } }
+}
+
+// Keep a buffer cached while nobody holds it, e.g. until the log
+// has installed it.
+pub fn bpin(b: &mut Buf) -> () {
+    BCACHE_LOCK.acquire();
+    b.refcnt += 1;
+    BCACHE_LOCK.release();
+}
+
+pub fn bunpin(b: &mut Buf) -> () {
+    BCACHE_LOCK.acquire();
+    b.refcnt -= 1;
+    BCACHE_LOCK.release();
+}
+
+// Write every dirty buffer to disk, waiting for buffers in use.
+// The caller must not hold any buffer.
+pub fn bsync() -> () {
+    unsafe { flush_unused(true); }
+}
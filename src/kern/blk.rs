@@ -0,0 +1,54 @@
+// Block devices.
+//
+// Every disk driver implements BlockDevice and registers its disks here.
+// The layers above (buffer cache, filesystems) address a disk by the
+// device number register_blkdev returned.
+
+use crate::*;
+use spin::Mutex;
+
+pub const SECTSIZE: usize = 512;
+const NBLKDEV: usize = 8;
+
+pub trait BlockDevice: Sync {
+    fn name(&self) -> &'static str;
+
+    // Capacity in sectors.
+    fn nsectors(&self) -> u64;
+
+    // Read buf.len() / SECTSIZE sectors starting at sector into buf.
+    // May sleep, unless called before the first process runs.
+    fn read(&self, sector: u64, buf: &mut [u8]) -> Result<(), &'static str>;
+
+    // Write buf.len() / SECTSIZE sectors starting at sector.
+    fn write(&self, sector: u64, buf: &[u8]) -> Result<(), &'static str>;
+}
+
+lazy_static! {
+    static ref BLKDEVS: Mutex<[Option<&'static dyn BlockDevice>; NBLKDEV]> = Mutex::new([None; NBLKDEV]);
+}
+
+// Returns the device number of the new disk.
+pub fn register_blkdev(dev: &'static dyn BlockDevice) -> Result<usize, &'static str> {
+    let mut devs = BLKDEVS.lock();
+    match devs.iter().position(|d| d.is_none()) {
+        Some(i) => {
+            devs[i] = Some(dev);
+            println!("blk{}: {}, {} sectors", i, dev.name(), dev.nsectors());
+            Ok(i)
+        }
+        None => Err("register_blkdev: too many disks"),
+    }
+}
+
+pub fn blkdev(dev: usize) -> Option<&'static dyn BlockDevice> {
+    if dev >= NBLKDEV { return None }
+    BLKDEVS.lock()[dev]
+}
+
+// Check a transfer is a whole number of sectors within the disk.
+pub fn check_range(dev: &dyn BlockDevice, sector: u64, len: usize) -> Result<(), &'static str> {
+    if len % SECTSIZE != 0 { return Err("blk: partial sector") }
+    if sector + (len / SECTSIZE) as u64 > dev.nsectors() { return Err("blk: out of range") }
+    Ok(())
+}
@@ -6,10 +6,24 @@ use x86_64::instructions::port::Port;
 use crate::memmove;
 use core::mem::size_of;
 use crate::memset;
+use crate::p2v;
+use crate::kern::dev::CharDevice;
+use crate::kern::param::{param_num, param_str};
+use crate::kern::proc::{sleep, wakeup, my_proc};
+use crate::kern::spinlock::SpinLock;
+use crate::kern::uart::uart_putc;
+use crate::kern::uaccess::{either_copy_out, either_copy_in};
+use x86_64::VirtAddr as VA;
+use x86_64::instructions::interrupts::without_interrupts;
+use core::cell::UnsafeCell;
 
-const VGA_BUFFER: u64 = 0xffffffff800b8000;
+const VGA_BUFFER: u64 = 0xb8000;
 const CRT_PORT: u16 = 0x3d4;
 const BACKSPACE: u8 = 0x08;
+const DEL: u8 = 0x7f;
+const CTRL_D: u8 = 0x04;
+const CTRL_U: u8 = 0x15;
+const INPUT_BUF: u32 = 128;
 
 lazy_static! {
     /// A global `Writer` instance that can be used for printing to the VGA text buffer.
@@ -17,7 +31,7 @@ lazy_static! {
     /// Used by the `print!` and `println!` macros.
     pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
         color_code: ColorCode::new(Color::Yellow, Color::Black),
-        buffer: unsafe { &mut *(VGA_BUFFER as *mut Buffer) },
+        buffer: unsafe { &mut *(p2v!(VGA_BUFFER) as *mut Buffer) },
         crt_p1: Port::new(CRT_PORT),
         crt_p2: Port::new(CRT_PORT + 1),
     });
@@ -139,12 +153,17 @@ impl Writer {
     /// mode.
     fn write_string(&mut self, s: &str) {
         for byte in s.bytes() {
-            match byte {
-                // printable ASCII byte or newline
-                0x20...0x7e | b'\n' | BACKSPACE => self.write_byte(byte),
-                // not part of printable ASCII range
-                _ => self.write_byte(0xfe),
-            }
+            self.write_ascii(byte);
+        }
+    }
+
+    /// Writes a byte, or `■` if it is not printable ASCII or newline.
+    pub fn write_ascii(&mut self, byte: u8) {
+        match byte {
+            // printable ASCII byte or newline
+            0x20...0x7e | b'\n' | BACKSPACE => self.write_byte(byte),
+            // not part of printable ASCII range
+            _ => self.write_byte(0xfe),
         }
     }
 }
@@ -169,19 +188,200 @@ macro_rules! println {
     ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
 }
 
+// Kernel message levels. A message is printed if its level is below
+// loglevel= from the command line, all of them by default.
+pub const LOG_ERR: u64 = 0;             // Panics
+pub const LOG_INFO: u64 = 1;            // Everything println! prints
+const LOGLEVEL: u64 = 2;
+
 /// Prints the given formatted string to the VGA text buffer through the global `WRITER` instance.
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
+    _print_level(LOG_INFO, args);
+}
+
+#[doc(hidden)]
+pub fn _print_level(level: u64, args: fmt::Arguments) {
     use core::fmt::Write;
     use x86_64::instructions::interrupts;
 
+    if level >= param_num("loglevel").unwrap_or(LOGLEVEL) { return }
     interrupts::without_interrupts(|| {
-        WRITER.lock().write_fmt(args).unwrap();
+        // The lock of the VGA writer serializes the serial output too.
+        let mut writer = WRITER.lock();
+        if unsafe { TO_VGA } { writer.write_fmt(args).unwrap(); }
+        if unsafe { TO_SERIAL } { Serial.write_fmt(args).unwrap(); }
     });
 }
 
+// Where kernel messages go, changed by console= on the command line.
+static mut TO_VGA: bool = true;
+static mut TO_SERIAL: bool = false;
+
+// Kernel messages on COM1.
+struct Serial;
+
+impl fmt::Write for Serial {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.bytes() {
+            if c == b'\n' { uart_putc(b'\r'); }
+            uart_putc(c);
+        }
+        Ok(())
+    }
+}
+
 pub fn console_init() -> () {
     use crate::kern::ioapic::ioapic_enable;
-    // TODO: Link console read/write to stdin/out
     ioapic_enable(crate::IRQ_KBD, 0);
-}
\ No newline at end of file
+
+    match param_str("console") {
+        Some("ttyS0") => unsafe {
+            TO_VGA = false;
+            TO_SERIAL = true;
+        },
+        Some("both") => unsafe { TO_SERIAL = true; },
+        _ => {}
+    }
+}
+
+// Terminal input. Typed characters are collected and can be edited until
+// the line is complete; only then readers get it.
+pub struct Input {
+    lock: SpinLock,
+    line: UnsafeCell<Line>,             // Protected by lock
+}
+
+struct Line {
+    buf: [u8; INPUT_BUF as usize],
+    r: u32,                             // Read index
+    w: u32,                             // Write index, end of the complete lines
+    e: u32,                             // Edit index
+}
+
+unsafe impl Sync for Input {}
+
+impl Input {
+    pub const fn new() -> Input {
+        Input { lock: SpinLock::new(), line: UnsafeCell::new(Line { buf: [0; INPUT_BUF as usize], r: 0, w: 0, e: 0 }) }
+    }
+
+    // The caller holds lock.
+    unsafe fn line(&self) -> &mut Line { &mut *self.line.get() }
+
+    fn chan(&self) -> VA { VA::from_ptr(self as *const Input) }
+
+    // Take the character c from the interrupt handler of the terminal,
+    // showing it with echo. ^U erases the line, ^D ends the input.
+    pub fn intr(&self, c: u8, echo: fn(u8)) -> () {
+        self.lock.acquire();
+        let inp = unsafe { self.line() };
+        match c {
+            CTRL_U => {
+                while inp.e != inp.w && inp.buf[((inp.e - 1) % INPUT_BUF) as usize] != b'\n' {
+                    inp.e -= 1;
+                    echo(BACKSPACE);
+                }
+            }
+            BACKSPACE | DEL => {
+                if inp.e != inp.w {
+                    inp.e -= 1;
+                    echo(BACKSPACE);
+                }
+            }
+            c if c != 0 && inp.e - inp.r < INPUT_BUF => {
+                let c = if c == b'\r' { b'\n' } else { c };
+                inp.buf[(inp.e % INPUT_BUF) as usize] = c;
+                inp.e += 1;
+                if c != CTRL_D { echo(c); }
+                if c == b'\n' || c == CTRL_D || inp.e == inp.r + INPUT_BUF {
+                    inp.w = inp.e;
+                    unsafe { wakeup(self.chan()); }
+                }
+            }
+            _ => {}
+        }
+        self.lock.release();
+    }
+
+    // Read up to n bytes, at most one line, into dst. Sleeps until a line
+    // is complete. Returns 0 at ^D.
+    pub fn read(&self, user: bool, dst: u64, n: u32) -> Result<usize, &'static str> {
+        // The interrupt handler takes the lock too.
+        without_interrupts(|| unsafe {
+            self.lock.acquire();
+            let inp = self.line();
+            let mut tot = 0;
+            while tot < n {
+                while inp.r == inp.w {
+                    if my_proc().map_or(false, |p| p.is_killed()) {
+                        self.lock.release();
+                        return Err("killed");
+                    }
+                    sleep(self.chan(), &self.lock);
+                }
+                let c = inp.buf[(inp.r % INPUT_BUF) as usize];
+                inp.r += 1;
+                if c == CTRL_D {
+                    // Leave ^D for the next read, which then returns 0.
+                    if tot > 0 { inp.r -= 1; }
+                    break;
+                }
+                if either_copy_out(user, dst + tot as u64, &c as *const u8, 1) < 0 {
+                    inp.r -= 1;
+                    if tot == 0 {
+                        self.lock.release();
+                        return Err("bad address");
+                    }
+                    break;
+                }
+                tot += 1;
+                if c == b'\n' { break }
+            }
+            self.lock.release();
+            Ok(tot as usize)
+        })
+    }
+}
+
+static CONSOLE_INPUT: Input = Input::new();
+
+fn console_echo(c: u8) -> () {
+    WRITER.lock().write_byte(c);
+}
+
+// A key typed on the keyboard, from its interrupt handler.
+pub fn console_intr(c: u8) -> () {
+    CONSOLE_INPUT.intr(c, console_echo);
+}
+
+// /dev/console: the keyboard and the VGA text buffer.
+pub struct Console;
+
+pub static CONSOLE_DEV: Console = Console;
+
+impl CharDevice for Console {
+    fn read(&self, minor: i16, user: bool, dst: u64, _off: u64, n: u32) -> Result<usize, &'static str> {
+        if minor != 0 { return Err("no such device") }
+        CONSOLE_INPUT.read(user, dst, n)
+    }
+
+    fn write(&self, minor: i16, user: bool, src: u64, _off: u64, n: u32) -> Result<usize, &'static str> {
+        if minor != 0 { return Err("no such device") }
+        let mut buf = [0u8; 128];
+        let mut tot = 0;
+        while tot < n {
+            let m = core::cmp::min(n - tot, buf.len() as u32);
+            if unsafe { either_copy_in(user, buf.as_mut_ptr(), src + tot as u64, m as usize) } < 0 {
+                if tot == 0 { return Err("bad address") }
+                break;
+            }
+            without_interrupts(|| {
+                let mut w = WRITER.lock();
+                for &c in buf[..m as usize].iter() { w.write_ascii(c); }
+            });
+            tot += m;
+        }
+        Ok(tot as usize)
+    }
+}
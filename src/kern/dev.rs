@@ -0,0 +1,121 @@
+// Character devices.
+//
+// A device inode (T_DEV) on any file system carries a major and a minor
+// number. Reads and writes of a file opened on one go to the driver of
+// the major number, which tells its devices apart by the minor number.
+
+use crate::kern::blk::{blkdev, SECTSIZE};
+use crate::kern::console::CONSOLE_DEV;
+use crate::kern::uaccess::{either_copy_out, either_copy_in};
+use crate::kern::uart::TTY_DEV;
+
+// Major numbers
+pub const CONSOLE: i16 = 1;             // The console, minor 0
+pub const TTYS: i16 = 2;                // Serial ports, minor n is ttySn
+pub const MEM: i16 = 3;                 // Minors MEM_NULL and MEM_ZERO
+pub const DISK: i16 = 4;                // Whole disks, minor is the blk device number
+
+pub const MEM_NULL: i16 = 0;
+pub const MEM_ZERO: i16 = 1;
+
+pub trait CharDevice: Sync {
+    // Read or write at offset off of device minor. If user is set, the
+    // address is a user virtual address; otherwise, a kernel address.
+    // Return the number of bytes transferred.
+    fn read(&self, minor: i16, user: bool, dst: u64, off: u64, n: u32) -> Result<usize, &'static str>;
+
+    fn write(&self, minor: i16, user: bool, src: u64, off: u64, n: u32) -> Result<usize, &'static str>;
+}
+
+static MEM_DEV: Mem = Mem;
+static DISK_DEV: Disk = Disk;
+
+// The driver of major.
+pub fn chrdev(major: i16) -> Option<&'static dyn CharDevice> {
+    match major {
+        CONSOLE => Some(&CONSOLE_DEV),
+        TTYS => Some(&TTY_DEV),
+        MEM => Some(&MEM_DEV),
+        DISK => Some(&DISK_DEV),
+        _ => None,
+    }
+}
+
+// /dev/null reads nothing and swallows everything, /dev/zero reads zeros.
+struct Mem;
+
+impl CharDevice for Mem {
+    fn read(&self, minor: i16, user: bool, dst: u64, _off: u64, n: u32) -> Result<usize, &'static str> {
+        match minor {
+            MEM_NULL => Ok(0),
+            MEM_ZERO => {
+                let zero = [0u8; 512];
+                let mut tot = 0;
+                while tot < n {
+                    let m = core::cmp::min(n - tot, zero.len() as u32);
+                    if unsafe { either_copy_out(user, dst + tot as u64, zero.as_ptr(), m as usize) } < 0 {
+                        return Err("bad address");
+                    }
+                    tot += m;
+                }
+                Ok(n as usize)
+            }
+            _ => Err("no such device"),
+        }
+    }
+
+    fn write(&self, minor: i16, _user: bool, _src: u64, _off: u64, n: u32) -> Result<usize, &'static str> {
+        match minor {
+            MEM_NULL | MEM_ZERO => Ok(n as usize),
+            _ => Err("no such device"),
+        }
+    }
+}
+
+// The raw bytes of a disk, bypassing the buffer cache. Partial sectors
+// are read, patched and written back.
+struct Disk;
+
+impl CharDevice for Disk {
+    fn read(&self, minor: i16, user: bool, dst: u64, off: u64, n: u32) -> Result<usize, &'static str> {
+        let d = blkdev(minor as usize).ok_or("no such device")?;
+        let size = d.nsectors().checked_mul(SECTSIZE as u64).ok_or("disk too large")?;
+        let n = core::cmp::min(n as u64, size.saturating_sub(off)) as u32;
+
+        let mut sect = [0u8; SECTSIZE];
+        let mut tot = 0;
+        while tot < n {
+            let pos = off + tot as u64;             // Below size
+            let st = (pos % SECTSIZE as u64) as usize;
+            let m = core::cmp::min(n - tot, (SECTSIZE - st) as u32);
+            d.read(pos / SECTSIZE as u64, &mut sect)?;
+            if unsafe { either_copy_out(user, dst + tot as u64, sect[st..].as_ptr(), m as usize) } < 0 {
+                return Err("bad address");
+            }
+            tot += m;
+        }
+        Ok(tot as usize)
+    }
+
+    fn write(&self, minor: i16, user: bool, src: u64, off: u64, n: u32) -> Result<usize, &'static str> {
+        let d = blkdev(minor as usize).ok_or("no such device")?;
+        let size = d.nsectors().checked_mul(SECTSIZE as u64).ok_or("disk too large")?;
+        if off.checked_add(n as u64).map_or(true, |ed| ed > size) { return Err("no space left on device") }
+
+        let mut sect = [0u8; SECTSIZE];
+        let mut tot = 0;
+        while tot < n {
+            let pos = off + tot as u64;
+            let st = (pos % SECTSIZE as u64) as usize;
+            let m = core::cmp::min(n - tot, (SECTSIZE - st) as u32);
+            let sector = pos / SECTSIZE as u64;
+            if m as usize != SECTSIZE { d.read(sector, &mut sect)?; }
+            if unsafe { either_copy_in(user, sect[st..].as_mut_ptr(), src + tot as u64, m as usize) } < 0 {
+                return Err("bad address");
+            }
+            d.write(sector, &sect)?;
+            tot += m;
+        }
+        Ok(tot as usize)
+    }
+}
@@ -0,0 +1,175 @@
+// Replace the memory of the current process with an ELF executable.
+//
+// The program headers of type PT_LOAD are loaded page aligned from 0 up,
+// writable only if the segment is, executable only if it is. Above them
+// comes an inaccessible guard page and a page of stack, holding argv.
+
+use crate::*;
+use crate::kern::kalloc::kalloc;
+use crate::kern::log::{begin_op, end_op};
+use crate::kern::proc::my_proc_mut;
+use crate::kern::vfs::{INode, namei};
+use crate::kern::vm::*;
+use x86_64::structures::paging::page_table::PageTable;
+use x86_64::structures::paging::page_table::PageTableFlags as Flags;
+
+pub const MAXARG: usize = 32;
+
+const ELF_MAGIC: u32 = 0x464c457f;          // "\x7FELF" in little endian
+const ELF_CLASS64: u8 = 2;
+const ELF_PROG_LOAD: u32 = 1;
+
+// Flag bits of ProgHdr::flags
+const ELF_PROG_FLAG_EXEC: u32 = 1;
+const ELF_PROG_FLAG_WRITE: u32 = 2;
+
+// User memory ends below this, far from the kernel half.
+const USERTOP: u64 = 0x0000_8000_0000_0000;
+
+#[repr(C)]
+#[derive(Default)]
+struct ElfHdr {
+    magic: u32,
+    elf: [u8; 12],
+    etype: u16,
+    machine: u16,
+    version: u32,
+    entry: u64,
+    phoff: u64,
+    shoff: u64,
+    flags: u32,
+    ehsize: u16,
+    phentsize: u16,
+    phnum: u16,
+    shentsize: u16,
+    shnum: u16,
+    shstrndx: u16,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct ProgHdr {
+    ptype: u32,
+    flags: u32,
+    off: u64,
+    vaddr: u64,
+    paddr: u64,
+    filesz: u64,
+    memsz: u64,
+    align: u64,
+}
+
+// Read a T at off of ip.
+fn read_struct<T>(ip: &dyn INode, off: u64, x: &mut T) -> Result<(), &'static str> {
+    let n = size_of::<T>();
+    if off > u32::max_value() as u64 - n as u64 { return Err("exec: offset too large") }
+    if ip.read(false, x as *mut T as u64, off as u32, n as u32)? != n { return Err("exec: short read") }
+    Ok(())
+}
+
+// Load the segments of ip into pml4, return the entry point. sz follows
+// the memory mapped so far.
+unsafe fn load(pml4: &mut PageTable, ip: &dyn INode, sz: &mut u64) -> Result<u64, &'static str> {
+    let mut elf = ElfHdr::default();
+    read_struct(ip, 0, &mut elf)?;
+    if elf.magic != ELF_MAGIC || elf.elf[0] != ELF_CLASS64 { return Err("exec: not an ELF64 executable") }
+    if elf.phoff > u32::max_value() as u64 { return Err("exec: offset too large") }
+
+    for i in 0..elf.phnum as u64 {
+        let mut ph = ProgHdr::default();
+        read_struct(ip, elf.phoff + i * size_of::<ProgHdr>() as u64, &mut ph)?;
+        if ph.ptype != ELF_PROG_LOAD { continue }
+        if ph.memsz < ph.filesz { return Err("exec: bad segment size") }
+        let end = ph.vaddr.checked_add(ph.memsz).filter(|&e| e <= USERTOP).ok_or("exec: bad segment address")?;
+        if ph.vaddr % PGSIZE != 0 || ph.vaddr < *sz { return Err("exec: bad segment address") }
+
+        let mut flags = Flags::empty();
+        if ph.flags & ELF_PROG_FLAG_WRITE != 0 { flags |= Flags::WRITABLE; }
+        if ph.flags & ELF_PROG_FLAG_EXEC == 0 { flags |= Flags::NO_EXECUTE; }
+        *sz = alloc_uvm(pml4, *sz, end, flags)?;
+        load_uvm(pml4, VA::new(ph.vaddr), ip, ph.off, ph.filesz)?;
+    }
+    Ok(elf.entry)
+}
+
+// Lay out argv on the stack [lo, sp). Returns the new sp and the user
+// address of argv.
+unsafe fn push_args(pml4: &mut PageTable, lo: u64, mut sp: u64, argv: &[&[u8]]) -> Result<(u64, u64), &'static str> {
+    // Room for argv and the return address below the strings.
+    let room = lo + (MAXARG as u64 + 4) * size_of::<u64>() as u64;
+    let mut ustack = [0u64; MAXARG + 1];
+    for (i, arg) in argv.iter().enumerate() {
+        if sp < room + arg.len() as u64 + 8 { return Err("exec: arguments too long") }
+        sp = (sp - arg.len() as u64 - 1) & !7;
+        copy_out_uvm(pml4, sp, arg.as_ptr(), arg.len())?;
+        copy_out_uvm(pml4, sp + arg.len() as u64, [0u8].as_ptr(), 1)?;
+        ustack[i] = sp;
+    }
+    ustack[argv.len()] = 0;
+
+    // argv, then a fake return address, keeping sp 16 byte aligned at the
+    // entry point as if start had been called.
+    let n = (argv.len() + 1) * size_of::<u64>();
+    sp = (sp - n as u64) & !15;
+    let uargv = sp;
+    copy_out_uvm(pml4, uargv, ustack.as_ptr() as *const u8, n)?;
+    sp -= size_of::<u64>() as u64;
+    copy_out_uvm(pml4, sp, [0xffff_ffffu64].as_ptr() as *const u8, size_of::<u64>())?;
+    Ok((sp, uargv))
+}
+
+// Build the new image in pml4, return its entry point, stack pointer and
+// argv. sz follows the memory mapped so far.
+unsafe fn build(pml4: &mut PageTable, path: &str, argv: &[&[u8]], sz: &mut u64)
+    -> Result<(u64, u64, u64), &'static str> {
+    setup_kvm(pml4)?;
+
+    begin_op();
+    let r = namei(path).and_then(|ip| {
+        let r = load(pml4, ip, sz);
+        ip.put();
+        r
+    });
+    end_op();
+    let entry = r?;
+
+    // A guard page, then the stack.
+    let st = VA::new(*sz).align_up(PGSIZE).as_u64();
+    if st + 2 * PGSIZE > USERTOP { return Err("exec: image too large") }
+    *sz = alloc_uvm(pml4, *sz, st + 2 * PGSIZE, Flags::WRITABLE | Flags::NO_EXECUTE)?;
+    protect_uvm(pml4, VA::new(st), PGSIZE as usize, Flags::NO_EXECUTE)?;
+    let (sp, uargv) = push_args(pml4, *sz - PGSIZE, *sz, argv)?;
+    Ok((entry, sp, uargv))
+}
+
+// Run path with the arguments argv. Does not return to the old image on
+// success, the new one starts with argc in rdi and argv in rsi. Returns
+// argc, which the system call leaves in rax.
+pub unsafe fn exec(path: &str, argv: &[&[u8]]) -> Result<u64, &'static str> {
+    if argv.len() > MAXARG { return Err("exec: too many arguments") }
+    let p = my_proc_mut().ok_or("exec: no process")?;
+
+    let pml4 = kalloc().ok_or("exec: out of memory")?.as_mut_ptr::<PageTable>();
+    memset(pml4, 0, PGSIZE);
+    let mut sz = 0;
+    let (entry, sp, uargv) = match build(&mut *pml4, path, argv, &mut sz) {
+        Ok(r) => r,
+        Err(e) => {
+            free_uvm(&mut *pml4, sz as usize);
+            return Err(e);
+        }
+    };
+
+    let name = path.rsplit('/').next().unwrap_or(path);
+    p.set_name(name.as_bytes());
+    let tf = p.get_mut_tf();
+    tf.rip = entry;
+    tf.rsp = sp;
+    tf.rdi = argv.len() as u64;
+    tf.rsi = uargv;
+
+    let (old, oldsz) = p.set_image(&mut *pml4, sz);
+    switch_uvm(p);
+    if let Some(old) = old { free_uvm(old, oldsz as usize); }
+    Ok(argv.len() as u64)
+}
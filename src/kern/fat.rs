@@ -0,0 +1,526 @@
+// Read-only FAT32.
+//
+// Mounts a volume made by mkfs.vfat on the host, either the whole disk or
+// the first FAT32 partition of an MBR, so files can be exchanged with the
+// host. Everything is read through the buffer cache. Long file names are
+// supported, names compare case-insensitively as on FAT. There is no
+// write support yet, files are opened read-only.
+//
+// FAT has no inode numbers. A directory is numbered by its first cluster,
+// which "." and ".." entries agree on; a file by the position of its
+// directory entry, above FILE_INO.
+
+use crate::*;
+use array_init::array_init;
+use core::cell::Cell;
+use crate::kern::bio::{BSIZE, bread, brelse};
+use crate::kern::blk::{blkdev, SECTSIZE};
+use crate::kern::log::{begin_op, end_op};
+use crate::kern::spinlock::SpinLock;
+use crate::kern::uaccess::copy_to_user;
+use crate::kern::vfs::{FileSystem, INode, FsType, Stat, NAME_MAX, T_DIR, T_FILE};
+use crate::kern::vfs::{register_fs, mount_dev, namei};
+
+const DIRENT_SIZE: u32 = 32;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_LFN: u8 = 0x0F;
+const LFN_LAST: u8 = 0x40;
+const DELETED: u8 = 0xE5;
+
+const NT_LOWER_BASE: u8 = 0x08;         // Short name stored upper case, show lower case
+const NT_LOWER_EXT: u8 = 0x10;
+
+const FAT_MASK: u32 = 0x0FFFFFFF;
+const FAT_BAD: u32 = 0x0FFFFFF7;
+const FAT_EOC: u32 = 0x0FFFFFF8;        // This and above end a chain
+
+const MIN_CLUSTERS: u32 = 65525;        // Fewer clusters make it FAT12/16
+
+const MBR_PART: usize = 0x1BE;
+const PART_FAT32: u8 = 0x0B;
+const PART_FAT32_LBA: u8 = 0x0C;
+
+pub const FAT_NAME_MAX: usize = NAME_MAX;
+
+const NFAT: usize = 2;                  // Mounted volumes
+const NFATINODE: usize = 32;            // Active inodes of all volumes
+const FILE_INO: u64 = 1 << 28;          // Cluster numbers are 28 bits
+
+pub struct FatFs {
+    dev: usize,
+    clus_bytes: u32,                    // Bytes per cluster
+    fat: u64,                           // Disk offsets of the first FAT
+    data: u64,                          // and of cluster 2
+    nclusters: u32,
+    root: u32,                          // First cluster of the root directory
+}
+
+// A file or directory. Directory sizes are not recorded, they end at the
+// first free entry or the end of the chain.
+#[derive(Copy, Clone)]
+pub struct FatNode {
+    fs: &'static FatFs,
+    first: u32,                         // First cluster, 0 for an empty file
+    pub size: u32,
+    pub dir: bool,
+    pos: u64,                           // Disk offset of the directory entry, 0 for the root
+}
+
+static FAT_LOCK: SpinLock = SpinLock::new();
+static mut FATFS: [Option<FatFs>; NFAT] = [None, None];
+
+fn le16(b: &[u8], off: usize) -> u16 { u16::from_le_bytes([b[off], b[off + 1]]) }
+fn le32(b: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes([b[off], b[off + 1], b[off + 2], b[off + 3]])
+}
+
+// Copy n bytes at disk offset off of dev through the buffer cache.
+// If user is set, dst is a user virtual address, otherwise a kernel address.
+fn disk_read(dev: usize, off: u64, user: bool, dst: u64, n: usize) -> Result<(), &'static str> {
+    let mut off = off;
+    let mut dst = dst;
+    let mut tot = 0;
+    while tot < n {
+        let bp = bread(dev, (off / BSIZE as u64) as u32)?;
+        let st = (off % BSIZE as u64) as usize;
+        let m = core::cmp::min(n - tot, BSIZE - st);
+        let src = &bp.data[st..st + m];
+        let r = if user {
+            unsafe { copy_to_user(dst, src.as_ptr(), m) }
+        } else {
+            unsafe { memmove(dst as *mut u8, src.as_ptr(), m); }
+            0
+        };
+        brelse(bp);
+        if r < 0 { return Err("fat: bad address") }
+        tot += m;
+        off += m as u64;
+        dst += m as u64;
+    }
+    Ok(())
+}
+
+fn read_sector(dev: usize, off: u64, buf: &mut [u8; SECTSIZE]) -> Result<(), &'static str> {
+    disk_read(dev, off, false, buf.as_mut_ptr() as u64, SECTSIZE)
+}
+
+impl FatFs {
+    // Parse the BIOS parameter block of the volume at disk offset base.
+    fn probe(dev: usize, base: u64) -> Result<FatFs, &'static str> {
+        let mut bs = [0u8; SECTSIZE];
+        read_sector(dev, base, &mut bs)?;
+        if bs[510] != 0x55 || bs[511] != 0xAA { return Err("fat: no boot signature") }
+
+        let bytes_per_sec = le16(&bs, 11) as u32;
+        let sec_per_clus = bs[13] as u32;
+        let rsvd = le16(&bs, 14) as u32;
+        let nfats = bs[16] as u32;
+        let root_ents = le16(&bs, 17);
+        let fat_sz16 = le16(&bs, 22);
+        let tot_sec = if le16(&bs, 19) != 0 { le16(&bs, 19) as u32 } else { le32(&bs, 32) };
+        let fat_sz = le32(&bs, 36);
+        let root = le32(&bs, 44);
+
+        if !bytes_per_sec.is_power_of_two() || bytes_per_sec < 512 || bytes_per_sec > 4096 {
+            return Err("fat: bad sector size")
+        }
+        if sec_per_clus == 0 || !sec_per_clus.is_power_of_two() { return Err("fat: bad cluster size") }
+        if nfats == 0 || rsvd == 0 { return Err("fat: bad BPB") }
+        if root_ents != 0 || fat_sz16 != 0 || fat_sz == 0 { return Err("fat: not FAT32") }
+
+        let data_sec = rsvd + nfats * fat_sz;
+        if data_sec >= tot_sec { return Err("fat: bad BPB") }
+        let nclusters = (tot_sec - data_sec) / sec_per_clus;
+        if nclusters < MIN_CLUSTERS { return Err("fat: not FAT32") }
+        if root < 2 || root >= nclusters + 2 { return Err("fat: bad root cluster") }
+
+        Ok(FatFs {
+            dev: dev,
+            clus_bytes: bytes_per_sec * sec_per_clus,
+            fat: base + (rsvd * bytes_per_sec) as u64,
+            data: base + (data_sec as u64) * bytes_per_sec as u64,
+            nclusters: nclusters,
+            root: root,
+        })
+    }
+
+    // The FAT32 volume on dev: the whole disk, or the first FAT32 partition.
+    fn find(dev: usize) -> Result<FatFs, &'static str> {
+        let r = FatFs::probe(dev, 0);
+        if r.is_ok() { return r }
+
+        let mut mbr = [0u8; SECTSIZE];
+        read_sector(dev, 0, &mut mbr)?;
+        if mbr[510] != 0x55 || mbr[511] != 0xAA { return r }
+        for i in 0..4 {
+            let p = MBR_PART + i * 16;
+            if mbr[p + 4] == PART_FAT32 || mbr[p + 4] == PART_FAT32_LBA {
+                return FatFs::probe(dev, le32(&mbr, p + 8) as u64 * SECTSIZE as u64);
+            }
+        }
+        r
+    }
+
+    fn valid(&self, clus: u32) -> bool { clus >= 2 && clus < self.nclusters + 2 }
+
+    // The cluster after clus in its chain, None at the end.
+    fn next(&self, clus: u32) -> Result<Option<u32>, &'static str> {
+        let mut e = [0u8; 4];
+        disk_read(self.dev, self.fat + clus as u64 * 4, false, e.as_mut_ptr() as u64, 4)?;
+        let next = u32::from_le_bytes(e) & FAT_MASK;
+        if next >= FAT_EOC { return Ok(None) }
+        if next == FAT_BAD || !self.valid(next) { return Err("fat: broken cluster chain") }
+        Ok(Some(next))
+    }
+
+    fn cluster_off(&self, clus: u32) -> u64 {
+        self.data + (clus - 2) as u64 * self.clus_bytes as u64
+    }
+
+    fn dir_node(&'static self, clus: u32) -> FatNode {
+        FatNode { fs: self, first: clus, size: 0, dir: true, pos: 0 }
+    }
+
+    // The node of directory entry e, found at disk offset pos.
+    fn entry_node(&'static self, e: &[u8], pos: u64) -> FatNode {
+        let dir = e[11] & ATTR_DIRECTORY != 0;
+        let first = (le16(e, 20) as u32) << 16 | le16(e, 26) as u32;
+        // ".." of a directory in the root refers to cluster 0.
+        if dir { return self.dir_node(if first == 0 { self.root } else { first }) }
+        FatNode { fs: self, first: first, size: le32(e, 28), dir: false, pos: pos }
+    }
+}
+
+// Case-insensitive, as FAT looks names up.
+fn name_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).all(|(x, y)| x.eq_ignore_ascii_case(y))
+}
+
+// Checksum of a short name, stored in its long name entries.
+fn lfn_checksum(short: &[u8]) -> u8 {
+    short[..11].iter().fold(0u8, |sum, &c| ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(c))
+}
+
+// Display form of an 8.3 name, e.g. "README  TXT" to "README.TXT".
+fn short_name(e: &[u8], name: &mut [u8; FAT_NAME_MAX]) -> usize {
+    let nt = e[12];
+    let mut n = 0;
+    for &c in e[..8].iter().take_while(|&&c| c != b' ') {
+        name[n] = if nt & NT_LOWER_BASE != 0 { c.to_ascii_lowercase() } else { c };
+        n += 1;
+    }
+    if n > 0 && name[0] == 0x05 { name[0] = DELETED; }    // 0xE5 as first character
+    if e[8] != b' ' {
+        name[n] = b'.';
+        n += 1;
+        for &c in e[8..11].iter().take_while(|&&c| c != b' ') {
+            name[n] = if nt & NT_LOWER_EXT != 0 { c.to_ascii_lowercase() } else { c };
+            n += 1;
+        }
+    }
+    n
+}
+
+impl FatNode {
+    fn ino(&self) -> u64 {
+        if self.dir { self.first as u64 } else { FILE_INO + self.pos / DIRENT_SIZE as u64 }
+    }
+
+    // Read data from the file. If user is set, dst is a user virtual
+    // address; otherwise, dst is a kernel address.
+    // Returns the number of bytes read.
+    pub fn read(&self, user: bool, dst: u64, off: u32, n: u32) -> Result<usize, &'static str> {
+        if self.dir { return Err("fat: is a directory") }
+        if off >= self.size { return Ok(0) }
+        let n = core::cmp::min(n, self.size - off);
+        let cb = self.fs.clus_bytes;
+
+        // Skip to the cluster holding off.
+        let mut clus = self.first;
+        for _ in 0..off / cb {
+            clus = self.fs.next(clus)?.ok_or("fat: file shorter than its size")?;
+        }
+
+        let mut off = off;
+        let mut dst = dst;
+        let mut tot = 0;
+        loop {
+            if !self.fs.valid(clus) { return Err("fat: broken cluster chain") }
+            let m = core::cmp::min(n - tot, cb - off % cb);
+            disk_read(self.fs.dev, self.fs.cluster_off(clus) + (off % cb) as u64, user, dst, m as usize)?;
+            tot += m;
+            off += m;
+            dst += m as u64;
+            if tot == n { break }
+            clus = self.fs.next(clus)?.ok_or("fat: file shorter than its size")?;
+        }
+        Ok(tot as usize)
+    }
+
+    // Call f with the name and node of every entry of the directory, in
+    // order, until it returns true.
+    pub fn for_each(&self, f: &mut dyn FnMut(&[u8], FatNode) -> bool) -> Result<(), &'static str> {
+        if !self.dir { return Err("fat: not a directory") }
+
+        let mut lfn = [0u8; FAT_NAME_MAX];
+        let mut lfn_len = 0;
+        let mut lfn_sum = 0u8;
+        let mut name = [0u8; FAT_NAME_MAX];
+        let mut clus = Some(self.first);
+        while let Some(c) = clus {
+            if !self.fs.valid(c) { return Err("fat: broken cluster chain") }
+            let base = self.fs.cluster_off(c);
+            for i in 0..self.fs.clus_bytes / DIRENT_SIZE {
+                let pos = base + (i * DIRENT_SIZE) as u64;
+                let mut e = [0u8; DIRENT_SIZE as usize];
+                disk_read(self.fs.dev, pos, false, e.as_mut_ptr() as u64, DIRENT_SIZE as usize)?;
+                if e[0] == 0 { return Ok(()) }              // End of directory
+                if e[0] == DELETED {
+                    lfn_len = 0;
+                    continue;
+                }
+
+                let attr = e[11];
+                if attr & ATTR_LFN == ATTR_LFN {
+                    // 13 UCS-2 characters per entry, stored last part first.
+                    let ord = (e[0] & !LFN_LAST) as usize;
+                    if e[0] & LFN_LAST != 0 {
+                        lfn_len = 0;
+                        lfn_sum = e[13];
+                    }
+                    if ord == 0 || ord * 13 > FAT_NAME_MAX + 13 || e[13] != lfn_sum { continue }
+                    for (k, &o) in [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30].iter().enumerate() {
+                        let pos = (ord - 1) * 13 + k;
+                        let ch = le16(&e, o);
+                        if ch == 0 || ch == 0xFFFF || pos >= FAT_NAME_MAX { break }
+                        // Anything beyond ASCII shows up as '?'.
+                        lfn[pos] = if ch < 0x80 { ch as u8 } else { b'?' };
+                        lfn_len = core::cmp::max(lfn_len, pos + 1);
+                    }
+                    continue;
+                }
+                if attr & ATTR_VOLUME_ID != 0 {
+                    lfn_len = 0;
+                    continue;
+                }
+
+                let n = if lfn_len > 0 && lfn_checksum(&e) == lfn_sum {
+                    name[..lfn_len].copy_from_slice(&lfn[..lfn_len]);
+                    lfn_len
+                } else {
+                    short_name(&e, &mut name)
+                };
+                lfn_len = 0;
+
+                if f(&name[..n], self.fs.entry_node(&e, pos)) { return Ok(()) }
+            }
+            clus = self.fs.next(c)?;
+        }
+        Ok(())
+    }
+
+    // Look for an entry in the directory.
+    pub fn lookup(&self, name: &[u8]) -> Result<Option<FatNode>, &'static str> {
+        let mut found = None;
+        self.for_each(&mut |n: &[u8], node: FatNode| {
+            if name_eq(n, name) { found = Some(node); }
+            found.is_some()
+        })?;
+        Ok(found)
+    }
+}
+
+// Inodes
+
+pub struct FatINode {
+    refcnt: Cell<u32>,                  // Protected by FAT_LOCK
+    node: Cell<Option<FatNode>>,        // Set under FAT_LOCK while refcnt is 0
+}
+
+unsafe impl Sync for FatINode {}
+
+lazy_static! {
+    static ref FATINODES: [FatINode; NFATINODE] =
+        array_init(|_| FatINode { refcnt: Cell::new(0), node: Cell::new(None) });
+}
+
+impl FatINode {
+    fn node(&self) -> FatNode { self.node.get().expect("fat: free inode") }
+}
+
+fn same_vol(a: &FatFs, b: &FatFs) -> bool { a as *const FatFs == b as *const FatFs }
+
+// The in-memory inode of node, with a new reference.
+fn fat_iget(node: FatNode) -> Result<&'static FatINode, &'static str> {
+    FAT_LOCK.acquire();
+    let ino = node.ino();
+    let r = match FATINODES.iter()
+        .find(|ip| ip.refcnt.get() > 0 && ip.node.get().map_or(false, |n| same_vol(n.fs, node.fs) && n.ino() == ino)) {
+        Some(ip) => {
+            ip.refcnt.set(ip.refcnt.get() + 1);
+            Ok(ip)
+        }
+        None => match FATINODES.iter().find(|ip| ip.refcnt.get() == 0) {
+            Some(ip) => {
+                ip.refcnt.set(1);
+                ip.node.set(Some(node));
+                Ok(ip)
+            }
+            None => Err("fat: no inodes"),
+        },
+    };
+    FAT_LOCK.release();
+    r
+}
+
+impl INode for FatINode {
+    fn fs(&self) -> &'static dyn FileSystem { self.node().fs }
+
+    fn ino(&self) -> u64 { self.node().ino() }
+
+    fn dup(&'static self) -> &'static dyn INode {
+        FAT_LOCK.acquire();
+        self.refcnt.set(self.refcnt.get() + 1);
+        FAT_LOCK.release();
+        self
+    }
+
+    fn put(&self) -> () {
+        FAT_LOCK.acquire();
+        if self.refcnt.get() < 1 { panic!("fat: put"); }
+        self.refcnt.set(self.refcnt.get() - 1);
+        FAT_LOCK.release();
+    }
+
+    fn stat(&self) -> Result<Stat, &'static str> {
+        let n = self.node();
+        Ok(Stat {
+            itype: if n.dir { T_DIR } else { T_FILE },
+            dev: n.fs.dev as i32,
+            ino: n.ino() as u32,
+            nlink: 1,
+            size: n.size as u64,
+            major: 0,
+            minor: 0,
+        })
+    }
+
+    fn read(&self, user: bool, dst: u64, off: u32, n: u32) -> Result<usize, &'static str> {
+        self.node().read(user, dst, off, n)
+    }
+
+    fn lookup(&self, name: &[u8]) -> Result<Option<&'static dyn INode>, &'static str> {
+        match self.node().lookup(name)? {
+            Some(node) => Ok(Some(fat_iget(node)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn readdir(&self, idx: usize, name: &mut [u8; NAME_MAX]) -> Result<Option<(usize, u64)>, &'static str> {
+        let mut i = 0;
+        let mut found = None;
+        self.node().for_each(&mut |n: &[u8], node: FatNode| {
+            if i == idx {
+                name[..n.len()].copy_from_slice(n);
+                found = Some((n.len(), node.ino()));
+            }
+            i += 1;
+            found.is_some()
+        })?;
+        Ok(found)
+    }
+}
+
+impl FileSystem for FatFs {
+    fn name(&self) -> &'static str { "fat" }
+
+    fn root(&self) -> Result<&'static dyn INode, &'static str> {
+        let fs = unsafe { &*(self as *const FatFs) };
+        Ok(fat_iget(fs.dir_node(fs.root))?)
+    }
+
+    fn iget(&self, ino: u64) -> Result<&'static dyn INode, &'static str> {
+        let fs = unsafe { &*(self as *const FatFs) };
+        if ino < FILE_INO {
+            if !fs.valid(ino as u32) { return Err("fat: bad inode number") }
+            return Ok(fat_iget(fs.dir_node(ino as u32))?);
+        }
+        let pos = (ino - FILE_INO) * DIRENT_SIZE as u64;
+        let mut e = [0u8; DIRENT_SIZE as usize];
+        disk_read(fs.dev, pos, false, e.as_mut_ptr() as u64, DIRENT_SIZE as usize)?;
+        if e[0] == 0 || e[0] == DELETED || e[11] & ATTR_LFN == ATTR_LFN {
+            return Err("fat: stale inode number")
+        }
+        Ok(fat_iget(fs.entry_node(&e, pos))?)
+    }
+
+    fn busy(&self) -> bool {
+        FAT_LOCK.acquire();
+        let busy = FATINODES.iter().any(|ip| ip.refcnt.get() > 0 && ip.node.get().map_or(false, |n| {
+            same_vol(n.fs, self) && !(n.dir && n.first == self.root && ip.refcnt.get() == 1)
+        }));
+        FAT_LOCK.release();
+        busy
+    }
+
+    fn unmount(&self) -> () {
+        FAT_LOCK.acquire();
+        unsafe {
+            for v in FATFS.iter_mut() {
+                if v.as_ref().map_or(false, |v| same_vol(v, self)) { *v = None; }
+            }
+        }
+        FAT_LOCK.release();
+    }
+}
+
+static FAT_TYPE: FsType = FsType { name: "fat", mount: fat_mount, nodev: false };
+
+fn fat_mount(dev: usize) -> Result<&'static dyn FileSystem, &'static str> {
+    let fs = FatFs::find(dev)?;
+    FAT_LOCK.acquire();
+    let r = unsafe {
+        if FATFS.iter().any(|v| v.as_ref().map_or(false, |v| v.dev == dev)) {
+            Err("fat: already mounted")
+        } else {
+            match FATFS.iter_mut().find(|v| v.is_none()) {
+                Some(v) => {
+                    *v = Some(fs);
+                    Ok(v.as_ref().unwrap() as &'static dyn FileSystem)
+                }
+                None => Err("fat: too many volumes"),
+            }
+        }
+    };
+    FAT_LOCK.release();
+    r
+}
+
+// Register the fat type and mount the first FAT32 volume found on any
+// disk at /fat, if the root file system has that directory.
+pub fn fat_init() -> Result<(), &'static str> {
+    register_fs(&FAT_TYPE)?;
+
+    let mut dev = 0;
+    while let Some(d) = blkdev(dev) {
+        if let Ok(fs) = FatFs::find(dev) {
+            begin_op();
+            let r = match namei("/fat") {
+                Ok(ip) => {
+                    ip.put();
+                    mount_dev(dev, "/fat", "fat")
+                }
+                Err(_) => Err("fat: no /fat to mount on"),
+            };
+            end_op();
+            if r.is_ok() {
+                println!("fat: blk{} ({}) on /fat, {} clusters of {} bytes",
+                         dev, d.name(), fs.nclusters, fs.clus_bytes);
+            }
+            return r;
+        }
+        dev += 1;
+    }
+    Ok(())
+}
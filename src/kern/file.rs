@@ -1,21 +1,282 @@
-#[derive(Copy, Clone)]
-pub struct File {
+// Open files.
+//
+// A File is an open inode of any mounted file system plus the mode it was
+// opened with and the offset of the next read or write. Files live in a
+// global table and are reference counted: file descriptors of processes,
+// also the ones copied by fork and dup, point to the same File and share
+// its offset. For directories read by getdents, the offset counts entries.
+// A File may also be one end of a pipe, which has no offset, or a device
+// inode, whose reads and writes go to the driver.
 
-}
+use crate::*;
+use crate::kern::log::{begin_op, end_op, MAXOPBLOCKS};
+use crate::kern::pipe::Pipe;
+use crate::kern::bio::BSIZE;
+use crate::kern::dev::CharDevice;
+use crate::kern::sleeplock::SleepLock;
+use crate::kern::spinlock::SpinLock;
+use crate::kern::uaccess::copy_to_user;
+use crate::kern::vfs::{DirEntry, Stat, NAME_MAX, NOT_DIR, T_DIR};
+use array_init::array_init;
+use core::cell::Cell;
+
+pub use crate::kern::vfs::INode;
+
+const NFILE: usize = 100;               // Open files per system
+
+pub const SEEK_SET: u64 = 0;
+pub const SEEK_CUR: u64 = 1;
+pub const SEEK_END: u64 = 2;
 
 #[derive(Copy, Clone)]
-pub struct INode {
+pub enum FileKind {
+    None,
+    INode(&'static dyn INode),
+    Pipe(&'static Pipe),
+    Device(&'static dyn INode, &'static dyn CharDevice, i16),   // Inode, driver, minor
+}
 
+pub struct File {
+    refcnt: Cell<u32>,                  // Protected by FTABLE_LOCK
+    kind: Cell<FileKind>,
+    readable: Cell<bool>,
+    writable: Cell<bool>,
+    append: Cell<bool>,                 // Every write goes to the end
+    lock: SleepLock,                    // Protects off
+    off: Cell<u64>,
 }
 
-impl File {
-    pub const fn new() -> File { File {
+// refcnt and kind change under FTABLE_LOCK, off under lock. The mode is
+// set by open before the File is handed to anyone else.
+unsafe impl Sync for File {}
 
-    } }
+static FTABLE_LOCK: SpinLock = SpinLock::new();
+lazy_static! {
+    static ref FTABLE: [File; NFILE] = array_init(|_| File::new());
 }
 
-impl INode {
-    pub const fn new() -> INode { INode {
+// Allocate a file structure. It refers to nothing until open is called.
+pub fn file_alloc() -> Result<&'static File, &'static str> {
+    FTABLE_LOCK.acquire();
+    let r = match FTABLE.iter().find(|f| f.refcnt.get() == 0) {
+        Some(f) => {
+            f.refcnt.set(1);
+            f.kind.set(FileKind::None);
+            Ok(f)
+        }
+        None => Err("file: too many open files"),
+    };
+    FTABLE_LOCK.release();
+    r
+}
 
+impl File {
+    const fn new() -> File { File {
+        refcnt: Cell::new(0),
+        kind: Cell::new(FileKind::None),
+        readable: Cell::new(false),
+        writable: Cell::new(false),
+        append: Cell::new(false),
+        lock: SleepLock::new("file"),
+        off: Cell::new(0),
     } }
-}
\ No newline at end of file
+
+    // Make a freshly allocated file refer to kind. Takes over the
+    // reference to the inode.
+    pub fn open(&self, kind: FileKind, readable: bool, writable: bool, append: bool) -> () {
+        self.kind.set(kind);
+        self.readable.set(readable);
+        self.writable.set(writable);
+        self.append.set(append);
+        self.off.set(0);
+    }
+
+    // Increment ref count for file f.
+    pub fn dup(&'static self) -> &'static File {
+        FTABLE_LOCK.acquire();
+        if self.refcnt.get() < 1 { panic!("file dup"); }
+        self.refcnt.set(self.refcnt.get() + 1);
+        FTABLE_LOCK.release();
+        self
+    }
+
+    // Close file f. (Decrement ref count, close when reaches 0.)
+    // Must not be called inside a transaction.
+    pub fn close(&self) -> () {
+        FTABLE_LOCK.acquire();
+        if self.refcnt.get() < 1 { panic!("file close"); }
+        self.refcnt.set(self.refcnt.get() - 1);
+        if self.refcnt.get() > 0 {
+            FTABLE_LOCK.release();
+            return;
+        }
+        let kind = self.kind.replace(FileKind::None);
+        FTABLE_LOCK.release();
+
+        match kind {
+            FileKind::INode(ip) | FileKind::Device(ip, _, _) => {
+                begin_op();
+                ip.put();
+                end_op();
+            }
+            FileKind::Pipe(pi) => pi.close(self.writable.get()),
+            FileKind::None => (),
+        }
+    }
+
+    // Get metadata about file f.
+    pub fn stat(&self) -> Result<Stat, &'static str> {
+        match self.kind.get() {
+            FileKind::INode(ip) | FileKind::Device(ip, _, _) => ip.stat(),
+            FileKind::Pipe(_) => Err("file: is a pipe"),
+            FileKind::None => Err("file: not open"),
+        }
+    }
+
+    // Read up to n bytes into user address addr.
+    pub fn read(&self, addr: u64, n: u32) -> Result<usize, &'static str> {
+        if !self.readable.get() { return Err("file: not readable") }
+        match self.kind.get() {
+            FileKind::INode(ip) => {
+                self.lock.acquire();
+                // Inodes end below 4GB.
+                let off = self.off.get();
+                let r = if off > u32::max_value() as u64 { Ok(0) } else { ip.read(true, addr, off as u32, n) };
+                if let Ok(r) = r { self.off.set(off + r as u64); }
+                self.lock.release();
+                r
+            }
+            FileKind::Pipe(pi) => pi.read(addr, n),
+            // Not under lock: a terminal read may wait long, and the
+            // File is often shared with the writer of the same terminal.
+            FileKind::Device(_, dev, minor) => {
+                let r = dev.read(minor, true, addr, self.off.get(), n);
+                if let Ok(r) = r { self.off.set(self.off.get() + r as u64); }
+                r
+            }
+            FileKind::None => Err("file: not open"),
+        }
+    }
+
+    // Write n bytes from user address addr.
+    pub fn write(&self, addr: u64, n: u32) -> Result<usize, &'static str> {
+        if !self.writable.get() { return Err("file: not writable") }
+        let ip = match self.kind.get() {
+            FileKind::INode(ip) => ip,
+            FileKind::Pipe(pi) => return pi.write(addr, n),
+            FileKind::Device(_, dev, minor) => {
+                let r = dev.write(minor, true, addr, self.off.get(), n);
+                if let Ok(r) = r { self.off.set(self.off.get() + r as u64); }
+                return r;
+            }
+            FileKind::None => return Err("file: not open"),
+        };
+
+        self.lock.acquire();
+        let r = self.write_locked(ip, addr, n);
+        self.lock.release();
+        r
+    }
+
+    fn write_locked(&self, ip: &dyn INode, addr: u64, n: u32) -> Result<usize, &'static str> {
+        if self.append.get() { self.off.set(ip.stat()?.size as u64); }
+
+        // Write a few blocks at a time to avoid exceeding
+        // the maximum log transaction size, including
+        // inode, indirect block, allocation blocks,
+        // and 2 blocks of slop for non-aligned writes.
+        let max = (((MAXOPBLOCKS - 1 - 1 - 2) / 2) * BSIZE) as u32;
+        let mut i = 0;
+        while i < n {
+            let n1 = core::cmp::min(n - i, max);
+            let off = self.off.get();
+            if off > u32::max_value() as u64 { return Err("file: bad offset") }
+
+            begin_op();
+            let r = ip.write(true, addr + i as u64, off as u32, n1);
+            end_op();
+
+            let r = r?;
+            self.off.set(off + r as u64);
+            if r != n1 as usize { return Err("file: short write") }
+            i += n1;
+        }
+        Ok(n as usize)
+    }
+
+    // Copy as many directory entries as fit into n bytes at user address
+    // addr, as DirEntry records. Returns the number of bytes used, 0 at
+    // the end of the directory.
+    pub fn getdents(&self, addr: u64, n: u32) -> Result<usize, &'static str> {
+        if !self.readable.get() { return Err("file: not readable") }
+        let ip = match self.kind.get() {
+            FileKind::INode(ip) => ip,
+            FileKind::Pipe(_) | FileKind::Device(..) => return Err(NOT_DIR),
+            FileKind::None => return Err("file: not open"),
+        };
+        if ip.stat()?.itype != T_DIR { return Err(NOT_DIR) }
+
+        self.lock.acquire();
+        let r = (|| -> Result<usize, &'static str> {
+            const HDR: usize = size_of::<DirEntry>();
+            let mut rec = [0u8; HDR + NAME_MAX + 8];
+            let mut tot = 0;
+            loop {
+                let mut name = [0; NAME_MAX];
+                let (len, ino) = match ip.readdir(self.off.get() as usize, &mut name)? {
+                    Some(e) => e,
+                    None => break,
+                };
+                let reclen = (HDR + len + 1 + 7) & !7;
+                if tot + reclen > n as usize {
+                    if tot == 0 { return Err("getdents: buffer too small") }
+                    break;
+                }
+                for b in rec[..reclen].iter_mut() { *b = 0; }
+                let de = DirEntry { ino: ino, reclen: reclen as u16, namelen: len as u16 };
+                unsafe { core::ptr::write_unaligned(rec.as_mut_ptr() as *mut DirEntry, de); }
+                rec[HDR..HDR + len].copy_from_slice(&name[..len]);
+                if unsafe { copy_to_user(addr + tot as u64, rec.as_ptr(), reclen) } < 0 {
+                    return Err("bad address");
+                }
+                tot += reclen;
+                self.off.set(self.off.get() + 1);
+            }
+            Ok(tot)
+        })();
+        self.lock.release();
+        r
+    }
+
+    // Move the offset to off relative to the start, the current offset
+    // or the end, by whence. Returns the new offset. Devices, e.g. disks,
+    // take 64 bit offsets, inodes end below 4GB.
+    pub fn seek(&self, off: i64, whence: u64) -> Result<u64, &'static str> {
+        let (ip, max) = match self.kind.get() {
+            FileKind::INode(ip) => (ip, u32::max_value() as i64),
+            FileKind::Device(ip, _, _) => (ip, i64::max_value()),
+            FileKind::Pipe(_) => return Err("file: illegal seek"),
+            FileKind::None => return Err("file: not open"),
+        };
+
+        self.lock.acquire();
+        let r = (|| -> Result<u64, &'static str> {
+            let st = ip.stat()?;
+            // Directory offsets are entry numbers: only rewinding and
+            // returning to a position told before make sense.
+            if st.itype == T_DIR && whence != SEEK_SET { return Err("file: is a directory") }
+            let base = match whence {
+                SEEK_SET => 0,
+                SEEK_CUR => self.off.get() as i64,
+                SEEK_END => st.size as i64,
+                _ => return Err("file: bad whence"),
+            };
+            let off = base.checked_add(off).ok_or("file: bad offset")?;
+            if off < 0 || off > max { return Err("file: bad offset") }
+            self.off.set(off as u64);
+            Ok(off as u64)
+        })();
+        self.lock.release();
+        r
+    }
+}
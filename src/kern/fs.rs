@@ -0,0 +1,1135 @@
+// File system implementation. Five layers:
+//   + Blocks: allocator for raw disk blocks.
+//   + Log: crash recovery for multi-step updates.
+//   + Files: inode allocator, reading, writing, metadata.
+//   + Directories: inode with special contents (list of other inodes!)
+//   + Names: paths like /usr/rtm/xv6/fs.c for convenient naming,
+//     resolved by kern::vfs across all mounted file systems.
+//
+// This file contains the low-level file system manipulation
+// routines, and the FileSystem and RosINode of kern::vfs for them.
+// The type is called rosfs.
+//
+// Disk layout:
+// [ boot block | super block | log | inode blocks | free bit map | data blocks ]
+//
+// tools/mkfs computes the super block and builds an initial file system.
+
+use crate::*;
+use crate::kern::bio::{Buf, BSIZE, bread, brelse};
+use crate::kern::blk::{blkdev, SECTSIZE};
+use crate::kern::log::{init_log, log_write};
+use crate::kern::sleeplock::SleepLock;
+use crate::kern::spinlock::SpinLock;
+use crate::kern::vfs::{FileSystem, INode, FsType, Stat, NAME_MAX, NOT_FOUND, NOT_DIR, T_DIR, T_FILE, T_DEV};
+use crate::kern::vfs::{same_fs, register_fs, mount_root, root_mounted, find_blkdev, namei};
+use crate::kern::param::param_str;
+use crate::kern::uaccess::{copy_to_user, copy_from_user};
+use core::cell::{Cell, UnsafeCell};
+
+pub const ROOTINO: u32 = 1;             // Root i-number
+pub const FSMAGIC: u32 = 0x10203040;
+
+pub const NDIRECT: usize = 11;
+pub const NINDIRECT: usize = BSIZE / size_of::<u32>();
+pub const MAXFILE: usize = NDIRECT + NINDIRECT + NINDIRECT * NINDIRECT;
+
+// Directory is a file containing a sequence of dirent structures.
+pub const DIRSIZ: usize = 14;
+
+const NINODE: usize = 50;               // Maximum number of active i-nodes
+
+// Super block, describes the disk layout.
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct SuperBlock {
+    pub magic: u32,                     // Must be FSMAGIC
+    pub size: u32,                      // Size of file system image (blocks)
+    pub nblocks: u32,                   // Number of data blocks
+    pub ninodes: u32,                   // Number of inodes.
+    pub nlog: u32,                      // Number of log blocks
+    pub logstart: u32,                  // Block number of first log block
+    pub inodestart: u32,                // Block number of first inode block
+    pub bmapstart: u32,                 // Block number of first free map block
+}
+
+// On-disk inode structure
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct DInode {
+    pub itype: i16,                     // File type
+    pub major: i16,                     // Major device number (T_DEV only)
+    pub minor: i16,                     // Minor device number (T_DEV only)
+    pub nlink: i16,                     // Number of links to inode in file system
+    pub size: u32,                      // Size of file (bytes)
+    pub addrs: [u32; NDIRECT + 2],      // Data block addresses
+}
+
+impl DInode {
+    const fn new() -> Self {
+        DInode { itype: 0, major: 0, minor: 0, nlink: 0, size: 0, addrs: [0; NDIRECT + 2] }
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct Dirent {
+    pub inum: u16,
+    pub name: [u8; DIRSIZ],
+}
+
+// Inodes per block.
+const IPB: u32 = (BSIZE / size_of::<DInode>()) as u32;
+// Bitmap bits per block
+const BPB: u32 = (BSIZE * 8) as u32;
+
+const DIRENT_SIZE: u32 = size_of::<Dirent>() as u32;
+
+static mut SB: SuperBlock = SuperBlock {
+    magic: 0, size: 0, nblocks: 0, ninodes: 0, nlog: 0, logstart: 0, inodestart: 0, bmapstart: 0,
+};
+static mut ROOTDEV: usize = 0;
+
+// Block containing inode i
+fn iblock(inum: u32) -> u32 { unsafe { inum / IPB + SB.inodestart } }
+
+// Block of free map containing bit for block b
+fn bblock(b: u32) -> u32 { unsafe { b / BPB + SB.bmapstart } }
+
+fn dinode(bp: &mut Buf, inum: u32) -> &mut DInode {
+    unsafe { &mut *(bp.data.as_mut_ptr() as *mut DInode).offset((inum % IPB) as isize) }
+}
+
+pub fn root_dev() -> usize { unsafe { ROOTDEV } }
+
+// Read the super block.
+fn readsb(dev: usize) -> Result<SuperBlock, &'static str> {
+    let bp = bread(dev, 1)?;
+    let sb = unsafe { *(bp.data.as_ptr() as *const SuperBlock) };
+    brelse(bp);
+    Ok(sb)
+}
+
+// Zero a block.
+fn bzero(dev: usize, bno: u32) -> Result<(), &'static str> {
+    let bp = bread(dev, bno)?;
+    for b in bp.data.iter_mut() { *b = 0; }
+    log_write(bp);
+    brelse(bp);
+    Ok(())
+}
+
+// Blocks.
+
+// Allocate a zeroed disk block.
+fn balloc(dev: usize) -> Result<u32, &'static str> {
+    let size = unsafe { SB.size };
+    let mut b = 0;
+    while b < size {
+        let bp = bread(dev, bblock(b))?;
+        let mut bi = 0;
+        while bi < BPB && b + bi < size {
+            let m = 1u8 << (bi % 8);
+            if bp.data[(bi / 8) as usize] & m == 0 {  // Is block free?
+                bp.data[(bi / 8) as usize] |= m;      // Mark block in use.
+                log_write(bp);
+                brelse(bp);
+                bzero(dev, b + bi)?;
+                return Ok(b + bi);
+            }
+            bi += 1;
+        }
+        brelse(bp);
+        b += BPB;
+    }
+    Err("balloc: out of blocks")
+}
+
+// Free a disk block.
+fn bfree(dev: usize, b: u32) -> Result<(), &'static str> {
+    let bp = bread(dev, bblock(b))?;
+    let bi = b % BPB;
+    let m = 1u8 << (bi % 8);
+    if bp.data[(bi / 8) as usize] & m == 0 { panic!("freeing free block"); }
+    bp.data[(bi / 8) as usize] &= !m;
+    log_write(bp);
+    brelse(bp);
+    Ok(())
+}
+
+// Inodes.
+//
+// An inode describes a single unnamed file.
+// The inode disk structure holds metadata: the file's type,
+// its size, the number of links referring to it, and the
+// list of blocks holding the file's content.
+//
+// The inodes are laid out sequentially on disk at
+// SB.inodestart. Each inode has a number, indicating its
+// position on the disk.
+//
+// The kernel keeps a table of in-use inodes in memory
+// to provide a place for synchronizing access
+// to inodes used by multiple processes. The in-memory
+// inodes include book-keeping information that is
+// not stored on disk: refcnt and valid.
+//
+// An inode and its in-memory representation go through a
+// sequence of states before they can be used by the
+// rest of the file system code.
+//
+// * Allocation: an inode is allocated if its type (on disk)
+//   is non-zero. ialloc() allocates, and iput() frees if
+//   the reference and link counts have fallen to zero.
+//
+// * Referencing in table: an entry in the inode table
+//   is free if refcnt is zero. Otherwise refcnt tracks
+//   the number of in-memory pointers to the entry (open
+//   files and current directories). iget() finds or
+//   creates a table entry and increments its ref; iput()
+//   decrements ref.
+//
+// * Valid: the information (type, size, &c) in an inode
+//   table entry is only correct when valid is set.
+//   ilock() reads the inode from
+//   the disk and sets valid, while iput() clears
+//   valid if refcnt has fallen to zero.
+//
+// * Locked: file system code may only examine and modify
+//   the information in an inode and its content if it
+//   has first locked the inode.
+//
+// Thus a typical sequence is:
+//   let ip = iget(dev, inum)?;
+//   ip.ilock()?;
+//   ... examine and modify ip.d() / ip.d_mut() ...
+//   ip.iunlock();
+//   ip.iput();
+//
+// ilock() is separate from iget() so that system calls can
+// get a long-term reference to an inode (as for an open file)
+// and only lock it for short periods (e.g., in read()).
+// The separation also helps avoid deadlock and races during
+// pathname lookup. iget() increments refcnt so that the inode
+// stays in the table and pointers to it remain valid.
+//
+// ITABLE_LOCK protects the allocation of table entries: dev, inum
+// and refcnt. The lock of an inode protects everything else.
+//
+// Many internal file system functions expect the caller to
+// have locked the inodes involved; this lets callers create
+// multi-step atomic operations.
+
+pub struct RosINode {
+    dev: Cell<usize>,                   // Device number
+    inum: Cell<u32>,                    // Inode number
+    refcnt: Cell<u32>,                  // Reference count
+    lock: SleepLock,                    // Protects everything below here
+    valid: Cell<bool>,                  // Inode has been read from disk?
+    d: UnsafeCell<DInode>,              // Copy of disk inode
+}
+
+unsafe impl Sync for RosINode {}
+
+static ITABLE_LOCK: SpinLock = SpinLock::new();
+static ITABLE: [RosINode; NINODE] = [
+    RosINode::new(), RosINode::new(), RosINode::new(), RosINode::new(), RosINode::new(), RosINode::new(), RosINode::new(), RosINode::new(), RosINode::new(), RosINode::new(),
+    RosINode::new(), RosINode::new(), RosINode::new(), RosINode::new(), RosINode::new(), RosINode::new(), RosINode::new(), RosINode::new(), RosINode::new(), RosINode::new(),
+    RosINode::new(), RosINode::new(), RosINode::new(), RosINode::new(), RosINode::new(), RosINode::new(), RosINode::new(), RosINode::new(), RosINode::new(), RosINode::new(),
+    RosINode::new(), RosINode::new(), RosINode::new(), RosINode::new(), RosINode::new(), RosINode::new(), RosINode::new(), RosINode::new(), RosINode::new(), RosINode::new(),
+    RosINode::new(), RosINode::new(), RosINode::new(), RosINode::new(), RosINode::new(), RosINode::new(), RosINode::new(), RosINode::new(), RosINode::new(), RosINode::new(),
+];
+
+// Allocate an inode on device dev.
+// Mark it as allocated by giving it type itype.
+// Returns an unlocked but allocated and referenced inode.
+pub fn ialloc(dev: usize, itype: i16) -> Result<&'static RosINode, &'static str> {
+    let ninodes = unsafe { SB.ninodes };
+    for inum in 1..ninodes {
+        let bp = bread(dev, iblock(inum))?;
+        let dip = dinode(bp, inum);
+        if dip.itype == 0 {  // a free inode
+            *dip = DInode::new();
+            dip.itype = itype;
+            log_write(bp);   // mark it allocated on the disk
+            brelse(bp);
+            return iget(dev, inum);
+        }
+        brelse(bp);
+    }
+    Err("ialloc: no inodes")
+}
+
+// Find the inode with number inum on device dev
+// and return the in-memory copy. Does not lock
+// the inode and does not read it from disk.
+fn iget(dev: usize, inum: u32) -> Result<&'static RosINode, &'static str> {
+    ITABLE_LOCK.acquire();
+
+    // Is the inode already in the table?
+    let mut empty = None;
+    for ip in ITABLE.iter() {
+        if ip.refcnt.get() > 0 && ip.dev.get() == dev && ip.inum.get() == inum {
+            ip.refcnt.set(ip.refcnt.get() + 1);
+            ITABLE_LOCK.release();
+            return Ok(ip);
+        }
+        if empty.is_none() && ip.refcnt.get() == 0 {   // Remember empty slot.
+            empty = Some(ip);
+        }
+    }
+
+    // Recycle an inode entry.
+    let r = match empty {
+        Some(ip) => {
+            ip.dev.set(dev);
+            ip.inum.set(inum);
+            ip.refcnt.set(1);
+            ip.valid.set(false);
+            Ok(ip)
+        }
+        None => Err("iget: no inodes"),
+    };
+    ITABLE_LOCK.release();
+    r
+}
+
+impl RosINode {
+    const fn new() -> Self {
+        RosINode {
+            dev: Cell::new(0),
+            inum: Cell::new(0),
+            refcnt: Cell::new(0),
+            lock: SleepLock::new("inode"),
+            valid: Cell::new(false),
+            d: UnsafeCell::new(DInode::new()),
+        }
+    }
+
+    pub fn dev(&self) -> usize { self.dev.get() }
+    pub fn inum(&self) -> u32 { self.inum.get() }
+
+    // The copy of the disk inode. Caller must hold the lock, or a
+    // reference keeping it unchanged.
+    pub fn d(&self) -> &DInode { unsafe { &*self.d.get() } }
+
+    // Increment reference count.
+    // Returns self to enable `let ip = ip1.idup()` idiom.
+    pub fn idup(&'static self) -> &'static RosINode {
+        ITABLE_LOCK.acquire();
+        self.refcnt.set(self.refcnt.get() + 1);
+        ITABLE_LOCK.release();
+        self
+    }
+
+    // Lock the inode.
+    // Reads the inode from disk if necessary.
+    pub fn ilock(&self) -> Result<(), &'static str> {
+        if self.refcnt.get() < 1 { panic!("ilock"); }
+        self.lock.acquire();
+        if !self.valid.get() {
+            let bp = match bread(self.dev(), iblock(self.inum())) {
+                Ok(bp) => bp,
+                Err(e) => {
+                    self.lock.release();
+                    return Err(e);
+                }
+            };
+            *self.d_mut() = *dinode(bp, self.inum());
+            self.valid.set(true);
+            brelse(bp);
+            if self.d().itype == 0 { panic!("ilock: no type"); }
+        }
+        Ok(())
+    }
+
+    // Unlock the inode.
+    pub fn iunlock(&self) -> () {
+        if !self.lock.holding() || self.refcnt.get() < 1 { panic!("iunlock"); }
+        self.lock.release();
+    }
+
+    // Drop a reference to an in-memory inode.
+    // If that was the last reference, the inode table entry can
+    // be recycled.
+    // If that was the last reference and the inode has no links
+    // to it, free the inode (and its content) on disk.
+    // All calls to iput() must be inside a transaction in
+    // case it has to free the inode.
+    pub fn iput(&self) -> () {
+        self.lock.acquire();
+        if self.valid.get() && self.d().nlink == 0 {
+            // inode has no links and no other references: truncate and free.
+            ITABLE_LOCK.acquire();
+            let r = self.refcnt.get();
+            ITABLE_LOCK.release();
+
+            if r == 1 {
+                if let Err(e) = self.itrunc() { println!("iput: {}", e); }
+                self.d_mut().itype = 0;
+                if let Err(e) = self.iupdate() { println!("iput: {}", e); }
+                self.valid.set(false);
+            }
+        }
+        self.lock.release();
+
+        ITABLE_LOCK.acquire();
+        self.refcnt.set(self.refcnt.get() - 1);
+        ITABLE_LOCK.release();
+    }
+
+    // Common idiom: unlock, then put.
+    pub fn iunlockput(&self) -> () {
+        self.iunlock();
+        self.iput();
+    }
+
+    pub fn holding(&self) -> bool { self.lock.holding() }
+
+    // Mutable copy of the disk inode, write it back with update().
+    // Caller must hold the lock.
+    pub fn d_mut(&self) -> &mut DInode {
+        if !self.lock.holding() { panic!("d_mut"); }
+        unsafe { &mut *self.d.get() }
+    }
+
+    // Copy a modified in-memory inode to disk.
+    // Must be called after every change to a field
+    // that lives on disk.
+    // Caller must hold the lock.
+    pub fn iupdate(&self) -> Result<(), &'static str> {
+        let bp = bread(self.dev(), iblock(self.inum()))?;
+        *dinode(bp, self.inum()) = *self.d();
+        log_write(bp);
+        brelse(bp);
+        Ok(())
+    }
+
+    // Inode content
+    //
+    // The content (data) associated with each inode is stored
+    // in blocks on the disk. The first NDIRECT block numbers
+    // are listed in d.addrs[]. The next NINDIRECT blocks are
+    // listed in block d.addrs[NDIRECT]. The rest are listed in the
+    // NINDIRECT indirect blocks which block d.addrs[NDIRECT + 1] lists.
+
+    // Return the disk block address of the nth block in the inode,
+    // allocating it if there is no such block.
+    fn bmap(&self, bn: usize) -> Result<u32, &'static str> {
+        let d = self.d_mut();
+        if bn < NDIRECT {
+            if d.addrs[bn] == 0 { d.addrs[bn] = balloc(self.dev())?; }
+            return Ok(d.addrs[bn]);
+        }
+
+        let bn = bn - NDIRECT;
+        if bn < NINDIRECT {
+            // Load indirect block, allocating if necessary.
+            if d.addrs[NDIRECT] == 0 { d.addrs[NDIRECT] = balloc(self.dev())?; }
+            return self.bmap_ind(d.addrs[NDIRECT], bn);
+        }
+
+        let bn = bn - NINDIRECT;
+        if bn >= NINDIRECT * NINDIRECT { panic!("bmap: out of range"); }
+        if d.addrs[NDIRECT + 1] == 0 { d.addrs[NDIRECT + 1] = balloc(self.dev())?; }
+        let ind = self.bmap_ind(d.addrs[NDIRECT + 1], bn / NINDIRECT)?;
+        self.bmap_ind(ind, bn % NINDIRECT)
+    }
+
+    // Return entry i of indirect block ind, allocating the block it
+    // names if there is none.
+    fn bmap_ind(&self, ind: u32, i: usize) -> Result<u32, &'static str> {
+        let bp = bread(self.dev(), ind)?;
+        let a = unsafe { &mut *(bp.data.as_mut_ptr() as *mut [u32; NINDIRECT]) };
+        if a[i] == 0 {
+            match balloc(self.dev()) {
+                Ok(b) => {
+                    a[i] = b;
+                    log_write(bp);
+                }
+                Err(e) => {
+                    brelse(bp);
+                    return Err(e);
+                }
+            }
+        }
+        let addr = a[i];
+        brelse(bp);
+        Ok(addr)
+    }
+
+    // Free indirect block ind and the blocks it lists, which are
+    // indirect blocks themselves if level is more than 1.
+    fn free_ind(&self, ind: u32, level: u32) -> Result<(), &'static str> {
+        let bp = bread(self.dev(), ind)?;
+        let a = unsafe { *(bp.data.as_ptr() as *const [u32; NINDIRECT]) };
+        brelse(bp);
+        for &b in a.iter() {
+            if b == 0 { continue }
+            if level > 1 { self.free_ind(b, level - 1)?; } else { bfree(self.dev(), b)?; }
+        }
+        bfree(self.dev(), ind)
+    }
+
+    // Truncate inode (discard contents).
+    // Caller must hold the lock.
+    pub fn itrunc(&self) -> Result<(), &'static str> {
+        let d = self.d_mut();
+        for i in 0..NDIRECT {
+            if d.addrs[i] != 0 {
+                bfree(self.dev(), d.addrs[i])?;
+                d.addrs[i] = 0;
+            }
+        }
+
+        if d.addrs[NDIRECT] != 0 {
+            self.free_ind(d.addrs[NDIRECT], 1)?;
+            d.addrs[NDIRECT] = 0;
+        }
+        if d.addrs[NDIRECT + 1] != 0 {
+            self.free_ind(d.addrs[NDIRECT + 1], 2)?;
+            d.addrs[NDIRECT + 1] = 0;
+        }
+
+        d.size = 0;
+        self.iupdate()
+    }
+
+    // Read data from inode. Caller must hold the lock.
+    // If user is set, dst is a user virtual address;
+    // otherwise, dst is a kernel address.
+    // Returns the number of bytes read.
+    pub fn readi(&self, user: bool, dst: u64, off: u32, n: u32) -> Result<usize, &'static str> {
+        let size = self.d().size;
+        if off > size || off.checked_add(n).is_none() { return Ok(0) }
+        let n = if off + n > size { size - off } else { n };
+
+        let mut tot = 0;
+        let mut off = off;
+        let mut dst = dst;
+        while tot < n {
+            let bp = bread(self.dev(), self.bmap(off as usize / BSIZE)?)?;
+            let m = core::cmp::min(n - tot, BSIZE as u32 - off % BSIZE as u32);
+            let src = &bp.data[(off as usize % BSIZE)..];
+            let r = if user {
+                unsafe { copy_to_user(dst, src.as_ptr(), m as usize) }
+            } else {
+                unsafe { memmove(dst as *mut u8, src.as_ptr(), m as usize); }
+                0
+            };
+            brelse(bp);
+            if r < 0 { return Err("readi: bad address") }
+            tot += m;
+            off += m;
+            dst += m as u64;
+        }
+        Ok(tot as usize)
+    }
+
+    // Write data to inode. Caller must hold the lock.
+    // If user is set, src is a user virtual address;
+    // otherwise, src is a kernel address.
+    // Returns the number of bytes successfully written.
+    // If the return value is less than the requested n,
+    // there was an error of some kind.
+    pub fn writei(&self, user: bool, src: u64, off: u32, n: u32) -> Result<usize, &'static str> {
+        if off > self.d().size || off.checked_add(n).is_none() { return Err("writei: bad offset") }
+        if off as usize + n as usize > MAXFILE * BSIZE { return Err("writei: file too large") }
+
+        let mut tot = 0;
+        let mut off = off;
+        let mut src = src;
+        while tot < n {
+            let bno = match self.bmap(off as usize / BSIZE) {
+                Ok(b) => b,
+                Err(_) => break,
+            };
+            let bp = bread(self.dev(), bno)?;
+            let m = core::cmp::min(n - tot, BSIZE as u32 - off % BSIZE as u32);
+            let dst = &mut bp.data[(off as usize % BSIZE)..];
+            let r = if user {
+                unsafe { copy_from_user(dst.as_mut_ptr(), src, m as usize) }
+            } else {
+                unsafe { memmove(dst.as_mut_ptr(), src as *const u8, m as usize); }
+                0
+            };
+            if r < 0 {
+                brelse(bp);
+                break;
+            }
+            log_write(bp);
+            brelse(bp);
+            tot += m;
+            off += m;
+            src += m as u64;
+        }
+
+        if off > self.d().size { self.d_mut().size = off; }
+
+        // Write the inode back to disk even if the size didn't change
+        // because the loop above might have called bmap() and added a new
+        // block to d.addrs[].
+        self.iupdate()?;
+        Ok(tot as usize)
+    }
+
+    // Directories
+
+    // Look for a directory entry in a directory.
+    // If found, return the entry's inode and its byte offset.
+    pub fn dirlookup(&self, name: &[u8]) -> Option<(&'static RosINode, u32)> {
+        if self.d().itype != T_DIR { panic!("dirlookup not DIR"); }
+
+        let mut de = Dirent { inum: 0, name: [0; DIRSIZ] };
+        let mut off = 0;
+        while off < self.d().size {
+            match self.readi(false, &mut de as *mut Dirent as u64, off, DIRENT_SIZE) {
+                Ok(n) if n == DIRENT_SIZE as usize => {}
+                _ => panic!("dirlookup read"),
+            }
+            if de.inum != 0 && namecmp(name, &de.name) {
+                // entry matches path element
+                return iget(self.dev(), de.inum as u32).ok().map(|ip| (ip, off));
+            }
+            off += DIRENT_SIZE;
+        }
+        None
+    }
+
+    // Write a new directory entry (name, inum) into the directory.
+    pub fn dirlink(&self, name: &[u8], inum: u32) -> Result<(), &'static str> {
+        // Check that name is not present.
+        if let Some((ip, _)) = self.dirlookup(name) {
+            ip.iput();
+            return Err("dirlink: name exists");
+        }
+
+        // Look for an empty dirent.
+        let mut de = Dirent { inum: 0, name: [0; DIRSIZ] };
+        let mut off = 0;
+        while off < self.d().size {
+            if self.readi(false, &mut de as *mut Dirent as u64, off, DIRENT_SIZE)? != DIRENT_SIZE as usize {
+                panic!("dirlink read");
+            }
+            if de.inum == 0 { break }
+            off += DIRENT_SIZE;
+        }
+
+        de.name = [0; DIRSIZ];
+        let n = core::cmp::min(name.len(), DIRSIZ);
+        de.name[..n].copy_from_slice(&name[..n]);
+        de.inum = inum as u16;
+        if self.writei(false, &de as *const Dirent as u64, off, DIRENT_SIZE)? != DIRENT_SIZE as usize {
+            return Err("dirlink: write");
+        }
+        Ok(())
+    }
+}
+
+// Directory entry names are padded with zeros, at most DIRSIZ long.
+pub fn namecmp(s: &[u8], name: &[u8; DIRSIZ]) -> bool {
+    let len = name.iter().position(|&c| c == 0).unwrap_or(DIRSIZ);
+    let s = &s[..core::cmp::min(s.len(), DIRSIZ)];
+    s == &name[..len]
+}
+
+// Serializes renames, so no directory can move while rename checks that
+// it does not move a directory below itself.
+static RENAME_LOCK: SleepLock = SleepLock::new("rename");
+
+impl RosINode {
+    // Can entries be looked up and added: a directory not yet removed?
+    // Caller must hold the lock.
+    fn live_dir(&self) -> Result<(), &'static str> {
+        if self.d().itype != T_DIR { return Err(NOT_DIR) }
+        if self.d().nlink == 0 { return Err(NOT_FOUND) }
+        Ok(())
+    }
+
+    // Is the directory empty except for "." and ".." ?
+    // Caller must hold the lock.
+    fn isdirempty(&self) -> Result<bool, &'static str> {
+        let mut de = Dirent { inum: 0, name: [0; DIRSIZ] };
+        let mut off = 2 * DIRENT_SIZE;
+        while off < self.d().size {
+            if self.readi(false, &mut de as *mut Dirent as u64, off, DIRENT_SIZE)? != DIRENT_SIZE as usize {
+                panic!("isdirempty: readi");
+            }
+            if de.inum != 0 { return Ok(false) }
+            off += DIRENT_SIZE;
+        }
+        Ok(true)
+    }
+
+    // Allocate a new inode and link it into this directory as name.
+    // Caller must hold the lock of the directory.
+    fn create_locked(&self, name: &[u8], itype: i16, major: i16, minor: i16)
+        -> Result<&'static RosINode, &'static str> {
+        let ip = ialloc(self.dev(), itype)?;
+        if let Err(e) = ip.ilock() {
+            ip.iput();
+            return Err(e);
+        }
+        {
+            let d = ip.d_mut();
+            d.major = major;
+            d.minor = minor;
+            d.nlink = 1;
+        }
+
+        let r = (|| -> Result<(), &'static str> {
+            ip.iupdate()?;
+            if itype == T_DIR {  // Create . and .. entries.
+                // No ip.nlink++ for ".": avoid cyclic ref count.
+                ip.dirlink(b".", ip.inum())?;
+                ip.dirlink(b"..", self.inum())?;
+            }
+            self.dirlink(name, ip.inum())?;
+            if itype == T_DIR {
+                // now that success is guaranteed:
+                self.d_mut().nlink += 1;  // for ".."
+                self.iupdate()?;
+            }
+            Ok(())
+        })();
+
+        match r {
+            Ok(()) => {
+                ip.iunlock();
+                Ok(ip)
+            }
+            Err(e) => {
+                // something went wrong. de-allocate ip.
+                ip.d_mut().nlink = 0;
+                let _ = ip.iupdate();
+                ip.iunlockput();
+                Err(e)
+            }
+        }
+    }
+
+    // Remove the entry name from this directory.
+    // Caller must hold the lock of the directory.
+    fn unlink_locked(&self, name: &[u8]) -> Result<(), &'static str> {
+        let (ip, off) = self.dirlookup(name).ok_or(NOT_FOUND)?;
+        if let Err(e) = ip.ilock() {
+            ip.iput();
+            return Err(e);
+        }
+
+        if ip.d().nlink < 1 { panic!("unlink: nlink < 1"); }
+        let r = (|| -> Result<(), &'static str> {
+            if ip.d().itype == T_DIR && !ip.isdirempty()? { return Err("directory not empty") }
+
+            self.set_entry(off, 0)?;
+            if ip.d().itype == T_DIR {
+                self.d_mut().nlink -= 1;
+                self.iupdate()?;
+            }
+            ip.d_mut().nlink -= 1;
+            ip.iupdate()
+        })();
+        ip.iunlockput();
+        r
+    }
+
+    // Point the directory entry at offset off to inum, 0 to free it.
+    // Caller must hold the lock.
+    fn set_entry(&self, off: u32, inum: u32) -> Result<(), &'static str> {
+        let mut de = Dirent { inum: 0, name: [0; DIRSIZ] };
+        if inum != 0 && self.readi(false, &mut de as *mut Dirent as u64, off, DIRENT_SIZE)? != DIRENT_SIZE as usize {
+            panic!("set_entry: readi");
+        }
+        de.inum = inum as u16;
+        if self.writei(false, &de as *const Dirent as u64, off, DIRENT_SIZE)? != DIRENT_SIZE as usize {
+            panic!("set_entry: writei");
+        }
+        Ok(())
+    }
+
+    // The parent directory, following "..".
+    fn parent(&'static self) -> Result<&'static RosINode, &'static str> {
+        self.ilock()?;
+        let r = self.dirlookup(b"..").map(|(dp, _)| dp).ok_or(NOT_FOUND);
+        self.iunlock();
+        r
+    }
+
+    // Is directory dp, or one of its ancestors, ip?
+    // Caller must hold RENAME_LOCK.
+    fn is_below(dp: &'static RosINode, ip: &RosINode) -> Result<bool, &'static str> {
+        let mut dp = dp.idup();
+        loop {
+            if dp.inum() == ip.inum() {
+                dp.iput();
+                return Ok(true);
+            }
+            if dp.inum() == ROOTINO {
+                dp.iput();
+                return Ok(false);
+            }
+            let next = dp.parent();
+            dp.iput();
+            dp = next?;
+        }
+    }
+
+    // May a directory (isdir) or file replace old?
+    fn replaceable(old: &RosINode, isdir: bool) -> Result<(), &'static str> {
+        old.ilock()?;
+        let r = match (old.d().itype == T_DIR, isdir) {
+            (true, false) => Err("is a directory"),
+            (false, true) => Err(NOT_DIR),
+            (true, true) => match old.isdirempty() {
+                Ok(true) => Ok(()),
+                Ok(false) => Err("directory not empty"),
+                Err(e) => Err(e),
+            },
+            (false, false) => Ok(()),
+        };
+        old.iunlock();
+        r
+    }
+
+    // Move ip, the entry name of this directory, to newname in nd.
+    // Caller must hold RENAME_LOCK and no inode locks.
+    fn rename_locked(&self, name: &[u8], ip: &'static RosINode, nd: &'static RosINode, newname: &[u8])
+        -> Result<(), &'static str> {
+        ip.ilock()?;
+        let isdir = ip.d().itype == T_DIR;
+        ip.iunlock();
+        let moved = self.inum() != nd.inum();
+        if isdir && moved && RosINode::is_below(nd, ip)? { return Err("invalid argument") }
+
+        // Enter ip as newname. Returns the inode it replaced, if any, or
+        // None if newname is ip already and there is nothing to do.
+        nd.ilock()?;
+        let r = (|| -> Result<Option<Option<&'static RosINode>>, &'static str> {
+            nd.live_dir()?;
+            let old = match nd.dirlookup(newname) {
+                Some((old, _)) if old.inum() == ip.inum() => {
+                    // Both names are links to the same file.
+                    old.iput();
+                    return Ok(None);
+                }
+                Some((old, off)) => {
+                    if let Err(e) = RosINode::replaceable(old, isdir).and_then(|_| nd.set_entry(off, ip.inum())) {
+                        old.iput();
+                        return Err(e);
+                    }
+                    if isdir {
+                        nd.d_mut().nlink -= 1;  // for the ".." of old
+                    }
+                    Some(old)
+                }
+                None => {
+                    nd.dirlink(newname, ip.inum())?;
+                    None
+                }
+            };
+            if isdir && moved {
+                nd.d_mut().nlink += 1;      // for the ".." of ip
+            }
+            nd.iupdate()?;
+            Ok(Some(old))
+        })();
+        nd.iunlock();
+        let old = match r? {
+            Some(old) => old,
+            None => return Ok(()),
+        };
+
+        // Drop the old name.
+        self.ilock()?;
+        let r = match self.dirlookup(name) {
+            Some((dp, off)) if dp.inum() == ip.inum() => {
+                dp.iput();
+                self.set_entry(off, 0)
+            }
+            _ => panic!("rename: entry changed"),
+        };
+        let r = r.and_then(|_| {
+            if !(isdir && moved) { return Ok(()) }
+            self.d_mut().nlink -= 1;
+            self.iupdate()
+        });
+        self.iunlock();
+        r?;
+
+        // A moved directory gets a new parent.
+        if isdir && moved {
+            ip.ilock()?;
+            let r = match ip.dirlookup(b"..") {
+                Some((dp, off)) => {
+                    dp.iput();
+                    ip.set_entry(off, nd.inum())
+                }
+                None => Err("rename: no .. entry"),
+            };
+            ip.iunlock();
+            r?;
+        }
+
+        // The replaced inode loses its link, and is freed if that was the last.
+        if let Some(old) = old {
+            old.ilock()?;
+            old.d_mut().nlink -= 1;
+            let r = old.iupdate();
+            old.iunlockput();
+            r?;
+        }
+        Ok(())
+    }
+}
+
+impl INode for RosINode {
+    fn fs(&self) -> &'static dyn FileSystem { &ROSFS }
+
+    fn ino(&self) -> u64 { self.inum() as u64 }
+
+    fn dup(&'static self) -> &'static dyn INode { self.idup() }
+
+    fn put(&self) -> () { self.iput() }
+
+    fn stat(&self) -> Result<Stat, &'static str> {
+        self.ilock()?;
+        let st = Stat {
+            itype: self.d().itype,
+            dev: self.dev() as i32,
+            ino: self.inum(),
+            nlink: self.d().nlink,
+            size: self.d().size as u64,
+            major: self.d().major,
+            minor: self.d().minor,
+        };
+        self.iunlock();
+        Ok(st)
+    }
+
+    fn read(&self, user: bool, dst: u64, off: u32, n: u32) -> Result<usize, &'static str> {
+        self.ilock()?;
+        let r = self.readi(user, dst, off, n);
+        self.iunlock();
+        r
+    }
+
+    fn write(&self, user: bool, src: u64, off: u32, n: u32) -> Result<usize, &'static str> {
+        self.ilock()?;
+        let r = self.writei(user, src, off, n);
+        self.iunlock();
+        r
+    }
+
+    fn trunc(&self) -> Result<(), &'static str> {
+        self.ilock()?;
+        let r = self.itrunc();
+        self.iunlock();
+        r
+    }
+
+    fn lookup(&self, name: &[u8]) -> Result<Option<&'static dyn INode>, &'static str> {
+        self.ilock()?;
+        let r = self.live_dir().map(|_| self.dirlookup(name).map(|(ip, _)| ip as &'static dyn INode));
+        self.iunlock();
+        r
+    }
+
+    fn readdir(&self, idx: usize, name: &mut [u8; NAME_MAX]) -> Result<Option<(usize, u64)>, &'static str> {
+        self.ilock()?;
+        let r = (|| -> Result<Option<(usize, u64)>, &'static str> {
+            if self.d().itype != T_DIR { return Err(NOT_DIR) }
+            let mut de = Dirent { inum: 0, name: [0; DIRSIZ] };
+            let mut i = 0;
+            let mut off = 0;
+            while off < self.d().size {
+                if self.readi(false, &mut de as *mut Dirent as u64, off, DIRENT_SIZE)? != DIRENT_SIZE as usize {
+                    return Err("readdir: short read");
+                }
+                off += DIRENT_SIZE;
+                if de.inum == 0 { continue }
+                if i == idx {
+                    let len = de.name.iter().position(|&c| c == 0).unwrap_or(DIRSIZ);
+                    name[..len].copy_from_slice(&de.name[..len]);
+                    return Ok(Some((len, de.inum as u64)));
+                }
+                i += 1;
+            }
+            Ok(None)
+        })();
+        self.iunlock();
+        r
+    }
+
+    fn create(&self, name: &[u8], itype: i16, major: i16, minor: i16)
+        -> Result<&'static dyn INode, &'static str> {
+        if name.len() > DIRSIZ { return Err("file name too long") }
+        self.ilock()?;
+        if let Err(e) = self.live_dir() {
+            self.iunlock();
+            return Err(e);
+        }
+
+        if let Some((ip, _)) = self.dirlookup(name) {
+            self.iunlock();
+            if let Err(e) = ip.ilock() {
+                ip.iput();
+                return Err(e);
+            }
+            let t = ip.d().itype;
+            ip.iunlock();
+            if itype == T_FILE && (t == T_FILE || t == T_DEV) { return Ok(ip) }
+            ip.iput();
+            return Err("file exists");
+        }
+
+        let r = self.create_locked(name, itype, major, minor);
+        self.iunlock();
+        r.map(|ip| ip as &'static dyn INode)
+    }
+
+    fn link(&self, name: &[u8], target: &dyn INode) -> Result<(), &'static str> {
+        if !same_fs(target.fs(), &ROSFS) { return Err("cross-device link") }
+        if name.len() > DIRSIZ { return Err("file name too long") }
+
+        let ip = iget(self.dev(), target.ino() as u32)?;
+        if let Err(e) = ip.ilock() {
+            ip.iput();
+            return Err(e);
+        }
+        if ip.d().itype == T_DIR {
+            ip.iunlockput();
+            return Err("is a directory");
+        }
+        ip.d_mut().nlink += 1;
+        let r = ip.iupdate();
+        ip.iunlock();
+
+        let r = r.and_then(|_| {
+            self.ilock()?;
+            let r = self.live_dir().and_then(|_| self.dirlink(name, ip.inum()));
+            self.iunlock();
+            r
+        });
+        if r.is_err() && ip.ilock().is_ok() {
+            ip.d_mut().nlink -= 1;
+            let _ = ip.iupdate();
+            ip.iunlock();
+        }
+        ip.iput();
+        r
+    }
+
+    fn unlink(&self, name: &[u8]) -> Result<(), &'static str> {
+        // Cannot unlink "." or "..".
+        if name == b"." || name == b".." { return Err("invalid argument") }
+        self.ilock()?;
+        let r = self.live_dir().and_then(|_| self.unlink_locked(name));
+        self.iunlock();
+        r
+    }
+
+    fn rename(&self, name: &[u8], newdir: &dyn INode, newname: &[u8]) -> Result<(), &'static str> {
+        if !same_fs(newdir.fs(), &ROSFS) { return Err("cross-device link") }
+        if newname.len() > DIRSIZ { return Err("file name too long") }
+        for n in [name, newname].iter() {
+            if *n == b"." || *n == b".." { return Err("invalid argument") }
+        }
+
+        let nd = iget(self.dev(), newdir.ino() as u32)?;
+        RENAME_LOCK.acquire();
+        let r = self.ilock().and_then(|_| {
+            let r = self.live_dir().and_then(|_| self.dirlookup(name).ok_or(NOT_FOUND));
+            self.iunlock();
+            r
+        }).and_then(|(ip, _)| {
+            let r = self.rename_locked(name, ip, nd, newname);
+            ip.iput();
+            r
+        });
+        RENAME_LOCK.release();
+        nd.iput();
+        r
+    }
+}
+
+// There is one log, so one instance of rosfs at a time, on ROOTDEV.
+struct RosFs;
+
+static ROSFS: RosFs = RosFs;
+
+impl FileSystem for RosFs {
+    fn name(&self) -> &'static str { "rosfs" }
+
+    fn root(&self) -> Result<&'static dyn INode, &'static str> {
+        Ok(iget(root_dev(), ROOTINO)?)
+    }
+
+    fn iget(&self, ino: u64) -> Result<&'static dyn INode, &'static str> {
+        if ino == 0 || ino >= unsafe { SB.ninodes } as u64 { return Err("rosfs: bad inode number") }
+        Ok(iget(root_dev(), ino as u32)?)
+    }
+
+    fn busy(&self) -> bool {
+        ITABLE_LOCK.acquire();
+        let busy = ITABLE.iter().any(|ip| ip.refcnt.get() > 0 && !(ip.inum() == ROOTINO && ip.refcnt.get() == 1));
+        ITABLE_LOCK.release();
+        busy
+    }
+
+    fn unmount(&self) -> () {
+        unsafe { SB.magic = 0; }
+    }
+}
+
+static ROSFS_TYPE: FsType = FsType { name: "rosfs", mount: rosfs_mount, nodev: false };
+
+fn rosfs_mount(dev: usize) -> Result<&'static dyn FileSystem, &'static str> {
+    if unsafe { SB.magic } == FSMAGIC { return Err("rosfs: already mounted") }
+    let sb = readsb(dev)?;
+    if sb.magic != FSMAGIC { return Err("rosfs: bad magic") }
+    unsafe {
+        SB = sb;
+        ROOTDEV = dev;
+    }
+    if let Err(e) = init_log(dev, sb.logstart, sb.nlog) {
+        unsafe { SB.magic = 0; }
+        return Err(e);
+    }
+    Ok(&ROSFS)
+}
+
+// Mount the root file system: the disk given by root= on the command
+// line, else the first disk holding a super block with our magic. Unless
+// booted with an initrd, which is the root then.
+pub fn fs_init() -> Result<(), &'static str> {
+    register_fs(&ROSFS_TYPE)?;
+    if root_mounted() { return Ok(()) }
+
+    let has_fs = |dev: usize| match blkdev(dev) {
+        Some(d) if d.nsectors() >= 2 * (BSIZE / SECTSIZE) as u64 => {
+            readsb(dev).map(|sb| sb.magic == FSMAGIC).unwrap_or(false)
+        }
+        _ => false,
+    };
+    let dev = match param_str("root") {
+        Some(name) => {
+            let dev = find_blkdev(name)?;
+            if !has_fs(dev) { return Err("fs: no file system on the root disk") }
+            dev
+        }
+        None => (0..).take_while(|&dev| blkdev(dev).is_some()).find(|&dev| has_fs(dev))
+            .ok_or("fs: no disk with a file system")?,
+    };
+
+    mount_root(rosfs_mount(dev)?)?;
+    unsafe {
+        println!("fs: root on blk{} ({}), {} blocks, {} inodes",
+                 dev, blkdev(dev).map(|d| d.name()).unwrap_or("?"), SB.size, SB.ninodes);
+    }
+
+    match namei("/init") {
+        Ok(ip) => ip.put(),
+        Err(_) => println!("fs: no /init"),
+    }
+    Ok(())
+}
@@ -5,20 +5,13 @@ use x86_64::VirtAddr;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+// Selectors of the user segments, in the order they are added below.
+pub const USER_DATA_SELECTOR: u16 = 3 << 3 | 3;
+pub const USER_CODE_SELECTOR: u16 = 6 << 3 | 3;
 
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end
-        };
-        tss
-    };
-}
+// Mutable, the scheduler points rsp0 at the kernel stack of the process
+// it switches to (see set_kernel_stack).
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
 
 lazy_static! {
     static ref GDT: (GlobalDescriptorTable, Selectors) = {
@@ -32,7 +25,19 @@ lazy_static! {
         // I'll keep it here.
         gdt.add_entry(Descriptor::UserSegment(0x0020f20000000000));
 
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
+        let tss = unsafe { &mut TSS };
+        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
+            const STACK_SIZE: usize = 4096;
+            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+
+            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
+            let stack_end = stack_start + STACK_SIZE;
+            stack_end
+        };
+        let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
+
+        // 64-bit ring 3 code, for user processes.
+        gdt.add_entry(Descriptor::UserSegment(0x0020f80000000000));
         (
             gdt,
             Selectors {
@@ -57,4 +62,10 @@ pub fn gdt_init() {
         set_cs(GDT.1.code_selector);
         load_tss(GDT.1.tss_selector);
     }
+}
+
+// The stack the cpu switches to on an interrupt or system call from user
+// mode, i.e. the top of the kernel stack of the process about to run.
+pub unsafe fn set_kernel_stack(top: u64) -> () {
+    TSS.privilege_stack_table[0] = VirtAddr::new(top);
 }
\ No newline at end of file
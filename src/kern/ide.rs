@@ -0,0 +1,289 @@
+// Simple PIO-based (non-DMA) IDE driver code.
+//
+// Requests are queued, the head of the queue is the one the controller is
+// working on. The requester sleeps until the IRQ 14 handler has moved all
+// its sectors, one sector per interrupt.
+// Before the first process runs nobody can sleep, requests are then
+// completed by polling the controller.
+
+use crate::*;
+use crate::kern::blk::{BlockDevice, register_blkdev, check_range, SECTSIZE};
+use crate::kern::ioapic::ioapic_enable;
+use crate::kern::spinlock::SpinLock;
+use crate::kern::proc::{sleep, wakeup, has_proc};
+use x86_64::instructions::port::Port;
+use x86_64::instructions::interrupts::without_interrupts;
+
+const IDE_BSY: u8 = 0x80;
+const IDE_DRDY: u8 = 0x40;
+const IDE_DF: u8 = 0x20;
+const IDE_DRQ: u8 = 0x08;
+const IDE_ERR: u8 = 0x01;
+
+const IDE_CMD_READ: u8 = 0x20;
+const IDE_CMD_WRITE: u8 = 0x30;
+const IDE_CMD_IDENTIFY: u8 = 0xEC;
+
+// Primary channel
+const IDE_DATA: u16 = 0x1F0;
+const IDE_COUNT: u16 = 0x1F2;
+const IDE_LBA0: u16 = 0x1F3;
+const IDE_LBA1: u16 = 0x1F4;
+const IDE_LBA2: u16 = 0x1F5;
+const IDE_DRIVE: u16 = 0x1F6;
+const IDE_CMD: u16 = 0x1F7;     // Status when read
+const IDE_CTRL: u16 = 0x3F6;
+
+const NDRIVE: usize = 2;
+const MAX_SECTORS: usize = 256; // Per command, a count of 0 means 256
+const LBA28_MAX: u64 = 1 << 28;
+
+struct IdeRequest {
+    drive: u8,
+    sector: u64,
+    count: usize,           // Sectors left
+    data: *mut u8,          // Buffer for the next sector
+    write: bool,
+    done: bool,
+    error: bool,
+    next: *mut IdeRequest,
+}
+
+// IDEQUEUE points to the request now being read/written to the disk.
+// IDEQUEUE->next points to the next request to be processed.
+// You must hold IDELOCK while manipulating the queue.
+static IDELOCK: SpinLock = SpinLock::new();
+static mut IDEQUEUE: *mut IdeRequest = null_mut();
+
+pub struct IdeDisk {
+    drive: u8,
+    nsectors: u64,
+}
+
+static mut DISKS: [IdeDisk; NDRIVE] = [
+    IdeDisk { drive: 0, nsectors: 0 },
+    IdeDisk { drive: 1, nsectors: 0 },
+];
+
+fn status() -> u8 { unsafe { Port::<u8>::new(IDE_CMD).read() } }
+
+// Wait for IDE disk to become ready.
+fn wait_ready(checkerr: bool) -> Result<(), &'static str> {
+    let mut r = status();
+    while r & (IDE_BSY | IDE_DRDY) != IDE_DRDY {
+        r = status();
+    }
+    if checkerr && r & (IDE_DF | IDE_ERR) != 0 { return Err("ide: disk error") }
+    Ok(())
+}
+
+fn wait_drq() -> Result<(), &'static str> {
+    loop {
+        let r = status();
+        if r & (IDE_DF | IDE_ERR) != 0 { return Err("ide: disk error") }
+        if r & IDE_BSY == 0 && r & IDE_DRQ != 0 { return Ok(()) }
+    }
+}
+
+unsafe fn read_sector(dst: *mut u8) -> () {
+    let port = Port::<u32>::new(IDE_DATA);
+    let dst = dst as *mut u32;
+    for i in 0..(SECTSIZE / 4) as isize {
+        *dst.offset(i) = port.read();
+    }
+}
+
+unsafe fn write_sector(src: *const u8) -> () {
+    let port = Port::<u32>::new(IDE_DATA);
+    let src = src as *const u32;
+    for i in 0..(SECTSIZE / 4) as isize {
+        port.write(*src.offset(i));
+    }
+}
+
+// Check whether a drive is present and read its capacity.
+unsafe fn identify(drive: u8) -> Option<u64> {
+    Port::<u8>::new(IDE_DRIVE).write(0xA0 | (drive << 4));
+    for _ in 0..1000 {
+        if status() != 0 { break }
+    }
+    if status() == 0 || status() == 0xFF { return None }
+
+    wait_ready(false).ok()?;
+    Port::<u8>::new(IDE_CMD).write(IDE_CMD_IDENTIFY);
+    wait_drq().ok()?;
+
+    let mut id = [0u16; 256];
+    read_sector(id.as_mut_ptr() as *mut u8);
+    // Words 60-61: number of user addressable sectors (LBA28)
+    Some((id[61] as u64) << 16 | id[60] as u64)
+}
+
+// Start the request. Caller must hold IDELOCK.
+unsafe fn start(r: &mut IdeRequest) -> Result<(), &'static str> {
+    let count = if r.count >= MAX_SECTORS { 0 } else { r.count as u8 };
+
+    wait_ready(false)?;
+    Port::<u8>::new(IDE_CTRL).write(0);  // generate interrupt
+    Port::<u8>::new(IDE_COUNT).write(count);
+    Port::<u8>::new(IDE_LBA0).write(r.sector as u8);
+    Port::<u8>::new(IDE_LBA1).write((r.sector >> 8) as u8);
+    Port::<u8>::new(IDE_LBA2).write((r.sector >> 16) as u8);
+    Port::<u8>::new(IDE_DRIVE).write(0xE0 | (r.drive << 4) | ((r.sector >> 24) as u8 & 0x0F));
+    if r.write {
+        Port::<u8>::new(IDE_CMD).write(IDE_CMD_WRITE);
+        wait_drq()?;
+        write_sector(r.data);
+    } else {
+        Port::<u8>::new(IDE_CMD).write(IDE_CMD_READ);
+    }
+    Ok(())
+}
+
+// Start the head of the queue. Requests the disk refuses are failed
+// right away, since no interrupt will come for them.
+unsafe fn start_queue() -> () {
+    while !IDEQUEUE.is_null() {
+        let r = &mut *IDEQUEUE;
+        if start(r).is_ok() { return }
+        r.error = true;
+        IDEQUEUE = r.next;
+        r.done = true;
+        wakeup(VA::from_ptr(r as *const IdeRequest));
+    }
+}
+
+// Finish the head of the queue and start the next request.
+unsafe fn finish(r: &mut IdeRequest) -> () {
+    IDEQUEUE = r.next;
+    r.done = true;
+    wakeup(VA::from_ptr(r as *const IdeRequest));
+    start_queue();
+}
+
+// One sector has been transferred. Caller must hold IDELOCK.
+unsafe fn ide_intr_locked() -> () {
+    if IDEQUEUE.is_null() { return }
+    let r = &mut *IDEQUEUE;
+
+    if wait_ready(true).is_err() {
+        r.error = true;
+        return finish(r);
+    }
+
+    if !r.write { read_sector(r.data); }
+    r.data = r.data.offset(SECTSIZE as isize);
+    r.count -= 1;
+
+    if r.count == 0 { return finish(r) }
+    if r.write {
+        if wait_drq().is_err() {
+            r.error = true;
+            return finish(r);
+        }
+        write_sector(r.data);
+    }
+}
+
+// Interrupt handler.
+pub fn ide_intr() -> () {
+    IDELOCK.acquire();
+    unsafe { ide_intr_locked(); }
+    IDELOCK.release();
+}
+
+// Queue a request and wait for it to complete.
+unsafe fn ide_rw(r: &mut IdeRequest) -> Result<(), &'static str> {
+    without_interrupts(|| {
+        IDELOCK.acquire();
+
+        // Append r to IDEQUEUE.
+        r.next = null_mut();
+        let mut pp = &mut IDEQUEUE as *mut *mut IdeRequest;
+        while !(*pp).is_null() {
+            pp = &mut (**pp).next;
+        }
+        *pp = r;
+
+        // Start disk if necessary.
+        if IDEQUEUE == r as *mut IdeRequest { start_queue(); }
+
+        // Wait for request to finish.
+        while !r.done {
+            if has_proc() {
+                sleep(VA::from_ptr(r as *const IdeRequest), &IDELOCK);
+            } else {
+                // No process to put to sleep yet, and interrupts are off.
+                while status() & IDE_BSY != 0 { }
+                ide_intr_locked();
+            }
+        }
+
+        IDELOCK.release();
+    });
+    if r.error { Err("ide: disk error") } else { Ok(()) }
+}
+
+impl IdeDisk {
+    unsafe fn rw(&self, sector: u64, data: *mut u8, len: usize, write: bool) -> Result<(), &'static str> {
+        check_range(self, sector, len)?;
+        let mut sector = sector;
+        let mut data = data;
+        let mut left = len / SECTSIZE;
+        while left > 0 {
+            let count = if left > MAX_SECTORS { MAX_SECTORS } else { left };
+            let mut r = IdeRequest {
+                drive: self.drive,
+                sector: sector,
+                count: count,
+                data: data,
+                write: write,
+                done: false,
+                error: false,
+                next: null_mut(),
+            };
+            ide_rw(&mut r)?;
+            sector += count as u64;
+            data = data.offset((count * SECTSIZE) as isize);
+            left -= count;
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for IdeDisk {
+    fn name(&self) -> &'static str {
+        match self.drive { 0 => "ide0", _ => "ide1" }
+    }
+
+    fn nsectors(&self) -> u64 { self.nsectors }
+
+    fn read(&self, sector: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        unsafe { self.rw(sector, buf.as_mut_ptr(), buf.len(), false) }
+    }
+
+    fn write(&self, sector: u64, buf: &[u8]) -> Result<(), &'static str> {
+        unsafe { self.rw(sector, buf.as_ptr() as *mut u8, buf.len(), true) }
+    }
+}
+
+// Probe both drives on the primary channel and register the ones present.
+// Drive 0 holds the kernel, fs.img is attached as drive 1 (see Makefile).
+pub fn ide_init() -> () {
+    ioapic_enable(IRQ_IDE, 0);
+    unsafe {
+        for disk in DISKS.iter_mut() {
+            match identify(disk.drive) {
+                Some(n) if n > 0 => {
+                    disk.nsectors = if n > LBA28_MAX { LBA28_MAX } else { n };
+                    if let Err(e) = register_blkdev(&*(disk as *const IdeDisk)) {
+                        println!("{}", e);
+                    }
+                }
+                _ => {}
+            }
+        }
+        // Switch back to disk 0.
+        Port::<u8>::new(IDE_DRIVE).write(0xE0);
+    }
+}
@@ -10,7 +10,14 @@ use x86_64::PrivilegeLevel;
 use crate::kern::lapic::lapic_eoi;
 use crate::kern::mp::my_cpu;
 use crate::kern::spinlock::SpinLock;
-use crate::kern::proc::wakeup;
+use crate::kern::proc::{wakeup, kstack_overflow};
+use crate::kern::tlb::shootdown_interrupt;
+use crate::kern::uaccess::search_exception_table;
+use crate::kern::ide::ide_intr;
+use crate::kern::console::console_intr;
+use crate::kern::ioapic::ioapic_enable;
+use crate::kern::syscall::syscall_entry;
+use spin::Mutex;
 
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
@@ -37,15 +44,145 @@ lazy_static! {
         idt.alignment_check.set_handler_fn(alignment_check);
         idt[(T_IRQ0 + IRQ_TIMER) as usize].set_handler_fn(timer_interrupt_handler);
         idt[(T_IRQ0 + IRQ_KBD)   as usize].set_handler_fn(keyboard_interrupt_handler);
+        idt[(T_IRQ0 + IRQ_IDE)   as usize].set_handler_fn(ide_interrupt_handler);
+        idt[T_TLBFLUSH as usize].set_handler_fn(tlb_shootdown_handler);
 
-        idt[T_SYSCALL as usize].set_handler_fn(syscall).set_privilege_level(PrivilegeLevel::Ring3);
+        // IRQ lines handed out at runtime, see irq_register.
+        for (irq, stub) in IRQ_STUBS.iter() {
+            idt[(T_IRQ0 + *irq) as usize].set_handler_fn(*stub);
+        }
+        // Vectors handed out at runtime, see vector_alloc.
+        for (i, stub) in VECTOR_STUBS.iter().enumerate() {
+            idt[T_DYN0 as usize + i].set_handler_fn(*stub);
+        }
+
+        // syscall_entry saves a whole TrapFrame, which the x86-interrupt
+        // calling convention cannot give us. A trap gate: system calls run
+        // with interrupts on, code holding a lock the interrupt handlers
+        // take turns them off (see proc.rs).
+        unsafe {
+            idt[T_SYSCALL as usize]
+                .set_handler_fn(core::mem::transmute(syscall_entry as unsafe extern "C" fn()))
+                .set_privilege_level(PrivilegeLevel::Ring3)
+                .disable_interrupts(false);
+        }
         idt
     };
 }
 
+// Legacy IRQ lines without a fixed handler above. Devices discovered at
+// runtime (e.g. on PCI) register their handler for the line they are wired
+// to, a line may be shared by a few devices.
+const NIRQ: usize = 24;
+const NSHARED: usize = 4;
+
+lazy_static! {
+    static ref IRQ_HANDLERS: Mutex<[[Option<fn()>; NSHARED]; NIRQ]> = Mutex::new([[None; NSHARED]; NIRQ]);
+}
+
+macro_rules! irq_stubs {
+    ($($irq:expr => $name:ident),*) => {
+        $(extern "x86-interrupt" fn $name(_stack_frame: &mut InterruptStackFrame) {
+            irq_dispatch($irq);
+        })*
+        const IRQ_STUBS: &[(u32, extern "x86-interrupt" fn(&mut InterruptStackFrame))] = &[$(($irq, $name)),*];
+    }
+}
+
+irq_stubs!(2 => irq2, 3 => irq3, 4 => irq4, 5 => irq5, 6 => irq6, 7 => irq7,
+           8 => irq8, 9 => irq9, 10 => irq10, 11 => irq11, 12 => irq12, 13 => irq13,
+           15 => irq15, 16 => irq16, 17 => irq17, 18 => irq18, 20 => irq20,
+           21 => irq21, 22 => irq22, 23 => irq23);
+
+fn irq_dispatch(irq: u32) -> () {
+    count_intr(T_IRQ0 + irq);
+    let handlers = IRQ_HANDLERS.lock()[irq as usize];
+    for h in handlers.iter() {
+        if let Some(h) = h { h(); }
+    }
+    lapic_eoi();
+}
+
+// Install a handler for a legacy IRQ line and route the line to cpu 0.
+pub fn irq_register(irq: u32, handler: fn()) -> Result<(), &'static str> {
+    if !IRQ_STUBS.iter().any(|s| s.0 == irq) { return Err("irq_register: line not available") }
+    {
+        let mut handlers = IRQ_HANDLERS.lock();
+        match handlers[irq as usize].iter().position(|h| h.is_none()) {
+            Some(i) => handlers[irq as usize][i] = Some(handler),
+            None => return Err("irq_register: line full"),
+        }
+    }
+    ioapic_enable(irq, 0);
+    Ok(())
+}
+
+// Vectors for interrupts which are not wired to an IOAPIC line, e.g. MSI.
+// A vector has one handler, called with the data it was registered with.
+const NDYNVEC: usize = (T_DYNEND - T_DYN0) as usize;
+
+lazy_static! {
+    static ref VECTORS: Mutex<[Option<(fn(usize), usize)>; NDYNVEC]> = Mutex::new([None; NDYNVEC]);
+}
+
+macro_rules! vector_stubs {
+    ($($i:expr => $name:ident),*) => {
+        $(extern "x86-interrupt" fn $name(_stack_frame: &mut InterruptStackFrame) {
+            vector_dispatch($i);
+        })*
+        const VECTOR_STUBS: [extern "x86-interrupt" fn(&mut InterruptStackFrame); NDYNVEC] = [$($name),*];
+    }
+}
+
+vector_stubs!(0 => vec0, 1 => vec1, 2 => vec2, 3 => vec3, 4 => vec4, 5 => vec5, 6 => vec6,
+              7 => vec7, 8 => vec8, 9 => vec9, 10 => vec10, 11 => vec11, 12 => vec12,
+              13 => vec13, 14 => vec14, 15 => vec15, 16 => vec16, 17 => vec17, 18 => vec18,
+              19 => vec19, 20 => vec20, 21 => vec21, 22 => vec22, 23 => vec23, 24 => vec24,
+              25 => vec25, 26 => vec26, 27 => vec27, 28 => vec28, 29 => vec29, 30 => vec30,
+              31 => vec31);
+
+fn vector_dispatch(i: usize) -> () {
+    count_intr(T_DYN0 + i as u32);
+    let h = VECTORS.lock()[i];
+    if let Some((f, data)) = h { f(data); }
+    lapic_eoi();
+}
+
+// Allocate a vector and install a handler for it, returns the vector.
+pub fn vector_alloc(handler: fn(usize), data: usize) -> Result<u32, &'static str> {
+    let mut vectors = VECTORS.lock();
+    match vectors.iter().position(|v| v.is_none()) {
+        Some(i) => {
+            vectors[i] = Some((handler, data));
+            Ok(T_DYN0 + i as u32)
+        }
+        None => Err("vector_alloc: out of vectors"),
+    }
+}
+
+pub fn vector_free(vector: u32) -> () {
+    if vector < T_DYN0 || vector >= T_DYNEND { panic!("vector_free") }
+    VECTORS.lock()[(vector - T_DYN0) as usize] = None;
+}
+
+// Interrupts taken, per cpu and vector. Each cpu only counts its own.
+static mut INTR_COUNTS: [[u64; 256]; MAX_CPU] = [[0; 256]; MAX_CPU];
+
+pub fn count_intr(vector: u32) -> () {
+    unsafe { INTR_COUNTS[my_cpu().id as usize][vector as usize] += 1; }
+}
+
+// Interrupts cpu took on vector.
+pub fn intr_count(cpu: usize, vector: u32) -> u64 {
+    unsafe { core::ptr::read_volatile(&INTR_COUNTS[cpu][vector as usize]) }
+}
+
 static TICKSLOCK: SpinLock = SpinLock::new();
 static mut ticks: u64 = 0;
 
+// Timer interrupts since boot.
+pub fn get_ticks() -> u64 { unsafe { core::ptr::read_volatile(&ticks) } }
+
 pub fn idt_init() {
     IDT.load();
 }
@@ -112,11 +249,19 @@ extern "x86-interrupt" fn general_protection_fault(
     hlt_loop();
 }
 
+// Runs on its own stack (IST0), so it still works after a kernel stack
+// overflow: pushing the page fault frame onto the guard page faults again.
 extern "x86-interrupt" fn double_fault_handler(
     stack_frame: &mut InterruptStackFrame,
     _error_code: u64,
 ) {
-    println!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
+    use x86_64::registers::control::Cr2;
+
+    println!("EXCEPTION: DOUBLE FAULT");
+    if let Some((pid, name)) = kstack_overflow(Cr2::read()) {
+        println!("Kernel stack overflow: pid {} ({}), guard page {:?}", pid, name, Cr2::read());
+    }
+    println!("{:#?}", stack_frame);
     hlt_loop();
 }
 
@@ -126,8 +271,23 @@ extern "x86-interrupt" fn page_fault_handler(
 ) {
     use x86_64::registers::control::Cr2;
 
+    count_intr(T_PGFLT);
+
+    // A kernel access to user memory through the uaccess helpers,
+    // resume at the fixup so the helper returns -EFAULT.
+    if !_error_code.contains(PageFaultErrorCode::USER_MODE) {
+        let rip = stack_frame.instruction_pointer.as_u64();
+        if let Some(fixup) = search_exception_table(rip) {
+            unsafe { stack_frame.as_mut().instruction_pointer = VA::new(fixup); }
+            return;
+        }
+    }
+
     println!("EXCEPTION: PAGE FAULT");
     println!("Accessed Address: {:?}", Cr2::read());
+    if let Some((pid, name)) = kstack_overflow(Cr2::read()) {
+        println!("Kernel stack overflow: pid {} ({})", pid, name);
+    }
     println!("{:#?}", stack_frame);
     hlt_loop();
 }
@@ -147,22 +307,21 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: &mut Interrup
         static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> =
             Mutex::new(Keyboard::new(layouts::Us104Key, ScancodeSet1));
     }
+    count_intr(T_IRQ0 + IRQ_KBD);
     let mut keyboard = KEYBOARD.lock();
     let port = Port::new(0x60);
 
     let scancode: u8 = unsafe { port.read() };
     if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-        if let Some(key) = keyboard.process_keyevent(key_event) {
-            match key {
-                DecodedKey::Unicode(character) => print!("{}", character),
-                DecodedKey::RawKey(key) => print!("{:?}", key),
-            }
+        if let Some(DecodedKey::Unicode(character)) = keyboard.process_keyevent(key_event) {
+            if character.is_ascii() { console_intr(character as u8); }
         }
     }
     lapic_eoi();
 }
 
 extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
+    count_intr(T_IRQ0 + IRQ_TIMER);
     unsafe {
         if my_cpu().id == 0 {
             TICKSLOCK.acquire();
@@ -175,6 +334,14 @@ extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: &mut InterruptSt
     lapic_eoi();
 }
 
-extern "x86-interrupt" fn syscall(_stack_frame: &mut InterruptStackFrame) {
-    
-}
\ No newline at end of file
+extern "x86-interrupt" fn ide_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
+    count_intr(T_IRQ0 + IRQ_IDE);
+    ide_intr();
+    lapic_eoi();
+}
+
+extern "x86-interrupt" fn tlb_shootdown_handler(_stack_frame: &mut InterruptStackFrame) {
+    count_intr(T_TLBFLUSH);
+    shootdown_interrupt();
+    lapic_eoi();
+}
@@ -0,0 +1,295 @@
+// Initial ramdisk.
+//
+// The first Multiboot module, a cpio (newc) or ustar archive, served as
+// a read-only file system and mounted as the root. Booting from the iso
+// thus needs no disk driver: the Makefile packs the user programs into
+// the initrd which grub.cfg loads next to the kernel.
+//
+// The archive stays where the boot loader put it. At boot a node is made
+// for each entry, and for the directories only implied by the paths of
+// others; names and contents point into the archive. The inode number
+// of a node is its index plus one, the root is node 0.
+
+use crate::*;
+use crate::kern::multiboot::module;
+use crate::kern::param::param_str;
+use crate::kern::spinlock::SpinLock;
+use crate::kern::uaccess::either_copy_out;
+use crate::kern::vfs::{FileSystem, INode, Stat, NAME_MAX, NOT_DIR, T_DIR, T_FILE, T_DEV};
+use crate::kern::vfs::{mount_root, namei};
+
+const NNODE: usize = 256;
+const ROOT: usize = 0;
+
+const BAD_ARCHIVE: &str = "initrd: bad archive";
+
+// cpio modes
+const S_IFMT: usize = 0o170000;
+const S_IFDIR: usize = 0o040000;
+const S_IFREG: usize = 0o100000;
+const S_IFCHR: usize = 0o020000;
+
+#[derive(Copy, Clone)]
+struct Node {
+    parent: usize,
+    name: &'static [u8],
+    itype: i16,
+    major: i16,
+    minor: i16,
+    data: &'static [u8],
+}
+
+impl Node {
+    const fn new() -> Self {
+        Node { parent: ROOT, name: &[], itype: 0, major: 0, minor: 0, data: &[] }
+    }
+}
+
+struct Initrd;
+
+static INITRD: Initrd = Initrd;
+static INITRD_LOCK: SpinLock = SpinLock::new();
+static mut NODES: [Node; NNODE] = [Node::new(); NNODE];
+static mut NNODES: usize = 0;
+// References to each node, kept apart so that NODES does not change
+// after the mount. Protected by INITRD_LOCK.
+static mut REFCNT: [u32; NNODE] = [0; NNODE];
+
+fn node(i: usize) -> &'static Node {
+    unsafe { &NODES[i] }
+}
+
+fn index(n: &Node) -> usize {
+    (n as *const Node as usize - unsafe { NODES.as_ptr() } as usize) / size_of::<Node>()
+}
+
+// The ith entry of directory dir, besides "." and "..".
+fn child(dir: usize, i: usize) -> Option<usize> {
+    (1..unsafe { NNODES }).filter(|&c| node(c).parent == dir).nth(i)
+}
+
+fn lookup_child(dir: usize, name: &[u8]) -> Option<usize> {
+    (1..unsafe { NNODES }).find(|&c| node(c).parent == dir && node(c).name == name)
+}
+
+// Add the entry at path, given as prefix and name as tar splits it,
+// making the directories on the way. A later entry of the same path
+// replaces the earlier one. Only called before the mount.
+unsafe fn add(path: [&'static [u8]; 2], itype: i16, major: i16, minor: i16, data: &'static [u8])
+              -> Result<(), &'static str> {
+    let mut elems = path.iter().cloned()
+        .flat_map(|p: &'static [u8]| p.split(|&c| c == b'/'))
+        .filter(|e| !e.is_empty() && *e != b".")
+        .peekable();
+    let mut dir = ROOT;
+    while let Some(e) = elems.next() {
+        let last = elems.peek().is_none();
+        let i = match lookup_child(dir, e) {
+            Some(i) => i,
+            None => {
+                if NNODES == NNODE { return Err("initrd: too many files") }
+                NODES[NNODES] = Node { parent: dir, name: e, itype: T_DIR, ..Node::new() };
+                NNODES += 1;
+                NNODES - 1
+            }
+        };
+        if last {
+            NODES[i] = Node { itype, major, minor, data, ..NODES[i] };
+        } else if NODES[i].itype != T_DIR {
+            return Err(NOT_DIR);
+        }
+        dir = i;
+    }
+    Ok(())
+}
+
+// The bytes up to the first NUL.
+fn cstr(s: &'static [u8]) -> &'static [u8] {
+    &s[..s.iter().position(|&c| c == 0).unwrap_or(s.len())]
+}
+
+fn parse_num(s: &[u8], radix: u32) -> Result<usize, &'static str> {
+    let mut n = 0;
+    for &c in s.iter().skip_while(|&&c| c == b' ').take_while(|&&c| c != 0 && c != b' ') {
+        n = n * radix as usize + (c as char).to_digit(radix).ok_or(BAD_ARCHIVE)? as usize;
+    }
+    Ok(n)
+}
+
+fn align4(n: usize) -> usize { (n + 3) & !3 }
+
+// cpio, "new ASCII" format: a 110 byte header of hex fields, the name,
+// the data, each padded to 4 bytes.
+unsafe fn parse_cpio(a: &'static [u8]) -> Result<(), &'static str> {
+    let mut off = 0;
+    loop {
+        let h = a.get(off..off + 110).ok_or(BAD_ARCHIVE)?;
+        if &h[..6] != b"070701" { return Err(BAD_ARCHIVE) }
+        let mode = parse_num(&h[14..22], 16)?;
+        let size = parse_num(&h[54..62], 16)?;
+        let major = parse_num(&h[78..86], 16)? as i16;
+        let minor = parse_num(&h[86..94], 16)? as i16;
+        let namesize = parse_num(&h[94..102], 16)?;
+        let name = cstr(a.get(off + 110..off + 110 + namesize).ok_or(BAD_ARCHIVE)?);
+        let st = align4(off + 110 + namesize);
+        let data = a.get(st..st + size).ok_or(BAD_ARCHIVE)?;
+        off = align4(st + size);
+
+        if name == b"TRAILER!!!" { return Ok(()) }
+        let itype = match mode & S_IFMT {
+            S_IFDIR => T_DIR,
+            S_IFREG => T_FILE,
+            S_IFCHR => T_DEV,
+            _ => continue,              // Symbolic links and the like
+        };
+        add([&b""[..], name], itype, major, minor, data)?;
+    }
+}
+
+// ustar: a 512 byte header of octal fields, the data padded to 512
+// bytes; two zero blocks at the end.
+unsafe fn parse_tar(a: &'static [u8]) -> Result<(), &'static str> {
+    let mut off = 0;
+    while let Some(h) = a.get(off..off + 512) {
+        if h[0] == 0 { break }
+        let size = parse_num(&h[124..136], 8)?;
+        let data = a.get(off + 512..off + 512 + size).ok_or(BAD_ARCHIVE)?;
+        off += 512 + (size + 511) / 512 * 512;
+
+        let itype = match h[156] {
+            0 | b'0' => T_FILE,
+            b'5' => T_DIR,
+            b'3' => T_DEV,
+            _ => continue,
+        };
+        let major = parse_num(&h[329..337], 8)? as i16;
+        let minor = parse_num(&h[337..345], 8)? as i16;
+        add([cstr(&h[345..500]), cstr(&h[..100])], itype, major, minor, data)?;
+    }
+    Ok(())
+}
+
+impl INode for Node {
+    fn fs(&self) -> &'static dyn FileSystem { &INITRD }
+
+    fn ino(&self) -> u64 { index(self) as u64 + 1 }
+
+    fn dup(&'static self) -> &'static dyn INode {
+        INITRD_LOCK.acquire();
+        unsafe { REFCNT[index(self)] += 1; }
+        INITRD_LOCK.release();
+        self
+    }
+
+    fn put(&self) -> () {
+        INITRD_LOCK.acquire();
+        unsafe {
+            let r = &mut REFCNT[index(self)];
+            if *r < 1 { panic!("initrd: put"); }
+            *r -= 1;
+        }
+        INITRD_LOCK.release();
+    }
+
+    fn stat(&self) -> Result<Stat, &'static str> {
+        Ok(Stat {
+            itype: self.itype,
+            dev: -1,                    // No disk
+            ino: self.ino() as u32,
+            nlink: 1,
+            size: self.data.len() as u64,
+            major: self.major,
+            minor: self.minor,
+        })
+    }
+
+    fn read(&self, user: bool, dst: u64, off: u32, n: u32) -> Result<usize, &'static str> {
+        if self.itype == T_DIR { return Err("is a directory") }
+        let off = core::cmp::min(off as usize, self.data.len());
+        let n = core::cmp::min(n as usize, self.data.len() - off);
+        if unsafe { either_copy_out(user, dst, self.data[off..].as_ptr(), n) } < 0 {
+            return Err("bad address");
+        }
+        Ok(n)
+    }
+
+    fn lookup(&self, name: &[u8]) -> Result<Option<&'static dyn INode>, &'static str> {
+        if self.itype != T_DIR { return Err(NOT_DIR) }
+        let i = match name {
+            b"." => Some(index(self)),
+            b".." => Some(self.parent),
+            _ => lookup_child(index(self), name),
+        };
+        Ok(i.map(|i| node(i).dup()))
+    }
+
+    fn readdir(&self, idx: usize, name: &mut [u8; NAME_MAX]) -> Result<Option<(usize, u64)>, &'static str> {
+        if self.itype != T_DIR { return Err(NOT_DIR) }
+        let (n, i): (&[u8], usize) = match idx {
+            0 => (&b"."[..], index(self)),
+            1 => (&b".."[..], self.parent),
+            _ => match child(index(self), idx - 2) {
+                Some(i) => (node(i).name, i),
+                None => return Ok(None),
+            },
+        };
+        let len = core::cmp::min(n.len(), NAME_MAX);
+        name[..len].copy_from_slice(&n[..len]);
+        Ok(Some((len, i as u64 + 1)))
+    }
+}
+
+impl FileSystem for Initrd {
+    fn name(&self) -> &'static str { "initrd" }
+
+    fn root(&self) -> Result<&'static dyn INode, &'static str> {
+        Ok(node(ROOT).dup())
+    }
+
+    fn iget(&self, ino: u64) -> Result<&'static dyn INode, &'static str> {
+        if ino == 0 || ino > unsafe { NNODES } as u64 { return Err("initrd: bad inode number") }
+        Ok(node(ino as usize - 1).dup())
+    }
+
+    fn busy(&self) -> bool {
+        INITRD_LOCK.acquire();
+        let busy = unsafe {
+            REFCNT[..NNODES].iter().enumerate().any(|(i, &r)| r > 0 && !(i == ROOT && r == 1))
+        };
+        INITRD_LOCK.release();
+        busy
+    }
+
+    fn unmount(&self) -> () {}
+}
+
+// Mount the initrd as the root file system, if the boot loader loaded
+// one and root= does not ask for a disk.
+pub fn initrd_init() -> Result<(), &'static str> {
+    let m = match module(0) {
+        Some(m) if param_str("root").is_none() => m,
+        _ => return Ok(()),
+    };
+    let a = unsafe { core::slice::from_raw_parts(p2v!(m.start) as *const u8, (m.end - m.start) as usize) };
+
+    unsafe {
+        NODES[ROOT] = Node { parent: ROOT, itype: T_DIR, ..Node::new() };
+        NNODES = 1;
+        if a.starts_with(b"070701") {
+            parse_cpio(a)?;
+        } else if a.len() >= 512 && &a[257..262] == b"ustar" {
+            parse_tar(a)?;
+        } else {
+            return Err("initrd: neither cpio nor tar");
+        }
+    }
+
+    mount_root(&INITRD)?;
+    println!("initrd: root, {} bytes, {} files", a.len(), unsafe { NNODES });
+
+    match namei("/init") {
+        Ok(ip) => ip.put(),
+        Err(_) => println!("initrd: no /init"),
+    }
+    Ok(())
+}
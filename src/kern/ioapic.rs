@@ -11,15 +11,17 @@ struct Ioapic {
 }
 
 lazy_static! {
-    static ref IOAPIC: Mutex<&'static mut Ioapic> = unsafe { Mutex::new(&mut *(io2v!(IOAPIC_ADDR) as *mut Ioapic)) };
+    static ref IOAPIC: Mutex<&'static mut Ioapic> = unsafe { Mutex::new(&mut *(kern::mp::CPU_INFO.get_ioapic().as_u64() as *mut Ioapic)) };
 }
 
 impl Ioapic {
     fn init(&mut self) -> () {
         let maxintr = (self.read(REG_VER) >> 16) & 0xFF;
         let id = self.read(REG_ID) >> 24;
+        // Only a warning, as in xv6: without an MP table or MADT the id is
+        // a guess.
         if id != kern::mp::CPU_INFO.ioapic_id() as u32 {
-            panic!("ID do not match! Perhaps not MP");
+            println!("ioapic: id {} is not the expected {}", id, kern::mp::CPU_INFO.ioapic_id());
         }
 
         // Mark all interrupts edge-triggered, active high, disabled,
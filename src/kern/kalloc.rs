@@ -9,15 +9,18 @@ struct Run {
     next: *mut Run,
 }
 
-#[repr(transparent)]
 struct PhysPgAllocator {
     freelist: *mut Run,
+    total: usize,                       // Pages handed to the allocator
+    nfree: usize,                       // Pages on freelist
 }
 
 // TODO: Change to Lock free structure
 lazy_static! {
     static ref KMEM: Mutex<PhysPgAllocator> = Mutex::new(PhysPgAllocator{
         freelist: null_mut(),
+        total: 0,
+        nfree: 0,
     });
 }
 
@@ -28,16 +31,26 @@ impl PhysPgAllocator {
         self.free_range(st, ed);
     }
 
+    // Free [pivot, ed) first and [st, pivot) last, so allocation starts
+    // right below pivot instead of at the top of the range.
+    fn free_range_rotated(&mut self, st: VA, ed: VA, pivot: VA) -> () {
+        let pivot = pivot.align_down(PGSIZE);
+        self.free_range(pivot, ed);
+        self.free_range(st, pivot);
+    }
+
     fn free_range(&mut self, st: VA, ed: VA) -> () {
         let  mut p = st.align_up(PGSIZE);
         while p + PGSIZE < ed {
             self.kfree(p);
+            self.total += 1;
             p += PGSIZE;
         }
     }
 
     fn kfree(&mut self, v: VA) -> () {
-        if !v.is_aligned(PGSIZE) || v.lt(&KERN_END) || v2p!(v.as_u64()) >= PHYSTOP {
+        let pa = v2p!(v.as_u64());
+        if !v.is_aligned(PGSIZE) || pa < v2p!(KERN_END.as_u64()) || pa >= PHYSTOP {
             panic!("kfree")
         }
 
@@ -47,6 +60,7 @@ impl PhysPgAllocator {
             (*r).next = self.freelist;
         }
         self.freelist = r;
+        self.nfree += 1;
     }
 
     fn kalloc(&mut self) -> VA {
@@ -54,12 +68,47 @@ impl PhysPgAllocator {
         unsafe {
             if !r.is_null() {
                 self.freelist = (*r).next;
+                self.nfree -= 1;
                 VA::new(r as u64)
             } else{
                 VA::new(0x0)
             }
         }
     }
+
+    // Take n physically contiguous pages and return the lowest. Pages
+    // which do not extend the run are put back once it is complete.
+    fn kalloc_contig(&mut self, n: usize) -> VA {
+        let mut skipped: *mut Run = null_mut();
+        let mut base = VA::new(0x0);
+        let mut run = 0;
+        while run < n {
+            let v = self.kalloc();
+            // The freelist mostly hands out pages top down.
+            if run > 0 && v.as_u64() != 0 && v + PGSIZE == base {
+                base = v;
+                run += 1;
+                continue;
+            }
+            for i in 0..run {
+                let r = (base + i as u64 * PGSIZE).as_mut_ptr::<Run>();
+                unsafe { (*r).next = skipped; }
+                skipped = r;
+            }
+            if v.as_u64() == 0 {
+                run = 0;
+                break;
+            }
+            base = v;
+            run = 1;
+        }
+        while !skipped.is_null() {
+            let next = unsafe { (*skipped).next };
+            self.kfree(VA::from_ptr(skipped));
+            skipped = next;
+        }
+        if run == n { base } else { VA::new(0x0) }
+    }
 }
 
 unsafe impl Send for PhysPgAllocator {}
@@ -80,6 +129,14 @@ pub fn kinit(st: VA, ed: VA) {
     KMEM.lock().kinit1(st, ed);
 }
 
+// Like kinit, but the first allocations come from a random place in the range.
+pub fn kinit_random(st: VA, ed: VA, seed: u64) {
+    let npages = (ed.as_u64() - st.align_up(PGSIZE).as_u64()) / PGSIZE;
+    if npages == 0 { return kinit(st, ed) }
+    let pivot = st.align_up(PGSIZE) + (seed % npages) * PGSIZE;
+    KMEM.lock().free_range_rotated(st, ed, pivot);
+}
+
 pub fn kfree(v: VA) -> () {
     KMEM.lock().kfree(v);
 }
@@ -92,6 +149,21 @@ pub fn kalloc() -> Option<VA> {
     }
 }
 
+// Pages managed by the allocator and how many of them are free.
+pub fn kmem_stats() -> (usize, usize) {
+    let kmem = KMEM.lock();
+    (kmem.total, kmem.nfree)
+}
+
+// n physically contiguous pages, for tables larger than a page.
+pub fn kalloc_contig(n: usize) -> Option<VA> {
+    let v = KMEM.lock().kalloc_contig(n);
+    match v.as_u64() {
+        0x0 => None,
+        _ => Some(v),
+    }
+}
+
 pub fn kalloc_pg() -> Option<Page> {
     let v = KMEM.lock().kalloc();
     match v.as_u64() {
@@ -102,15 +174,15 @@ pub fn kalloc_pg() -> Option<Page> {
 
 #[macro_export]
 macro_rules! p2v {
-    ($x:expr) => ($x + (*$crate::KERN_BASE).as_u64());
+    ($x:expr) => ($x + $crate::phys_base());
 }
 
 #[macro_export]
 macro_rules! v2p {
-    ($x:expr) => ($x - (*$crate::KERN_BASE).as_u64());
+    ($x:expr) => ($crate::virt_to_phys($x));
 }
 
 #[macro_export]
 macro_rules! io2v {
-    ($x:expr) => ($x - ($crate::DEVSPACE) + ($crate::DEVBASE))
+    ($x:expr) => ($x - ($crate::DEVSPACE) + ($crate::dev_base()))
 }
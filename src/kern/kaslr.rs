@@ -0,0 +1,59 @@
+// Kernel address space layout randomization.
+//
+// The kernel image lives in a window starting at KERN_BASE, which maps all
+// of physical memory. entry.S slides that window by a random multiple of
+// 2MB before paging is turned on, using the relocation table which
+// tools/relocs writes into the image (build with `make KASLR=1`). An image
+// without the table is never moved and everything in here is a no-op.
+//
+// Once running, the physical memory map p2v! uses moves to an alias of its
+// own at a random offset, independent of the kernel slide. The device
+// window and the first page handed out by kalloc are randomized as well.
+
+use crate::*;
+use x86_64::instructions::random::RdRand;
+
+extern "C" {
+    // Both defined in entry.S
+    static kaslr_slide: u64;
+    static kaslr_relocs: [u32; 2];
+}
+
+const SLIDE_ALIGN: u64 = 0x200000;
+
+// Offset of the kernel from its link address.
+pub fn slide() -> u64 { unsafe { kaslr_slide } }
+
+// Whether the image carries a relocation table.
+pub fn enabled() -> bool {
+    // The table lives in low memory which is no longer identity mapped.
+    let relocs = unsafe { p2v!(&kaslr_relocs as *const [u32; 2] as u64) } as *const [u32; 2];
+    let counts = unsafe { *relocs };
+    counts[0] != 0 || counts[1] != 0
+}
+
+pub fn random_u64() -> u64 {
+    if let Some(r) = RdRand::new().and_then(|r| r.get_u64()) {
+        return r;
+    }
+    let (lo, hi): (u32, u32);
+    unsafe { asm!("rdtsc" : "={eax}"(lo), "={edx}"(hi) ::: "volatile"); }
+    (hi as u64) << 32 | lo as u64
+}
+
+// Must run before the kernel page table is built, since it moves the
+// device window which setup_vm maps.
+pub fn kaslr_init() -> () {
+    if !enabled() { return }
+
+    let devsz = 0x100000000 - DEVSPACE;
+    let slots = (DEVWINDOW - devsz - DEVEXTRA) / SLIDE_ALIGN;
+    unsafe { DEV_BASE = DEVBASE + (random_u64() % slots) * SLIDE_ALIGN; }
+
+    let slots = (PHYSMAPWINDOW - PHYSTOP) / SLIDE_ALIGN;
+    unsafe { PHYSMAP = PHYSMAPBASE + (random_u64() % slots) * SLIDE_ALIGN; }
+
+    println!("KASLR: kernel slide 0x{:x}, run `kaslr 0x{:x}` in gdb", slide(), slide());
+    println!("KASLR: device window at 0x{:x}", dev_base());
+    println!("KASLR: physical memory map at 0x{:x}", unsafe { PHYSMAP });
+}
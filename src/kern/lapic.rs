@@ -1,5 +1,6 @@
 use kern::mp::CPU_INFO;
 use crate::*;
+use crate::kern::param::param_num;
 use volatile::Volatile;
 use x86_64::instructions::port::Port;
 
@@ -43,9 +44,10 @@ impl Lapic {
         // from lapic[TICR] and then issues an interrupt.
         // If xv6 cared more about precise timekeeping,
         // TICR would be calibrated using an external time source.
+        // The count can be set with timer= on the command line.
         self.wrt(TDCR, X1 as u32);
         self.wrt(TIMER, PERIODIC | (T_IRQ0 + IRQ_TIMER));
-        self.wrt(TICR, 10000000);
+        self.wrt(TICR, param_num("timer").unwrap_or(10000000) as u32);
 
         // Disable logical interrupt lines.
         self.wrt(LINT0, MASKED);
@@ -110,6 +112,14 @@ impl Lapic {
             self.wrt(ICRLO, STARTUP | (addr.as_u64() >> 12) as u32);
         }
     }
+
+    // Send a fixed interrupt with the given vector to one CPU.
+    pub fn send_ipi(&self, apic_id: u8, vector: u32) -> () {
+        while self.rd(ICRLO) & DELIVS != 0 { }
+        self.wrt(ICRHI, (apic_id as u32) << 24);
+        self.wrt(ICRLO, FIXED | ASSERT | vector);
+        while self.rd(ICRLO) & DELIVS != 0 { }
+    }
 }
 
 pub fn lapic_init() -> () {
@@ -142,3 +152,7 @@ pub unsafe fn lapic_id() -> u64 { LAPIC.lapic_id() }
 pub unsafe fn lapic_start_ap(apic_id: u8, addr: VA) -> () {
     LAPIC.start_ap(apic_id, addr);
 }
+
+pub fn lapic_send_ipi(apic_id: u8, vector: u32) -> () {
+    LAPIC.send_ipi(apic_id, vector);
+}
@@ -0,0 +1,257 @@
+// Simple logging that allows concurrent FS system calls.
+//
+// A log transaction contains the updates of multiple FS system
+// calls. The logging system only commits when there are
+// no FS system calls active. Thus there is never
+// any reasoning required about whether a commit might
+// write an uncommitted system call's updates to disk.
+//
+// A system call should call begin_op()/end_op() to mark
+// its start and end. Usually begin_op() just increments
+// the count of in-progress FS system calls and returns.
+// But if it thinks the log is close to running out, it
+// sleeps until the last outstanding end_op() commits.
+//
+// The log is a physical re-do log containing disk blocks.
+// The on-disk log format:
+//   header block, containing block #s for block A, B, C, ...
+//   block A
+//   block B
+//   block C
+//   ...
+// Log appends are synchronous.
+//
+// Blocks in a transaction are not written back by the buffer cache, they
+// stay pinned (and clean) until the commit has installed them.
+
+use crate::*;
+use crate::kern::bio::{Buf, BSIZE, bread, bwrite, brelse, bpin, bunpin};
+use crate::kern::spinlock::SpinLock;
+use crate::kern::proc::{sleep, wakeup, has_proc};
+use x86_64::instructions::interrupts::without_interrupts;
+
+pub const MAXOPBLOCKS: usize = 10;              // Max # of blocks any FS op writes
+pub const LOGSIZE: usize = MAXOPBLOCKS * 3;     // Max data blocks in on-disk log
+
+// Contents of the header block, used for both the on-disk header block
+// and to keep track in memory of logged block# before commit.
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct LogHeader {
+    n: u32,
+    block: [u32; LOGSIZE],
+}
+
+struct Log {
+    start: u32,             // Block number of the header block
+    size: u32,
+    outstanding: usize,     // How many FS sys calls are executing.
+    committing: bool,       // In commit(), please wait.
+    dev: usize,
+    lh: LogHeader,
+}
+
+static LOGLOCK: SpinLock = SpinLock::new();
+static mut LOG: Log = Log {
+    start: 0,
+    size: 0,
+    outstanding: 0,
+    committing: false,
+    dev: 0,
+    lh: LogHeader { n: 0, block: [0; LOGSIZE] },
+};
+
+fn chan() -> VA { unsafe { VA::from_ptr(&LOG as *const Log) } }
+
+// Wait for the log to change. Caller holds LOGLOCK, interrupts are off.
+unsafe fn wait() -> () {
+    if has_proc() {
+        sleep(chan(), &LOGLOCK);
+    } else {
+        // Nobody else can be in a transaction before the first process.
+        panic!("log: wait before the first process");
+    }
+}
+
+// Set up the log in blocks [start, start + size) of dev and replay a
+// committed transaction left by a crash.
+pub fn init_log(dev: usize, start: u32, size: u32) -> Result<(), &'static str> {
+    if size_of::<LogHeader>() >= BSIZE { panic!("init_log: too big logheader"); }
+    if (size as usize) < 2 { return Err("log: too small") }
+    unsafe {
+        LOG.start = start;
+        LOG.size = core::cmp::min(size, LOGSIZE as u32 + 1);
+        LOG.dev = dev;
+    }
+    recover_from_log()
+}
+
+// Copy committed blocks from log to their home location.
+unsafe fn install_trans(recovering: bool) -> Result<(), &'static str> {
+    for tail in 0..LOG.lh.n {
+        let lbuf = bread(LOG.dev, LOG.start + tail + 1)?;     // read log block
+        let dbuf = match bread(LOG.dev, LOG.lh.block[tail as usize]) {  // read dst
+            Ok(b) => b,
+            Err(e) => {
+                brelse(lbuf);
+                return Err(e);
+            }
+        };
+        dbuf.data.copy_from_slice(&lbuf.data);  // copy block to dst
+        let r = bwrite(dbuf);                   // write dst to disk
+        if !recovering { bunpin(dbuf); }
+        brelse(lbuf);
+        brelse(dbuf);
+        r?;
+    }
+    Ok(())
+}
+
+// Read the log header from disk into the in-memory log header.
+unsafe fn read_head() -> Result<(), &'static str> {
+    let buf = bread(LOG.dev, LOG.start)?;
+    let lh = &*(buf.data.as_ptr() as *const LogHeader);
+    LOG.lh.n = lh.n;
+    LOG.lh.block.copy_from_slice(&lh.block);
+    brelse(buf);
+    if LOG.lh.n as usize > LOGSIZE || LOG.lh.n >= LOG.size { return Err("log: corrupt header") }
+    Ok(())
+}
+
+// Write in-memory log header to disk.
+// This is the true point at which the
+// current transaction commits.
+unsafe fn write_head() -> Result<(), &'static str> {
+    let buf = bread(LOG.dev, LOG.start)?;
+    let hb = &mut *(buf.data.as_mut_ptr() as *mut LogHeader);
+    hb.n = LOG.lh.n;
+    hb.block.copy_from_slice(&LOG.lh.block);
+    let r = bwrite(buf);
+    brelse(buf);
+    r
+}
+
+fn recover_from_log() -> Result<(), &'static str> {
+    unsafe {
+        read_head()?;
+        if LOG.lh.n > 0 { println!("log: recovering {} blocks", LOG.lh.n); }
+        install_trans(true)?;       // if committed, copy from log to disk
+        LOG.lh.n = 0;
+        write_head()                // clear the log
+    }
+}
+
+// Called at the start of each FS system call.
+pub fn begin_op() -> () {
+    without_interrupts(|| unsafe {
+        LOGLOCK.acquire();
+        loop {
+            if LOG.committing {
+                wait();
+            } else if LOG.lh.n as usize + (LOG.outstanding + 1) * MAXOPBLOCKS > LOGSIZE {
+                // This op might exhaust log space; wait for commit.
+                wait();
+            } else {
+                LOG.outstanding += 1;
+                break;
+            }
+        }
+        LOGLOCK.release();
+    });
+}
+
+// Called at the end of each FS system call.
+// Commits if this was the last outstanding operation.
+pub fn end_op() -> () {
+    let mut do_commit = false;
+
+    without_interrupts(|| unsafe {
+        LOGLOCK.acquire();
+        LOG.outstanding -= 1;
+        if LOG.committing { panic!("log.committing"); }
+        if LOG.outstanding == 0 {
+            do_commit = true;
+            LOG.committing = true;
+        } else {
+            // begin_op() may be waiting for log space,
+            // and decrementing log.outstanding has decreased
+            // the amount of reserved space.
+            wakeup(chan());
+        }
+        LOGLOCK.release();
+    });
+
+    if do_commit {
+        // Call commit w/o holding locks, since not allowed
+        // to sleep with locks.
+        if let Err(e) = unsafe { commit() } {
+            panic!("log: commit: {}", e);
+        }
+        without_interrupts(|| unsafe {
+            LOGLOCK.acquire();
+            LOG.committing = false;
+            wakeup(chan());
+            LOGLOCK.release();
+        });
+    }
+}
+
+// Copy modified blocks from cache to log.
+unsafe fn write_log() -> Result<(), &'static str> {
+    for tail in 0..LOG.lh.n {
+        let to = bread(LOG.dev, LOG.start + tail + 1)?;               // log block
+        let from = match bread(LOG.dev, LOG.lh.block[tail as usize]) { // cache block
+            Ok(b) => b,
+            Err(e) => {
+                brelse(to);
+                return Err(e);
+            }
+        };
+        to.data.copy_from_slice(&from.data);
+        let r = bwrite(to);         // write the log
+        brelse(from);
+        brelse(to);
+        r?;
+    }
+    Ok(())
+}
+
+unsafe fn commit() -> Result<(), &'static str> {
+    if LOG.lh.n > 0 {
+        write_log()?;           // Write modified blocks from cache to log
+        write_head()?;          // Write header to disk -- the real commit
+        install_trans(false)?;  // Now install writes to home locations
+        LOG.lh.n = 0;
+        write_head()?;          // Erase the transaction from the log
+    }
+    Ok(())
+}
+
+// Caller has modified b->data and is done with the buffer.
+// Record the block number and pin in the cache by increasing refcnt.
+// commit()/write_log() will do the disk write.
+//
+// log_write() replaces bwrite(); a typical use is:
+//   let bp = bread(...)?;
+//   modify bp.data[]
+//   log_write(bp);
+//   brelse(bp);
+pub fn log_write(b: &mut Buf) -> () {
+    without_interrupts(|| unsafe {
+        LOGLOCK.acquire();
+        if LOG.lh.n as usize >= LOGSIZE || LOG.lh.n >= LOG.size - 1 {
+            panic!("too big a transaction");
+        }
+        if LOG.outstanding < 1 { panic!("log_write outside of trans"); }
+
+        let n = LOG.lh.n as usize;
+        // Log absorption
+        let i = LOG.lh.block[..n].iter().position(|&blk| blk == b.blockno).unwrap_or(n);
+        LOG.lh.block[i] = b.blockno;
+        if i == n {  // Add new block to log?
+            bpin(b);
+            LOG.lh.n += 1;
+        }
+        LOGLOCK.release();
+    });
+}
@@ -8,5 +8,33 @@ pub mod idt;
 pub mod ioapic;
 pub mod uart;
 pub mod proc;
+pub mod exec;
 pub mod file;
-pub mod spinlock;
\ No newline at end of file
+pub mod spinlock;
+pub mod tlb;
+pub mod uaccess;
+pub mod kaslr;
+pub mod blk;
+pub mod ide;
+pub mod pci;
+pub mod virtio;
+pub mod virtio_blk;
+pub mod ahci;
+pub mod acpi;
+pub mod msi;
+pub mod sleeplock;
+pub mod bio;
+pub mod log;
+pub mod fs;
+pub mod fat;
+pub mod vfs;
+pub mod syscall;
+pub mod sysfile;
+pub mod sysproc;
+pub mod pipe;
+pub mod dev;
+pub mod tmpfs;
+pub mod procfs;
+pub mod multiboot;
+pub mod initrd;
+pub mod param;
\ No newline at end of file
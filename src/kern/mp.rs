@@ -5,6 +5,10 @@ use kern::proc::Context;
 use kern::proc::Proc;
 use crate::kern::kalloc::kalloc;
 use crate::kern::lapic::lapic_start_ap;
+use crate::kern::param::param_num;
+use crate::kern::acpi::{find_table, SdtHeader};
+use crate::kern::vm::mmio2v;
+use core::ptr::read_unaligned;
 
 
 #[repr(C)]
@@ -24,9 +28,15 @@ pub struct MPioapic {
     apic_no: u8,
     version: u8,
     flags: u8,
-    addr: u64
+    addr: u32
 }
 
+// MADT entry types, each entry starts with its type and length
+const MADT_LAPIC: u8 = 0;           // Processor local APIC
+const MADT_IOAPIC: u8 = 1;          // I/O APIC
+const MADT_LAPIC_ADDR: u8 = 5;      // 64-bit local APIC address override
+const MADT_ENABLED: u32 = 1;        // Flag of MADT_LAPIC: usable processor
+
 #[repr(C)]
 #[thread_local]
 pub struct CPU {
@@ -185,55 +195,124 @@ pub struct CpuInfo {
     lapic: VA,
     ncpu: u8,
     ioapicid: u8,
+    ioapic: VA,
 }
 
 impl CpuInfo {
+    // Take the CPUs and APICs from the MP table, else from the ACPI MADT
+    // (UEFI firmware such as OVMF has no MP table). With neither, run on
+    // the boot CPU alone with the APICs at their default addresses.
     unsafe fn init() -> Self {
-        let mp = MP::search();
-        let opt_conf = MPConf::config(mp);
+        let maxcpus = param_num("maxcpus").unwrap_or(MAX_CPU as u64) as u8;
+        if let Some(conf) = MPConf::config(MP::search()) {
+            return CpuInfo::from_mp(conf, maxcpus);
+        }
+        if let Some(madt) = find_table(b"APIC") {
+            return CpuInfo::from_madt(madt, maxcpus);
+        }
+        println!("mpinit: no MP table or MADT, running on one cpu");
+        let mut cpus: [(u8, u8); MAX_CPU] = [(0, 0); MAX_CPU];
+        cpus[0] = (0, boot_apic_id());
+        MY_CPU.apic_id = cpus[0].1;
+        CpuInfo::new(cpus, LAPIC_ADDR, 1, 0, IOAPIC_ADDR)
+    }
+
+    fn new(cpus: [(u8, u8); MAX_CPU], lapic: u64, ncpu: u8, ioapic_id: u8, ioapic: u64) -> Self {
+        println!("lapic pa: 0x{:x}", lapic);
+        CpuInfo {
+            cpus: cpus,
+            lapic: VA::new(mmio2v(lapic).expect("mpinit: LAPIC outside the device window")),
+            ncpu: ncpu,
+            ioapicid: ioapic_id,
+            ioapic: VA::new(mmio2v(ioapic).expect("mpinit: IOAPIC outside the device window")),
+        }
+    }
+
+    unsafe fn from_mp(conf: &MPConf, maxcpus: u8) -> Self {
         let mut cpus: [(u8, u8); MAX_CPU] = [(0, 0); MAX_CPU];
         let mut ncpu: u8 = 0;
         let mut ioapic_id: u8 = 0;
+        let mut ioapic: u64 = IOAPIC_ADDR;
+
+        let ptr = conf as *const MPConf;
+        let mut p = ptr.offset(1) as *const u8;
+        let length = conf.length;
+        let e = (ptr as *const u8).offset(length as isize);
+        while p < e {
+            match *p {
+                MPPROC => {
+                    let proc = p as *const MPProc;
+                    if ncpu < maxcpus {
+                        cpus[ncpu as usize] = (ncpu, (*proc).apic_id);
+                        MY_CPU.apic_id = (*proc).apic_id;
+                        ncpu += 1;
+                    }
+                    p = p.offset(size_of::<MPProc>() as isize);
+                }
+                MPIOAPIC => {
+                    ioapic_id = (*(p as *const MPioapic)).apic_no;
+                    ioapic = (*(p as *const MPioapic)).addr as u64;
+                    p = p.offset(size_of::<MPioapic>() as isize);
+                }
+                MPBUS |
+                MPIOINTR |
+                MPLINTR => p = p.offset(8),
+                x => println!("mpinit: unknown config type {:x}", x),
+            }
+        }
+        CpuInfo::new(cpus, conf.lapicaddr as u64, ncpu, ioapic_id, ioapic)
+    }
 
-        match opt_conf {
-            Some(conf) => {
-                let ptr = conf as *const MPConf;
-                let mut p = ptr.offset(1) as *const u8;
-                let length = conf.length;
-                let e = (ptr as *const u8).offset(length as isize);
-                println!("lapic pa: 0x{:x}", (*conf).lapicaddr);
-                while p < e {
-                    match *p {
-                        MPPROC => {
-                            let proc = p as *const MPProc;
-                            cpus[ncpu as usize] = (ncpu, (*proc).apic_id);
-                            MY_CPU.apic_id = (*proc).apic_id;
-                            ncpu += 1;
-                            p = p.offset(size_of::<MPProc>() as isize);
-                        }
-                        MPIOAPIC => {
-                            ioapic_id = (*(p as *const MPioapic)).apic_no;
-                            p = p.offset(size_of::<MPioapic>() as isize);
-                        }
-                        MPBUS |
-                        MPIOINTR |
-                        MPLINTR => p = p.offset(8),
-                        x => println!("mpinit: unknown config type {:x}", x),
+    unsafe fn from_madt(madt: &SdtHeader, maxcpus: u8) -> Self {
+        let mut cpus: [(u8, u8); MAX_CPU] = [(0, 0); MAX_CPU];
+        let mut ncpu: u8 = 0;
+        let mut ioapic_id: u8 = 0;
+        let mut ioapic: u64 = IOAPIC_ADDR;
+
+        let base = madt as *const SdtHeader as *const u8;
+        let mut lapic = read_unaligned(base.add(size_of::<SdtHeader>()) as *const u32) as u64;
+        let mut p = base.add(size_of::<SdtHeader>() + 8);
+        let e = base.add(madt.length as usize);
+        while p.add(2) <= e {
+            let len = *p.add(1) as usize;
+            if len < 2 || p.add(len) > e { break }
+            match *p {
+                MADT_LAPIC if len >= 8 => {
+                    let flags = read_unaligned(p.add(4) as *const u32);
+                    if flags & MADT_ENABLED != 0 && ncpu < maxcpus {
+                        cpus[ncpu as usize] = (ncpu, *p.add(3));
+                        MY_CPU.apic_id = *p.add(3);
+                        ncpu += 1;
                     }
                 }
-                CpuInfo {
-                    cpus: cpus,
-                    lapic: VA::new(io2v!((*conf).lapicaddr as u64)),
-                    ncpu: ncpu,
-                    ioapicid: ioapic_id,
+                MADT_IOAPIC if len >= 8 => {
+                    ioapic_id = *p.add(2);
+                    ioapic = read_unaligned(p.add(4) as *const u32) as u64;
                 }
+                MADT_LAPIC_ADDR if len >= 12 => lapic = read_unaligned(p.add(4) as *const u64),
+                _ => {}
             }
-            _ => panic!("Expect to run on an SMP"),
+            p = p.add(len);
         }
+        if ncpu == 0 {
+            println!("mpinit: no enabled cpu in the MADT, running on one cpu");
+            cpus[0] = (0, boot_apic_id());
+            MY_CPU.apic_id = cpus[0].1;
+            ncpu = 1;
+        }
+        CpuInfo::new(cpus, lapic, ncpu, ioapic_id, ioapic)
     }
 
     pub fn get_lapic(&self) -> VA { self.lapic }
     pub fn ioapic_id(&self) -> u8 { self.ioapicid }
+    pub fn get_ioapic(&self) -> VA { self.ioapic }
+    pub fn ncpu(&self) -> usize { self.ncpu as usize }
+    pub fn apic_id(&self, i: usize) -> u8 { self.cpus[i].1 }
+
+    // Map a LAPIC id back to its index in the cpus array.
+    pub fn cpu_index(&self, apic_id: u8) -> Option<usize> {
+        self.cpus[..self.ncpu as usize].iter().position(|c| c.1 == apic_id)
+    }
 }
 
 lazy_static! {
@@ -250,6 +329,11 @@ pub fn mp_init() -> () {
     println!("ioapicid: {}", CPU_INFO.ioapicid);
 }
 
+// The initial APIC id of the CPU we run on, from CPUID.
+fn boot_apic_id() -> u8 {
+    (cpuid(1, 0).1 >> 24) as u8
+}
+
 // this sum is used to calculate checksum.
 // the spec requires that all fields add up to 0
 // and all fields are unsigned.
@@ -0,0 +1,119 @@
+// MSI and MSI-X.
+//
+// A message signalled interrupt is a memory write by the device to the
+// local APIC of the target CPU: the address selects the CPU, the data
+// the vector. Every interrupt gets a vector of its own from vector_alloc,
+// so nothing is shared and the handler knows what fired.
+
+use crate::*;
+use crate::kern::pci::*;
+use crate::kern::mp::CPU_INFO;
+use crate::kern::vm::mmio2v;
+use crate::kern::idt::{vector_alloc, vector_free};
+use core::ptr::write_volatile;
+
+const MSI_ADDR_BASE: u64 = 0xFEE00000;
+
+const MSI_CTRL_ENABLE: u16 = 1 << 0;
+const MSI_CTRL_MME: u16 = 0x7 << 4;     // Multiple message enable
+const MSIX_CTRL_FMASK: u16 = 1 << 14;   // Function mask
+const MSIX_CTRL_ENABLE: u16 = 1 << 15;
+
+const MSIX_ENTRY_SIZE: u64 = 16;        // addr lo, addr hi, data, vector control
+const MSIX_VCTRL_MASK: u32 = 1;
+
+// Message address and data delivering vector to cpu: fixed delivery mode,
+// edge triggered, physical destination.
+fn message(cpu: usize, vector: u32) -> Result<(u64, u32), &'static str> {
+    if cpu >= CPU_INFO.ncpu() { return Err("msi: no such cpu") }
+    Ok((MSI_ADDR_BASE | (CPU_INFO.apic_id(cpu) as u64) << 12, vector))
+}
+
+// Allocate a vector for handler and the message which delivers it to cpu.
+fn alloc(cpu: usize, handler: fn(usize), data: usize) -> Result<(u32, u64, u32), &'static str> {
+    let vector = vector_alloc(handler, data)?;
+    match message(cpu, vector) {
+        Ok((addr, val)) => Ok((vector, addr, val)),
+        Err(e) => {
+            vector_free(vector);
+            Err(e)
+        }
+    }
+}
+
+// Enable MSI with a single vector delivered to cpu, returns the vector.
+// handler is called with data on every interrupt.
+pub fn msi_enable(dev: &PciDev, cpu: usize, handler: fn(usize), data: usize) -> Result<u32, &'static str> {
+    let cap = dev.msi.ok_or("msi: not supported")?;
+    let (vector, addr, val) = alloc(cpu, handler, data)?;
+
+    let a = &dev.addr;
+    let off = cap.off;
+    a.write32(off + 4, addr as u32);
+    let data_off = if cap.is64 {
+        a.write32(off + 8, (addr >> 32) as u32);
+        off + 12
+    } else {
+        off + 8
+    };
+    a.write16(data_off, val as u16);
+    if cap.maskable { a.write32(data_off + 4, 0); }
+
+    let ctrl = a.read16(off + 2) & !MSI_CTRL_MME;
+    a.write16(off + 2, ctrl | MSI_CTRL_ENABLE);
+    a.enable(PCI_COMMAND_MASTER | PCI_COMMAND_INTX_DISABLE);
+    Ok(vector)
+}
+
+pub fn msi_disable(dev: &PciDev, vector: u32) -> () {
+    if let Some(cap) = dev.msi {
+        let ctrl = dev.addr.read16(cap.off + 2);
+        dev.addr.write16(cap.off + 2, ctrl & !MSI_CTRL_ENABLE);
+        dev.addr.disable(PCI_COMMAND_INTX_DISABLE);
+    }
+    vector_free(vector);
+}
+
+// Address of an entry in the MSI-X table. pci_reserve has the tables
+// outside DEVSPACE mapped.
+fn msix_entry(dev: &PciDev, cap: &MsixCap, entry: u16) -> Result<u64, &'static str> {
+    if entry >= cap.nvec { return Err("msix: no such entry") }
+    let table = match dev.bars[cap.table_bar as usize] {
+        Bar::Mem { addr, .. } => mmio2v(addr + cap.table_off as u64).ok_or("msix: table not mapped")?,
+        _ => return Err("msix: table not in a memory BAR"),
+    };
+    Ok(table + entry as u64 * MSIX_ENTRY_SIZE)
+}
+
+// Point MSI-X table entry at a new vector delivered to cpu and unmask it,
+// returns the vector. Entries not set up this way stay masked.
+pub fn msix_enable(dev: &PciDev, entry: u16, cpu: usize, handler: fn(usize), data: usize)
+    -> Result<u32, &'static str> {
+    let cap = dev.msix.ok_or("msix: not supported")?;
+    let e = msix_entry(dev, &cap, entry)?;
+    let (vector, addr, val) = alloc(cpu, handler, data)?;
+
+    let a = &dev.addr;
+    a.enable(PCI_COMMAND_MEMORY | PCI_COMMAND_MASTER | PCI_COMMAND_INTX_DISABLE);
+    // Mask the whole function while the entry is half written.
+    let ctrl = a.read16(cap.off + 2);
+    a.write16(cap.off + 2, ctrl | MSIX_CTRL_ENABLE | MSIX_CTRL_FMASK);
+    unsafe {
+        write_volatile(e as *mut u32, addr as u32);
+        write_volatile((e + 4) as *mut u32, (addr >> 32) as u32);
+        write_volatile((e + 8) as *mut u32, val);
+        write_volatile((e + 12) as *mut u32, 0);
+    }
+    a.write16(cap.off + 2, (ctrl | MSIX_CTRL_ENABLE) & !MSIX_CTRL_FMASK);
+    Ok(vector)
+}
+
+// Mask an entry and release its vector. MSI-X stays enabled for the others.
+pub fn msix_disable(dev: &PciDev, entry: u16, vector: u32) -> () {
+    if let Some(cap) = dev.msix {
+        if let Ok(e) = msix_entry(dev, &cap, entry) {
+            unsafe { write_volatile((e + 12) as *mut u32, MSIX_VCTRL_MASK); }
+        }
+    }
+    vector_free(vector);
+}
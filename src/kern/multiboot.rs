@@ -0,0 +1,420 @@
+// Multiboot boot information.
+//
+// entry.S saves the magic and the physical address of what the boot
+// loader passes: the Multiboot 1 information block, or the tag list of
+// Multiboot 2. Only the first 4MB are mapped that early, so
+// multiboot_init copies what the kernel needs before the page allocator
+// may hand out the memory it lies in. Either way the kernel sees the
+// same modules, memory map, command line, framebuffer, ACPI RSDP and
+// ELF section headers.
+
+use crate::*;
+
+pub const MULTIBOOT_BOOTLOADER_MAGIC: u32 = 0x2BADB002;
+pub const MULTIBOOT2_BOOTLOADER_MAGIC: u32 = 0x36d76289;
+
+// Flags of MultibootInfo
+const MB_INFO_BOOTDEV: u32 = 1 << 1;    // boot_device is valid
+const MB_INFO_CMDLINE: u32 = 1 << 2;    // cmdline is valid
+const MB_INFO_MODS: u32 = 1 << 3;       // mods_count and mods_addr are valid
+const MB_INFO_ELF_SHDR: u32 = 1 << 5;   // syms holds ELF section headers
+const MB_INFO_MEM_MAP: u32 = 1 << 6;    // mmap_length and mmap_addr are valid
+const MB_INFO_FRAMEBUFFER: u32 = 1 << 12;
+
+// Multiboot 2 tag types
+const MB2_TAG_END: u32 = 0;
+const MB2_TAG_CMDLINE: u32 = 1;
+const MB2_TAG_MODULE: u32 = 3;
+const MB2_TAG_MMAP: u32 = 6;
+const MB2_TAG_FRAMEBUFFER: u32 = 8;
+const MB2_TAG_ELF_SECTIONS: u32 = 9;
+const MB2_TAG_ACPI_OLD: u32 = 14;
+const MB2_TAG_ACPI_NEW: u32 = 15;
+
+// Types of MemRegion
+pub const MEM_AVAILABLE: u32 = 1;
+pub const MEM_RESERVED: u32 = 2;
+pub const MEM_ACPI: u32 = 3;            // ACPI tables, reclaimable
+pub const MEM_NVS: u32 = 4;
+pub const MEM_BAD: u32 = 5;
+
+const NMODULE: usize = 8;
+const NMEMREGION: usize = 32;
+const NSECTION: usize = 32;
+const CMDLINE_MAX: usize = 256;
+const RSDP_SIZE: usize = 36;            // ACPI 2.0 and later
+const EARLYTOP: u64 = 4 * 1024 * 1024;  // Mapped by entry.S
+
+extern "C" {
+    static mboot_sig: u32;
+    static mboot_ptr: u32;
+}
+
+#[repr(C, packed)]
+struct MultibootInfo {
+    flags: u32,
+    mem_lower: u32,
+    mem_upper: u32,
+    boot_device: u32,
+    cmdline: u32,
+    mods_count: u32,
+    mods_addr: u32,
+    shdr_num: u32,                      // syms, ELF section headers
+    shdr_size: u32,
+    shdr_addr: u32,
+    shdr_shndx: u32,
+    mmap_length: u32,
+    mmap_addr: u32,
+    drives_length: u32,
+    drives_addr: u32,
+    config_table: u32,
+    boot_loader_name: u32,
+    apm_table: u32,
+    vbe_control_info: u32,
+    vbe_mode_info: u32,
+    vbe_mode: u16,
+    vbe_interface_seg: u16,
+    vbe_interface_off: u16,
+    vbe_interface_len: u16,
+    framebuffer_addr: u64,
+    framebuffer_pitch: u32,
+    framebuffer_width: u32,
+    framebuffer_height: u32,
+    framebuffer_bpp: u8,
+    framebuffer_type: u8,
+}
+
+#[repr(C)]
+struct MultibootModule {
+    mod_start: u32,
+    mod_end: u32,
+    string: u32,
+    reserved: u32,
+}
+
+// Multiboot 1 memory map entry, size does not count itself.
+#[repr(C, packed)]
+struct MultibootMmapEntry {
+    size: u32,
+    addr: u64,
+    len: u64,
+    mtype: u32,
+}
+
+// Multiboot 2 tags, 8 byte aligned, each starting with this.
+#[repr(C)]
+struct Tag {
+    ttype: u32,
+    size: u32,
+}
+
+#[repr(C)]
+struct TagModule {
+    tag: Tag,
+    mod_start: u32,
+    mod_end: u32,
+}
+
+#[repr(C)]
+struct TagMmap {
+    tag: Tag,
+    entry_size: u32,
+    entry_version: u32,
+}
+
+#[repr(C, packed)]
+struct TagMmapEntry {
+    addr: u64,
+    len: u64,
+    mtype: u32,
+    reserved: u32,
+}
+
+#[repr(C, packed)]
+struct TagFramebuffer {
+    tag: Tag,
+    addr: u64,
+    pitch: u32,
+    width: u32,
+    height: u32,
+    bpp: u8,
+    fbtype: u8,
+}
+
+#[repr(C)]
+struct TagElfSections {
+    tag: Tag,
+    num: u32,
+    entsize: u32,
+    shndx: u32,
+}
+
+// Elf64_Shdr
+#[repr(C, packed)]
+struct Shdr {
+    name: u32,
+    stype: u32,
+    flags: u64,
+    addr: u64,
+    offset: u64,
+    size: u64,
+    link: u32,
+    info: u32,
+    addralign: u64,
+    entsize: u64,
+}
+
+// A module the boot loader loaded, [start, end) in physical memory.
+#[derive(Copy, Clone)]
+pub struct Module {
+    pub start: u64,
+    pub end: u64,
+}
+
+// A range of physical memory, one of the MEM_ types.
+#[derive(Copy, Clone)]
+pub struct MemRegion {
+    pub base: u64,
+    pub len: u64,
+    pub mtype: u32,
+}
+
+#[derive(Copy, Clone)]
+pub struct Framebuffer {
+    pub addr: u64,                      // Physical
+    pub pitch: u32,                     // Bytes per line
+    pub width: u32,
+    pub height: u32,
+    pub bpp: u8,
+    pub fbtype: u8,                     // 0 indexed, 1 RGB, 2 EGA text
+}
+
+// A section of the kernel image, where the boot loader put it.
+#[derive(Copy, Clone)]
+pub struct ElfSection {
+    pub stype: u32,
+    pub flags: u64,
+    pub addr: u64,
+    pub size: u64,
+}
+
+static mut MODULES: [Module; NMODULE] = [Module { start: 0, end: 0 }; NMODULE];
+static mut NMODULES: usize = 0;
+static mut MEMMAP: [MemRegion; NMEMREGION] = [MemRegion { base: 0, len: 0, mtype: 0 }; NMEMREGION];
+static mut NMEMMAP: usize = 0;
+static mut SECTIONS: [ElfSection; NSECTION] = [ElfSection { stype: 0, flags: 0, addr: 0, size: 0 }; NSECTION];
+static mut NSECTIONS: usize = 0;
+static mut CMDLINE: [u8; CMDLINE_MAX] = [0; CMDLINE_MAX];
+static mut CMDLINE_LEN: usize = 0;
+static mut FRAMEBUFFER: Option<Framebuffer> = None;
+static mut RSDP: [u8; RSDP_SIZE] = [0; RSDP_SIZE];
+static mut RSDP_LEN: usize = 0;
+static mut BOOT_DRIVE: Option<u8> = None;
+
+// The ith module, in the order of grub.cfg.
+pub fn module(i: usize) -> Option<Module> {
+    unsafe { if i < NMODULES { Some(MODULES[i]) } else { None } }
+}
+
+pub fn memory_map() -> &'static [MemRegion] {
+    unsafe { &MEMMAP[..NMEMMAP] }
+}
+
+// Call f with each piece [st, ed) of [lo, hi) which the memory map says
+// is RAM, physical addresses. Returns false if there was no map.
+pub fn available<F: FnMut(u64, u64)>(lo: u64, hi: u64, mut f: F) -> bool {
+    for r in memory_map().iter().filter(|r| r.mtype == MEM_AVAILABLE) {
+        let st = core::cmp::max(r.base, lo);
+        let ed = core::cmp::min(r.base.saturating_add(r.len), hi);
+        if st < ed { f(st, ed); }
+    }
+    !memory_map().is_empty()
+}
+
+// The framebuffer, the ELF sections and the boot drive are not used by
+// the kernel yet. They are kept so both boot paths hand over the same
+// information, for a graphics console and symbolized backtraces.
+pub fn elf_sections() -> &'static [ElfSection] {
+    unsafe { &SECTIONS[..NSECTIONS] }
+}
+
+// The kernel command line.
+pub fn cmdline() -> &'static str {
+    let s = unsafe { core::str::from_utf8(&CMDLINE[..CMDLINE_LEN]) }.unwrap_or("");
+    // GRUB's Multiboot 1 command line starts with the kernel path.
+    if s.starts_with('/') { s.splitn(2, ' ').nth(1).unwrap_or("").trim_start() } else { s }
+}
+
+pub fn framebuffer() -> Option<Framebuffer> {
+    unsafe { FRAMEBUFFER }
+}
+
+// A copy of the ACPI RSDP, for machines without a BIOS to search.
+pub fn rsdp() -> Option<&'static [u8]> {
+    unsafe { if RSDP_LEN > 0 { Some(&RSDP[..RSDP_LEN]) } else { None } }
+}
+
+// The BIOS number of the drive booted from, 0x80 for the first disk.
+pub fn boot_drive() -> Option<u8> {
+    unsafe { BOOT_DRIVE }
+}
+
+// The kernel address of len bytes at pa, if entry.S mapped them.
+fn early(pa: u64, len: u64) -> Option<u64> {
+    if pa == 0 || pa + len > EARLYTOP { None } else { Some(p2v!(pa)) }
+}
+
+unsafe fn save_cmdline(pa: u64) -> () {
+    let mut n = 0;
+    while n < CMDLINE_MAX {
+        let c = match early(pa + n as u64, 1) {
+            Some(p) => *(p as *const u8),
+            None => break,
+        };
+        if c == 0 { break }
+        CMDLINE[n] = c;
+        n += 1;
+    }
+    CMDLINE_LEN = n;
+}
+
+unsafe fn save_module(start: u64, end: u64) -> () {
+    if NMODULES == NMODULE || end > PHYSTOP || end < start {
+        println!("multiboot: module at {:#x} ignored", start);
+        return;
+    }
+    MODULES[NMODULES] = Module { start, end };
+    NMODULES += 1;
+}
+
+unsafe fn save_region(base: u64, len: u64, mtype: u32) -> () {
+    if NMEMMAP == NMEMREGION { return }
+    MEMMAP[NMEMMAP] = MemRegion { base, len, mtype };
+    NMEMMAP += 1;
+}
+
+unsafe fn save_sections(pa: u64, num: u32, entsize: u32) -> () {
+    if (entsize as usize) < size_of::<Shdr>() { return }
+    for i in 0..core::cmp::min(num as usize, NSECTION) {
+        let sh = match early(pa + (i * entsize as usize) as u64, size_of::<Shdr>() as u64) {
+            Some(p) => &*(p as *const Shdr),
+            None => return,
+        };
+        SECTIONS[NSECTIONS] = ElfSection { stype: sh.stype, flags: sh.flags, addr: sh.addr, size: sh.size };
+        NSECTIONS += 1;
+    }
+}
+
+unsafe fn save_rsdp(p: u64, len: usize) -> () {
+    let len = core::cmp::min(len, RSDP_SIZE);
+    memmove(RSDP.as_mut_ptr(), p as *const u8, len);
+    RSDP_LEN = len;
+}
+
+unsafe fn multiboot1(mbi: &MultibootInfo) -> () {
+    let flags = mbi.flags;
+    if flags & MB_INFO_BOOTDEV != 0 { BOOT_DRIVE = Some((mbi.boot_device >> 24) as u8); }
+    if flags & MB_INFO_CMDLINE != 0 { save_cmdline(mbi.cmdline as u64); }
+
+    if flags & MB_INFO_MODS != 0 {
+        let n = mbi.mods_count as usize;
+        match early(mbi.mods_addr as u64, (n * size_of::<MultibootModule>()) as u64) {
+            Some(p) => {
+                for i in 0..n {
+                    let m = &*(p as *const MultibootModule).offset(i as isize);
+                    save_module(m.mod_start as u64, m.mod_end as u64);
+                }
+            }
+            None => println!("multiboot: modules not mapped"),
+        }
+    }
+
+    if flags & MB_INFO_MEM_MAP != 0 {
+        let (st, len) = (mbi.mmap_addr as u64, mbi.mmap_length as u64);
+        let mut off = 0;
+        while off + size_of::<MultibootMmapEntry>() as u64 <= len {
+            let e = match early(st + off, size_of::<MultibootMmapEntry>() as u64) {
+                Some(p) => &*(p as *const MultibootMmapEntry),
+                None => break,
+            };
+            save_region(e.addr, e.len, e.mtype);
+            off += e.size as u64 + 4;
+        }
+    }
+
+    if flags & MB_INFO_ELF_SHDR != 0 {
+        save_sections(mbi.shdr_addr as u64, mbi.shdr_num, mbi.shdr_size);
+    }
+
+    if flags & MB_INFO_FRAMEBUFFER != 0 {
+        FRAMEBUFFER = Some(Framebuffer {
+            addr: mbi.framebuffer_addr,
+            pitch: mbi.framebuffer_pitch,
+            width: mbi.framebuffer_width,
+            height: mbi.framebuffer_height,
+            bpp: mbi.framebuffer_bpp,
+            fbtype: mbi.framebuffer_type,
+        });
+    }
+}
+
+unsafe fn multiboot2(pa: u64) -> () {
+    let total = early(pa, 8).map(|p| *(p as *const u32) as u64).unwrap_or(0);
+    if total == 0 || early(pa, total).is_none() {
+        println!("multiboot2: info at {:#x} not mapped", pa);
+        return;
+    }
+
+    let mut off = 8;
+    while off + size_of::<Tag>() as u64 <= total {
+        let p = p2v!(pa + off);
+        let tag = &*(p as *const Tag);
+        if tag.ttype == MB2_TAG_END || (tag.size as usize) < size_of::<Tag>() { break }
+        let body = p + size_of::<Tag>() as u64;
+        match tag.ttype {
+            MB2_TAG_CMDLINE => save_cmdline(pa + off + size_of::<Tag>() as u64),
+            MB2_TAG_MODULE => {
+                let m = &*(p as *const TagModule);
+                save_module(m.mod_start as u64, m.mod_end as u64);
+            }
+            MB2_TAG_MMAP => {
+                let m = &*(p as *const TagMmap);
+                let mut e = p + size_of::<TagMmap>() as u64;
+                while m.entry_size as usize >= size_of::<TagMmapEntry>() && e + m.entry_size as u64 <= p + tag.size as u64 {
+                    let r = &*(e as *const TagMmapEntry);
+                    save_region(r.addr, r.len, r.mtype);
+                    e += m.entry_size as u64;
+                }
+            }
+            MB2_TAG_FRAMEBUFFER => {
+                let f = &*(p as *const TagFramebuffer);
+                FRAMEBUFFER = Some(Framebuffer {
+                    addr: f.addr, pitch: f.pitch, width: f.width, height: f.height, bpp: f.bpp, fbtype: f.fbtype,
+                });
+            }
+            MB2_TAG_ELF_SECTIONS => {
+                let s = &*(p as *const TagElfSections);
+                save_sections(pa + off + size_of::<TagElfSections>() as u64, s.num, s.entsize);
+            }
+            // The new one wins, GRUB passes both.
+            MB2_TAG_ACPI_OLD if RSDP_LEN == 0 => save_rsdp(body, tag.size as usize - size_of::<Tag>()),
+            MB2_TAG_ACPI_NEW => save_rsdp(body, tag.size as usize - size_of::<Tag>()),
+            _ => {}
+        }
+        off += (tag.size as u64 + 7) & !7;
+    }
+}
+
+// Read the boot information. Returns the end of the memory the modules
+// take, the page allocator must start above it.
+pub unsafe fn multiboot_init() -> u64 {
+    match mboot_sig {
+        MULTIBOOT_BOOTLOADER_MAGIC => match early(mboot_ptr as u64, size_of::<MultibootInfo>() as u64) {
+            Some(p) => multiboot1(&*(p as *const MultibootInfo)),
+            None => println!("multiboot: info at {:#x} not mapped", mboot_ptr),
+        },
+        MULTIBOOT2_BOOTLOADER_MAGIC => multiboot2(mboot_ptr as u64),
+        _ => return 0,
+    }
+    MODULES[..NMODULES].iter().map(|m| m.end).max().unwrap_or(0)
+}
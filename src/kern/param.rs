@@ -0,0 +1,111 @@
+// Boot parameters.
+//
+// The kernel command line, what grub.cfg gives after the kernel path, is
+// a list of key=value options separated by spaces. param_init checks
+// each against PARAMS and parses its value by the type declared there;
+// subsystems ask for theirs at init and fall back to their default if it
+// was not given. Unknown options and bad values are reported and
+// otherwise ignored.
+
+use crate::*;
+use crate::kern::multiboot::cmdline;
+use crate::kern::proc::MAX_KSTACKPAGES;
+
+#[derive(Copy, Clone)]
+enum Kind {
+    Num(u64, u64),                      // A number in [min, max]
+    Str(&'static [&'static str]),       // One of these, anything if empty
+}
+
+struct Param {
+    name: &'static str,
+    kind: Kind,
+}
+
+#[derive(Copy, Clone)]
+enum Value {
+    Num(u64),
+    Str(&'static str),
+}
+
+const NPARAM: usize = 6;
+
+const PARAMS: [Param; NPARAM] = [
+    // Bus cycles between timer interrupts, see kern::lapic.
+    Param { name: "timer", kind: Kind::Num(10000, 0xffffffff) },
+    // Use only the first CPUs of the MP table or MADT.
+    Param { name: "maxcpus", kind: Kind::Num(1, MAX_CPU as u64) },
+    // Pages of a kernel stack.
+    Param { name: "kstack", kind: Kind::Num(1, MAX_KSTACKPAGES) },
+    // Where kernel messages go: the screen, COM1 or both.
+    Param { name: "console", kind: Kind::Str(&["vga", "ttyS0", "both"]) },
+    // The disk holding the root file system, named as for mount.
+    Param { name: "root", kind: Kind::Str(&[]) },
+    // Which kernel messages are printed: 0 none, 1 panics, 2 all.
+    Param { name: "loglevel", kind: Kind::Num(0, 2) },
+];
+
+static mut VALUES: [Option<Value>; NPARAM] = [None; NPARAM];
+
+// Parse a decimal or 0x hexadecimal number.
+fn parse_num(s: &str) -> Option<u64> {
+    if s.starts_with("0x") {
+        u64::from_str_radix(&s[2..], 16).ok()
+    } else {
+        s.parse().ok()
+    }
+}
+
+fn parse(kind: Kind, s: &'static str) -> Option<Value> {
+    match kind {
+        Kind::Num(min, max) => match parse_num(s) {
+            Some(n) if n >= min && n <= max => Some(Value::Num(n)),
+            _ => None,
+        },
+        Kind::Str(choices) if choices.is_empty() || choices.contains(&s) => Some(Value::Str(s)),
+        Kind::Str(_) => None,
+    }
+}
+
+// Parse the command line. Must run before the subsystems ask.
+pub fn param_init() -> () {
+    let line = cmdline();
+    if !line.is_empty() { println!("cmdline: {}", line); }
+
+    for opt in line.split(' ').filter(|o| !o.is_empty()) {
+        let (key, val) = match opt.find('=') {
+            Some(i) => (&opt[..i], &opt[i + 1..]),
+            None => (opt, ""),
+        };
+        match PARAMS.iter().position(|p| p.name == key) {
+            Some(i) => match parse(PARAMS[i].kind, val) {
+                Some(v) => unsafe { VALUES[i] = Some(v) },
+                None => println!("param: bad value for {}: '{}'", key, val),
+            },
+            None => println!("param: unknown option {}", key),
+        }
+    }
+}
+
+fn value(name: &str) -> Option<Value> {
+    match PARAMS.iter().position(|p| p.name == name) {
+        Some(i) => unsafe { VALUES[i] },
+        None => panic!("param: {} not in PARAMS", name),
+    }
+}
+
+// The value of a number parameter, None if not given.
+pub fn param_num(name: &str) -> Option<u64> {
+    match value(name) {
+        Some(Value::Num(n)) => Some(n),
+        _ => None,
+    }
+}
+
+// The value of a string parameter, None if not given.
+pub fn param_str(name: &str) -> Option<&'static str> {
+    match value(name) {
+        Some(Value::Str(s)) => Some(s),
+        _ => None,
+    }
+}
@@ -0,0 +1,440 @@
+// PCI bus enumeration and driver binding.
+//
+// Configuration space is accessed through ECAM when ACPI has an MCFG
+// table, otherwise through configuration mechanism #1 (ports 0xCF8/0xCFC).
+// pci_init enumerates every function once and decodes its BARs and
+// MSI/MSI-X capabilities. Drivers register a PciDriver with the IDs they
+// handle, its probe function is called for every matching device no other
+// driver has claimed yet.
+
+use crate::*;
+use crate::kern::acpi::{find_table, SdtHeader};
+use crate::kern::vm::{dev_reserve, dev_space_left, mmio2v};
+use core::ptr::{read_volatile, write_volatile};
+use x86_64::instructions::port::Port;
+
+const CONFIG_ADDRESS: u16 = 0xCF8;
+const CONFIG_DATA: u16 = 0xCFC;
+
+pub const PCI_VENDOR_ID: u8 = 0x00;
+pub const PCI_DEVICE_ID: u8 = 0x02;
+pub const PCI_COMMAND: u8 = 0x04;
+pub const PCI_STATUS: u8 = 0x06;
+pub const PCI_CLASS: u8 = 0x08;      // revision, prog if, subclass, class
+pub const PCI_HEADER_TYPE: u8 = 0x0E;
+pub const PCI_BAR0: u8 = 0x10;
+pub const PCI_CAP_PTR: u8 = 0x34;
+pub const PCI_INTERRUPT_LINE: u8 = 0x3C;
+
+pub const PCI_COMMAND_IO: u16 = 0x1;
+pub const PCI_COMMAND_MEMORY: u16 = 0x2;
+pub const PCI_COMMAND_MASTER: u16 = 0x4;
+pub const PCI_COMMAND_INTX_DISABLE: u16 = 0x400;
+pub const PCI_STATUS_CAP_LIST: u16 = 0x10;
+
+pub const PCI_CAP_ID_MSI: u8 = 0x05;
+pub const PCI_CAP_ID_MSIX: u8 = 0x11;
+
+pub const PCI_ANY_ID: u16 = 0xFFFF;
+
+const MAXPCIDEV: usize = 64;
+const MAXPCIDRV: usize = 16;
+
+// ECAM window of segment 0: physical and virtual address of the first
+// mapped bus, the first and last bus mapped. ECAM_VA is 0 until pci_init.
+static mut ECAM_PA: u64 = 0;
+static mut ECAM_VA: u64 = 0;
+static mut ECAM_BUS: (u8, u8) = (0, 0);
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct PciAddr {
+    pub bus: u8,
+    pub dev: u8,
+    pub func: u8,
+}
+
+impl PciAddr {
+    fn address(&self, off: u8) -> u32 {
+        0x80000000 | (self.bus as u32) << 16 | (self.dev as u32) << 11 |
+            (self.func as u32) << 8 | (off as u32 & 0xFC)
+    }
+
+    // Address of a register in the ECAM window, if the bus is in there.
+    fn ecam(&self, off: u8) -> Option<u64> {
+        unsafe {
+            if ECAM_VA == 0 || self.bus < ECAM_BUS.0 || self.bus > ECAM_BUS.1 { return None }
+            Some(ECAM_VA + (((self.bus - ECAM_BUS.0) as u64) << 20 | (self.dev as u64) << 15 |
+                            (self.func as u64) << 12 | (off as u64 & 0xFC)))
+        }
+    }
+
+    pub fn read32(&self, off: u8) -> u32 {
+        unsafe {
+            if let Some(a) = self.ecam(off) { return read_volatile(a as *const u32) }
+            Port::<u32>::new(CONFIG_ADDRESS).write(self.address(off));
+            Port::<u32>::new(CONFIG_DATA).read()
+        }
+    }
+
+    pub fn write32(&self, off: u8, v: u32) -> () {
+        unsafe {
+            if let Some(a) = self.ecam(off) { return write_volatile(a as *mut u32, v) }
+            Port::<u32>::new(CONFIG_ADDRESS).write(self.address(off));
+            Port::<u32>::new(CONFIG_DATA).write(v);
+        }
+    }
+
+    pub fn read16(&self, off: u8) -> u16 { (self.read32(off) >> ((off & 2) * 8)) as u16 }
+    pub fn read8(&self, off: u8) -> u8 { (self.read32(off) >> ((off & 3) * 8)) as u8 }
+
+    pub fn write16(&self, off: u8, v: u16) -> () {
+        let shift = (off & 2) * 8;
+        let old = self.read32(off) & !(0xFFFF << shift);
+        self.write32(off, old | (v as u32) << shift);
+    }
+
+    pub fn vendor(&self) -> u16 { self.read16(PCI_VENDOR_ID) }
+    pub fn device(&self) -> u16 { self.read16(PCI_DEVICE_ID) }
+    pub fn irq_line(&self) -> u8 { self.read8(PCI_INTERRUPT_LINE) }
+
+    // Class code: class << 16 | subclass << 8 | prog if
+    pub fn class(&self) -> u32 { self.read32(PCI_CLASS) >> 8 }
+
+    // Raw value of a base address register.
+    pub fn bar(&self, i: u8) -> u32 { self.read32(PCI_BAR0 + 4 * i) }
+
+    // Physical address of a memory BAR, 64-bit BARs take two slots.
+    pub fn mem_bar(&self, i: u8) -> u64 {
+        let lo = self.bar(i);
+        if lo & 0x6 == 0x4 {
+            (self.bar(i + 1) as u64) << 32 | (lo & !0xF) as u64
+        } else {
+            (lo & !0xF) as u64
+        }
+    }
+
+    pub fn io_bar(&self, i: u8) -> u16 { (self.bar(i) & !0x3) as u16 }
+
+    pub fn enable(&self, bits: u16) -> () {
+        let cmd = self.read16(PCI_COMMAND);
+        self.write16(PCI_COMMAND, cmd | bits);
+    }
+
+    pub fn disable(&self, bits: u16) -> () {
+        let cmd = self.read16(PCI_COMMAND);
+        self.write16(PCI_COMMAND, cmd & !bits);
+    }
+
+    // Offset of the first capability with the given id after `from`
+    // (0 to start at the head of the list).
+    pub fn find_cap(&self, id: u8, from: u8) -> Option<u8> {
+        if self.read16(PCI_STATUS) & PCI_STATUS_CAP_LIST == 0 { return None }
+        let mut p = if from == 0 { self.read8(PCI_CAP_PTR) } else { self.read8(from + 1) };
+        while p != 0 {
+            if self.read8(p) == id { return Some(p) }
+            p = self.read8(p + 1);
+        }
+        None
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum Bar {
+    None,
+    Io { port: u16, size: u32 },
+    Mem { addr: u64, size: u64, prefetch: bool },
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct MsiCap {
+    pub off: u8,
+    pub is64: bool,         // 64-bit message address
+    pub maskable: bool,     // Per-vector masking
+    pub nvec: u8,           // Vectors the device asks for
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct MsixCap {
+    pub off: u8,
+    pub nvec: u16,
+    pub table_bar: u8,
+    pub table_off: u32,
+    pub pba_bar: u8,
+    pub pba_off: u32,
+}
+
+#[derive(Copy, Clone)]
+pub struct PciDev {
+    pub addr: PciAddr,
+    pub vendor: u16,
+    pub device: u16,
+    pub class: u32,
+    pub irq_line: u8,
+    pub bars: [Bar; 6],
+    pub msi: Option<MsiCap>,
+    pub msix: Option<MsixCap>,
+    driver: Option<&'static PciDriver>,
+}
+
+impl PciDev {
+    const fn empty() -> Self {
+        PciDev {
+            addr: PciAddr { bus: 0, dev: 0, func: 0 },
+            vendor: 0,
+            device: 0,
+            class: 0,
+            irq_line: 0,
+            bars: [Bar::None; 6],
+            msi: None,
+            msix: None,
+            driver: None,
+        }
+    }
+
+    fn new(a: PciAddr) -> Self {
+        let nbars = match a.read8(PCI_HEADER_TYPE) & 0x7F {
+            0 => 6,
+            1 => 2,             // PCI-to-PCI bridge
+            _ => 0,
+        };
+        PciDev {
+            addr: a,
+            vendor: a.vendor(),
+            device: a.device(),
+            class: a.class(),
+            irq_line: a.irq_line(),
+            bars: decode_bars(&a, nbars),
+            msi: a.find_cap(PCI_CAP_ID_MSI, 0).map(|off| {
+                let ctrl = a.read16(off + 2);
+                MsiCap {
+                    off: off,
+                    is64: ctrl & (1 << 7) != 0,
+                    maskable: ctrl & (1 << 8) != 0,
+                    nvec: (1u16 << ((ctrl >> 1) & 0x7)) as u8,
+                }
+            }),
+            msix: a.find_cap(PCI_CAP_ID_MSIX, 0).map(|off| {
+                let table = a.read32(off + 4);
+                let pba = a.read32(off + 8);
+                MsixCap {
+                    off: off,
+                    nvec: (a.read16(off + 2) & 0x7FF) + 1,
+                    table_bar: (table & 0x7) as u8,
+                    table_off: table & !0x7,
+                    pba_bar: (pba & 0x7) as u8,
+                    pba_off: pba & !0x7,
+                }
+            }),
+            driver: None,
+        }
+    }
+
+    pub fn driver(&self) -> Option<&'static str> { self.driver.map(|d| d.name) }
+}
+
+// Size the BARs by writing all ones and reading back which bits stick.
+// Decoding is turned off meanwhile so the device does not answer at
+// the bogus addresses.
+fn decode_bars(a: &PciAddr, n: u8) -> [Bar; 6] {
+    let mut bars = [Bar::None; 6];
+    let cmd = a.read16(PCI_COMMAND);
+    a.write16(PCI_COMMAND, cmd & !(PCI_COMMAND_IO | PCI_COMMAND_MEMORY));
+
+    let probe = |off: u8| -> (u32, u32) {
+        let v = a.read32(off);
+        a.write32(off, 0xFFFFFFFF);
+        let mask = a.read32(off);
+        a.write32(off, v);
+        (v, mask)
+    };
+
+    let mut i = 0;
+    while i < n {
+        let (v, mask) = probe(PCI_BAR0 + 4 * i);
+        if v & 1 == 1 {
+            let mask = mask & 0xFFFC;
+            if mask != 0 {
+                bars[i as usize] = Bar::Io { port: (v & !0x3) as u16, size: (!mask & 0xFFFF) + 1 };
+            }
+            i += 1;
+            continue;
+        }
+
+        let is64 = v & 0x6 == 0x4 && i + 1 < n;
+        let mut addr = (v & !0xF) as u64;
+        let mut mask = (mask & !0xF) as u64;
+        if is64 {
+            let (hi, mask_hi) = probe(PCI_BAR0 + 4 * (i + 1));
+            addr |= (hi as u64) << 32;
+            mask |= (mask_hi as u64) << 32;
+        } else if mask != 0 {
+            mask |= 0xFFFFFFFF00000000;
+        }
+        if mask != 0 {
+            bars[i as usize] = Bar::Mem { addr: addr, size: (!mask).wrapping_add(1), prefetch: v & 0x8 != 0 };
+        }
+        i += if is64 { 2 } else { 1 };
+    }
+
+    a.write16(PCI_COMMAND, cmd);
+    bars
+}
+
+// A device a driver handles: vendor/device IDs (PCI_ANY_ID matches any)
+// and the class code bits selected by class_mask.
+pub struct PciId {
+    pub vendor: u16,
+    pub device: u16,
+    pub class: u32,
+    pub class_mask: u32,
+}
+
+impl PciId {
+    pub const fn device(vendor: u16, device: u16) -> Self {
+        PciId { vendor: vendor, device: device, class: 0, class_mask: 0 }
+    }
+
+    pub const fn class(class: u32, class_mask: u32) -> Self {
+        PciId { vendor: PCI_ANY_ID, device: PCI_ANY_ID, class: class, class_mask: class_mask }
+    }
+
+    fn matches(&self, d: &PciDev) -> bool {
+        (self.vendor == PCI_ANY_ID || self.vendor == d.vendor) &&
+            (self.device == PCI_ANY_ID || self.device == d.device) &&
+            d.class & self.class_mask == self.class & self.class_mask
+    }
+}
+
+pub struct PciDriver {
+    pub name: &'static str,
+    pub ids: &'static [PciId],
+    // Set up the device, an error leaves it to other drivers.
+    pub probe: fn(&PciDev) -> Result<(), &'static str>,
+}
+
+// Only changed during boot, on the boot CPU.
+static mut PCIDEVS: [PciDev; MAXPCIDEV] = [PciDev::empty(); MAXPCIDEV];
+static mut NPCIDEV: usize = 0;
+static mut PCIDRVS: [Option<&'static PciDriver>; MAXPCIDRV] = [None; MAXPCIDRV];
+
+// Call f on every function present on the bus.
+pub fn pci_scan<F: FnMut(PciAddr)>(mut f: F) -> () {
+    for bus in 0..256u16 {
+        for dev in 0..32u8 {
+            let a = PciAddr { bus: bus as u8, dev: dev, func: 0 };
+            if a.vendor() == 0xFFFF { continue }
+            let nfunc = if a.read8(PCI_HEADER_TYPE) & 0x80 != 0 { 8 } else { 1 };
+            for func in 0..nfunc {
+                let a = PciAddr { bus: bus as u8, dev: dev, func: func };
+                if a.vendor() != 0xFFFF { f(a); }
+            }
+        }
+    }
+}
+
+fn ecam_reserve() -> () {
+    let mcfg = match find_table(b"MCFG") {
+        Some(t) => t,
+        None => return,
+    };
+    // Allocation entries follow the header and 8 reserved bytes:
+    // base address (u64), segment (u16), start bus, end bus, reserved (u32)
+    let first = mcfg as *const SdtHeader as u64 + size_of::<SdtHeader>() as u64 + 8;
+    let n = (mcfg.length as u64 - size_of::<SdtHeader>() as u64 - 8) / 16;
+    unsafe {
+        for i in 0..n {
+            let ent = first + i * 16;
+            let base = core::ptr::read_unaligned(ent as *const u64);
+            let segment = core::ptr::read_unaligned((ent + 8) as *const u16);
+            let (st, ed) = (*((ent + 10) as *const u8), *((ent + 11) as *const u8));
+            if segment != 0 || st > ed { continue }
+
+            // ECAM takes 1MB per bus. Outside DEVSPACE map as many buses
+            // as the device window still has room for.
+            let want = (ed - st) as u64 + 1;
+            let pa = base + ((st as u64) << 20);
+            let nbus = if pa >= DEVSPACE { want } else { core::cmp::min(want, dev_space_left() >> 20) };
+            if nbus == 0 {
+                println!("pci: ECAM: no room in the device window");
+                return;
+            }
+            if nbus < want {
+                println!("pci: ECAM: window truncated to buses {}-{} of {}-{}", st, st as u64 + nbus - 1, st, ed);
+            }
+            if let Err(e) = dev_reserve(pa, nbus << 20) {
+                println!("pci: ECAM: {}", e);
+                return;
+            }
+            ECAM_PA = pa;
+            ECAM_BUS = (st, st + (nbus - 1) as u8);
+            return;
+        }
+    }
+}
+
+// Have the MSI-X tables and the ECAM window mapped, must run before
+// kvm_alloc. The ECAM window takes whatever room the devices leave, so
+// this runs after the reservations of the drivers.
+pub fn pci_reserve() -> () {
+    pci_scan(|a| {
+        if let Some(off) = a.find_cap(PCI_CAP_ID_MSIX, 0) {
+            let table = a.read32(off + 4);
+            let nvec = (a.read16(off + 2) & 0x7FF) as u64 + 1;
+            let bar = a.mem_bar((table & 0x7) as u8);
+            if bar == 0 { return }
+            if let Err(e) = unsafe { dev_reserve(bar + (table & !0x7) as u64, nvec * 16) } {
+                println!("pci: MSI-X table: {}", e);
+            }
+        }
+    });
+    ecam_reserve();
+}
+
+// Enumerate the bus. Drivers can register once this has run.
+pub fn pci_init() -> () {
+    unsafe {
+        if ECAM_PA != 0 {
+            if let Some(va) = mmio2v(ECAM_PA) {
+                ECAM_VA = va;
+                println!("pci: ECAM at 0x{:x}, buses {}-{}", ECAM_PA, ECAM_BUS.0, ECAM_BUS.1);
+            }
+        }
+        pci_scan(|a| {
+            if NPCIDEV == MAXPCIDEV {
+                println!("pci: too many devices");
+                return;
+            }
+            let d = PciDev::new(a);
+            println!("pci {:02x}:{:02x}.{} {:04x}:{:04x} class {:06x}",
+                     a.bus, a.dev, a.func, d.vendor, d.device, d.class);
+            PCIDEVS[NPCIDEV] = d;
+            NPCIDEV += 1;
+        });
+    }
+}
+
+pub fn pci_devices() -> &'static [PciDev] {
+    unsafe { &PCIDEVS[..NPCIDEV] }
+}
+
+// Register a driver and probe the unclaimed devices it matches.
+pub fn pci_register_driver(drv: &'static PciDriver) -> Result<(), &'static str> {
+    unsafe {
+        match PCIDRVS.iter().position(|d| d.is_none()) {
+            Some(i) => PCIDRVS[i] = Some(drv),
+            None => return Err("pci_register_driver: too many drivers"),
+        }
+        for d in PCIDEVS[..NPCIDEV].iter_mut() {
+            if d.driver.is_some() || !drv.ids.iter().any(|id| id.matches(d)) { continue }
+            let a = d.addr;
+            match (drv.probe)(d) {
+                Ok(_) => {
+                    d.driver = Some(drv);
+                    println!("pci {:02x}:{:02x}.{}: {}", a.bus, a.dev, a.func, drv.name);
+                }
+                Err(e) => println!("pci {:02x}:{:02x}.{}: {}: {}", a.bus, a.dev, a.func, drv.name, e),
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,177 @@
+// Pipes.
+//
+// A pipe is a ring buffer in a page of its own, shared by a File for the
+// read end and one for the write end. Readers sleep while it is empty and
+// writers while it is full. Once the write end is closed, reads return
+// what is left and then 0; once the read end is closed, writes fail and
+// the writer is killed, as SIGPIPE would.
+
+use crate::*;
+use crate::kern::file::{File, FileKind, file_alloc};
+use crate::kern::kalloc::{kalloc, kfree};
+use crate::kern::proc::{sleep, wakeup, my_proc, my_proc_mut};
+use crate::kern::spinlock::SpinLock;
+use crate::kern::uaccess::{copy_to_user, copy_from_user};
+use x86_64::instructions::interrupts::without_interrupts;
+use core::cell::UnsafeCell;
+
+const PIPESIZE: u32 = 512;
+
+// Error of a write to a pipe whose read end is closed.
+pub const BROKEN_PIPE: &str = "broken pipe";
+
+pub struct Pipe {
+    lock: SpinLock,
+    ring: UnsafeCell<Ring>,             // Protected by lock
+}
+
+struct Ring {
+    data: [u8; PIPESIZE as usize],
+    nread: u32,                         // Number of bytes read, wraps around
+    nwrite: u32,                        // Number of bytes written, wraps around
+    readopen: bool,                     // Read fd is still open
+    writeopen: bool,                    // Write fd is still open
+}
+
+unsafe impl Sync for Pipe {}
+
+// A new pipe, as the File of its read end and the File of its write end.
+pub fn pipe_alloc() -> Result<(&'static File, &'static File), &'static str> {
+    let rf = file_alloc()?;
+    let wf = match file_alloc() {
+        Ok(wf) => wf,
+        Err(e) => {
+            rf.close();
+            return Err(e);
+        }
+    };
+    let pg = match kalloc() {
+        Some(pg) => pg,
+        None => {
+            rf.close();
+            wf.close();
+            return Err("pipe: out of memory");
+        }
+    };
+
+    let pi = pg.as_mut_ptr::<Pipe>();
+    unsafe {
+        core::ptr::write(pi, Pipe {
+            lock: SpinLock::new(),
+            ring: UnsafeCell::new(Ring {
+                data: [0; PIPESIZE as usize],
+                nread: 0,
+                nwrite: 0,
+                readopen: true,
+                writeopen: true,
+            }),
+        });
+    }
+    let pi: &'static Pipe = unsafe { &*pi };
+    rf.open(FileKind::Pipe(pi), true, false, false);
+    wf.open(FileKind::Pipe(pi), false, true, false);
+    Ok((rf, wf))
+}
+
+fn killed() -> bool { unsafe { my_proc().map_or(false, |p| p.is_killed()) } }
+
+impl Pipe {
+    // The caller holds lock.
+    unsafe fn ring(&self) -> &mut Ring { &mut *self.ring.get() }
+
+    // Readers and writers sleep on two different addresses in the pipe.
+    fn read_chan(&self) -> VA { VA::from_ptr(self as *const Pipe) }
+    fn write_chan(&self) -> VA { self.read_chan() + 1u64 }
+
+    // Close one end, and free the pipe with the last one.
+    pub fn close(&self, writable: bool) -> () {
+        let free = without_interrupts(|| unsafe {
+            self.lock.acquire();
+            let p = self.ring();
+            if writable {
+                p.writeopen = false;
+                wakeup(self.read_chan());
+            } else {
+                p.readopen = false;
+                wakeup(self.write_chan());
+            }
+            let free = !p.readopen && !p.writeopen;
+            self.lock.release();
+            free
+        });
+        if free { kfree(VA::from_ptr(self as *const Pipe)); }
+    }
+
+    // Write n bytes from user address addr, sleeping while the pipe is full.
+    pub fn write(&self, addr: u64, n: u32) -> Result<usize, &'static str> {
+        let r = without_interrupts(|| unsafe {
+            self.lock.acquire();
+            let p = self.ring();
+            let mut tot = 0;
+            while tot < n {
+                if !p.readopen {
+                    self.lock.release();
+                    return Err(BROKEN_PIPE);
+                }
+                if killed() {
+                    self.lock.release();
+                    return Err("killed");
+                }
+                if p.nwrite == p.nread.wrapping_add(PIPESIZE) {  // pipe is full
+                    wakeup(self.read_chan());
+                    sleep(self.write_chan(), &self.lock);
+                    continue;
+                }
+                let st = p.nwrite % PIPESIZE;
+                let m = core::cmp::min(n - tot, core::cmp::min(PIPESIZE - p.nwrite.wrapping_sub(p.nread), PIPESIZE - st));
+                if copy_from_user(p.data[st as usize..].as_mut_ptr(), addr + tot as u64, m as usize) < 0 {
+                    break;
+                }
+                p.nwrite = p.nwrite.wrapping_add(m);
+                tot += m;
+            }
+            wakeup(self.read_chan());
+            self.lock.release();
+            if tot == 0 && n > 0 { Err("bad address") } else { Ok(tot as usize) }
+        });
+
+        // Writing to a pipe nobody reads kills the writer.
+        if r == Err(BROKEN_PIPE) {
+            if let Some(p) = unsafe { my_proc_mut() } { p.set_killed(); }
+        }
+        r
+    }
+
+    // Read up to n bytes into user address addr, sleeping while the pipe
+    // is empty and still open for writing.
+    pub fn read(&self, addr: u64, n: u32) -> Result<usize, &'static str> {
+        without_interrupts(|| unsafe {
+            self.lock.acquire();
+            let p = self.ring();
+            while p.nread == p.nwrite && p.writeopen {  // pipe is empty
+                if killed() {
+                    self.lock.release();
+                    return Err("killed");
+                }
+                sleep(self.read_chan(), &self.lock);
+            }
+            let mut tot = 0;
+            while tot < n && p.nread != p.nwrite {
+                let st = p.nread % PIPESIZE;
+                let m = core::cmp::min(n - tot, core::cmp::min(p.nwrite.wrapping_sub(p.nread), PIPESIZE - st));
+                if copy_to_user(addr + tot as u64, p.data[st as usize..].as_ptr(), m as usize) < 0 {
+                    if tot == 0 {
+                        self.lock.release();
+                        return Err("bad address");
+                    }
+                    break;
+                }
+                p.nread = p.nread.wrapping_add(m);
+                tot += m;
+            }
+            wakeup(self.write_chan());
+            self.lock.release();
+            Ok(tot as usize)
+        })
+    }
+}
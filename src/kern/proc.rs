@@ -1,22 +1,34 @@
 use crate::*;
-use x86_64::structures::idt::{InterruptStackFrame};
-use kern::file::{File, INode};
+use kern::file::File;
+use kern::vfs::{INode, namei};
+use kern::gdt64::{USER_CODE_SELECTOR, USER_DATA_SELECTOR, set_kernel_stack};
 use kern::lapic::sti;
 use crate::kern::spinlock::SpinLock;
-use crate::kern::kalloc::kalloc;
+use crate::kern::kalloc::{kalloc, kalloc_contig, kfree};
+use crate::kern::log::{begin_op, end_op};
 use crate::kern::mp::my_cpu;
+use crate::kern::param::param_num;
 use crate::kern::vm::*;
 use core::ptr::Unique;
 use core::borrow::{BorrowMut};
 use x86_64::structures::paging::page_table::PageTable;
+use x86_64::structures::paging::page_table::PageTableFlags as Flags;
 use array_init::array_init;
 use core::mem::{MaybeUninit, uninitialized};
 use core::default::Default;
-use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::interrupts::{self, without_interrupts};
 
 const NPROC: usize = 32;
 const NO_FILE: usize = 16;
-const KSTACKSIZE: u64 = 4096;
+const PNAME_MAX: usize = 16;
+
+// Kernel stacks live in their own virtual region at KSTACKBASE, one slot
+// per process. Only the top pages of a slot are mapped, everything below
+// stays unmapped as a guard so an overflow faults instead of silently
+// corrupting the neighbouring stack.
+pub const MAX_KSTACKPAGES: u64 = 15;
+const KSTACKSLOT: u64 = (MAX_KSTACKPAGES + 1) * PGSIZE;
+static mut KSTACKPAGES: u64 = 4;
 
 #[derive(Copy, Clone, Eq, PartialEq)]
 pub enum ProcState { UNUSED, EMBRYO, SLEEPING, RUNNABLE, RUNNING, ZOMBIE }
@@ -30,6 +42,7 @@ lazy_static! {
 // We cannot wrap the entire PTable with mutex, we need some fine grained sync.
 static PTLOCK: SpinLock = SpinLock::new();
 static mut NEXT_PID: usize = 1;
+static mut INITPROC: Option<&'static Proc<'static>> = None;
 
 // Process table.
 // All modification of process should be routed through PTable struct.
@@ -45,10 +58,7 @@ impl PTable {
         // We have only 8kb of kernel stack.
         // We need to create the PTable on the heap.
         let num_pages = size_of::<PTable>() / PGSIZE as usize + 1;
-        let mut addr = VA::zero();
-        for _ in 0..num_pages {
-            addr = kalloc().expect("NOT ENOUGH MEM FOR PTABLE!");
-        }
+        let addr = kalloc_contig(num_pages).expect("NOT ENOUGH MEM FOR PTABLE!");
         let ptr = addr.as_mut_ptr::<PTable>();
         let pt = &mut *ptr;
         for (i, item) in pt.procs.iter_mut().enumerate() {
@@ -58,24 +68,31 @@ impl PTable {
     }
 
     pub unsafe fn alloc_proc(&mut self) -> Option<&'static mut Proc> {
-        PTLOCK.acquire();
-
-        match self.procs.iter_mut().find(|x|{(**x).is_unused()}) {
-            Some(p) => {
-                p.state = ProcState::EMBRYO;
-                p.pid = NEXT_PID; NEXT_PID += 1;
-
-                // We change the state to EMBRYO and it's safe to release the lock
-                PTLOCK.release();
-                match kalloc() {
+        let procs = &mut self.procs;
+        let found = without_interrupts(|| {
+            PTLOCK.acquire();
+            let i = procs.iter().position(|x| x.is_unused());
+            if let Some(i) = i {
+                procs[i].state = ProcState::EMBRYO;
+                procs[i].pid = NEXT_PID; NEXT_PID += 1;
+            }
+            // We change the state to EMBRYO and it's safe to release the lock
+            PTLOCK.release();
+            i
+        });
+
+        match found {
+            Some(i) => {
+                let p = &mut self.procs[i];
+                match kstack_alloc(i) {
                     Some(v) => p.kstack = v,
                     None => { p.state = ProcState::UNUSED; return None;}
                 }
 
-                let mut sp = p.kstack + KSTACKSIZE;
-                sp -= size_of::<InterruptStackFrame>();
-                p.tf = Some(&mut *(sp.as_mut_ptr::<InterruptStackFrame>()));
-                sp -= 4 as usize;
+                let mut sp = p.kstack + kstack_size();
+                sp -= size_of::<TrapFrame>();
+                p.tf = Some(&mut *(sp.as_mut_ptr::<TrapFrame>()));
+                sp -= size_of::<u64>();
 
                 // TODO: trap return and fork return
                 *sp.as_mut_ptr() = trap_ret;
@@ -86,14 +103,77 @@ impl PTable {
                 p.context = Some(ctx);
                 Some(p)
             }
+            None => None
+        }
+    }
+
+
+}
+
+// Set the number of pages of every kernel stack allocated from now on.
+pub fn set_kstack_pages(n: u64) -> Result<(), &'static str> {
+    if n == 0 || n > MAX_KSTACKPAGES { return Err("kstack pages out of range") }
+    unsafe { KSTACKPAGES = n; }
+    Ok(())
+}
+
+pub fn kstack_size() -> u64 { unsafe { KSTACKPAGES * PGSIZE } }
+
+fn kstack_top(slot: usize) -> VA { VA::new(KSTACKBASE + (slot as u64 + 1) * KSTACKSLOT) }
+
+// Map a fresh kernel stack in the given slot, returns its bottom.
+unsafe fn kstack_alloc(slot: usize) -> Option<VA> {
+    let bottom = kstack_top(slot) - kstack_size();
+    let mut a = bottom;
+    while a < kstack_top(slot) {
+        let pg = match kalloc() {
+            Some(pg) => pg,
             None => {
-                PTLOCK.release();
-                None
+                kstack_free(slot, bottom);
+                return None;
             }
+        };
+        if map_kvm(a, PGSIZE as usize, PA::new(v2p!(pg.as_u64())), Flags::WRITABLE).is_err() {
+            kfree(pg);
+            kstack_free(slot, bottom);
+            return None;
         }
+        a += PGSIZE;
     }
+    Some(bottom)
+}
+
+// Free a process structure and the memory hanging off it, leaving it
+// UNUSED. Its files are closed by exit.
+// Must not hold PTLOCK: freeing the memory shoots down TLB entries, which
+// needs interrupts on.
+unsafe fn free_proc(p: &mut Proc) -> () {
+    let slot = ((p.kstack.as_u64() - KSTACKBASE) / KSTACKSLOT) as usize;
+    kstack_free(slot, p.kstack);
+    if let Some(pml4) = p.pml4.take() { free_uvm(pml4, p.sz as usize); }
+    without_interrupts(|| {
+        PTLOCK.acquire();
+        p.init(slot);
+        PTLOCK.release();
+    });
+}
 
+// Unmap and free the kernel stack of a slot. Pages which were
+// never mapped are skipped.
+unsafe fn kstack_free(slot: usize, bottom: VA) -> () {
+    let top = kstack_top(slot);
+    unmap_kvm(bottom, (top - bottom) as usize, true);
+}
 
+// If addr lies in the guard area below a kernel stack, return the pid
+// and name of the process which overflowed it.
+pub fn kstack_overflow(addr: VA) -> Option<(usize, &'static str)> {
+    let a = addr.as_u64();
+    if a < KSTACKBASE || a >= KSTACKBASE + NPROC as u64 * KSTACKSLOT { return None }
+    let slot = ((a - KSTACKBASE) / KSTACKSLOT) as usize;
+    let p = unsafe { &(*PTABLE.as_ptr()).procs[slot] };
+    if p.is_unused() || addr >= p.kstack { return None }
+    Some((p.pid, p.get_name()))
 }
 
 pub struct Proc<'a> {
@@ -103,13 +183,13 @@ pub struct Proc<'a> {
     state: ProcState,                           // Process state
     pid: usize,                                 // Process id
     parent: Option<&'a Proc<'a>>,               // Parent process
-    tf: Option<&'a mut InterruptStackFrame>,    // ESF for current syscall
+    tf: Option<&'a mut TrapFrame>,              // Trap frame for current syscall
     context: Option<&'a mut Context>,           // Context
     chan: VA,                                   // If valid, sleeping on chan
     killed: bool,                               // If true, has been killed
-    op_files: [Option<File>; NO_FILE],          // Opened files
-    cwd: Option<INode>,                         // Current directory
-    name: &'static str,                         // Process name
+    op_files: [Option<&'static File>; NO_FILE], // Open files, indexed by fd
+    cwd: Option<&'static dyn INode>,            // Current directory
+    name: [u8; PNAME_MAX],                      // Process name, NUL padded
 }
 
 impl<'a> Proc<'a> {
@@ -126,7 +206,7 @@ impl<'a> Proc<'a> {
             killed: false,
             op_files: [None; NO_FILE],
             cwd: None,
-            name: "",
+            name: [0; PNAME_MAX],
     } }
 
     fn init(&mut self, pid: usize) -> () {
@@ -142,11 +222,58 @@ impl<'a> Proc<'a> {
         self.killed = false;
         self.op_files = [None; NO_FILE];
         self.cwd = None;
-        self.name = "";
+        self.name = [0; PNAME_MAX];
     }
 
     pub fn is_unused(&self) -> bool { self.state == ProcState::UNUSED }
 
+    pub fn get_sz(&self) -> u64 { self.sz }
+
+    pub fn get_pid(&self) -> usize { self.pid }
+
+    pub fn get_name(&self) -> &str {
+        let n = self.name.iter().position(|&c| c == 0).unwrap_or(PNAME_MAX);
+        core::str::from_utf8(&self.name[..n]).unwrap_or("?")
+    }
+
+    // Set the name, cut to what fits.
+    pub fn set_name(&mut self, name: &[u8]) -> () {
+        let n = core::cmp::min(name.len(), PNAME_MAX - 1);
+        self.name = [0; PNAME_MAX];
+        self.name[..n].copy_from_slice(&name[..n]);
+    }
+
+    pub fn get_cwd(&self) -> Option<&'static dyn INode> { self.cwd }
+
+    pub fn is_killed(&self) -> bool { self.killed }
+
+    // Mark the process to exit the next time it leaves a system call.
+    pub fn set_killed(&mut self) -> () { self.killed = true; }
+
+    // Make ip the current directory, returning the old one for the caller
+    // to put.
+    pub fn set_cwd(&mut self, ip: &'static dyn INode) -> Option<&'static dyn INode> {
+        self.cwd.replace(ip)
+    }
+
+    // The open file of descriptor fd.
+    pub fn get_file(&self, fd: usize) -> Option<&'static File> {
+        self.op_files.get(fd).and_then(|f| *f)
+    }
+
+    // Allocate the lowest free file descriptor for f.
+    // Takes over the file reference from the caller on success.
+    pub fn fd_alloc(&mut self, f: &'static File) -> Result<usize, &'static str> {
+        let fd = self.op_files.iter().position(|f| f.is_none()).ok_or("too many open files")?;
+        self.op_files[fd] = Some(f);
+        Ok(fd)
+    }
+
+    // Remove descriptor fd, the caller closes the file it returns.
+    pub fn fd_free(&mut self, fd: usize) -> Option<&'static File> {
+        self.op_files.get_mut(fd).and_then(|f| f.take())
+    }
+
     pub fn get_pml4(&self) -> &PageTable {
         if let Some(ref x) = self.pml4 {
             &x
@@ -160,6 +287,21 @@ impl<'a> Proc<'a> {
         }
     }
 
+    pub fn get_mut_tf(&mut self) -> &mut TrapFrame {
+        match self.tf {
+            Some(ref mut tf) => tf,
+            None => panic!("TrapFrame empty!")
+        }
+    }
+
+    // Replace the user memory with pml4 of sz bytes, as exec does.
+    // Returns the old page table and its size for the caller to free.
+    pub fn set_image(&mut self, pml4: &'a mut PageTable, sz: u64) -> (Option<&'a mut PageTable>, u64) {
+        let old = (self.pml4.replace(pml4), self.sz);
+        self.sz = sz;
+        old
+    }
+
     pub fn get_mut_ctx(&mut self) -> &mut Context {
         if let Some(ref mut ctx) = self.context {
             *ctx
@@ -229,6 +371,10 @@ impl Context {
 }
 
 pub unsafe fn user_init() -> () {
+    if let Some(n) = param_num("kstack") {
+        if let Err(e) = set_kstack_pages(n) { println!("{}", e); }
+    }
+
     let ptr = PTABLE.as_ptr();
     let mut p: &'static mut Proc = (*ptr).alloc_proc().expect("alloc_proc failed");
 
@@ -243,25 +389,156 @@ pub unsafe fn user_init() -> () {
              (*BINARY_INITCODE_SIZE).as_u64() as usize);
 
     p.sz = PGSIZE;
+    p.cwd = namei("/").ok();
+    p.set_name(b"initcode");
     p.state = ProcState::RUNNABLE;
 
-    if let Some(ref mut non_mut_tf) = p.tf {
-        let tf = (*non_mut_tf).as_mut();
-        tf.instruction_pointer = VA::zero();
-        tf.stack_pointer = VA::new(PGSIZE);
-        tf.cpu_flags = 0x200;    // Interrupt enabled
+    if let Some(ref mut tf) = p.tf {
+        memset(&mut **tf as *mut TrapFrame, 0, size_of::<TrapFrame>() as u64);
+        tf.cs = USER_CODE_SELECTOR as u64;
+        tf.ss = USER_DATA_SELECTOR as u64;
+        tf.rip = 0;
+        tf.rsp = PGSIZE;
+        tf.rflags = 0x200;    // Interrupt enabled
+    }
+    INITPROC = Some(p);
+}
+
+// Create a new process copying the current one as the parent.
+// Returns the pid of the child; the child returns 0 from the system call.
+pub unsafe fn fork() -> Result<usize, &'static str> {
+    let cur = my_proc_mut().ok_or("fork: no process")?;
+    let np = (*PTABLE.as_ptr()).alloc_proc().ok_or("fork: no free process")?;
+
+    // Copy user memory from parent to child.
+    let pml4 = match kalloc() {
+        Some(pg) => pg.as_mut_ptr::<PageTable>(),
+        None => {
+            free_proc(np);
+            return Err("fork: out of memory");
+        }
+    };
+    memset(pml4, 0, PGSIZE);
+    np.pml4 = Some(&mut *pml4);
+    let sz = cur.sz;
+    let r = setup_kvm(&mut *pml4).and_then(|_| copy_uvm(cur.get_mut_pml4(), &mut *pml4, sz as usize));
+    if let Err(e) = r {
+        free_proc(np);
+        return Err(e);
+    }
+    let cur: &'static Proc = cur;
+    np.sz = cur.sz;
+    np.parent = Some(cur);
+
+    // Clear %rax so that fork returns 0 in the child.
+    if let (Some(tf), Some(ptf)) = (np.tf.as_mut(), cur.tf.as_ref()) {
+        **tf = **ptf;
+        tf.rax = 0;
+    }
+
+    for (nf, f) in np.op_files.iter_mut().zip(cur.op_files.iter()) {
+        *nf = f.map(|f| f.dup());
+    }
+    np.cwd = cur.cwd.map(|ip| ip.dup());
+    np.name = cur.name;
+
+    let pid = np.pid;
+    without_interrupts(|| {
+        PTLOCK.acquire();
+        np.state = ProcState::RUNNABLE;
+        PTLOCK.release();
+    });
+    Ok(pid)
+}
+
+// Exit the current process. Does not return.
+// An exited process remains in the zombie state
+// until its parent calls wait() to find out it exited.
+pub unsafe fn exit() -> ! {
+    let p = my_proc_mut().expect("exit: no process");
+    if INITPROC.map_or(false, |ip| core::ptr::eq(ip, p)) { panic!("init exiting"); }
+
+    // Close all open files.
+    for f in p.op_files.iter_mut() {
+        if let Some(f) = f.take() { f.close(); }
+    }
+    if let Some(cwd) = p.cwd.take() {
+        begin_op();
+        cwd.put();
+        end_op();
+    }
+
+    // The timer interrupt takes PTLOCK too. Interrupts stay off until the
+    // scheduler turns them on again.
+    interrupts::disable();
+    PTLOCK.acquire();
+
+    // Parent might be sleeping in wait().
+    if let Some(parent) = p.parent { wakeup1(VA::from_ptr(parent as *const Proc)); }
+
+    // Pass abandoned children to init.
+    for pp in (*PTABLE.as_ptr()).procs.iter_mut() {
+        if pp.parent.map_or(false, |pp| core::ptr::eq(pp, p)) {
+            pp.parent = INITPROC;
+            if pp.state == ProcState::ZOMBIE {
+                if let Some(init) = INITPROC { wakeup1(VA::from_ptr(init as *const Proc)); }
+            }
+        }
     }
+
+    // Jump into the scheduler, never to return.
+    p.state = ProcState::ZOMBIE;
+    sched();
+    panic!("zombie exit");
+}
+
+// Wait for a child process to exit and return its pid.
+pub unsafe fn wait() -> Result<usize, &'static str> {
+    let cur = my_proc().ok_or("wait: no process")?;
+
+    let p = without_interrupts(|| {
+        PTLOCK.acquire();
+        loop {
+            // Scan through table looking for exited children.
+            let mut havekids = false;
+            for p in (*PTABLE.as_ptr()).procs.iter_mut() {
+                if !p.parent.map_or(false, |pp| core::ptr::eq(pp, cur)) { continue }
+                havekids = true;
+                if p.state == ProcState::ZOMBIE {
+                    // Found one. Nobody else frees it, we do so below
+                    // without PTLOCK.
+                    PTLOCK.release();
+                    return Ok(p);
+                }
+            }
+
+            // No point waiting if we don't have any children.
+            if !havekids || cur.killed {
+                PTLOCK.release();
+                return Err("wait: no children");
+            }
+
+            // Wait for children to exit. (See wakeup1 call in exit.)
+            sleep(VA::from_ptr(cur as *const Proc), &PTLOCK);
+        }
+    })?;
+    let pid = p.pid;
+    free_proc(p);
+    Ok(pid)
 }
 
 pub unsafe fn scheduler() -> ! {
     let cpu = my_cpu();
 
     loop {
-        // Enabling interrupts on this cpu
+        // Let pending interrupts in, then keep them off while PTLOCK is
+        // held: the timer interrupt takes it too.
         sti();
+        interrupts::disable();
         PTLOCK.acquire();
         for p in (*PTABLE.as_ptr()).procs.iter_mut() {
             if p.state != ProcState::RUNNABLE { continue }
+            set_kernel_stack((p.kstack + kstack_size()).as_u64());
             cpu.set_proc(p);
             switch_uvm(cpu.get_proc());
 
@@ -290,10 +567,15 @@ pub unsafe fn sleep(chan: VA, lk: &SpinLock) -> () {
     let mut p: Option<&Proc> = None;
 
     let p = my_proc().expect("Sleep");
-    // if lk != PTLOCK {
-    //     PTLOCK.acquire();
-    //     lk.release();
-    // }
+
+    // Must acquire PTLOCK in order to change p->state and then call sched.
+    // Once we hold PTLOCK, we can be guaranteed that we won't miss any
+    // wakeup (wakeup runs with PTLOCK locked), so it's okay to release lk.
+    let ptlock = core::ptr::eq(lk, &PTLOCK);
+    if !ptlock {
+        PTLOCK.acquire();
+        lk.release();
+    }
     // p.chan = chan;
     // p.state = ProcState::SLEEPING;
     c.set_proc_chan(chan);
@@ -304,10 +586,11 @@ pub unsafe fn sleep(chan: VA, lk: &SpinLock) -> () {
     // p.chan = VA::zero();
     c.set_proc_chan(VA::zero());
 
-    // if lk != PTLOCK {
-    //     PTLOCK.release();
-    //     lk.acquire();
-    // }
+    // Reacquire original lock.
+    if !ptlock {
+        PTLOCK.release();
+        lk.acquire();
+    }
 }
 
 pub unsafe fn wakeup1(chan: VA) -> () {
@@ -319,9 +602,63 @@ pub unsafe fn wakeup1(chan: VA) -> () {
 }
 
 pub unsafe fn wakeup(chan: VA) -> () {
-    PTLOCK.acquire();
-    wakeup1(chan);
-    PTLOCK.release();
+    without_interrupts(|| {
+        PTLOCK.acquire();
+        wakeup1(chan);
+        PTLOCK.release();
+    });
+}
+
+// What /proc shows of a process.
+pub struct ProcInfo {
+    pub pid: usize,
+    pub name: &'static str,
+    pub state: &'static str,
+    pub ppid: usize,                            // 0 if none
+    pub sz: u64,
+    pub nfiles: usize,                          // Open file descriptors
+    pub ptpages: usize,                         // Pages of the page table
+    pub killed: bool,
+}
+
+// The pid of the nth process in the table.
+pub fn nth_pid(n: usize) -> Option<usize> {
+    without_interrupts(|| unsafe {
+        PTLOCK.acquire();
+        let pid = (*PTABLE.as_ptr()).procs.iter().filter(|p| !p.is_unused()).nth(n).map(|p| p.pid);
+        PTLOCK.release();
+        pid
+    })
+}
+
+pub fn proc_info(pid: usize) -> Option<ProcInfo> {
+    without_interrupts(|| unsafe {
+        PTLOCK.acquire();
+        let info = (*PTABLE.as_ptr()).procs.iter().find(|p| !p.is_unused() && p.pid == pid).map(|p| ProcInfo {
+            pid: p.pid,
+            name: p.get_name(),
+            state: match p.state {
+                ProcState::UNUSED => "unused",
+                ProcState::EMBRYO => "embryo",
+                ProcState::SLEEPING => "sleep",
+                ProcState::RUNNABLE => "runble",
+                ProcState::RUNNING => "run",
+                ProcState::ZOMBIE => "zombie",
+            },
+            ppid: p.parent.map_or(0, |pp| pp.pid),
+            sz: p.sz,
+            nfiles: p.op_files.iter().filter(|f| f.is_some()).count(),
+            ptpages: p.pml4.as_ref().map_or(0, |pml4| uvm_tables(pml4)),
+            killed: p.killed,
+        });
+        PTLOCK.release();
+        info
+    })
+}
+
+// Whether this cpu is running a process, i.e. whether it may sleep.
+pub unsafe fn has_proc() -> bool {
+    my_cpu().proc.is_some()
 }
 
 pub unsafe fn my_proc() -> Option<&'static Proc<'static>> {
@@ -333,7 +670,46 @@ pub unsafe fn my_proc() -> Option<&'static Proc<'static>> {
     return p;
 }
 
+pub unsafe fn my_proc_mut() -> Option<&'static mut Proc<'static>> {
+    let mut p = None;
+    without_interrupts(||{
+        p = my_cpu().proc.as_mut().map(|p| &mut **p);
+    });
+    return p;
+}
+
+// Saved by the trap entry (see syscall_entry), restored by trap_ret.
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct TrapFrame {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rbp: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub trapno: u64,
+    pub err: u64,
+
+    // Pushed by the cpu
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
 #[naked]
+#[no_mangle]
 pub unsafe extern "C" fn trap_ret() {
     asm!("pop %rax");
     asm!("pop %rbx");
@@ -351,7 +727,7 @@ pub unsafe extern "C" fn trap_ret() {
     asm!("pop %r14");
     asm!("pop %r15");
     asm!("add $$16, %rsp");
-    asm!("iret");
+    asm!("iretq");
 }
 
 pub fn fork_ret() -> () {
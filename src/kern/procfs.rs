@@ -0,0 +1,342 @@
+// /proc.
+//
+// A file system made up on the fly: reading a file formats the current
+// state of the kernel into it. The root holds meminfo, cpuinfo and
+// interrupts, and a directory per process, named by its pid, with a
+// status file.
+//
+// Inode numbers are fixed: the files of the root below PID_INO, then two
+// per process, its directory and its status file. Pids are never reused,
+// so a number stays valid until its process is gone.
+
+use crate::*;
+use crate::kern::idt::intr_count;
+use crate::kern::kalloc::{kalloc, kfree, kmem_stats};
+use crate::kern::log::{begin_op, end_op};
+use crate::kern::mp::CPU_INFO;
+use crate::kern::proc::{proc_info, nth_pid};
+use crate::kern::spinlock::SpinLock;
+use crate::kern::uaccess::either_copy_out;
+use crate::kern::vfs::{FileSystem, INode, FsType, Stat, NAME_MAX, NOT_FOUND, NOT_DIR, T_DIR, T_FILE};
+use crate::kern::vfs::{register_fs, mount_dev};
+use core::fmt::Write;
+use core::cell::Cell;
+use array_init::array_init;
+
+const NPROCINODE: usize = 32;           // Active inodes
+
+const ROOT_INO: u64 = 1;
+const MEMINFO_INO: u64 = 2;
+const CPUINFO_INO: u64 = 3;
+const INTERRUPTS_INO: u64 = 4;
+const PID_INO: u64 = 16;
+
+const ROOT_FILES: [(&str, u64); 3] = [
+    ("meminfo", MEMINFO_INO), ("cpuinfo", CPUINFO_INO), ("interrupts", INTERRUPTS_INO),
+];
+
+fn pid_ino(pid: usize) -> u64 { PID_INO + 2 * pid as u64 }
+
+// The process of a directory or status inode, and whether it is the status.
+fn ino_pid(ino: u64) -> Option<(usize, bool)> {
+    if ino < PID_INO { return None }
+    Some((((ino - PID_INO) / 2) as usize, (ino - PID_INO) % 2 == 1))
+}
+
+// Formats into a fixed buffer, dropping what does not fit.
+struct Text<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> Write for Text<'a> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let n = core::cmp::min(s.len(), self.buf.len() - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+fn meminfo(t: &mut Text) -> Result<(), &'static str> {
+    let (total, free) = kmem_stats();
+    let kb = PGSIZE as usize / 1024;
+    let _ = write!(t, "MemTotal:\t{} kB\nMemFree:\t{} kB\nMemUsed:\t{} kB\n",
+                   total * kb, free * kb, (total - free) * kb);
+    Ok(())
+}
+
+fn cpuinfo(t: &mut Text) -> Result<(), &'static str> {
+    for i in 0..CPU_INFO.ncpu() {
+        let _ = write!(t, "processor\t: {}\napic id\t\t: {}\n\n", i, CPU_INFO.apic_id(i));
+    }
+    let _ = write!(t, "lapic\t\t: {:#x}\nioapic id\t: {}\n", CPU_INFO.get_lapic().as_u64(), CPU_INFO.ioapic_id());
+    Ok(())
+}
+
+fn vector_name(v: u32) -> &'static str {
+    match v {
+        T_PGFLT => "page fault",
+        T_SYSCALL => "syscall",
+        T_TLBFLUSH => "tlb shootdown",
+        v if v == T_IRQ0 + IRQ_TIMER => "timer",
+        v if v == T_IRQ0 + IRQ_KBD => "keyboard",
+        v if v == T_IRQ0 + IRQ_COM1 => "uart",
+        v if v == T_IRQ0 + IRQ_IDE => "ide",
+        v if v > T_IRQ0 && v < T_IRQ0 + 24 => "irq",
+        v if v >= T_DYN0 && v < T_DYNEND => "msi",
+        _ => "",
+    }
+}
+
+// Vectors which were taken at all, with the count per cpu.
+fn interrupts(t: &mut Text) -> Result<(), &'static str> {
+    let ncpu = CPU_INFO.ncpu();
+    let _ = write!(t, "    ");
+    for i in 0..ncpu { let _ = write!(t, " {:>10}", format_args!("CPU{}", i)); }
+    let _ = writeln!(t);
+    for v in 0..256 {
+        if (0..ncpu).all(|i| intr_count(i, v) == 0) { continue }
+        let _ = write!(t, "{:>3}:", v);
+        for i in 0..ncpu { let _ = write!(t, " {:>10}", intr_count(i, v)); }
+        let _ = writeln!(t, "  {}", vector_name(v));
+    }
+    Ok(())
+}
+
+fn status(t: &mut Text, pid: usize) -> Result<(), &'static str> {
+    let p = proc_info(pid).ok_or(NOT_FOUND)?;
+    let _ = write!(t, "Name:\t{}\nPid:\t{}\nPPid:\t{}\nState:\t{}{}\nSize:\t{} kB\nFiles:\t{}\nPageTables:\t{} kB\n",
+                   p.name, p.pid, p.ppid, p.state, if p.killed { " (killed)" } else { "" },
+                   p.sz / 1024, p.nfiles, p.ptpages * PGSIZE as usize / 1024);
+    Ok(())
+}
+
+pub struct ProcINode {
+    refcnt: Cell<u32>,                  // Protected by PROC_LOCK
+    ino: Cell<u64>,                     // Set under PROC_LOCK while refcnt is 0
+}
+
+unsafe impl Sync for ProcINode {}
+
+struct ProcFs;
+
+static PROCFS: ProcFs = ProcFs;
+static PROC_LOCK: SpinLock = SpinLock::new();
+static mut MOUNTED: bool = false;
+lazy_static! {
+    static ref PROCINODES: [ProcINode; NPROCINODE] =
+        array_init(|_| ProcINode { refcnt: Cell::new(0), ino: Cell::new(0) });
+}
+
+// The inode numbered ino, which must exist.
+fn proc_iget(ino: u64) -> Result<&'static ProcINode, &'static str> {
+    PROC_LOCK.acquire();
+    let r = match PROCINODES.iter().find(|ip| ip.refcnt.get() > 0 && ip.ino.get() == ino) {
+        Some(ip) => {
+            ip.refcnt.set(ip.refcnt.get() + 1);
+            Ok(ip)
+        }
+        None => match PROCINODES.iter().find(|ip| ip.refcnt.get() == 0) {
+            Some(ip) => {
+                ip.refcnt.set(1);
+                ip.ino.set(ino);
+                Ok(ip)
+            }
+            None => Err("procfs: no inodes"),
+        },
+    };
+    PROC_LOCK.release();
+    r
+}
+
+impl ProcINode {
+    fn is_dir(&self) -> bool {
+        self.ino.get() == ROOT_INO || ino_pid(self.ino.get()).map_or(false, |(_, status)| !status)
+    }
+
+    // Format the contents into t.
+    fn text(&self, t: &mut Text) -> Result<(), &'static str> {
+        match self.ino.get() {
+            MEMINFO_INO => meminfo(t),
+            CPUINFO_INO => cpuinfo(t),
+            INTERRUPTS_INO => interrupts(t),
+            ino => match ino_pid(ino) {
+                Some((pid, true)) => status(t, pid),
+                _ => Err("is a directory"),
+            },
+        }
+    }
+
+    // The entries of a directory besides "." and "..", by index.
+    fn entry(&self, idx: usize, name: &mut [u8]) -> Option<(usize, u64)> {
+        let mut t = Text { buf: name, len: 0 };
+        let ino = if self.ino.get() == ROOT_INO {
+            match ROOT_FILES.get(idx) {
+                Some(&(n, ino)) => {
+                    let _ = t.write_str(n);
+                    ino
+                }
+                None => {
+                    let pid = nth_pid(idx - ROOT_FILES.len())?;
+                    let _ = write!(t, "{}", pid);
+                    pid_ino(pid)
+                }
+            }
+        } else {
+            let (pid, _) = ino_pid(self.ino.get())?;
+            if idx > 0 || proc_info(pid).is_none() { return None }
+            let _ = t.write_str("status");
+            self.ino.get() + 1
+        };
+        Some((t.len, ino))
+    }
+}
+
+impl INode for ProcINode {
+    fn fs(&self) -> &'static dyn FileSystem { &PROCFS }
+
+    fn ino(&self) -> u64 { self.ino.get() }
+
+    fn dup(&'static self) -> &'static dyn INode {
+        PROC_LOCK.acquire();
+        self.refcnt.set(self.refcnt.get() + 1);
+        PROC_LOCK.release();
+        self
+    }
+
+    fn put(&self) -> () {
+        PROC_LOCK.acquire();
+        if self.refcnt.get() < 1 { panic!("procfs: put"); }
+        self.refcnt.set(self.refcnt.get() - 1);
+        PROC_LOCK.release();
+    }
+
+    fn stat(&self) -> Result<Stat, &'static str> {
+        Ok(Stat {
+            itype: if self.is_dir() { T_DIR } else { T_FILE },
+            dev: -1,                    // No disk
+            ino: self.ino.get() as u32,
+            nlink: 1,
+            size: 0,
+            major: 0,
+            minor: 0,
+        })
+    }
+
+    // The text is made anew for every read, reading it in pieces may
+    // give pieces of different states.
+    fn read(&self, user: bool, dst: u64, off: u32, n: u32) -> Result<usize, &'static str> {
+        let pg = kalloc().ok_or("procfs: out of memory")?;
+        let buf = unsafe { core::slice::from_raw_parts_mut(pg.as_mut_ptr::<u8>(), PGSIZE as usize) };
+        let mut t = Text { buf, len: 0 };
+        let r = self.text(&mut t).and_then(|_| {
+            let off = core::cmp::min(off as usize, t.len);
+            let n = core::cmp::min(n as usize, t.len - off);
+            if unsafe { either_copy_out(user, dst, t.buf[off..].as_ptr(), n) } < 0 {
+                return Err("procfs: bad address");
+            }
+            Ok(n)
+        });
+        kfree(pg);
+        r
+    }
+
+    fn lookup(&self, name: &[u8]) -> Result<Option<&'static dyn INode>, &'static str> {
+        if !self.is_dir() { return Err(NOT_DIR) }
+        let ino = match name {
+            b"." => Some(self.ino.get()),
+            b".." => Some(ROOT_INO),
+            _ => {
+                let mut e = [0; NAME_MAX];
+                let mut idx = 0;
+                loop {
+                    match self.entry(idx, &mut e) {
+                        Some((len, ino)) if &e[..len] == name => break Some(ino),
+                        Some(_) => idx += 1,
+                        None => break None,
+                    }
+                }
+            }
+        };
+        match ino {
+            Some(ino) => Ok(Some(proc_iget(ino)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn readdir(&self, idx: usize, name: &mut [u8; NAME_MAX]) -> Result<Option<(usize, u64)>, &'static str> {
+        if !self.is_dir() { return Err(NOT_DIR) }
+        Ok(match idx {
+            0 => {
+                name[0] = b'.';
+                Some((1, self.ino.get()))
+            }
+            1 => {
+                name[..2].copy_from_slice(b"..");
+                Some((2, ROOT_INO))
+            }
+            _ => self.entry(idx - 2, name),
+        })
+    }
+}
+
+impl FileSystem for ProcFs {
+    fn name(&self) -> &'static str { "procfs" }
+
+    fn root(&self) -> Result<&'static dyn INode, &'static str> {
+        Ok(proc_iget(ROOT_INO)?)
+    }
+
+    fn iget(&self, ino: u64) -> Result<&'static dyn INode, &'static str> {
+        match ino_pid(ino) {
+            Some((pid, _)) if proc_info(pid).is_none() => Err(NOT_FOUND),
+            Some(_) => Ok(proc_iget(ino)?),
+            None if ino >= ROOT_INO && ino <= INTERRUPTS_INO => Ok(proc_iget(ino)?),
+            None => Err("procfs: bad inode number"),
+        }
+    }
+
+    fn busy(&self) -> bool {
+        PROC_LOCK.acquire();
+        let busy = PROCINODES.iter().any(|ip| ip.refcnt.get() > 0 && !(ip.ino.get() == ROOT_INO && ip.refcnt.get() == 1));
+        PROC_LOCK.release();
+        busy
+    }
+
+    fn unmount(&self) -> () {
+        unsafe { MOUNTED = false; }
+    }
+}
+
+static PROCFS_TYPE: FsType = FsType { name: "procfs", mount: procfs_mount, nodev: true };
+
+// There is only one instance, all mounts would show the same anyway.
+fn procfs_mount(_dev: usize) -> Result<&'static dyn FileSystem, &'static str> {
+    PROC_LOCK.acquire();
+    let r = unsafe {
+        if MOUNTED {
+            Err("procfs: already mounted")
+        } else {
+            MOUNTED = true;
+            Ok(&PROCFS as &'static dyn FileSystem)
+        }
+    };
+    PROC_LOCK.release();
+    r
+}
+
+// Register procfs and mount it on /proc, if the root file system has
+// that directory.
+pub fn procfs_init() -> Result<(), &'static str> {
+    register_fs(&PROCFS_TYPE)?;
+
+    begin_op();
+    let r = mount_dev(0, "/proc", "procfs");
+    end_op();
+    match r {
+        Ok(()) => println!("procfs: on /proc"),
+        Err(e) => println!("procfs: /proc: {}", e),
+    }
+    Ok(())
+}
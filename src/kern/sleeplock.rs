@@ -0,0 +1,89 @@
+// Long-term locks for processes.
+//
+// A sleep lock may be held across disk I/O: a process waiting for it
+// sleeps instead of spinning. Before the first process runs there is
+// nobody to put to sleep, waiters then spin.
+
+use crate::*;
+use crate::kern::spinlock::SpinLock;
+use crate::kern::proc::{sleep, wakeup, has_proc, my_proc};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use x86_64::instructions::interrupts::without_interrupts;
+
+pub struct SleepLock {
+    locked: AtomicBool,     // Is the lock held?
+    lk: SpinLock,           // Spinlock protecting this sleep lock
+    pid: AtomicUsize,       // Process holding lock, 0 before the first process
+    name: &'static str,
+}
+
+fn cur_pid() -> usize {
+    unsafe { if has_proc() { my_proc().map(|p| p.get_pid()).unwrap_or(0) } else { 0 } }
+}
+
+impl SleepLock {
+    pub const fn new(name: &'static str) -> Self {
+        SleepLock {
+            locked: AtomicBool::new(false),
+            lk: SpinLock::new(),
+            pid: AtomicUsize::new(0),
+            name: name,
+        }
+    }
+
+    pub fn acquire(&self) -> () {
+        // The timer interrupt takes PTLOCK, which sleep holds.
+        without_interrupts(|| {
+            self.lk.acquire();
+            while self.locked.load(Ordering::Relaxed) {
+                unsafe {
+                    if has_proc() {
+                        sleep(VA::from_ptr(self as *const SleepLock), &self.lk);
+                    } else {
+                        self.lk.release();
+                        asm!("pause" :::: "volatile");
+                        self.lk.acquire();
+                    }
+                }
+            }
+            self.locked.store(true, Ordering::Relaxed);
+            self.pid.store(cur_pid(), Ordering::Relaxed);
+            self.lk.release();
+        });
+    }
+
+    // Take the lock if nobody holds it, never sleeps.
+    pub fn try_acquire(&self) -> bool {
+        without_interrupts(|| {
+            self.lk.acquire();
+            let free = !self.locked.load(Ordering::Relaxed);
+            if free {
+                self.locked.store(true, Ordering::Relaxed);
+                self.pid.store(cur_pid(), Ordering::Relaxed);
+            }
+            self.lk.release();
+            free
+        })
+    }
+
+    pub fn release(&self) -> () {
+        if !self.holding() { panic!("release {}", self.name); }
+        without_interrupts(|| {
+            self.lk.acquire();
+            self.locked.store(false, Ordering::Relaxed);
+            self.pid.store(0, Ordering::Relaxed);
+            unsafe { wakeup(VA::from_ptr(self as *const SleepLock)); }
+            self.lk.release();
+        });
+    }
+
+    // Whether the current process holds the lock.
+    pub fn holding(&self) -> bool {
+        without_interrupts(|| {
+            self.lk.acquire();
+            let r = self.locked.load(Ordering::Relaxed) && self.pid.load(Ordering::Relaxed) == cur_pid();
+            self.lk.release();
+            r
+        })
+    }
+}
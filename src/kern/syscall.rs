@@ -0,0 +1,139 @@
+// System calls.
+//
+// User code traps with int $T_SYSCALL, the system call number in rax and
+// the arguments in rdi, rsi, rdx, r10, r8 and r9. The result is returned
+// in rax, -1 on failure.
+
+use crate::*;
+use crate::kern::idt::count_intr;
+use crate::kern::proc::{TrapFrame, my_proc, exit};
+use crate::kern::uaccess::strncpy_from_user;
+use crate::kern::sysfile::*;
+use crate::kern::sysproc::*;
+
+// System call numbers
+pub const SYS_FORK: u64 = 1;
+pub const SYS_EXIT: u64 = 2;
+pub const SYS_WAIT: u64 = 3;
+pub const SYS_PIPE: u64 = 4;
+pub const SYS_READ: u64 = 5;
+pub const SYS_KILL: u64 = 6;
+pub const SYS_EXEC: u64 = 7;
+pub const SYS_FSTAT: u64 = 8;
+pub const SYS_CHDIR: u64 = 9;
+pub const SYS_DUP: u64 = 10;
+pub const SYS_GETPID: u64 = 11;
+pub const SYS_SBRK: u64 = 12;
+pub const SYS_SLEEP: u64 = 13;
+pub const SYS_UPTIME: u64 = 14;
+pub const SYS_OPEN: u64 = 15;
+pub const SYS_WRITE: u64 = 16;
+pub const SYS_MKNOD: u64 = 17;
+pub const SYS_UNLINK: u64 = 18;
+pub const SYS_LINK: u64 = 19;
+pub const SYS_MKDIR: u64 = 20;
+pub const SYS_CLOSE: u64 = 21;
+pub const SYS_MOUNT: u64 = 22;
+pub const SYS_UMOUNT: u64 = 23;
+pub const SYS_LSEEK: u64 = 24;
+pub const SYS_RMDIR: u64 = 25;
+pub const SYS_RENAME: u64 = 26;
+pub const SYS_GETCWD: u64 = 27;
+pub const SYS_GETDENTS: u64 = 28;
+
+type Handler = fn(&TrapFrame) -> Result<u64, &'static str>;
+
+fn handler(num: u64) -> Option<Handler> {
+    match num {
+        SYS_FORK => Some(sys_fork),
+        SYS_EXIT => Some(sys_exit),
+        SYS_WAIT => Some(sys_wait),
+        SYS_PIPE => Some(sys_pipe),
+        SYS_READ => Some(sys_read),
+        SYS_EXEC => Some(sys_exec),
+        SYS_FSTAT => Some(sys_fstat),
+        SYS_CHDIR => Some(sys_chdir),
+        SYS_DUP => Some(sys_dup),
+        SYS_GETPID => Some(sys_getpid),
+        SYS_OPEN => Some(sys_open),
+        SYS_WRITE => Some(sys_write),
+        SYS_MKNOD => Some(sys_mknod),
+        SYS_UNLINK => Some(sys_unlink),
+        SYS_LINK => Some(sys_link),
+        SYS_MKDIR => Some(sys_mkdir),
+        SYS_CLOSE => Some(sys_close),
+        SYS_MOUNT => Some(sys_mount),
+        SYS_UMOUNT => Some(sys_umount),
+        SYS_LSEEK => Some(sys_lseek),
+        SYS_RMDIR => Some(sys_rmdir),
+        SYS_RENAME => Some(sys_rename),
+        SYS_GETCWD => Some(sys_getcwd),
+        SYS_GETDENTS => Some(sys_getdents),
+        _ => None,
+    }
+}
+
+// The nth system call argument.
+pub fn arg(tf: &TrapFrame, n: usize) -> u64 {
+    match n {
+        0 => tf.rdi,
+        1 => tf.rsi,
+        2 => tf.rdx,
+        3 => tf.r10,
+        4 => tf.r8,
+        5 => tf.r9,
+        _ => panic!("arg {}", n),
+    }
+}
+
+// Fetch the nth argument as a NUL terminated string into buf.
+pub fn argstr<'a>(tf: &TrapFrame, n: usize, buf: &'a mut [u8]) -> Result<&'a str, &'static str> {
+    let len = unsafe { strncpy_from_user(buf.as_mut_ptr(), arg(tf, n), buf.len()) };
+    if len < 0 { return Err("bad address") }
+    if len as usize == buf.len() { return Err("string too long") }
+    core::str::from_utf8(&buf[..len as usize]).map_err(|_| "invalid string")
+}
+
+#[no_mangle]
+extern "C" fn syscall_dispatch(tf: &mut TrapFrame) -> () {
+    count_intr(T_SYSCALL);
+    let num = tf.rax;
+    tf.rax = match handler(num) {
+        Some(h) => h(tf).unwrap_or(-1i64 as u64),
+        None => {
+            let pid = unsafe { my_proc().map(|p| p.get_pid()).unwrap_or(0) };
+            println!("{}: unknown sys call {}", pid, num);
+            -1i64 as u64
+        }
+    };
+    unsafe {
+        if my_proc().map_or(false, |p| p.is_killed()) { exit(); }
+    }
+}
+
+// Trap gate of T_SYSCALL. Build a TrapFrame below the frame the cpu pushed
+// and leave through trap_ret, which pops it.
+#[naked]
+pub unsafe extern "C" fn syscall_entry() {
+    asm!("push $$0");               // err
+    asm!("push $$64");              // trapno, T_SYSCALL
+    asm!("push %r15");
+    asm!("push %r14");
+    asm!("push %r13");
+    asm!("push %r12");
+    asm!("push %r11");
+    asm!("push %r10");
+    asm!("push %r9");
+    asm!("push %r8");
+    asm!("push %rdi");
+    asm!("push %rsi");
+    asm!("push %rbp");
+    asm!("push %rdx");
+    asm!("push %rcx");
+    asm!("push %rbx");
+    asm!("push %rax");
+
+    asm!("mov %rsp, %rdi");
+    asm!("call syscall_dispatch");
+    asm!("jmp trap_ret");
+}
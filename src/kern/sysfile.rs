@@ -0,0 +1,376 @@
+// File-system system calls.
+//
+// Arguments are checked and copied in here; the work is done by kern::file
+// and kern::vfs.
+
+use crate::*;
+use crate::kern::dev::chrdev;
+use crate::kern::file::{File, FileKind, file_alloc};
+use crate::kern::log::{begin_op, end_op};
+use crate::kern::pipe::pipe_alloc;
+use crate::kern::proc::{TrapFrame, my_proc, my_proc_mut};
+use crate::kern::syscall::{arg, argstr};
+use crate::kern::exec::{exec, MAXARG};
+use crate::kern::kalloc::{kalloc, kfree};
+use crate::kern::uaccess::{copy_from_user, copy_to_user, strncpy_from_user};
+use crate::kern::vfs;
+use crate::kern::vfs::{INode, Stat, NAME_MAX, PATH_MAX, T_DIR, T_FILE, T_DEV};
+
+// Modes of open
+pub const O_RDONLY: u64 = 0x000;
+pub const O_WRONLY: u64 = 0x001;
+pub const O_RDWR: u64 = 0x002;
+pub const O_CREATE: u64 = 0x200;
+pub const O_TRUNC: u64 = 0x400;
+pub const O_APPEND: u64 = 0x800;
+
+const FSTYPE_MAX: usize = 16;
+
+// Fetch the nth argument as a byte count.
+fn argcount(tf: &TrapFrame, n: usize) -> Result<u32, &'static str> {
+    let c = arg(tf, n);
+    if c > u32::max_value() as u64 { return Err("count too large") }
+    Ok(c as u32)
+}
+
+// Fetch the nth argument as a file descriptor and return both the
+// descriptor and the corresponding File.
+fn argfd(tf: &TrapFrame, n: usize) -> Result<(usize, &'static File), &'static str> {
+    let fd = arg(tf, n) as usize;
+    let p = unsafe { my_proc() }.ok_or("no process")?;
+    p.get_file(fd).map(|f| (fd, f)).ok_or("bad file descriptor")
+}
+
+// Allocate a file descriptor for the given file.
+// Takes over file reference from caller on success.
+fn fdalloc(f: &'static File) -> Result<usize, &'static str> {
+    unsafe { my_proc_mut() }.ok_or("no process")?.fd_alloc(f)
+}
+
+pub fn sys_dup(tf: &TrapFrame) -> Result<u64, &'static str> {
+    let (_, f) = argfd(tf, 0)?;
+    let f = f.dup();
+    match fdalloc(f) {
+        Ok(fd) => Ok(fd as u64),
+        Err(e) => {
+            f.close();
+            Err(e)
+        }
+    }
+}
+
+// read(fd, buf, n)
+pub fn sys_read(tf: &TrapFrame) -> Result<u64, &'static str> {
+    let (_, f) = argfd(tf, 0)?;
+    f.read(arg(tf, 1), argcount(tf, 2)?).map(|n| n as u64)
+}
+
+// write(fd, buf, n)
+pub fn sys_write(tf: &TrapFrame) -> Result<u64, &'static str> {
+    let (_, f) = argfd(tf, 0)?;
+    f.write(arg(tf, 1), argcount(tf, 2)?).map(|n| n as u64)
+}
+
+pub fn sys_close(tf: &TrapFrame) -> Result<u64, &'static str> {
+    let (fd, _) = argfd(tf, 0)?;
+    let f = unsafe { my_proc_mut() }.and_then(|p| p.fd_free(fd)).ok_or("bad file descriptor")?;
+    f.close();
+    Ok(0)
+}
+
+// fstat(fd, struct stat *)
+pub fn sys_fstat(tf: &TrapFrame) -> Result<u64, &'static str> {
+    let (_, f) = argfd(tf, 0)?;
+    let st = f.stat()?;
+    if unsafe { copy_to_user(arg(tf, 1), &st as *const Stat as *const u8, size_of::<Stat>()) } < 0 {
+        return Err("bad address");
+    }
+    Ok(0)
+}
+
+// lseek(fd, off, whence) returns the new offset
+pub fn sys_lseek(tf: &TrapFrame) -> Result<u64, &'static str> {
+    let (_, f) = argfd(tf, 0)?;
+    f.seek(arg(tf, 1) as i64, arg(tf, 2))
+}
+
+// Make a new inode called path of type itype, see INode::create.
+// Must be called inside a transaction.
+pub fn create(path: &str, itype: i16, major: i16, minor: i16) -> Result<&'static dyn INode, &'static str> {
+    let mut name = [0u8; NAME_MAX];
+    let (dp, len) = vfs::nameiparent(path, &mut name)?;
+    let r = dp.create(&name[..len], itype, major, minor);
+    dp.put();
+    r
+}
+
+// The inode to open path with omode, with a new reference, and what the
+// file refers to: the inode itself or, for a device, its driver.
+// Must be called inside a transaction.
+fn open_inode(path: &str, omode: u64) -> Result<(&'static dyn INode, FileKind), &'static str> {
+    let ip = if omode & O_CREATE != 0 { create(path, T_FILE, 0, 0)? } else { vfs::namei(path)? };
+    let r = (|| -> Result<FileKind, &'static str> {
+        let st = ip.stat()?;
+        if st.itype == T_DIR && omode & (O_WRONLY | O_RDWR) != 0 { return Err("is a directory") }
+        if st.itype == T_FILE && omode & O_TRUNC != 0 { ip.trunc()?; }
+        if st.itype == T_DEV {
+            let dev = chrdev(st.major).ok_or("no such device")?;
+            return Ok(FileKind::Device(ip, dev, st.minor));
+        }
+        Ok(FileKind::INode(ip))
+    })();
+    match r {
+        Ok(kind) => Ok((ip, kind)),
+        Err(e) => {
+            ip.put();
+            Err(e)
+        }
+    }
+}
+
+// open(path, omode) returns a file descriptor
+pub fn sys_open(tf: &TrapFrame) -> Result<u64, &'static str> {
+    let mut path = [0u8; PATH_MAX];
+    let path = argstr(tf, 0, &mut path)?;
+    let omode = arg(tf, 1);
+
+    begin_op();
+    let r = open_inode(path, omode);
+    end_op();
+    let (ip, kind) = r?;
+
+    let r = file_alloc().and_then(|f| match fdalloc(f) {
+        Ok(fd) => Ok((fd, f)),
+        Err(e) => {
+            f.close();
+            Err(e)
+        }
+    });
+    let (fd, f) = match r {
+        Ok(r) => r,
+        Err(e) => {
+            begin_op();
+            ip.put();
+            end_op();
+            return Err(e);
+        }
+    };
+
+    f.open(kind,
+           omode & O_WRONLY == 0,
+           omode & (O_WRONLY | O_RDWR) != 0,
+           omode & O_APPEND != 0);
+    Ok(fd as u64)
+}
+
+// getdents(fd, buf, n) returns the bytes of DirEntry records read
+pub fn sys_getdents(tf: &TrapFrame) -> Result<u64, &'static str> {
+    let (_, f) = argfd(tf, 0)?;
+    f.getdents(arg(tf, 1), argcount(tf, 2)?).map(|n| n as u64)
+}
+
+// link(old, new)
+pub fn sys_link(tf: &TrapFrame) -> Result<u64, &'static str> {
+    let mut old = [0u8; PATH_MAX];
+    let mut new = [0u8; PATH_MAX];
+    let old = argstr(tf, 0, &mut old)?;
+    let new = argstr(tf, 1, &mut new)?;
+
+    begin_op();
+    let r = vfs::link(old, new);
+    end_op();
+    r.map(|_| 0)
+}
+
+// Remove path, a directory if dir is set.
+fn remove(tf: &TrapFrame, dir: bool) -> Result<u64, &'static str> {
+    let mut path = [0u8; PATH_MAX];
+    let path = argstr(tf, 0, &mut path)?;
+    let mut name = [0u8; NAME_MAX];
+
+    begin_op();
+    let r = vfs::nameiparent(path, &mut name).and_then(|(dp, len)| {
+        let r = vfs::unlink(dp, &name[..len], dir);
+        dp.put();
+        r
+    });
+    end_op();
+    r.map(|_| 0)
+}
+
+// unlink(path)
+pub fn sys_unlink(tf: &TrapFrame) -> Result<u64, &'static str> {
+    remove(tf, false)
+}
+
+// rmdir(path)
+pub fn sys_rmdir(tf: &TrapFrame) -> Result<u64, &'static str> {
+    remove(tf, true)
+}
+
+// rename(old, new)
+pub fn sys_rename(tf: &TrapFrame) -> Result<u64, &'static str> {
+    let mut old = [0u8; PATH_MAX];
+    let mut new = [0u8; PATH_MAX];
+    let old = argstr(tf, 0, &mut old)?;
+    let new = argstr(tf, 1, &mut new)?;
+
+    begin_op();
+    let r = vfs::rename(old, new);
+    end_op();
+    r.map(|_| 0)
+}
+
+// mkdir(path)
+pub fn sys_mkdir(tf: &TrapFrame) -> Result<u64, &'static str> {
+    let mut path = [0u8; PATH_MAX];
+    let path = argstr(tf, 0, &mut path)?;
+
+    begin_op();
+    let r = create(path, T_DIR, 0, 0).map(|ip| ip.put());
+    end_op();
+    r.map(|_| 0)
+}
+
+// mknod(path, major, minor)
+pub fn sys_mknod(tf: &TrapFrame) -> Result<u64, &'static str> {
+    let mut path = [0u8; PATH_MAX];
+    let path = argstr(tf, 0, &mut path)?;
+    let (major, minor) = (arg(tf, 1) as i16, arg(tf, 2) as i16);
+
+    begin_op();
+    let r = create(path, T_DEV, major, minor).map(|ip| ip.put());
+    end_op();
+    r.map(|_| 0)
+}
+
+// chdir(path)
+pub fn sys_chdir(tf: &TrapFrame) -> Result<u64, &'static str> {
+    let mut path = [0u8; PATH_MAX];
+    let path = argstr(tf, 0, &mut path)?;
+    let p = unsafe { my_proc_mut() }.ok_or("no process")?;
+
+    begin_op();
+    let r = vfs::namei(path).and_then(|ip| match ip.stat() {
+        Ok(st) if st.itype == T_DIR => Ok(ip),
+        Ok(_) => {
+            ip.put();
+            Err(vfs::NOT_DIR)
+        }
+        Err(e) => {
+            ip.put();
+            Err(e)
+        }
+    });
+    if let Ok(ip) = r {
+        if let Some(old) = p.set_cwd(ip) { old.put(); }
+    }
+    end_op();
+    r.map(|_| 0)
+}
+
+// Copy the NULL terminated array of strings at user address uargv into
+// buf, one after another. Returns the number of strings.
+fn fetch_args<'a>(uargv: u64, mut buf: &'a mut [u8], args: &mut [&'a [u8]; MAXARG]) -> Result<usize, &'static str> {
+    for (i, a) in args.iter_mut().enumerate() {
+        let mut uarg: u64 = 0;
+        let src = uargv.checked_add(i as u64 * size_of::<u64>() as u64).ok_or("bad address")?;
+        if unsafe { copy_from_user(&mut uarg as *mut u64 as *mut u8, src, size_of::<u64>()) } < 0 {
+            return Err("bad address");
+        }
+        if uarg == 0 { return Ok(i) }
+        let len = unsafe { strncpy_from_user(buf.as_mut_ptr(), uarg, buf.len()) };
+        if len < 0 { return Err("bad address") }
+        if len as usize == buf.len() { return Err("exec: arguments too long") }
+        let (s, rest) = core::mem::replace(&mut buf, &mut []).split_at_mut(len as usize + 1);
+        *a = &s[..len as usize];
+        buf = rest;
+    }
+    Err("exec: too many arguments")
+}
+
+// exec(path, argv)
+pub fn sys_exec(tf: &TrapFrame) -> Result<u64, &'static str> {
+    let mut path = [0u8; PATH_MAX];
+    let path = argstr(tf, 0, &mut path)?;
+
+    // The strings are copied into a page before the old image goes.
+    let pg = kalloc().ok_or("exec: out of memory")?;
+    let buf = unsafe { core::slice::from_raw_parts_mut(pg.as_mut_ptr::<u8>(), PGSIZE as usize) };
+    let mut args: [&[u8]; MAXARG] = [&[]; MAXARG];
+    let r = fetch_args(arg(tf, 1), buf, &mut args).and_then(|argc| unsafe { exec(path, &args[..argc]) });
+    kfree(pg);
+    r
+}
+
+// getcwd(buf, size) returns the length of the path, which is copied to
+// buf with a NUL
+pub fn sys_getcwd(tf: &TrapFrame) -> Result<u64, &'static str> {
+    let mut path = [0u8; PATH_MAX];
+    let len = vfs::getcwd(&mut path[..PATH_MAX - 1])?;
+    path[len] = 0;
+    if len + 1 > arg(tf, 1) as usize { return Err("buffer too small") }
+    if unsafe { copy_to_user(arg(tf, 0), path.as_ptr(), len + 1) } < 0 {
+        return Err("bad address");
+    }
+    Ok(len as u64)
+}
+
+// pipe(int fd[2]) stores the read end in fd[0], the write end in fd[1]
+pub fn sys_pipe(tf: &TrapFrame) -> Result<u64, &'static str> {
+    let (rf, wf) = pipe_alloc()?;
+    let fd0 = match fdalloc(rf) {
+        Ok(fd) => fd,
+        Err(e) => {
+            rf.close();
+            wf.close();
+            return Err(e);
+        }
+    };
+    let fd1 = match fdalloc(wf) {
+        Ok(fd) => fd,
+        Err(e) => {
+            unsafe { my_proc_mut() }.and_then(|p| p.fd_free(fd0));
+            rf.close();
+            wf.close();
+            return Err(e);
+        }
+    };
+
+    let fd = [fd0 as i32, fd1 as i32];
+    if unsafe { copy_to_user(arg(tf, 0), fd.as_ptr() as *const u8, size_of::<[i32; 2]>()) } < 0 {
+        let p = unsafe { my_proc_mut() }.ok_or("no process")?;
+        p.fd_free(fd0);
+        p.fd_free(fd1);
+        rf.close();
+        wf.close();
+        return Err("bad address");
+    }
+    Ok(0)
+}
+
+// mount(source, target, fstype)
+pub fn sys_mount(tf: &TrapFrame) -> Result<u64, &'static str> {
+    let mut source = [0u8; PATH_MAX];
+    let mut target = [0u8; PATH_MAX];
+    let mut fstype = [0u8; FSTYPE_MAX];
+    let source = argstr(tf, 0, &mut source)?;
+    let target = argstr(tf, 1, &mut target)?;
+    let fstype = argstr(tf, 2, &mut fstype)?;
+
+    begin_op();
+    let r = vfs::mount(source, target, fstype);
+    end_op();
+    r.map(|_| 0)
+}
+
+// umount(target)
+pub fn sys_umount(tf: &TrapFrame) -> Result<u64, &'static str> {
+    let mut target = [0u8; PATH_MAX];
+    let target = argstr(tf, 0, &mut target)?;
+
+    begin_op();
+    let r = vfs::umount(target);
+    end_op();
+    r.map(|_| 0)
+}
@@ -0,0 +1,21 @@
+// Process system calls.
+
+use crate::kern::proc::{TrapFrame, my_proc, fork, exit, wait};
+
+// fork() returns the pid of the child, 0 in the child
+pub fn sys_fork(_tf: &TrapFrame) -> Result<u64, &'static str> {
+    unsafe { fork() }.map(|pid| pid as u64)
+}
+
+pub fn sys_exit(_tf: &TrapFrame) -> Result<u64, &'static str> {
+    unsafe { exit() }
+}
+
+// wait() returns the pid of a child which exited
+pub fn sys_wait(_tf: &TrapFrame) -> Result<u64, &'static str> {
+    unsafe { wait() }.map(|pid| pid as u64)
+}
+
+pub fn sys_getpid(_tf: &TrapFrame) -> Result<u64, &'static str> {
+    unsafe { my_proc() }.map(|p| p.get_pid() as u64).ok_or("no process")
+}
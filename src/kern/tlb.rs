@@ -0,0 +1,103 @@
+use crate::*;
+use crate::kern::spinlock::SpinLock;
+use crate::kern::lapic::{lapic_id, lapic_send_ipi};
+use crate::kern::mp::CPU_INFO;
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use x86_64::instructions::{interrupts, tlb};
+
+// Above this many pages it is cheaper to reload CR3 than to invlpg each page.
+const FLUSH_ALL_THRESHOLD: u64 = 32;
+
+// Every CPU records the (physical) page table it has loaded into CR3,
+// so a shootdown only interrupts the CPUs that may cache the mapping.
+// Each slot is only written by its own CPU.
+static mut ACTIVE_CR3: [u64; MAX_CPU] = [0; MAX_CPU];
+
+// The initiator holds SDLOCK for the whole shootdown, the range below
+// is read by the other CPUs in their interrupt handler.
+// PENDING counts the CPUs which have not acknowledged yet.
+static SDLOCK: SpinLock = SpinLock::new();
+static mut SD_START: u64 = 0;
+static mut SD_END: u64 = 0;
+static PENDING: AtomicUsize = AtomicUsize::new(0);
+
+fn this_cpu() -> usize {
+    let id = unsafe { lapic_id() } >> 24;
+    CPU_INFO.cpu_index(id as u8).expect("tlb: unknown cpu")
+}
+
+// Kernel mappings (the upper half) are present in every page table.
+fn is_kernel(va: u64) -> bool { va >> 63 == 1 }
+
+// Record the page table this cpu just loaded. Called on every CR3 switch.
+pub fn set_active(cr3: u64) -> () {
+    unsafe { write_volatile(&mut ACTIVE_CR3[this_cpu()], cr3); }
+}
+
+// Invalidate [st, ed) on this CPU.
+fn flush_local(st: u64, ed: u64) -> () {
+    if (ed - st) / PGSIZE > FLUSH_ALL_THRESHOLD {
+        tlb::flush_all();
+        return;
+    }
+    let mut a = st;
+    while a < ed {
+        tlb::flush(VA::new(a));
+        a += PGSIZE;
+    }
+}
+
+// Invalidate the translations of [st, st + sz) in the address space whose
+// PML4 is at physical address `cr3` on every CPU that may have cached them.
+// The whole range is sent with a single IPI per target, so callers should
+// batch their page table updates and flush once at the end.
+// Must be called with interrupts enabled, otherwise two CPUs shooting
+// down at the same time would wait for each other forever.
+pub fn flush_range(cr3: u64, st: VA, sz: usize) -> () {
+    if sz == 0 { return }
+    let st = st.align_down(PGSIZE).as_u64();
+    let ed = VA::new(st + sz as u64).align_up(PGSIZE).as_u64();
+    let kernel = is_kernel(st);
+    let me = this_cpu();
+
+    SDLOCK.acquire();
+    unsafe {
+        SD_START = st;
+        SD_END = ed;
+    }
+
+    let mut targets = [false; MAX_CPU];
+    let mut n = 0;
+    for i in 0..CPU_INFO.ncpu() {
+        if i == me { continue }
+        let active = unsafe { read_volatile(&ACTIVE_CR3[i]) };
+        // A CPU which has never loaded a page table is not running yet.
+        if active == 0 { continue }
+        if kernel || active == cr3 {
+            targets[i] = true;
+            n += 1;
+        }
+    }
+
+    if n > 0 && !interrupts::are_enabled() { panic!("flush_range: interrupts off"); }
+
+    PENDING.store(n, Ordering::SeqCst);
+    for i in 0..CPU_INFO.ncpu() {
+        if targets[i] { lapic_send_ipi(CPU_INFO.apic_id(i), T_TLBFLUSH); }
+    }
+
+    flush_local(st, ed);
+
+    while PENDING.load(Ordering::SeqCst) != 0 {
+        unsafe { asm!("pause" : : : : "intel", "volatile"); }
+    }
+    SDLOCK.release();
+}
+
+// Called from the T_TLBFLUSH interrupt handler.
+pub fn shootdown_interrupt() -> () {
+    let (st, ed) = unsafe { (read_volatile(&SD_START), read_volatile(&SD_END)) };
+    flush_local(st, ed);
+    PENDING.fetch_sub(1, Ordering::SeqCst);
+}
@@ -0,0 +1,615 @@
+// RAM file systems.
+//
+// tmpfs keeps everything in memory: the data of an inode lives in pages
+// from kalloc, found through an index page holding their addresses.
+// Directories are files of fixed size entries, "." and ".." included, as
+// on rosfs. Nothing survives unmount.
+//
+// devfs is a tmpfs which gets a device inode for every device when it is
+// mounted: console, ttyS0, null, zero and the disks by their names.
+//
+// All instances share one inode table, an inode is numbered by its slot
+// plus one. TMP_LOCK protects the reference counts and which slots are in
+// use; TMP_DATA_LOCK everything else, it is held across copies to and
+// from user space.
+
+use crate::*;
+use crate::kern::blk::blkdev;
+use crate::kern::dev::{CONSOLE, TTYS, MEM, DISK, MEM_NULL, MEM_ZERO};
+use crate::kern::kalloc::{kalloc, kfree};
+use crate::kern::log::{begin_op, end_op};
+use crate::kern::sleeplock::SleepLock;
+use crate::kern::spinlock::SpinLock;
+use crate::kern::uaccess::{either_copy_out, either_copy_in};
+use crate::kern::vfs::{FileSystem, INode, FsType, Stat, NAME_MAX, NOT_FOUND, NOT_DIR, T_DIR, T_FILE, T_DEV};
+use crate::kern::vfs::{register_fs, mount_dev, same_fs};
+use array_init::array_init;
+use core::cell::Cell;
+
+const NTMPFS: usize = 4;                // Mounted instances
+const NTMPINODE: usize = 128;           // Inodes of all instances
+const MAXFILE: u64 = ENTRY_COUNT as u64 * PGSIZE;
+const DIRSIZ: usize = 60;
+const ANON_DEV: i32 = 256;              // Stat.dev of instance 0, above all disks
+
+#[repr(C)]
+struct Dirent {
+    ino: u32,                           // 0 if free
+    name: [u8; DIRSIZ],                 // Padded with NULs
+}
+
+const DIRENT_SIZE: u32 = size_of::<Dirent>() as u32;
+
+impl Dirent {
+    fn name(&self) -> &[u8] {
+        &self.name[..self.name.iter().position(|&c| c == 0).unwrap_or(DIRSIZ)]
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct TmpFs {
+    id: usize,
+    name: &'static str,                 // Type it was mounted as
+    root: u32,                          // Inode number of /, 0 if the slot is free
+}
+
+pub struct TmpINode {
+    refcnt: Cell<u32>,
+    fs: Cell<usize>,                    // Instance, while itype is set
+    itype: Cell<i16>,                   // 0 if the slot is free
+    major: Cell<i16>,
+    minor: Cell<i16>,
+    nlink: Cell<i16>,                   // Directory entries naming it, ".." of subdirectories included
+    size: Cell<u32>,
+    index: Cell<u64>,                   // Kernel address of the index page, 0 if none
+}
+
+// Slots are taken and freed under TMP_LOCK, the rest changes under
+// TMP_DATA_LOCK, see above.
+unsafe impl Sync for TmpINode {}
+
+static TMP_LOCK: SpinLock = SpinLock::new();
+
+// What a hole in a file reads as.
+static ZEROS: [u8; PGSIZE as usize] = [0; PGSIZE as usize];
+static TMP_DATA_LOCK: SleepLock = SleepLock::new("tmpfs");
+static mut TMPFS: [TmpFs; NTMPFS] = [
+    TmpFs { id: 0, name: "", root: 0 }, TmpFs { id: 1, name: "", root: 0 },
+    TmpFs { id: 2, name: "", root: 0 }, TmpFs { id: 3, name: "", root: 0 },
+];
+lazy_static! {
+    static ref TMPINODES: [TmpINode; NTMPINODE] = array_init(|_| TmpINode::new());
+}
+
+// Run f with TMP_DATA_LOCK held.
+fn locked<T, F: FnOnce() -> Result<T, &'static str>>(f: F) -> Result<T, &'static str> {
+    TMP_DATA_LOCK.acquire();
+    let r = f();
+    TMP_DATA_LOCK.release();
+    r
+}
+
+fn slot(ino: u32) -> &'static TmpINode {
+    &TMPINODES[ino as usize - 1]
+}
+
+// A zeroed page, as its kernel address.
+fn zalloc() -> Result<u64, &'static str> {
+    let pg = kalloc().ok_or("tmpfs: out of memory")?;
+    unsafe { memset(pg.as_mut_ptr::<u8>(), 0, PGSIZE); }
+    Ok(pg.as_u64())
+}
+
+// Free the data pages listed in index, and index.
+fn free_pages(index: u64) -> () {
+    if index == 0 { return }
+    let pages = unsafe { core::slice::from_raw_parts(index as *const u64, ENTRY_COUNT) };
+    for &pg in pages.iter().filter(|&&pg| pg != 0) { kfree(VA::new(pg)); }
+    kfree(VA::new(index));
+}
+
+// Allocate an inode of type itype in instance fs, with one reference and
+// no links. Must hold TMP_LOCK.
+fn ialloc_locked(fs: usize, itype: i16, major: i16, minor: i16) -> Option<&'static TmpINode> {
+    TMPINODES.iter().find(|ip| ip.itype.get() == 0 && ip.refcnt.get() == 0).map(|ip| {
+        ip.clear();
+        ip.refcnt.set(1);
+        ip.fs.set(fs);
+        ip.itype.set(itype);
+        ip.major.set(major);
+        ip.minor.set(minor);
+        ip
+    })
+}
+
+fn ialloc(fs: usize, itype: i16, major: i16, minor: i16) -> Result<&'static TmpINode, &'static str> {
+    TMP_LOCK.acquire();
+    let ip = ialloc_locked(fs, itype, major, minor);
+    TMP_LOCK.release();
+    ip.ok_or("tmpfs: out of inodes")
+}
+
+impl TmpINode {
+    fn new() -> TmpINode {
+        TmpINode {
+            refcnt: Cell::new(0), fs: Cell::new(0), itype: Cell::new(0), major: Cell::new(0),
+            minor: Cell::new(0), nlink: Cell::new(0), size: Cell::new(0), index: Cell::new(0),
+        }
+    }
+
+    // Mark the slot free.
+    fn clear(&self) -> () {
+        self.refcnt.set(0);
+        self.fs.set(0);
+        self.itype.set(0);
+        self.major.set(0);
+        self.minor.set(0);
+        self.nlink.set(0);
+        self.size.set(0);
+        self.index.set(0);
+    }
+
+    fn inum(&self) -> u32 {
+        let base = TMPINODES.as_ptr() as usize;
+        ((self as *const TmpINode as usize - base) / size_of::<TmpINode>() + 1) as u32
+    }
+
+    fn idup(&'static self) -> &'static TmpINode {
+        TMP_LOCK.acquire();
+        self.refcnt.set(self.refcnt.get() + 1);
+        TMP_LOCK.release();
+        self
+    }
+
+    // Drop a reference. An inode without links is freed with the last one;
+    // links only change while a reference is held.
+    fn iput(&self) -> () {
+        TMP_LOCK.acquire();
+        if self.refcnt.get() < 1 { panic!("tmpfs: put"); }
+        self.refcnt.set(self.refcnt.get() - 1);
+        let index = if self.refcnt.get() == 0 && self.nlink.get() == 0 {
+            let index = self.index.get();
+            self.clear();
+            Some(index)
+        } else {
+            None
+        };
+        TMP_LOCK.release();
+        if let Some(index) = index { free_pages(index); }
+    }
+
+    // The rest must hold TMP_DATA_LOCK.
+
+    fn live_dir(&self) -> Result<(), &'static str> {
+        if self.itype.get() != T_DIR { return Err(NOT_DIR) }
+        if self.nlink.get() == 0 { return Err(NOT_FOUND) }
+        Ok(())
+    }
+
+    // The page holding byte off of the data, allocated if there is none.
+    fn page(&self, off: u32) -> Result<*mut u8, &'static str> {
+        if self.index.get() == 0 { self.index.set(zalloc()?); }
+        let pg = unsafe { &mut *(self.index.get() as *mut u64).add((off as u64 / PGSIZE) as usize) };
+        if *pg == 0 { *pg = zalloc()?; }
+        Ok(*pg as *mut u8)
+    }
+
+    // The page holding byte off of the data, if it has one.
+    fn page_present(&self, off: u32) -> Option<*const u8> {
+        if self.index.get() == 0 { return None }
+        let pg = unsafe { *(self.index.get() as *const u64).add((off as u64 / PGSIZE) as usize) };
+        if pg == 0 { None } else { Some(pg as *const u8) }
+    }
+
+    // Read data from inode, a page which was never written reads as zeros.
+    // If user is set, dst is a user virtual address; otherwise, a kernel address.
+    fn readi(&self, user: bool, dst: u64, off: u32, n: u32) -> Result<usize, &'static str> {
+        if off > self.size.get() || off.checked_add(n).is_none() { return Ok(0) }
+        let n = core::cmp::min(n, self.size.get() - off);
+
+        let mut tot = 0;
+        while tot < n {
+            let pos = off + tot;
+            let st = pos as u64 % PGSIZE;
+            let m = core::cmp::min(n - tot, (PGSIZE - st) as u32);
+            let src = match self.page_present(pos) {
+                Some(pg) => unsafe { pg.add(st as usize) },
+                None => ZEROS.as_ptr(),
+            };
+            if unsafe { either_copy_out(user, dst + tot as u64, src, m as usize) } < 0 {
+                return Err("tmpfs: bad address");
+            }
+            tot += m;
+        }
+        Ok(tot as usize)
+    }
+
+    // Write data to inode, growing it as needed.
+    // If user is set, src is a user virtual address; otherwise, a kernel address.
+    fn writei(&self, user: bool, src: u64, off: u32, n: u32) -> Result<usize, &'static str> {
+        if off > self.size.get() || off.checked_add(n).is_none() { return Err("tmpfs: bad offset") }
+        if off as u64 + n as u64 > MAXFILE { return Err("tmpfs: file too large") }
+
+        let mut tot = 0;
+        while tot < n {
+            let pos = off + tot;
+            let st = pos as u64 % PGSIZE;
+            let m = core::cmp::min(n - tot, (PGSIZE - st) as u32);
+            let r = self.page(pos).and_then(|pg| {
+                if unsafe { either_copy_in(user, pg.add(st as usize), src + tot as u64, m as usize) } < 0 {
+                    return Err("tmpfs: bad address");
+                }
+                Ok(())
+            });
+            if let Err(e) = r {
+                if tot == 0 { return Err(e) }
+                break;
+            }
+            tot += m;
+            if pos + m > self.size.get() { self.size.set(pos + m); }
+        }
+        Ok(tot as usize)
+    }
+
+    fn itrunc(&self) -> () {
+        free_pages(self.index.replace(0));
+        self.size.set(0);
+    }
+
+    fn dirent(&self, off: u32) -> Result<Dirent, &'static str> {
+        let mut de = Dirent { ino: 0, name: [0; DIRSIZ] };
+        if self.readi(false, &mut de as *mut Dirent as u64, off, DIRENT_SIZE)? != DIRENT_SIZE as usize {
+            return Err("tmpfs: short directory");
+        }
+        Ok(de)
+    }
+
+    fn set_dirent(&self, off: u32, ino: u32, name: &[u8]) -> Result<(), &'static str> {
+        let mut de = Dirent { ino: ino, name: [0; DIRSIZ] };
+        de.name[..name.len()].copy_from_slice(name);
+        self.writei(false, &de as *const Dirent as u64, off, DIRENT_SIZE)?;
+        Ok(())
+    }
+
+    // Look for a directory entry. Returns its inode number and offset.
+    fn dirlookup(&self, name: &[u8]) -> Result<Option<(u32, u32)>, &'static str> {
+        for off in (0..self.size.get()).step_by(DIRENT_SIZE as usize) {
+            let de = self.dirent(off)?;
+            if de.ino != 0 && de.name() == name { return Ok(Some((de.ino, off))) }
+        }
+        Ok(None)
+    }
+
+    // Write a new directory entry (name, ino) into the directory.
+    fn dirlink(&self, name: &[u8], ino: u32) -> Result<(), &'static str> {
+        let mut off = 0;
+        while off < self.size.get() && self.dirent(off)?.ino != 0 { off += DIRENT_SIZE; }
+        self.set_dirent(off, ino, name)
+    }
+
+    // Is the directory empty except for "." and ".." ?
+    fn isdirempty(&self) -> Result<bool, &'static str> {
+        for off in (2 * DIRENT_SIZE..self.size.get()).step_by(DIRENT_SIZE as usize) {
+            if self.dirent(off)?.ino != 0 { return Ok(false) }
+        }
+        Ok(true)
+    }
+
+    fn create_locked(&self, name: &[u8], itype: i16, major: i16, minor: i16)
+        -> Result<&'static TmpINode, &'static str> {
+        self.live_dir()?;
+        if let Some((ino, _)) = self.dirlookup(name)? {
+            let ip = slot(ino);
+            if itype == T_FILE && (ip.itype.get() == T_FILE || ip.itype.get() == T_DEV) { return Ok(ip.idup()) }
+            return Err("file exists");
+        }
+
+        let ip = ialloc(self.fs.get(), itype, major, minor)?;
+        let r = (|| -> Result<(), &'static str> {
+            if itype == T_DIR {
+                ip.dirlink(b".", ip.inum())?;
+                ip.dirlink(b"..", self.inum())?;
+            }
+            self.dirlink(name, ip.inum())
+        })();
+        if let Err(e) = r {
+            ip.iput();
+            return Err(e);
+        }
+        ip.nlink.set(1);
+        if itype == T_DIR { self.nlink.set(self.nlink.get() + 1); }    // for ".."
+        Ok(ip)
+    }
+
+    fn unlink_locked(&self, name: &[u8]) -> Result<&'static TmpINode, &'static str> {
+        self.live_dir()?;
+        let (ino, off) = self.dirlookup(name)?.ok_or(NOT_FOUND)?;
+        let ip = slot(ino);
+        if ip.itype.get() == T_DIR && !ip.isdirempty()? { return Err("directory not empty") }
+        self.set_dirent(off, 0, b"")?;
+        let ip = ip.idup();
+        if ip.itype.get() == T_DIR { self.nlink.set(self.nlink.get() - 1); }
+        ip.nlink.set(ip.nlink.get() - 1);
+        Ok(ip)
+    }
+
+    // Move name to newname in nd, see INode::rename. Returns the inode
+    // which lost its link to newname, for the caller to put.
+    fn rename_locked(&self, name: &[u8], nd: &TmpINode, newname: &[u8])
+        -> Result<Option<&'static TmpINode>, &'static str> {
+        self.live_dir()?;
+        nd.live_dir()?;
+        let (ino, off) = self.dirlookup(name)?.ok_or(NOT_FOUND)?;
+        let ip = slot(ino);
+        let old = nd.dirlookup(newname)?;
+        if old.map_or(false, |(oino, _)| oino == ino) { return Ok(None) }
+
+        // A directory cannot move below itself.
+        if ip.itype.get() == T_DIR {
+            let mut d = nd.inum();
+            loop {
+                if d == ino { return Err("invalid argument") }
+                let (parent, _) = slot(d).dirlookup(b"..")?.ok_or(NOT_FOUND)?;
+                if parent == d { break }
+                d = parent;
+            }
+        }
+
+        let replaced = match old {
+            Some((oino, ooff)) => {
+                let op = slot(oino);
+                match (ip.itype.get() == T_DIR, op.itype.get() == T_DIR) {
+                    (true, false) => return Err(NOT_DIR),
+                    (false, true) => return Err("is a directory"),
+                    (true, true) => if !op.isdirempty()? { return Err("directory not empty") },
+                    (false, false) => {}
+                }
+                nd.set_dirent(ooff, ino, newname)?;
+                let op = op.idup();
+                if op.itype.get() == T_DIR { nd.nlink.set(nd.nlink.get() - 1); }
+                op.nlink.set(op.nlink.get() - 1);
+                Some(op)
+            }
+            None => {
+                nd.dirlink(newname, ino)?;
+                None
+            }
+        };
+        self.set_dirent(off, 0, b"")?;
+
+        if ip.itype.get() == T_DIR && self.inum() != nd.inum() {
+            let (_, poff) = ip.dirlookup(b"..")?.ok_or(NOT_FOUND)?;
+            ip.set_dirent(poff, nd.inum(), b"..")?;
+            self.nlink.set(self.nlink.get() - 1);
+            nd.nlink.set(nd.nlink.get() + 1);
+        }
+        Ok(replaced)
+    }
+}
+
+impl INode for TmpINode {
+    fn fs(&self) -> &'static dyn FileSystem { unsafe { &TMPFS[self.fs.get()] } }
+
+    fn ino(&self) -> u64 { self.inum() as u64 }
+
+    fn dup(&'static self) -> &'static dyn INode { self.idup() }
+
+    fn put(&self) -> () { self.iput() }
+
+    fn stat(&self) -> Result<Stat, &'static str> {
+        locked(|| Ok(Stat {
+            itype: self.itype.get(),
+            dev: ANON_DEV + self.fs.get() as i32,
+            ino: self.inum(),
+            nlink: self.nlink.get(),
+            size: self.size.get() as u64,
+            major: self.major.get(),
+            minor: self.minor.get(),
+        }))
+    }
+
+    fn read(&self, user: bool, dst: u64, off: u32, n: u32) -> Result<usize, &'static str> {
+        locked(|| self.readi(user, dst, off, n))
+    }
+
+    fn write(&self, user: bool, src: u64, off: u32, n: u32) -> Result<usize, &'static str> {
+        locked(|| self.writei(user, src, off, n))
+    }
+
+    fn trunc(&self) -> Result<(), &'static str> {
+        locked(|| {
+            self.itrunc();
+            Ok(())
+        })
+    }
+
+    fn lookup(&self, name: &[u8]) -> Result<Option<&'static dyn INode>, &'static str> {
+        locked(|| {
+            self.live_dir()?;
+            Ok(self.dirlookup(name)?.map(|(ino, _)| slot(ino).idup() as &'static dyn INode))
+        })
+    }
+
+    fn readdir(&self, idx: usize, name: &mut [u8; NAME_MAX]) -> Result<Option<(usize, u64)>, &'static str> {
+        locked(|| {
+            if self.itype.get() != T_DIR { return Err(NOT_DIR) }
+            let mut i = 0;
+            for off in (0..self.size.get()).step_by(DIRENT_SIZE as usize) {
+                let de = self.dirent(off)?;
+                if de.ino == 0 { continue }
+                if i == idx {
+                    let n = de.name();
+                    name[..n.len()].copy_from_slice(n);
+                    return Ok(Some((n.len(), de.ino as u64)));
+                }
+                i += 1;
+            }
+            Ok(None)
+        })
+    }
+
+    fn create(&self, name: &[u8], itype: i16, major: i16, minor: i16)
+        -> Result<&'static dyn INode, &'static str> {
+        if name.len() > DIRSIZ { return Err("file name too long") }
+        locked(|| self.create_locked(name, itype, major, minor).map(|ip| ip as &'static dyn INode))
+    }
+
+    fn link(&self, name: &[u8], target: &dyn INode) -> Result<(), &'static str> {
+        if !same_fs(target.fs(), INode::fs(self)) { return Err("cross-device link") }
+        if name.len() > DIRSIZ { return Err("file name too long") }
+        let ip = slot(target.ino() as u32);
+        locked(|| {
+            self.live_dir()?;
+            if ip.itype.get() == T_DIR { return Err("is a directory") }
+            if self.dirlookup(name)?.is_some() { return Err("file exists") }
+            self.dirlink(name, ip.inum())?;
+            ip.nlink.set(ip.nlink.get() + 1);
+            Ok(())
+        })
+    }
+
+    fn unlink(&self, name: &[u8]) -> Result<(), &'static str> {
+        // Cannot unlink "." or "..".
+        if name == b"." || name == b".." { return Err("invalid argument") }
+        let ip = locked(|| self.unlink_locked(name))?;
+        ip.iput();
+        Ok(())
+    }
+
+    fn rename(&self, name: &[u8], newdir: &dyn INode, newname: &[u8]) -> Result<(), &'static str> {
+        if !same_fs(newdir.fs(), INode::fs(self)) { return Err("cross-device link") }
+        if newname.len() > DIRSIZ { return Err("file name too long") }
+        for n in [name, newname].iter() {
+            if *n == b"." || *n == b".." { return Err("invalid argument") }
+        }
+
+        let nd = slot(newdir.ino() as u32);
+        let old = locked(|| self.rename_locked(name, nd, newname))?;
+        if let Some(op) = old { op.iput(); }
+        Ok(())
+    }
+}
+
+impl FileSystem for TmpFs {
+    fn name(&self) -> &'static str { self.name }
+
+    fn root(&self) -> Result<&'static dyn INode, &'static str> {
+        Ok(slot(self.root).idup())
+    }
+
+    fn iget(&self, ino: u64) -> Result<&'static dyn INode, &'static str> {
+        if ino == 0 || ino > NTMPINODE as u64 { return Err("tmpfs: bad inode number") }
+        let ip = slot(ino as u32);
+        TMP_LOCK.acquire();
+        let ok = ip.itype.get() != 0 && ip.fs.get() == self.id;
+        if ok { ip.refcnt.set(ip.refcnt.get() + 1); }
+        TMP_LOCK.release();
+        if ok { Ok(ip) } else { Err("tmpfs: stale inode number") }
+    }
+
+    fn busy(&self) -> bool {
+        TMP_LOCK.acquire();
+        let busy = TMPINODES.iter().any(|ip| ip.itype.get() != 0 && ip.fs.get() == self.id && ip.refcnt.get() > 0 &&
+                                        !(ip.inum() == self.root && ip.refcnt.get() == 1));
+        TMP_LOCK.release();
+        busy
+    }
+
+    // Free all the files.
+    fn unmount(&self) -> () {
+        TMP_LOCK.acquire();
+        for ip in TMPINODES.iter().filter(|ip| ip.itype.get() != 0 && ip.fs.get() == self.id) {
+            free_pages(ip.index.get());
+            ip.clear();
+        }
+        unsafe { TMPFS[self.id].root = 0; }
+        TMP_LOCK.release();
+    }
+}
+
+// A new empty instance, of type name.
+fn tmpfs_new(name: &'static str) -> Result<&'static TmpFs, &'static str> {
+    TMP_LOCK.acquire();
+    let r = unsafe {
+        match TMPFS.iter_mut().find(|fs| fs.root == 0) {
+            Some(fs) => match ialloc_locked(fs.id, T_DIR, 0, 0) {
+                Some(root) => {
+                    fs.name = name;
+                    fs.root = root.inum();
+                    Ok(&*fs)
+                }
+                None => Err("tmpfs: out of inodes"),
+            },
+            None => Err("tmpfs: too many instances"),
+        }
+    };
+    TMP_LOCK.release();
+    let fs = r?;
+
+    // "." and ".." of the root are itself.
+    let root = slot(fs.root);
+    let r = locked(|| {
+        root.dirlink(b".", root.inum())?;
+        root.dirlink(b"..", root.inum())?;
+        root.nlink.set(1);
+        Ok(())
+    });
+    root.iput();
+    match r {
+        Ok(()) => Ok(fs),
+        Err(e) => {
+            fs.unmount();
+            Err(e)
+        }
+    }
+}
+
+static TMPFS_TYPE: FsType = FsType { name: "tmpfs", mount: tmpfs_mount, nodev: true };
+static DEVFS_TYPE: FsType = FsType { name: "devfs", mount: devfs_mount, nodev: true };
+
+fn tmpfs_mount(_dev: usize) -> Result<&'static dyn FileSystem, &'static str> {
+    Ok(tmpfs_new("tmpfs")?)
+}
+
+fn devfs_mount(_dev: usize) -> Result<&'static dyn FileSystem, &'static str> {
+    let fs = tmpfs_new("devfs")?;
+    let root = slot(fs.root);
+    let r = locked(|| {
+        let mknod = |name: &[u8], major, minor| root.create_locked(name, T_DEV, major, minor).map(|ip| ip.iput());
+        mknod(b"console", CONSOLE, 0)?;
+        mknod(b"ttyS0", TTYS, 0)?;
+        mknod(b"null", MEM, MEM_NULL)?;
+        mknod(b"zero", MEM, MEM_ZERO)?;
+        let mut dev = 0;
+        while let Some(d) = blkdev(dev) {
+            mknod(d.name().as_bytes(), DISK, dev as i16)?;
+            dev += 1;
+        }
+        Ok(())
+    });
+    match r {
+        Ok(()) => Ok(fs),
+        Err(e) => {
+            fs.unmount();
+            Err(e)
+        }
+    }
+}
+
+// Register tmpfs and devfs, and mount them on /tmp and /dev if the root
+// file system has these directories.
+pub fn tmpfs_init() -> Result<(), &'static str> {
+    register_fs(&TMPFS_TYPE)?;
+    register_fs(&DEVFS_TYPE)?;
+
+    for &(target, fstype) in [("/tmp", "tmpfs"), ("/dev", "devfs")].iter() {
+        begin_op();
+        let r = mount_dev(0, target, fstype);
+        end_op();
+        match r {
+            Ok(()) => println!("tmpfs: {} on {}", fstype, target),
+            Err(e) => println!("tmpfs: {}: {}", target, e),
+        }
+    }
+    Ok(())
+}
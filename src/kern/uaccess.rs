@@ -0,0 +1,194 @@
+// Access to user memory.
+//
+// With SMAP turned on the kernel faults on any access to a user page
+// unless EFLAGS.AC is set, so every access to user memory goes through
+// the helpers below which bracket it with STAC/CLAC.
+// The instructions which may fault are recorded in the exception table
+// (section .ex_table, see kernel.ld). page_fault_handler looks the faulting
+// rip up in this table and resumes at the fixup address instead of
+// panicking, which makes the helper return -EFAULT.
+
+use crate::*;
+use crate::kern::proc::my_proc;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+const CR4_UMIP: u64 = 1 << 11;  // User mode instruction prevention
+const CR4_SMEP: u64 = 1 << 20;  // Supervisor mode execution prevention
+const CR4_SMAP: u64 = 1 << 21;  // Supervisor mode access prevention
+
+// cpuid leaf 7, subleaf 0
+const CPUID_SMEP: u32 = 1 << 7;   // ebx
+const CPUID_SMAP: u32 = 1 << 20;  // ebx
+const CPUID_UMIP: u32 = 1 << 2;   // ecx
+
+// STAC/CLAC are invalid opcodes on CPUs without SMAP.
+static SMAP_ENABLED: AtomicBool = AtomicBool::new(false);
+
+extern "C" {
+    static __start_ex_table: u64;
+    static __stop_ex_table: u64;
+}
+
+// One entry per instruction which may fault on a user address.
+#[repr(C)]
+struct ExTableEntry {
+    insn: u64,
+    fixup: u64,
+}
+
+// Turn on SMEP, SMAP and UMIP if the cpu advertises them.
+// Called on every cpu.
+pub fn uaccess_init() -> () {
+    let (max, _, _, _) = cpuid(0, 0);
+    if max < 7 { return }
+    let (_, ebx, ecx, _) = cpuid(7, 0);
+
+    let mut bits = 0;
+    if ebx & CPUID_SMEP != 0 { bits |= CR4_SMEP; }
+    if ebx & CPUID_SMAP != 0 { bits |= CR4_SMAP; }
+    if ecx & CPUID_UMIP != 0 { bits |= CR4_UMIP; }
+
+    unsafe {
+        let mut cr4: u64;
+        asm!("mov %cr4, $0" : "=r" (cr4));
+        cr4 |= bits;
+        asm!("mov $0, %cr4" :: "r" (cr4) : "memory");
+    }
+    SMAP_ENABLED.store(bits & CR4_SMAP != 0, Ordering::SeqCst);
+}
+
+#[inline(always)]
+fn stac() -> () {
+    if SMAP_ENABLED.load(Ordering::Relaxed) {
+        unsafe { asm!("stac" :::: "volatile"); }
+    }
+}
+
+#[inline(always)]
+fn clac() -> () {
+    if SMAP_ENABLED.load(Ordering::Relaxed) {
+        unsafe { asm!("clac" :::: "volatile"); }
+    }
+}
+
+// Look up the fixup address of a faulting instruction.
+pub fn search_exception_table(rip: u64) -> Option<u64> {
+    unsafe {
+        let st = &__start_ex_table as *const u64 as *const ExTableEntry;
+        let ed = &__stop_ex_table as *const u64 as *const ExTableEntry;
+        let mut e = st;
+        while e < ed {
+            if (*e).insn == rip { return Some((*e).fixup) }
+            e = e.offset(1);
+        }
+    }
+    None
+}
+
+// Check that [addr, addr + len) lies inside the current process's memory.
+fn user_range_ok(addr: u64, len: usize) -> bool {
+    let sz = match unsafe { my_proc() } {
+        Some(p) => p.get_sz(),
+        None => return false,
+    };
+    match addr.checked_add(len as u64) {
+        Some(end) => end <= sz,
+        None => false,
+    }
+}
+
+// rep movsb leaves the number of bytes not copied in rcx, so on a fault the
+// fixup simply resumes right after it. Returns the number of bytes left.
+unsafe fn copy_user_generic(dst: *mut u8, src: *const u8, n: usize) -> usize {
+    let left: usize;
+    let (_d, _s): (usize, usize);
+    stac();
+    asm!("1: rep movsb
+          2:
+          .pushsection .ex_table, \"a\"
+          .balign 8
+          .quad 1b, 2b
+          .popsection"
+         : "={rcx}" (left), "={rdi}" (_d), "={rsi}" (_s)
+         : "{rdi}" (dst), "{rsi}" (src), "{rcx}" (n)
+         : "memory"
+         : "volatile");
+    clac();
+    left
+}
+
+// Read a single byte from user space, None if it faults.
+unsafe fn get_user_u8(src: *const u8) -> Option<u8> {
+    let err: u64;
+    let val: u8;
+    stac();
+    // If the load faults the xor is skipped and err stays 1.
+    asm!("1: movb ($2), $1
+          xor $0, $0
+          2:
+          .pushsection .ex_table, \"a\"
+          .balign 8
+          .quad 1b, 2b
+          .popsection"
+         : "=r" (err), "=&q" (val)
+         : "r" (src), "0" (1u64)
+         : "memory"
+         : "volatile");
+    clac();
+    if err == 0 { Some(val) } else { None }
+}
+
+// Copy len bytes from user address src into dst.
+// Returns 0 on success, -EFAULT if src is not a valid user range.
+pub unsafe fn copy_from_user(dst: *mut u8, src: u64, len: usize) -> isize {
+    if !user_range_ok(src, len) { return -EFAULT }
+    if copy_user_generic(dst, src as *const u8, len) != 0 { return -EFAULT }
+    0
+}
+
+// Copy len bytes from src into user address dst.
+// Returns 0 on success, -EFAULT if dst is not a valid user range.
+pub unsafe fn copy_to_user(dst: u64, src: *const u8, len: usize) -> isize {
+    if !user_range_ok(dst, len) { return -EFAULT }
+    if copy_user_generic(dst as *mut u8, src, len) != 0 { return -EFAULT }
+    0
+}
+
+// Copy len bytes from src into dst, a user address if user is set and a
+// kernel address otherwise. Returns 0 on success or -EFAULT.
+pub unsafe fn either_copy_out(user: bool, dst: u64, src: *const u8, len: usize) -> isize {
+    if user { return copy_to_user(dst, src, len) }
+    memmove(dst as *mut u8, src, len);
+    0
+}
+
+// Copy len bytes into dst from src, a user address if user is set and a
+// kernel address otherwise. Returns 0 on success or -EFAULT.
+pub unsafe fn either_copy_in(user: bool, dst: *mut u8, src: u64, len: usize) -> isize {
+    if user { return copy_from_user(dst, src, len) }
+    memmove(dst, src as *const u8, len);
+    0
+}
+
+// Copy a NUL terminated string of at most len bytes (including the NUL)
+// from user address src into dst.
+// Returns the length of the string without the NUL, or -EFAULT.
+// If no NUL is found within len bytes, dst is not terminated and len is returned.
+pub unsafe fn strncpy_from_user(dst: *mut u8, src: u64, len: usize) -> isize {
+    let sz = match my_proc() {
+        Some(p) => p.get_sz(),
+        None => return -EFAULT,
+    };
+    for i in 0..len {
+        let a = src + i as u64;
+        if a >= sz { return -EFAULT }
+        match get_user_u8(a as *const u8) {
+            Some(c) => {
+                *dst.offset(i as isize) = c;
+                if c == 0 { return i as isize }
+            }
+            None => return -EFAULT,
+        }
+    }
+    len as isize
+}
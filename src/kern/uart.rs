@@ -1,14 +1,84 @@
 use x86_64::instructions::port::Port;
-use crate::kern::ioapic::ioapic_enable;
+use crate::kern::console::Input;
+use crate::kern::dev::CharDevice;
+use crate::kern::idt::irq_register;
+use crate::kern::uaccess::either_copy_in;
 use crate::*;
 
 // UART. The SerialPort::new is a const fn.
 const COM1: u16 = 0x3f8;
 pub const UART: uart_16550::SerialPort = uart_16550::SerialPort::new(COM1);
 
+const LSR_DATA_READY: u8 = 0x01;
+
 pub unsafe fn uart_init() -> () {
     UART.init();
     let _: u8 = Port::new(COM1 + 2u16).read();
     let _: u8 = Port::new(COM1 + 0u16).read();
-    ioapic_enable(IRQ_COM1, 0);
+    if let Err(e) = irq_register(IRQ_COM1, uart_intr) {
+        println!("uart: {}", e);
+    }
+}
+
+pub fn uart_putc(c: u8) -> () {
+    let mut port = UART;
+    port.send(c);
+}
+
+// Terminals send \r for the return key and expect \r\n back.
+fn uart_echo(c: u8) -> () {
+    match c {
+        b'\n' => {
+            uart_putc(b'\r');
+            uart_putc(b'\n');
+        }
+        0x08 => {
+            uart_putc(0x08);
+            uart_putc(b' ');
+            uart_putc(0x08);
+        }
+        c => uart_putc(c),
+    }
+}
+
+static TTY_INPUT: Input = Input::new();
+
+fn uart_intr() -> () {
+    unsafe {
+        while Port::<u8>::new(COM1 + 5u16).read() & LSR_DATA_READY != 0 {
+            let c: u8 = Port::new(COM1 + 0u16).read();
+            TTY_INPUT.intr(c, uart_echo);
+        }
+    }
+}
+
+// /dev/ttyS0, COM1.
+pub struct Tty;
+
+pub static TTY_DEV: Tty = Tty;
+
+impl CharDevice for Tty {
+    fn read(&self, minor: i16, user: bool, dst: u64, _off: u64, n: u32) -> Result<usize, &'static str> {
+        if minor != 0 { return Err("no such device") }
+        TTY_INPUT.read(user, dst, n)
+    }
+
+    fn write(&self, minor: i16, user: bool, src: u64, _off: u64, n: u32) -> Result<usize, &'static str> {
+        if minor != 0 { return Err("no such device") }
+        let mut buf = [0u8; 128];
+        let mut tot = 0;
+        while tot < n {
+            let m = core::cmp::min(n - tot, buf.len() as u32);
+            if unsafe { either_copy_in(user, buf.as_mut_ptr(), src + tot as u64, m as usize) } < 0 {
+                if tot == 0 { return Err("bad address") }
+                break;
+            }
+            for &c in buf[..m as usize].iter() {
+                if c == b'\n' { uart_putc(b'\r'); }
+                uart_putc(c);
+            }
+            tot += m;
+        }
+        Ok(tot as usize)
+    }
 }
@@ -0,0 +1,691 @@
+// Virtual file system switch.
+//
+// Every file system type implements FileSystem, and INode for its files.
+// The VFS strings the mounted file systems together into one tree and
+// walks paths through it; reads, writes and directory changes go straight
+// to the inodes.
+//
+// Inodes are reference counted by their file system: a &'static dyn INode
+// stays valid until put() drops the reference lookup, create, iget or dup
+// handed out. Inode operations take the locks they need themselves.
+// Calls which may write to disk, put() included, must be inside a
+// transaction (begin_op/end_op of kern::log).
+//
+// Lookups are remembered in the dentry cache. Entries only hold inode
+// numbers, so they pin nothing and can be dropped at any time.
+
+use crate::*;
+use crate::kern::spinlock::SpinLock;
+use crate::kern::proc::{has_proc, my_proc};
+use crate::kern::blk::blkdev;
+
+pub const NAME_MAX: usize = 255;        // Longest file name
+pub const PATH_MAX: usize = 256;        // Longest path, including the NUL
+
+// Inode types
+pub const T_DIR: i16 = 1;               // Directory
+pub const T_FILE: i16 = 2;              // File
+pub const T_DEV: i16 = 3;               // Device
+
+pub const NOT_FOUND: &str = "no such file or directory";
+pub const NOT_DIR: &str = "not a directory";
+pub const READ_ONLY: &str = "read-only file system";
+
+const NFSTYPE: usize = 8;
+const NMOUNT: usize = 8;
+const NDENTRY: usize = 64;
+const DNAME_LEN: usize = 30;            // Longer names are not cached
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct Stat {
+    pub itype: i16,                     // Type of file
+    pub dev: i32,                       // Disk device
+    pub ino: u32,                       // Inode number
+    pub nlink: i16,                     // Number of links to file
+    pub size: u64,                      // Size of file in bytes
+    pub major: i16,                     // Device numbers (T_DEV only)
+    pub minor: i16,
+}
+
+// Entries returned by getdents: the header, then the name and a NUL,
+// padded to a multiple of 8 bytes, reclen in all.
+#[repr(C)]
+pub struct DirEntry {
+    pub ino: u64,
+    pub reclen: u16,
+    pub namelen: u16,
+}
+
+pub trait FileSystem: Sync {
+    // Type name, as given to mount.
+    fn name(&self) -> &'static str;
+
+    fn root(&self) -> Result<&'static dyn INode, &'static str>;
+
+    // The inode numbered ino, see INode::ino.
+    fn iget(&self, ino: u64) -> Result<&'static dyn INode, &'static str>;
+
+    // Whether any inode is in use besides the one root reference the
+    // mount holds.
+    fn busy(&self) -> bool;
+
+    // The last reference is gone, forget the instance.
+    fn unmount(&self) -> ();
+}
+
+pub trait INode: Sync {
+    fn fs(&self) -> &'static dyn FileSystem;
+
+    // Number of the inode within its file system.
+    fn ino(&self) -> u64;
+
+    // Take another reference.
+    fn dup(&'static self) -> &'static dyn INode;
+
+    // Drop a reference, freeing the inode if it was the last one and
+    // there are no links to it.
+    fn put(&self) -> ();
+
+    fn stat(&self) -> Result<Stat, &'static str>;
+
+    // Read or write file data at offset off. If user is set, the address
+    // is a user virtual address; otherwise, a kernel address.
+    // Return the number of bytes transferred.
+    fn read(&self, user: bool, dst: u64, off: u32, n: u32) -> Result<usize, &'static str>;
+
+    fn write(&self, _user: bool, _src: u64, _off: u32, _n: u32) -> Result<usize, &'static str> {
+        Err(READ_ONLY)
+    }
+
+    // Discard the contents.
+    fn trunc(&self) -> Result<(), &'static str> { Err(READ_ONLY) }
+
+    // Directories. "." and ".." are resolved by the VFS at the root of a
+    // mount, elsewhere it passes them on like any other name.
+
+    // The entry name of the directory, None if there is none.
+    fn lookup(&self, name: &[u8]) -> Result<Option<&'static dyn INode>, &'static str>;
+
+    // Copy the name of the idx-th entry into name. Returns the length of
+    // the name and the inode number, None past the last entry.
+    fn readdir(&self, idx: usize, name: &mut [u8; NAME_MAX]) -> Result<Option<(usize, u64)>, &'static str>;
+
+    // Add a new inode of type itype as name. If a file is asked for and
+    // name is a file or device already, that one is returned.
+    fn create(&self, _name: &[u8], _itype: i16, _major: i16, _minor: i16)
+        -> Result<&'static dyn INode, &'static str> {
+        Err(READ_ONLY)
+    }
+
+    // Add name as another link to ip, which is on the same file system.
+    fn link(&self, _name: &[u8], _ip: &dyn INode) -> Result<(), &'static str> { Err(READ_ONLY) }
+
+    // Remove the entry name. Directories must be empty.
+    fn unlink(&self, _name: &[u8]) -> Result<(), &'static str> { Err(READ_ONLY) }
+
+    // Move the entry name to newname in newdir, which is on the same file
+    // system, in one step. An existing newname is replaced: a file only by
+    // a file, a directory only by a directory and only if it is empty.
+    fn rename(&self, _name: &[u8], _newdir: &dyn INode, _newname: &[u8]) -> Result<(), &'static str> {
+        Err(READ_ONLY)
+    }
+}
+
+// Whether a and b are the same inode. Inodes live in the tables of their
+// file system, so the same inode is always at the same address.
+pub fn same(a: &dyn INode, b: &dyn INode) -> bool {
+    a as *const dyn INode as *const u8 == b as *const dyn INode as *const u8
+}
+
+pub fn same_fs(a: &dyn FileSystem, b: &dyn FileSystem) -> bool {
+    a as *const dyn FileSystem as *const u8 == b as *const dyn FileSystem as *const u8
+}
+
+// File system types
+
+pub struct FsType {
+    pub name: &'static str,
+    // Set up an instance for the disk dev.
+    pub mount: fn(usize) -> Result<&'static dyn FileSystem, &'static str>,
+    // Needs no disk: mount ignores the source and passes dev 0.
+    pub nodev: bool,
+}
+
+static mut FSTYPES: [Option<&'static FsType>; NFSTYPE] = [None; NFSTYPE];
+
+pub fn register_fs(t: &'static FsType) -> Result<(), &'static str> {
+    MOUNTLOCK.acquire();
+    let r = unsafe {
+        match FSTYPES.iter_mut().find(|t| t.is_none()) {
+            Some(slot) => {
+                *slot = Some(t);
+                Ok(())
+            }
+            None => Err("register_fs: too many file system types"),
+        }
+    };
+    MOUNTLOCK.release();
+    r
+}
+
+fn fstype(name: &str) -> Option<&'static FsType> {
+    MOUNTLOCK.acquire();
+    let t = unsafe { FSTYPES.iter().filter_map(|t| *t).find(|t| t.name == name) };
+    MOUNTLOCK.release();
+    t
+}
+
+// Mount table. Slot 0 is the root file system.
+
+#[derive(Copy, Clone)]
+struct Mount {
+    fs: &'static dyn FileSystem,
+    root: &'static dyn INode,                   // Holds a reference
+    mountpoint: Option<&'static dyn INode>,     // Holds a reference, None for /
+}
+
+static MOUNTLOCK: SpinLock = SpinLock::new();
+static mut MOUNTS: [Option<Mount>; NMOUNT] = [None; NMOUNT];
+
+// Make fs the root file system.
+pub fn mount_root(fs: &'static dyn FileSystem) -> Result<(), &'static str> {
+    let root = fs.root()?;
+    MOUNTLOCK.acquire();
+    let r = unsafe {
+        if MOUNTS[0].is_some() {
+            Err("mount_root: root already mounted")
+        } else {
+            MOUNTS[0] = Some(Mount { fs: fs, root: root, mountpoint: None });
+            Ok(())
+        }
+    };
+    MOUNTLOCK.release();
+    if r.is_err() { root.put(); }
+    r
+}
+
+// Whether there is a root file system yet.
+pub fn root_mounted() -> bool {
+    MOUNTLOCK.acquire();
+    let r = unsafe { MOUNTS[0].is_some() };
+    MOUNTLOCK.release();
+    r
+}
+
+fn root() -> Result<&'static dyn INode, &'static str> {
+    MOUNTLOCK.acquire();
+    let r = unsafe { MOUNTS[0].map(|m| m.root.dup()) };
+    MOUNTLOCK.release();
+    r.ok_or("no root file system")
+}
+
+// The root of the file system mounted on ip, with a new reference.
+fn covering(ip: &dyn INode) -> Option<&'static dyn INode> {
+    MOUNTLOCK.acquire();
+    let r = unsafe {
+        MOUNTS.iter().filter_map(|m| *m)
+            .find(|m| m.mountpoint.map_or(false, |mp| same(mp, ip)))
+            .map(|m| m.root.dup())
+    };
+    MOUNTLOCK.release();
+    r
+}
+
+// If ip is the root of a mount, Some of the directory it is mounted on,
+// with a new reference, or Some(None) for /.
+fn covered(ip: &dyn INode) -> Option<Option<&'static dyn INode>> {
+    MOUNTLOCK.acquire();
+    let r = unsafe {
+        MOUNTS.iter().filter_map(|m| *m)
+            .find(|m| same(m.root, ip))
+            .map(|m| m.mountpoint.map(|mp| mp.dup()))
+    };
+    MOUNTLOCK.release();
+    r
+}
+
+// The disk called source: its name, e.g. "vda", or "blk" and its number.
+pub fn find_blkdev(source: &str) -> Result<usize, &'static str> {
+    let mut dev = 0;
+    while let Some(d) = blkdev(dev) {
+        if d.name() == source { return Ok(dev) }
+        dev += 1;
+    }
+    if source.starts_with("blk") {
+        if let Ok(dev) = source[3..].parse::<usize>() {
+            if blkdev(dev).is_some() { return Ok(dev) }
+        }
+    }
+    Err("no such device")
+}
+
+// Mount the file system of type fstype on disk source at directory target.
+pub fn mount(source: &str, target: &str, fstype_name: &str) -> Result<(), &'static str> {
+    let t = fstype(fstype_name).ok_or("unknown file system type")?;
+    let dev = if t.nodev { 0 } else { find_blkdev(source)? };
+    mount_dev(dev, target, fstype_name)
+}
+
+pub fn mount_dev(dev: usize, target: &str, fstype_name: &str) -> Result<(), &'static str> {
+    let t = fstype(fstype_name).ok_or("unknown file system type")?;
+    let mp = namei(target)?;
+    let r = (|| -> Result<(), &'static str> {
+        if mp.stat()?.itype != T_DIR { return Err(NOT_DIR) }
+        let fs = (t.mount)(dev)?;
+        let root = match fs.root() {
+            Ok(root) => root,
+            Err(e) => {
+                fs.unmount();
+                return Err(e);
+            }
+        };
+
+        MOUNTLOCK.acquire();
+        let r = unsafe {
+            if MOUNTS.iter().filter_map(|m| *m)
+                .any(|m| same(m.root, mp) || m.mountpoint.map_or(false, |p| same(p, mp))) {
+                Err("mount point busy")
+            } else {
+                match MOUNTS.iter_mut().find(|m| m.is_none()) {
+                    Some(slot) => {
+                        *slot = Some(Mount { fs: fs, root: root, mountpoint: Some(mp) });
+                        Ok(())
+                    }
+                    None => Err("mount table full"),
+                }
+            }
+        };
+        MOUNTLOCK.release();
+        if r.is_err() {
+            root.put();
+            fs.unmount();
+        }
+        r
+    })();
+    // The mount table keeps the reference to the mount point.
+    if r.is_err() { mp.put(); }
+    r
+}
+
+// Unmount the file system mounted at target, which must not be in use.
+pub fn umount(target: &str) -> Result<(), &'static str> {
+    let ip = namei(target)?;
+    MOUNTLOCK.acquire();
+    let i = unsafe {
+        MOUNTS.iter().skip(1).position(|m| m.map_or(false, |m| same(m.root, ip))).map(|i| i + 1)
+    };
+    MOUNTLOCK.release();
+    // The mount holds a reference of its own.
+    ip.put();
+    let i = i.ok_or("not a mount point")?;
+
+    MOUNTLOCK.acquire();
+    let r = unsafe {
+        match MOUNTS[i] {
+            Some(m) if same(m.root, ip) => {
+                let nested = MOUNTS.iter().filter_map(|n| *n)
+                    .any(|n| n.mountpoint.map_or(false, |p| same_fs(p.fs(), m.fs)));
+                if nested || m.fs.busy() {
+                    Err("device busy")
+                } else {
+                    MOUNTS[i] = None;
+                    Ok(m)
+                }
+            }
+            _ => Err("not a mount point"),
+        }
+    };
+    MOUNTLOCK.release();
+
+    let m = r?;
+    dcache_purge(m.fs);
+    m.root.put();
+    if let Some(mp) = m.mountpoint { mp.put(); }
+    m.fs.unmount();
+    Ok(())
+}
+
+// Dentry cache
+
+#[derive(Copy, Clone)]
+struct Dentry {
+    fs: *const u8,                      // File system, null if the entry is free
+    dir: u64,                           // Inode number of the directory
+    ino: u64,                           // Inode number of the entry
+    len: usize,
+    name: [u8; DNAME_LEN],
+    lastuse: u64,
+}
+
+const DENTRY_FREE: Dentry = Dentry {
+    fs: core::ptr::null(), dir: 0, ino: 0, len: 0, name: [0; DNAME_LEN], lastuse: 0,
+};
+
+static DCACHE_LOCK: SpinLock = SpinLock::new();
+static mut DCACHE: [Dentry; NDENTRY] = [DENTRY_FREE; NDENTRY];
+static mut DCLOCK: u64 = 0;
+
+fn fs_id(fs: &dyn FileSystem) -> *const u8 { fs as *const dyn FileSystem as *const u8 }
+
+fn dentry_is(d: &Dentry, dp: &dyn INode, name: &[u8]) -> bool {
+    d.fs == fs_id(dp.fs()) && d.dir == dp.ino() && &d.name[..d.len] == name
+}
+
+fn dcache_lookup(dp: &dyn INode, name: &[u8]) -> Option<u64> {
+    if name.len() > DNAME_LEN { return None }
+    DCACHE_LOCK.acquire();
+    let r = unsafe {
+        DCLOCK += 1;
+        let now = DCLOCK;
+        DCACHE.iter_mut().find(|d| dentry_is(d, dp, name)).map(|d| {
+            d.lastuse = now;
+            d.ino
+        })
+    };
+    DCACHE_LOCK.release();
+    r
+}
+
+// Remember name in dp, replacing the least recently used entry.
+fn dcache_insert(dp: &dyn INode, name: &[u8], ino: u64) -> () {
+    if name.len() > DNAME_LEN { return }
+    DCACHE_LOCK.acquire();
+    unsafe {
+        DCLOCK += 1;
+        let d = DCACHE.iter_mut().min_by_key(|d| if d.fs.is_null() { 0 } else { d.lastuse }).unwrap();
+        d.fs = fs_id(dp.fs());
+        d.dir = dp.ino();
+        d.ino = ino;
+        d.len = name.len();
+        d.name[..name.len()].copy_from_slice(name);
+        d.lastuse = DCLOCK;
+    }
+    DCACHE_LOCK.release();
+}
+
+// Forget name in dp, and everything below it in case it was a directory.
+fn dcache_remove(dp: &dyn INode, name: &[u8], ino: u64) -> () {
+    DCACHE_LOCK.acquire();
+    unsafe {
+        let fs = fs_id(dp.fs());
+        for d in DCACHE.iter_mut() {
+            if (d.fs == fs && d.dir == ino) || dentry_is(d, dp, name) { *d = DENTRY_FREE; }
+        }
+    }
+    DCACHE_LOCK.release();
+}
+
+fn dcache_purge(fs: &dyn FileSystem) -> () {
+    DCACHE_LOCK.acquire();
+    unsafe {
+        for d in DCACHE.iter_mut().filter(|d| d.fs == fs_id(fs)) { *d = DENTRY_FREE; }
+    }
+    DCACHE_LOCK.release();
+}
+
+// Paths
+
+// Split off the next path element, skipping slashes.
+// Return the element and the rest of the path with no leading slashes,
+// so the caller can check whether it is the last one.
+// If no name to remove, return None.
+//
+// Examples:
+//   skipelem("a/bb/c") = ("a", "bb/c")
+//   skipelem("///a//bb") = ("a", "bb")
+//   skipelem("a") = ("a", "")
+//   skipelem("") = skipelem("////") = None
+//
+fn skipelem(path: &[u8]) -> Option<(&[u8], &[u8])> {
+    let st = path.iter().position(|&c| c != b'/')?;
+    let path = &path[st..];
+    let len = path.iter().position(|&c| c == b'/').unwrap_or(path.len());
+    let rest = &path[len..];
+    let rest = &rest[rest.iter().position(|&c| c != b'/').unwrap_or(rest.len())..];
+    Some((&path[..len], rest))
+}
+
+// Follow mounts: the root of a file system stands in for the directory it
+// is mounted on.
+fn cross(ip: &'static dyn INode) -> &'static dyn INode {
+    let mut ip = ip;
+    while let Some(root) = covering(ip) {
+        ip.put();
+        ip = root;
+    }
+    ip
+}
+
+// Look up one path element in directory dp. Returns a new reference.
+fn step(dp: &'static dyn INode, name: &[u8]) -> Result<&'static dyn INode, &'static str> {
+    if name == b"." { return Ok(dp.dup()) }
+
+    if name == b".." {
+        // The parent of a mount root is the parent of its mount point.
+        let mut dp = dp.dup();
+        loop {
+            match covered(dp) {
+                Some(Some(mp)) => {
+                    dp.put();
+                    dp = mp;
+                }
+                Some(None) => return Ok(dp),    // / is its own parent
+                None => break,
+            }
+        }
+        let r = dp.lookup(b"..");
+        dp.put();
+        return r?.ok_or(NOT_FOUND);
+    }
+
+    let ip = match dcache_lookup(dp, name) {
+        Some(ino) => dp.fs().iget(ino)?,
+        None => {
+            let ip = dp.lookup(name)?.ok_or(NOT_FOUND)?;
+            dcache_insert(dp, name, ip.ino());
+            ip
+        }
+    };
+    Ok(cross(ip))
+}
+
+fn cwd() -> Option<&'static dyn INode> {
+    unsafe { if has_proc() { my_proc().and_then(|p| p.get_cwd()) } else { None } }
+}
+
+// Look up and return the inode for a path name.
+// If parent is set, return the inode for the parent and copy the final
+// path element into name, returning its length.
+// Relative paths start at the current directory of the process, or at /
+// before the first process runs.
+// Must be called inside a transaction since it calls put().
+fn namex(path: &[u8], parent: bool, name: &mut [u8; NAME_MAX])
+    -> Result<(&'static dyn INode, usize), &'static str> {
+    let mut ip = match cwd() {
+        Some(cwd) if path.first() != Some(&b'/') => cwd.dup(),
+        _ => root()?,
+    };
+
+    let mut path = path;
+    while let Some((elem, rest)) = skipelem(path) {
+        if elem.len() > NAME_MAX {
+            ip.put();
+            return Err("file name too long");
+        }
+        path = rest;
+        if parent && path.is_empty() {
+            // Stop one level early.
+            name[..elem.len()].copy_from_slice(elem);
+            return Ok((ip, elem.len()));
+        }
+        let next = step(ip, elem);
+        ip.put();
+        ip = next?;
+    }
+    if parent {
+        ip.put();
+        return Err("no file name");
+    }
+    Ok((ip, 0))
+}
+
+pub fn namei(path: &str) -> Result<&'static dyn INode, &'static str> {
+    let mut name = [0; NAME_MAX];
+    namex(path.as_bytes(), false, &mut name).map(|(ip, _)| ip)
+}
+
+// The parent directory of path and the length of the last element,
+// which is copied into name.
+pub fn nameiparent(path: &str, name: &mut [u8; NAME_MAX]) -> Result<(&'static dyn INode, usize), &'static str> {
+    namex(path.as_bytes(), true, name)
+}
+
+// Remove name from directory dp. If dir is set, it must name a directory,
+// otherwise anything but.
+pub fn unlink(dp: &dyn INode, name: &[u8], dir: bool) -> Result<(), &'static str> {
+    if name == b"." || name == b".." { return Err("invalid argument") }
+    // Look it up first, to know what to drop from the cache.
+    let ip = dp.lookup(name)?.ok_or(NOT_FOUND)?;
+    let ino = ip.ino();
+    let busy = covering(ip).map(|root| root.put()).is_some();
+    let st = ip.stat();
+    ip.put();
+    if busy { return Err("device busy") }
+    match st?.itype {
+        T_DIR if !dir => return Err("is a directory"),
+        T_DIR => {}
+        _ if dir => return Err(NOT_DIR),
+        _ => {}
+    }
+    dp.unlink(name)?;
+    dcache_remove(dp, name, ino);
+    Ok(())
+}
+
+// Add newpath as another name of the file oldpath.
+pub fn link(oldpath: &str, newpath: &str) -> Result<(), &'static str> {
+    let ip = namei(oldpath)?;
+    let mut name = [0; NAME_MAX];
+    let r = nameiparent(newpath, &mut name).and_then(|(dp, len)| {
+        let r = if !same_fs(dp.fs(), ip.fs()) {
+            Err("cross-device link")
+        } else {
+            dp.link(&name[..len], ip)
+        };
+        dp.put();
+        r
+    });
+    ip.put();
+    r
+}
+
+// Move oldpath to newpath.
+pub fn rename(oldpath: &str, newpath: &str) -> Result<(), &'static str> {
+    let mut oname = [0; NAME_MAX];
+    let mut nname = [0; NAME_MAX];
+    let (odp, olen) = nameiparent(oldpath, &mut oname)?;
+    let (ndp, nlen) = match nameiparent(newpath, &mut nname) {
+        Ok(r) => r,
+        Err(e) => {
+            odp.put();
+            return Err(e);
+        }
+    };
+    let (oname, nname) = (&oname[..olen], &nname[..nlen]);
+
+    let r = (|| -> Result<(), &'static str> {
+        if !same_fs(odp.fs(), ndp.fs()) { return Err("cross-device link") }
+
+        // Neither side may be a mount point. The inodes are looked up
+        // again by the file system, only their numbers are kept.
+        let mut inos = [None, None];
+        for (i, (dp, name)) in [(odp, oname), (ndp, nname)].iter().enumerate() {
+            if *name == b"." || *name == b".." { return Err("invalid argument") }
+            if let Some(ip) = dp.lookup(name)? {
+                inos[i] = Some(ip.ino());
+                let busy = covering(ip).map(|root| root.put()).is_some();
+                ip.put();
+                if busy { return Err("device busy") }
+            }
+        }
+        let ino = inos[0].ok_or(NOT_FOUND)?;
+
+        odp.rename(oname, ndp, nname)?;
+        dcache_remove(odp, oname, ino);
+        if let Some(nino) = inos[1] { dcache_remove(ndp, nname, nino); }
+        Ok(())
+    })();
+    odp.put();
+    ndp.put();
+    r
+}
+
+// The absolute path of the current directory, copied into buf.
+// Returns the length, without NUL.
+pub fn getcwd(buf: &mut [u8]) -> Result<usize, &'static str> {
+    let mut ip = match cwd() {
+        Some(cwd) => cwd.dup(),
+        None => root()?,
+    };
+
+    // Build the path backwards at the end of buf.
+    let mut pos = buf.len();
+    loop {
+        let parent = match step(ip, b"..") {
+            Ok(parent) => parent,
+            Err(e) => {
+                ip.put();
+                return Err(e);
+            }
+        };
+        if same(parent, ip) {
+            parent.put();
+            ip.put();
+            break;
+        }
+
+        // The name of ip in parent, for a mount root that of the directory
+        // it covers.
+        let mut child = ip;
+        while let Some(Some(mp)) = covered(child) {
+            child.put();
+            child = mp;
+        }
+        let ino = child.ino();
+        child.put();
+        ip = parent;
+
+        let mut name = [0; NAME_MAX];
+        let mut idx = 0;
+        let len = loop {
+            match ip.readdir(idx, &mut name) {
+                Ok(Some((len, i))) if i == ino && &name[..len] != b"." && &name[..len] != b".." => break Ok(len),
+                Ok(Some(_)) => idx += 1,
+                Ok(None) => break Err(NOT_FOUND),
+                Err(e) => break Err(e),
+            }
+        };
+        let len = match len {
+            Ok(len) if len < pos => Ok(len),
+            Ok(_) => Err("path too long"),
+            Err(e) => Err(e),
+        }.map_err(|e| {
+            ip.put();
+            e
+        })?;
+        pos -= len;
+        buf[pos..pos + len].copy_from_slice(&name[..len]);
+        pos -= 1;
+        buf[pos] = b'/';
+    }
+
+    if pos == buf.len() {
+        if buf.is_empty() { return Err("path too long") }
+        pos -= 1;
+        buf[pos] = b'/';
+    }
+    let len = buf.len() - pos;
+    for i in 0..len { buf[i] = buf[pos + i]; }
+    Ok(len)
+}
@@ -0,0 +1,387 @@
+// Virtio over PCI.
+//
+// Both the legacy transport (I/O port BAR0, virtio 0.9.5) and the modern
+// one (capabilities pointing into memory BARs, virtio 1.0) are supported,
+// device drivers do not need to care which one they got.
+// Queues are split virtqueues.
+
+use crate::*;
+use crate::kern::pci::*;
+use crate::kern::vm::{kva2pa, mmio2v};
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{fence, Ordering};
+use x86_64::instructions::port::Port;
+
+pub const VIRTIO_VENDOR: u16 = 0x1AF4;
+
+// Device status
+pub const STATUS_ACKNOWLEDGE: u8 = 1;
+pub const STATUS_DRIVER: u8 = 2;
+pub const STATUS_DRIVER_OK: u8 = 4;
+pub const STATUS_FEATURES_OK: u8 = 8;
+pub const STATUS_FAILED: u8 = 128;
+
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+// Modern transport capabilities (struct virtio_pci_cap)
+const PCI_CAP_ID_VNDR: u8 = 0x09;
+const CAP_COMMON_CFG: u8 = 1;
+const CAP_NOTIFY_CFG: u8 = 2;
+const CAP_ISR_CFG: u8 = 3;
+const CAP_DEVICE_CFG: u8 = 4;
+
+// Modern common configuration (struct virtio_pci_common_cfg)
+const COMMON_DFSELECT: u64 = 0x00;
+const COMMON_DF: u64 = 0x04;
+const COMMON_GFSELECT: u64 = 0x08;
+const COMMON_GF: u64 = 0x0C;
+const COMMON_STATUS: u64 = 0x14;
+const COMMON_Q_SELECT: u64 = 0x16;
+const COMMON_Q_SIZE: u64 = 0x18;
+const COMMON_Q_MSIX: u64 = 0x1A;
+const COMMON_Q_ENABLE: u64 = 0x1C;
+const COMMON_Q_NOFF: u64 = 0x1E;
+const COMMON_Q_DESC: u64 = 0x20;
+const COMMON_Q_AVAIL: u64 = 0x28;
+const COMMON_Q_USED: u64 = 0x30;
+
+// Legacy I/O registers
+const LEGACY_HOST_FEATURES: u16 = 0x00;
+const LEGACY_GUEST_FEATURES: u16 = 0x04;
+const LEGACY_QUEUE_PFN: u16 = 0x08;
+const LEGACY_QUEUE_NUM: u16 = 0x0C;
+const LEGACY_QUEUE_SEL: u16 = 0x0E;
+const LEGACY_QUEUE_NOTIFY: u16 = 0x10;
+const LEGACY_STATUS: u16 = 0x12;
+const LEGACY_ISR: u16 = 0x13;
+const LEGACY_CONFIG: u16 = 0x14;    // Without MSI-X
+
+pub const VIRTIO_MSI_NO_VECTOR: u16 = 0xFFFF;
+
+// Descriptor flags
+pub const VIRTQ_DESC_F_NEXT: u16 = 1;
+pub const VIRTQ_DESC_F_WRITE: u16 = 2;
+
+// Largest queue we set up. With the legacy layout (used ring on its own
+// page) a queue of this size takes three pages, see QueueMem.
+pub const QMAX: usize = 256;
+
+unsafe fn mmio_read<T>(a: u64) -> T { read_volatile(a as *const T) }
+unsafe fn mmio_write<T>(a: u64, v: T) -> () { write_volatile(a as *mut T, v) }
+
+#[derive(Copy, Clone)]
+pub enum Transport {
+    Legacy { iobase: u16 },
+    Modern { common: u64, notify: u64, notify_mult: u32, isr: u64, device: u64 },
+}
+
+// Virtual address of a memory BAR. Devices are expected in the device window.
+fn bar_va(pci: &PciAddr, bar: u8) -> Result<u64, &'static str> {
+    mmio2v(pci.mem_bar(bar)).ok_or("virtio: BAR outside the device window")
+}
+
+impl Transport {
+    // Find out how to talk to the device and enable it on the bus.
+    pub fn probe(pci: &PciAddr) -> Result<Transport, &'static str> {
+        pci.enable(PCI_COMMAND_IO | PCI_COMMAND_MEMORY | PCI_COMMAND_MASTER);
+
+        let (mut common, mut notify, mut isr, mut device) = (None, None, None, None);
+        let mut notify_mult = 0;
+        let mut cap = pci.find_cap(PCI_CAP_ID_VNDR, 0);
+        while let Some(c) = cap {
+            let cfg_type = pci.read8(c + 3);
+            let bar = pci.read8(c + 4);
+            let off = pci.read32(c + 8) as u64;
+            match cfg_type {
+                CAP_COMMON_CFG => common = Some(bar_va(pci, bar)? + off),
+                CAP_NOTIFY_CFG => {
+                    notify = Some(bar_va(pci, bar)? + off);
+                    notify_mult = pci.read32(c + 16);
+                }
+                CAP_ISR_CFG => isr = Some(bar_va(pci, bar)? + off),
+                CAP_DEVICE_CFG => device = Some(bar_va(pci, bar)? + off),
+                _ => {}
+            }
+            cap = pci.find_cap(PCI_CAP_ID_VNDR, c);
+        }
+
+        match (common, notify, isr, device) {
+            (Some(common), Some(notify), Some(isr), Some(device)) =>
+                Ok(Transport::Modern { common, notify, notify_mult, isr, device }),
+            _ if pci.bar(0) & 1 == 1 => Ok(Transport::Legacy { iobase: pci.io_bar(0) }),
+            _ => Err("virtio: no usable transport"),
+        }
+    }
+
+    pub fn status(&self) -> u8 {
+        unsafe {
+            match *self {
+                Transport::Legacy { iobase } => Port::<u8>::new(iobase + LEGACY_STATUS).read(),
+                Transport::Modern { common, .. } => mmio_read::<u8>(common + COMMON_STATUS),
+            }
+        }
+    }
+
+    pub fn set_status(&self, s: u8) -> () {
+        unsafe {
+            match *self {
+                Transport::Legacy { iobase } => Port::<u8>::new(iobase + LEGACY_STATUS).write(s),
+                Transport::Modern { common, .. } => mmio_write::<u8>(common + COMMON_STATUS, s),
+            }
+        }
+    }
+
+    pub fn add_status(&self, s: u8) -> () { self.set_status(self.status() | s) }
+
+    // Reset the device and tell it we found it and know how to drive it.
+    pub fn reset(&self) -> () {
+        self.set_status(0);
+        while self.status() != 0 { }
+        self.add_status(STATUS_ACKNOWLEDGE);
+        self.add_status(STATUS_DRIVER);
+    }
+
+    // Accept the wanted features the device offers, returns them.
+    pub fn negotiate(&self, wanted: u64) -> Result<u64, &'static str> {
+        unsafe {
+            match *self {
+                Transport::Legacy { iobase } => {
+                    let f = Port::<u32>::new(iobase + LEGACY_HOST_FEATURES).read() as u64 & wanted;
+                    Port::<u32>::new(iobase + LEGACY_GUEST_FEATURES).write(f as u32);
+                    Ok(f)
+                }
+                Transport::Modern { common, .. } => {
+                    mmio_write::<u32>(common + COMMON_DFSELECT, 0);
+                    let lo = mmio_read::<u32>(common + COMMON_DF) as u64;
+                    mmio_write::<u32>(common + COMMON_DFSELECT, 1);
+                    let hi = mmio_read::<u32>(common + COMMON_DF) as u64;
+                    let f = (hi << 32 | lo) & (wanted | VIRTIO_F_VERSION_1);
+
+                    mmio_write::<u32>(common + COMMON_GFSELECT, 0);
+                    mmio_write::<u32>(common + COMMON_GF, f as u32);
+                    mmio_write::<u32>(common + COMMON_GFSELECT, 1);
+                    mmio_write::<u32>(common + COMMON_GF, (f >> 32) as u32);
+
+                    self.add_status(STATUS_FEATURES_OK);
+                    if self.status() & STATUS_FEATURES_OK == 0 {
+                        return Err("virtio: features not accepted");
+                    }
+                    Ok(f)
+                }
+            }
+        }
+    }
+
+    // Hand queue idx, laid out in mem, to the device.
+    pub fn setup_queue(&self, idx: u16, q: &mut Virtq, mem: &mut QueueMem) -> Result<(), &'static str> {
+        unsafe {
+            match *self {
+                Transport::Legacy { iobase } => {
+                    Port::<u16>::new(iobase + LEGACY_QUEUE_SEL).write(idx);
+                    let num = Port::<u16>::new(iobase + LEGACY_QUEUE_NUM).read();
+                    if num == 0 { return Err("virtio: queue not available") }
+                    if num as usize > QMAX { return Err("virtio: queue too large") }
+                    q.init(mem, num);
+                    let pa = kva2pa(VA::from_ptr(mem as *const QueueMem)).expect("virtio: queue").as_u64();
+                    Port::<u32>::new(iobase + LEGACY_QUEUE_PFN).write((pa / PGSIZE) as u32);
+                    q.notify = iobase as u64 + LEGACY_QUEUE_NOTIFY as u64;
+                }
+                Transport::Modern { common, notify, notify_mult, .. } => {
+                    mmio_write::<u16>(common + COMMON_Q_SELECT, idx);
+                    let mut num = mmio_read::<u16>(common + COMMON_Q_SIZE);
+                    if num == 0 { return Err("virtio: queue not available") }
+                    if num as usize > QMAX { num = QMAX as u16; }
+                    mmio_write::<u16>(common + COMMON_Q_SIZE, num);
+                    q.init(mem, num);
+                    let (desc, avail, used) = q.addrs();
+                    mmio_write::<u64>(common + COMMON_Q_DESC, desc);
+                    mmio_write::<u64>(common + COMMON_Q_AVAIL, avail);
+                    mmio_write::<u64>(common + COMMON_Q_USED, used);
+                    let noff = mmio_read::<u16>(common + COMMON_Q_NOFF) as u64;
+                    q.notify = notify + noff * notify_mult as u64;
+                    mmio_write::<u16>(common + COMMON_Q_ENABLE, 1);
+                }
+            }
+        }
+        q.idx = idx;
+        Ok(())
+    }
+
+    // Have queue idx signal MSI-X table entry `entry`. Only done on the
+    // modern transport, the legacy one moves the device configuration
+    // when MSI-X is on.
+    pub fn set_queue_vector(&self, idx: u16, entry: u16) -> Result<(), &'static str> {
+        match *self {
+            Transport::Legacy { .. } => Err("virtio: no MSI-X on the legacy transport"),
+            Transport::Modern { common, .. } => unsafe {
+                mmio_write::<u16>(common + COMMON_Q_SELECT, idx);
+                mmio_write::<u16>(common + COMMON_Q_MSIX, entry);
+                if mmio_read::<u16>(common + COMMON_Q_MSIX) != entry {
+                    return Err("virtio: MSI-X vector not accepted");
+                }
+                Ok(())
+            }
+        }
+    }
+
+    // Tell the device there are new buffers in the queue.
+    pub fn notify(&self, q: &Virtq) -> () {
+        fence(Ordering::SeqCst);
+        unsafe {
+            match *self {
+                Transport::Legacy { .. } => Port::<u16>::new(q.notify as u16).write(q.idx),
+                Transport::Modern { .. } => mmio_write::<u16>(q.notify, q.idx),
+            }
+        }
+    }
+
+    // Read (and thereby acknowledge) the interrupt status.
+    // Bit 0: a queue was used, bit 1: the configuration changed.
+    pub fn isr(&self) -> u8 {
+        unsafe {
+            match *self {
+                Transport::Legacy { iobase } => Port::<u8>::new(iobase + LEGACY_ISR).read(),
+                Transport::Modern { isr, .. } => mmio_read::<u8>(isr),
+            }
+        }
+    }
+
+    pub fn config_read32(&self, off: u16) -> u32 {
+        unsafe {
+            match *self {
+                Transport::Legacy { iobase } => Port::<u32>::new(iobase + LEGACY_CONFIG + off).read(),
+                Transport::Modern { device, .. } => mmio_read::<u32>(device + off as u64),
+            }
+        }
+    }
+
+    pub fn config_read64(&self, off: u16) -> u64 {
+        (self.config_read32(off + 4) as u64) << 32 | self.config_read32(off) as u64
+    }
+}
+
+// Memory for one queue. Statically allocated, since the legacy
+// transport wants the queue physically contiguous and page aligned.
+#[repr(C, align(4096))]
+pub struct QueueMem([u8; 3 * PGSIZE as usize]);
+
+impl QueueMem {
+    pub const fn new() -> Self { QueueMem([0; 3 * PGSIZE as usize]) }
+}
+
+#[repr(C)]
+pub struct VirtqDesc {
+    pub addr: u64,
+    pub len: u32,
+    pub flags: u16,
+    pub next: u16,
+}
+
+// A split virtqueue. Free descriptors are chained through their next field.
+pub struct Virtq {
+    pub num: u16,
+    idx: u16,               // Queue index on the device
+    notify: u64,            // Port or address to notify the device
+    desc: *mut VirtqDesc,
+    avail: *mut u16,        // flags, idx, ring[num], used_event
+    used: *mut u16,         // flags, idx, ring[num] of (id: u32, len: u32), avail_event
+    free_head: u16,
+    nfree: u16,
+    last_used: u16,
+}
+
+impl Virtq {
+    pub const fn new() -> Self {
+        Virtq {
+            num: 0,
+            idx: 0,
+            notify: 0,
+            desc: null_mut(),
+            avail: null_mut(),
+            used: null_mut(),
+            free_head: 0,
+            nfree: 0,
+            last_used: 0,
+        }
+    }
+
+    // Legacy layout: descriptors, available ring, used ring on the next page.
+    fn init(&mut self, mem: &mut QueueMem, num: u16) -> () {
+        let base = mem.0.as_mut_ptr();
+        let n = num as u64;
+        let avail_off = 16 * n;
+        let used_off = VA::new(avail_off + 6 + 2 * n).align_up(PGSIZE).as_u64();
+        unsafe {
+            memset(base, 0, 3 * PGSIZE);
+            self.desc = base as *mut VirtqDesc;
+            self.avail = base.offset(avail_off as isize) as *mut u16;
+            self.used = base.offset(used_off as isize) as *mut u16;
+            for i in 0..num {
+                (*self.desc.offset(i as isize)).next = i + 1;
+            }
+        }
+        self.num = num;
+        self.free_head = 0;
+        self.nfree = num;
+        self.last_used = 0;
+    }
+
+    fn addrs(&self) -> (u64, u64, u64) {
+        let pa = |p: u64| unsafe { kva2pa(VA::new(p)).expect("virtio: queue").as_u64() };
+        (pa(self.desc as u64), pa(self.avail as u64), pa(self.used as u64))
+    }
+
+    pub fn desc(&mut self, i: u16) -> &mut VirtqDesc {
+        unsafe { &mut *self.desc.offset(i as isize) }
+    }
+
+    // Take n descriptors chained with VIRTQ_DESC_F_NEXT, returns the head.
+    pub fn alloc_chain(&mut self, n: u16) -> Option<u16> {
+        if n == 0 || n > self.nfree { return None }
+        let head = self.free_head;
+        let mut d = head;
+        for i in 0..n {
+            let next = self.desc(d).next;
+            self.desc(d).flags = if i + 1 < n { VIRTQ_DESC_F_NEXT } else { 0 };
+            if i + 1 < n { d = next; } else { self.free_head = next; }
+        }
+        self.nfree -= n;
+        Some(head)
+    }
+
+    pub fn free_chain(&mut self, head: u16) -> () {
+        let mut d = head;
+        let mut n = 1;
+        while self.desc(d).flags & VIRTQ_DESC_F_NEXT != 0 {
+            d = self.desc(d).next;
+            n += 1;
+        }
+        self.desc(d).next = self.free_head;
+        self.free_head = head;
+        self.nfree += n;
+    }
+
+    // Make a chain available to the device. Call Transport::notify after.
+    pub fn submit(&mut self, head: u16) -> () {
+        unsafe {
+            let idx = read_volatile(self.avail.offset(1));
+            write_volatile(self.avail.offset(2 + (idx % self.num) as isize), head);
+            fence(Ordering::SeqCst);
+            write_volatile(self.avail.offset(1), idx.wrapping_add(1));
+        }
+    }
+
+    // Next chain the device is done with: (head, bytes written).
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        unsafe {
+            let idx = read_volatile(self.used.offset(1));
+            if idx == self.last_used { return None }
+            fence(Ordering::SeqCst);
+            let elem = (self.used.offset(2) as *const u32).offset(2 * (self.last_used % self.num) as isize);
+            let id = read_volatile(elem) as u16;
+            let len = read_volatile(elem.offset(1));
+            self.last_used = self.last_used.wrapping_add(1);
+            Some((id, len))
+        }
+    }
+}
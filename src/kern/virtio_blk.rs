@@ -0,0 +1,323 @@
+// Virtio block device driver.
+//
+// Each request is a descriptor chain: the request header, the data split at
+// page boundaries, and the status byte the device writes back. Requests are
+// completed from the interrupt handler, so several of them can be in flight
+// at once. Test with QEMU's `-drive if=virtio`.
+
+use crate::*;
+use crate::kern::blk::{BlockDevice, register_blkdev, check_range, SECTSIZE};
+use crate::kern::pci::{PciDev, PciDriver, PciId, pci_register_driver};
+use crate::kern::msi::{msix_enable, msix_disable};
+use crate::kern::virtio::*;
+use crate::kern::spinlock::SpinLock;
+use crate::kern::proc::{sleep, wakeup, has_proc};
+use crate::kern::vm::kva2pa;
+use crate::kern::idt::irq_register;
+use core::cell::UnsafeCell;
+use core::ptr::read_volatile;
+use x86_64::instructions::interrupts::without_interrupts;
+
+const VIRTIO_BLK_LEGACY: u16 = 0x1001;
+const VIRTIO_BLK_MODERN: u16 = 0x1042;
+
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_S_OK: u8 = 0;
+
+const CONFIG_CAPACITY: u16 = 0;
+
+const NDISK: usize = 4;
+const MAX_SEGS: usize = 16;     // Data descriptors per request
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct BlkReqHdr {
+    rtype: u32,
+    reserved: u32,
+    sector: u64,
+}
+
+// Indexed by the head descriptor of the request's chain.
+#[derive(Copy, Clone)]
+struct ReqInfo {
+    hdr: BlkReqHdr,
+    status: u8,
+    done: bool,
+}
+
+impl ReqInfo {
+    const fn new() -> Self {
+        ReqInfo { hdr: BlkReqHdr { rtype: 0, reserved: 0, sector: 0 }, status: 0, done: false }
+    }
+}
+
+// The queue and the request slots, protected by VirtioBlk::lock.
+struct Queue {
+    vq: Virtq,
+    info: [ReqInfo; QMAX],
+}
+
+pub struct VirtioBlk {
+    lock: SpinLock,
+    name: &'static str,
+    transport: Option<Transport>,
+    q: UnsafeCell<Queue>,
+    nsectors: u64,
+    readonly: bool,
+    msix: bool,             // Interrupts come on a vector of its own
+}
+
+// The lock serializes all access to the queue.
+unsafe impl Sync for VirtioBlk {}
+
+static mut VDISKS: [VirtioBlk; NDISK] = [
+    VirtioBlk::new("vda"), VirtioBlk::new("vdb"), VirtioBlk::new("vdc"), VirtioBlk::new("vdd"),
+];
+static mut VQMEM: [QueueMem; NDISK] = [QueueMem::new(), QueueMem::new(), QueueMem::new(), QueueMem::new()];
+static mut NVDISK: usize = 0;
+
+impl VirtioBlk {
+    const fn new(name: &'static str) -> Self {
+        VirtioBlk {
+            lock: SpinLock::new(),
+            name: name,
+            transport: None,
+            q: UnsafeCell::new(Queue { vq: Virtq::new(), info: [ReqInfo::new(); QMAX] }),
+            nsectors: 0,
+            readonly: false,
+            msix: false,
+        }
+    }
+
+    fn transport(&self) -> &Transport { self.transport.as_ref().expect("virtio_blk: no transport") }
+
+    // The caller holds lock.
+    unsafe fn q(&self) -> &mut Queue { &mut *self.q.get() }
+
+    // With msix the queue signals MSI-X entry 0, which must be enabled.
+    fn init(&mut self, t: Transport, mem: &mut QueueMem, msix: bool) -> Result<(), &'static str> {
+        t.reset();
+        let features = t.negotiate(VIRTIO_BLK_F_RO)?;
+        t.setup_queue(0, &mut self.q.get_mut().vq, mem)?;
+        if msix { t.set_queue_vector(0, 0)?; }
+        self.readonly = features & VIRTIO_BLK_F_RO != 0;
+        self.nsectors = t.config_read64(CONFIG_CAPACITY);
+        self.msix = msix;
+        t.add_status(STATUS_DRIVER_OK);
+        self.transport = Some(t);
+        Ok(())
+    }
+
+    // Complete every request the device has finished. Caller holds the lock.
+    unsafe fn complete_locked(&self) -> () {
+        let q = self.q();
+        while let Some((head, _)) = q.vq.pop_used() {
+            let info = &mut q.info[head as usize];
+            info.done = true;
+            wakeup(VA::from_ptr(info as *const ReqInfo));
+        }
+    }
+
+    // Wait on chan, or poll the queue if there is no process to put to sleep.
+    // Caller holds the lock, interrupts are off.
+    unsafe fn wait(&self, chan: VA) -> () {
+        if has_proc() {
+            sleep(chan, &self.lock);
+        } else {
+            self.complete_locked();
+        }
+    }
+
+    // Transfer at most MAX_SEGS pages, returns the number of bytes moved.
+    unsafe fn rw_chunk(&self, sector: u64, data: *mut u8, len: usize, write: bool) -> Result<usize, &'static str> {
+        // Split the buffer where it crosses a page, it need not be
+        // physically contiguous (e.g. on a kernel stack).
+        let mut segs = [(0u64, 0u32); MAX_SEGS];
+        let mut nseg = 0;
+        let mut done = 0;
+        while done < len && nseg < MAX_SEGS {
+            let va = data as u64 + done as u64;
+            let n = core::cmp::min(len - done, (PGSIZE - va % PGSIZE) as usize);
+            let pa = kva2pa(VA::new(va)).ok_or("virtio_blk: buffer not mapped")?;
+            segs[nseg] = (pa.as_u64(), n as u32);
+            nseg += 1;
+            done += n;
+        }
+        // Keep whole sectors only, the rest goes with the next chunk.
+        let len = done - done % SECTSIZE;
+        while done > len {
+            let cut = core::cmp::min(done - len, segs[nseg - 1].1 as usize);
+            segs[nseg - 1].1 -= cut as u32;
+            done -= cut;
+            if segs[nseg - 1].1 == 0 { nseg -= 1; }
+        }
+
+        let mut result = Ok(len);
+        without_interrupts(|| {
+            self.lock.acquire();
+
+            let vq_chan = VA::from_ptr(&self.q().vq as *const Virtq);
+            let head = loop {
+                match self.q().vq.alloc_chain(nseg as u16 + 2) {
+                    Some(h) => break h,
+                    None => self.wait(vq_chan),
+                }
+            };
+
+            let info = &mut self.q().info[head as usize];
+            info.hdr = BlkReqHdr {
+                rtype: if write { VIRTIO_BLK_T_OUT } else { VIRTIO_BLK_T_IN },
+                reserved: 0,
+                sector: sector,
+            };
+            info.status = 0xFF;
+            info.done = false;
+            let hdr_pa = kva2pa(VA::from_ptr(&info.hdr as *const BlkReqHdr)).expect("virtio_blk: hdr").as_u64();
+            let status_pa = kva2pa(VA::from_ptr(&info.status as *const u8)).expect("virtio_blk: status").as_u64();
+
+            let mut d = head;
+            {
+                let desc = self.q().vq.desc(d);
+                desc.addr = hdr_pa;
+                desc.len = core::mem::size_of::<BlkReqHdr>() as u32;
+                d = desc.next;
+            }
+            for &(pa, n) in segs[..nseg].iter() {
+                let desc = self.q().vq.desc(d);
+                desc.addr = pa;
+                desc.len = n;
+                // The device writes into our buffer on a read.
+                if !write { desc.flags |= VIRTQ_DESC_F_WRITE; }
+                d = desc.next;
+            }
+            {
+                let desc = self.q().vq.desc(d);
+                desc.addr = status_pa;
+                desc.len = 1;
+                desc.flags |= VIRTQ_DESC_F_WRITE;
+            }
+
+            self.q().vq.submit(head);
+            self.transport().notify(&self.q().vq);
+
+            let info_chan = VA::from_ptr(&self.q().info[head as usize] as *const ReqInfo);
+            while !read_volatile(&self.q().info[head as usize].done) {
+                self.wait(info_chan);
+            }
+
+            if read_volatile(&self.q().info[head as usize].status) != VIRTIO_BLK_S_OK {
+                result = Err("virtio_blk: I/O error");
+            }
+            self.q().vq.free_chain(head);
+            wakeup(vq_chan);
+
+            self.lock.release();
+        });
+        result
+    }
+
+    unsafe fn rw(&self, sector: u64, data: *mut u8, len: usize, write: bool) -> Result<(), &'static str> {
+        check_range(self, sector, len)?;
+        if write && self.readonly { return Err("virtio_blk: read only disk") }
+        let mut done = 0;
+        while done < len {
+            let n = self.rw_chunk(sector + (done / SECTSIZE) as u64,
+                                  data.offset(done as isize), len - done, write)?;
+            if n == 0 { return Err("virtio_blk: buffer not sector aligned") }
+            done += n;
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for VirtioBlk {
+    fn name(&self) -> &'static str { self.name }
+
+    fn nsectors(&self) -> u64 { self.nsectors }
+
+    fn read(&self, sector: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        unsafe { self.rw(sector, buf.as_mut_ptr(), buf.len(), false) }
+    }
+
+    fn write(&self, sector: u64, buf: &[u8]) -> Result<(), &'static str> {
+        unsafe { self.rw(sector, buf.as_ptr() as *mut u8, buf.len(), true) }
+    }
+}
+
+// MSI-X handler of one disk.
+fn virtio_blk_msix(n: usize) -> () {
+    unsafe {
+        let disk = &VDISKS[n];
+        disk.lock.acquire();
+        disk.complete_locked();
+        disk.lock.release();
+    }
+}
+
+// Legacy interrupt handler, shared by all virtio disks.
+fn virtio_blk_intr() -> () {
+    unsafe {
+        for disk in VDISKS[..NVDISK].iter() {
+            if disk.msix { continue }
+            // Reading the ISR acknowledges the interrupt.
+            if disk.transport().isr() & 1 == 0 { continue }
+            disk.lock.acquire();
+            disk.complete_locked();
+            disk.lock.release();
+        }
+    }
+}
+
+static VIRTIO_BLK_IDS: [PciId; 2] = [
+    PciId::device(VIRTIO_VENDOR, VIRTIO_BLK_LEGACY),
+    PciId::device(VIRTIO_VENDOR, VIRTIO_BLK_MODERN),
+];
+
+static VIRTIO_BLK_DRIVER: PciDriver = PciDriver {
+    name: "virtio_blk",
+    ids: &VIRTIO_BLK_IDS,
+    probe: virtio_blk_probe,
+};
+
+// Interrupt lines virtio_blk_intr is registered on.
+static mut VIRTIO_IRQS: u32 = 0;
+
+fn virtio_blk_probe(dev: &PciDev) -> Result<(), &'static str> {
+    unsafe {
+        if NVDISK == NDISK { return Err("virtio_blk: too many disks") }
+        let n = NVDISK;
+        let disk = &mut VDISKS[n];
+        let t = Transport::probe(&dev.addr)?;
+
+        // A vector of its own if the device can do MSI-X, the shared
+        // legacy line otherwise.
+        let vector = match t {
+            Transport::Modern { .. } => msix_enable(dev, 0, 0, virtio_blk_msix, n).ok(),
+            Transport::Legacy { .. } => None,
+        };
+        if let Err(e) = disk.init(t, &mut VQMEM[n], vector.is_some()) {
+            if let Some(v) = vector { msix_disable(dev, 0, v); }
+            return Err(e);
+        }
+        NVDISK += 1;
+
+        let irq = dev.irq_line as u32;
+        if vector.is_none() && irq < 32 && VIRTIO_IRQS & (1 << irq) == 0 {
+            match irq_register(irq, virtio_blk_intr) {
+                Ok(_) => VIRTIO_IRQS |= 1 << irq,
+                Err(e) => println!("virtio_blk: irq {}: {}", irq, e),
+            }
+        }
+        register_blkdev(&*(disk as *const VirtioBlk))?;
+    }
+    Ok(())
+}
+
+pub fn virtio_blk_init() -> () {
+    if let Err(e) = pci_register_driver(&VIRTIO_BLK_DRIVER) {
+        println!("{}", e);
+    }
+}
@@ -1,6 +1,8 @@
-use crate::kern::kalloc::{kalloc};
+use crate::kern::kalloc::{kalloc, kfree};
+use crate::kern::tlb::{flush_range, set_active};
 use crate::*;
 use crate::kern::proc::Proc;
+use crate::kern::vfs::INode;
 use x86_64::ux::u9;
 use x86_64::structures::paging::page_table::PageTable;
 use x86_64::structures::paging::page_table::PageTableFlags as Flags;
@@ -20,6 +22,14 @@ lazy_static! {
     };
 }
 
+// Device memory (e.g. PCI BARs) the firmware placed below DEVSPACE.
+// Reserved before kvm_alloc, mapped by KMapper::setup_vm.
+const MAXDEVMAP: usize = 8;
+static mut DEVMAP: [(u64, u64); MAXDEVMAP] = [(0, 0); MAXDEVMAP];  // Physical start, size
+static mut NDEVMAP: usize = 0;
+
+fn devextra_base() -> u64 { dev_base() + (0x100000000 - DEVSPACE) }
+
 pub struct KMapper;
 pub struct UMapper;
 
@@ -34,13 +44,20 @@ impl Mapper for KMapper {
             (KERN_BASE.as_u64(),          0,             EXTMEM,        Flags::WRITABLE),
             (KERN_BASE.as_u64() + EXTMEM, EXTMEM,        v2p!(kd_u64),  Flags::empty()),
             (kd_u64,                      v2p!(kd_u64),  PHYSTOP,       Flags::WRITABLE),
-            (DEVBASE,                     DEVSPACE,      0x100000000,   Flags::WRITABLE)
+            (dev_base(),                  DEVSPACE,      0x100000000,   Flags::WRITABLE)
         ];
         for k in target_mapping.iter() {
             let r = self.map(p4, VA::new(k.0), (k.2 - k.1) as usize, PA::new(k.1), k.3);
             if r.is_err() { return Err(r.unwrap_err()); }
         }
 
+        // Device memory outside DEVSPACE goes right after it, see dev_reserve.
+        let mut va = devextra_base();
+        for &(pa, sz) in DEVMAP[..NDEVMAP].iter() {
+            self.map(p4, VA::new(va), sz as usize, PA::new(pa), Flags::WRITABLE | Flags::NO_CACHE)?;
+            va += sz;
+        }
+
         Ok(())
     }
 
@@ -115,6 +132,99 @@ trait Mapper {
         } else { panic!("init_vm map failed!"); }
     }
 
+    // Unmap [st, st + sz). Pages which are not mapped are skipped.
+    // If `free` is set the backing physical pages are returned to kalloc.
+    unsafe fn unmap(&self, pg: &mut PageTable, st: VA, sz: usize, free: bool) -> () {
+        if sz == 0 { return }
+        let mut a = st.align_down(PGSIZE);
+        let last = (st + (sz - 1)).align_down(PGSIZE);
+        while a <= last {
+            if let Some(entry) = self.walk(pg, a, 4, false) {
+                if entry.flags().contains(Flags::PRESENT) {
+                    if free { kfree(VA::new(p2v!(entry.addr().as_u64()))); }
+                    entry.set_unused();
+                }
+            }
+            a += PGSIZE;
+        }
+        flush_range(pml4_pa(pg), st, sz);
+    }
+
+    // Replace the flags of the mapped pages in [st, st + sz).
+    unsafe fn protect(&self, pg: &mut PageTable, st: VA, sz: usize, flags: Flags) -> Result<(), &'static str> {
+        if sz == 0 { return Ok(()) }
+        let mut a = st.align_down(PGSIZE);
+        let last = (st + (sz - 1)).align_down(PGSIZE);
+        while a <= last {
+            match self.walk(pg, a, 4, false) {
+                Some(entry) if entry.flags().contains(Flags::PRESENT) => {
+                    let pa = entry.addr();
+                    entry.set_addr(pa, flags | Flags::PRESENT);
+                }
+                _ => return Err("protect: page not mapped")
+            }
+            a += PGSIZE;
+        }
+        flush_range(pml4_pa(pg), st, sz);
+        Ok(())
+    }
+
+    // Copy the pages [0, sz) of src into fresh pages, mapped at the same
+    // addresses with the same flags in dst.
+    unsafe fn copy(&self, src: &mut PageTable, dst: &mut PageTable, sz: usize) -> Result<(), &'static str> {
+        let mut a = VA::zero();
+        while a.as_u64() < sz as u64 {
+            let (pa, flags) = match self.walk(src, a, 4, false) {
+                Some(entry) if entry.flags().contains(Flags::PRESENT) => (entry.addr(), entry.flags()),
+                _ => panic!("copy: page not present"),
+            };
+            let mem = match kalloc() {
+                Some(mem) => mem,
+                None => {
+                    if a.as_u64() > 0 { self.unmap(dst, VA::zero(), a.as_u64() as usize, true); }
+                    return Err("copy: out of memory")
+                }
+            };
+            memmove(mem.as_mut_ptr::<u8>(), p2v!(pa.as_u64()) as *const u8, PGSIZE as usize);
+            if let Err(e) = self.map(dst, a, PGSIZE as usize, PA::new(v2p!(mem.as_u64())), flags) {
+                kfree(mem);
+                if a.as_u64() > 0 { self.unmap(dst, VA::zero(), a.as_u64() as usize, true); }
+                return Err(e);
+            }
+            a += PGSIZE;
+        }
+        Ok(())
+    }
+
+    // Free the page-table pages below the entries [st, ed) of pg, a table
+    // of level lvl. The pages they map must be unmapped already.
+    unsafe fn free_tables(&self, pg: &mut PageTable, lvl: u8, st: usize, ed: usize) -> () {
+        for i in st..ed {
+            let entry = &mut pg[i];
+            if let Ok(fr) = entry.frame() {
+                if lvl == 1 { panic!("free_tables: leaf"); }
+                let next = p2v!(fr.start_address().as_u64());
+                self.free_tables(&mut *(next as *mut PageTable), lvl - 1, 0, ENTRY_COUNT);
+                kfree(VA::new(next));
+                entry.set_unused();
+            }
+        }
+    }
+
+    // Count the page-table pages below the entries [st, ed) of pg, a table
+    // of level lvl.
+    unsafe fn count_tables(&self, pg: &PageTable, lvl: u8, st: usize, ed: usize) -> usize {
+        if lvl == 1 { return 0 }
+        let mut n = 0;
+        for i in st..ed {
+            if let Ok(fr) = pg[i].frame() {
+                let next = p2v!(fr.start_address().as_u64());
+                n += 1 + self.count_tables(&*(next as *const PageTable), lvl - 1, 0, ENTRY_COUNT);
+            }
+        }
+        n
+    }
+
     fn free_vm(&self) -> () {}
 }
 
@@ -132,22 +242,194 @@ pub unsafe fn kvm_alloc() -> () {
     }
 }
 
+// Map the randomized alias of physical memory, see kaslr.rs, and have
+// p2v! use it from now on. Its page tables take another 1MB, so this
+// waits for all of memory to be in kalloc. Must run before the first
+// process copies the kernel half of KPML4.
+pub unsafe fn physmap_init() -> () {
+    if PHYSMAP == 0 { return }
+    let p4 = KPML4.as_ptr();
+    if let Err(e) = KMapper.map(&mut *p4, VA::new(PHYSMAP), PHYSTOP as usize, PA::new(0), Flags::WRITABLE | Flags::NO_EXECUTE) {
+        panic!(e);
+    }
+    PHYSMAP_ON = true;
+}
+
 pub unsafe fn switch_kvm() -> () {
     let p4 = KPML4.as_ptr();
     let value = v2p!(VA::from_ptr(p4).as_u64());
     asm!("mov $0, %cr3" :: "r" (value) : "memory");
+    set_active(value);
 }
 
 pub unsafe fn switch_uvm(p: &Proc) -> () {
-    let p4 = p.get_pml4();
-    let value = VA::from_ptr(p4 as *const PageTable).as_u64();
+    let value = pml4_pa(p.get_pml4());
     asm!("mov $0, %cr3" :: "r" (value) : "memory");
+    set_active(value);
+}
+
+// Physical address of a page table, i.e. the value loaded into CR3.
+pub fn pml4_pa(pt: &PageTable) -> u64 {
+    v2p!(VA::from_ptr(pt as *const PageTable).as_u64())
 }
 
+// Share the kernel half of KPML4 with a new page table, so kernel mappings
+// created later (e.g. kernel stacks) show up in every address space.
 pub unsafe fn setup_kvm(pt: &mut PageTable) -> Result<(), &'static str> {
-    KMapper.setup_vm(pt)
+    let kpml4 = &*KPML4.as_ptr();
+    for i in ENTRY_COUNT / 2..ENTRY_COUNT {
+        pt[i] = kpml4[i].clone();
+    }
+    Ok(())
 }
 
 pub unsafe fn init_uvm(pml4: &mut PageTable, init: *const u64, sz: usize) {
     UMapper.init_vm(pml4, init, sz);
-}
\ No newline at end of file
+}
+
+// Give a child of fork a copy of the user memory [0, sz) of its parent.
+pub unsafe fn copy_uvm(src: &mut PageTable, dst: &mut PageTable, sz: usize) -> Result<(), &'static str> {
+    UMapper.copy(src, dst, sz)
+}
+
+// Free the user memory [0, sz), the page tables of the user half and pml4.
+pub unsafe fn free_uvm(pml4: &mut PageTable, sz: usize) -> () {
+    if sz > 0 { UMapper.unmap(pml4, VA::zero(), sz, true); }
+    UMapper.free_tables(pml4, 4, 0, ENTRY_COUNT / 2);
+    kfree(VA::from_ptr(pml4 as *mut PageTable));
+}
+
+// Grow the user memory of pml4 from oldsz to newsz with zeroed pages
+// mapped with flags. Returns the new size; on failure the pages added are
+// freed again.
+pub unsafe fn alloc_uvm(pml4: &mut PageTable, oldsz: u64, newsz: u64, flags: Flags) -> Result<u64, &'static str> {
+    let mut a = VA::new(oldsz).align_up(PGSIZE);
+    while a.as_u64() < newsz {
+        let mem = match kalloc() {
+            Some(mem) => mem,
+            None => {
+                dealloc_uvm(pml4, oldsz, a.as_u64());
+                return Err("alloc_uvm: out of memory");
+            }
+        };
+        memset(mem.as_mut_ptr::<u8>(), 0, PGSIZE);
+        if let Err(e) = UMapper.map(pml4, a, PGSIZE as usize, PA::new(v2p!(mem.as_u64())),
+                                    flags | Flags::USER_ACCESSIBLE) {
+            kfree(mem);
+            dealloc_uvm(pml4, oldsz, a.as_u64());
+            return Err(e);
+        }
+        a += PGSIZE;
+    }
+    Ok(newsz)
+}
+
+// Free the pages of [oldsz, newsz) again, see alloc_uvm.
+unsafe fn dealloc_uvm(pml4: &mut PageTable, oldsz: u64, newsz: u64) -> () {
+    let st = VA::new(oldsz).align_up(PGSIZE);
+    if newsz > st.as_u64() { UMapper.unmap(pml4, st, (newsz - st.as_u64()) as usize, true); }
+}
+
+// The kernel address of user address va in pml4, which need not be the
+// page table loaded.
+unsafe fn uva2ka(pml4: &mut PageTable, va: VA) -> Option<*mut u8> {
+    match UMapper.walk(pml4, va, 4, false) {
+        Some(entry) if entry.flags().contains(Flags::PRESENT) =>
+            Some((p2v!(entry.addr().as_u64()) + (va.as_u64() & (PGSIZE - 1))) as *mut u8),
+        _ => None,
+    }
+}
+
+// Read sz bytes at off of ip into the mapped user memory at va of pml4.
+// va must be page aligned.
+pub unsafe fn load_uvm(pml4: &mut PageTable, va: VA, ip: &dyn INode, off: u64, sz: u64) -> Result<(), &'static str> {
+    if !va.is_aligned(PGSIZE) { return Err("load_uvm: addr not page aligned") }
+    let mut i = 0;
+    while i < sz {
+        let dst = uva2ka(pml4, va + i).ok_or("load_uvm: page not mapped")?;
+        let n = core::cmp::min(sz - i, PGSIZE);
+        let pos = off.checked_add(i).filter(|&p| p <= u32::max_value() as u64 - n).ok_or("load_uvm: offset too large")?;
+        if ip.read(false, dst as u64, pos as u32, n as u32)? != n as usize { return Err("load_uvm: short read") }
+        i += n;
+    }
+    Ok(())
+}
+
+// Copy len bytes from src into user address va of pml4, which need not
+// be the page table loaded.
+pub unsafe fn copy_out_uvm(pml4: &mut PageTable, va: u64, src: *const u8, len: usize) -> Result<(), &'static str> {
+    let mut i = 0;
+    while i < len {
+        let a = va + i as u64;
+        let dst = uva2ka(pml4, VA::new(a)).ok_or("copy_out_uvm: page not mapped")?;
+        let n = core::cmp::min(len - i, (PGSIZE - (a & (PGSIZE - 1))) as usize);
+        memmove(dst, src.add(i), n);
+        i += n;
+    }
+    Ok(())
+}
+
+// Pages of page tables a user address space takes, pml4 included.
+pub unsafe fn uvm_tables(pml4: &PageTable) -> usize {
+    1 + UMapper.count_tables(pml4, 4, 0, ENTRY_COUNT / 2)
+}
+
+// Unmap user pages and shoot down stale translations on other CPUs.
+pub unsafe fn unmap_uvm(pml4: &mut PageTable, va: VA, sz: usize, free: bool) {
+    UMapper.unmap(pml4, va, sz, free);
+}
+
+// Change permissions of user pages, e.g. to write protect them.
+pub unsafe fn protect_uvm(pml4: &mut PageTable, va: VA, sz: usize, flags: Flags) -> Result<(), &'static str> {
+    UMapper.protect(pml4, va, sz, flags)
+}
+
+// Physical address behind a kernel virtual address. Unlike v2p!, this also
+// works for mappings outside the linear map, e.g. kernel stacks.
+pub unsafe fn kva2pa(va: VA) -> Option<PA> {
+    match KMapper.walk(&mut *KPML4.as_ptr(), va, 4, false) {
+        Some(entry) if entry.flags().contains(Flags::PRESENT) =>
+            Some(entry.addr() + (va.as_u64() & (PGSIZE - 1))),
+        _ => None,
+    }
+}
+
+pub unsafe fn map_kvm(va: VA, sz: usize, pa: PA, flags: Flags) -> Result<(), &'static str> {
+    KMapper.map(&mut *KPML4.as_ptr(), va, sz, pa, flags)
+}
+
+pub unsafe fn unmap_kvm(va: VA, sz: usize, free: bool) {
+    KMapper.unmap(&mut *KPML4.as_ptr(), va, sz, free);
+}
+
+// Bytes left in the window for device memory outside DEVSPACE.
+pub unsafe fn dev_space_left() -> u64 {
+    DEVEXTRA - DEVMAP[..NDEVMAP].iter().map(|&(_, s)| s).sum::<u64>()
+}
+
+// Ask for device memory at [pa, pa + sz) to be mapped into the device window.
+// Must be called before kvm_alloc, memory inside DEVSPACE is always mapped.
+pub unsafe fn dev_reserve(pa: u64, sz: u64) -> Result<(), &'static str> {
+    if pa >= DEVSPACE && pa + sz <= 0x100000000 { return Ok(()) }
+    let st = pa & !(PGSIZE - 1);
+    let sz = (pa + sz - st + PGSIZE - 1) & !(PGSIZE - 1);
+    if DEVMAP[..NDEVMAP].iter().any(|&(p, s)| st >= p && st + sz <= p + s) { return Ok(()) }
+    let used: u64 = DEVMAP[..NDEVMAP].iter().map(|&(_, s)| s).sum();
+    if NDEVMAP == MAXDEVMAP || used + sz > DEVEXTRA { return Err("dev_reserve: device window full") }
+    DEVMAP[NDEVMAP] = (st, sz);
+    NDEVMAP += 1;
+    Ok(())
+}
+
+// Kernel virtual address of device memory, None if it is not mapped.
+pub fn mmio2v(pa: u64) -> Option<u64> {
+    if pa >= DEVSPACE && pa < 0x100000000 { return Some(io2v!(pa)) }
+    let mut va = devextra_base();
+    unsafe {
+        for &(p, s) in DEVMAP[..NDEVMAP].iter() {
+            if pa >= p && pa < p + s { return Some(va + (pa - p)) }
+            va += s;
+        }
+    }
+    None
+}
@@ -80,10 +80,50 @@ pub fn hlt_loop() -> ! {
     }
 }
 
+// Returns (eax, ebx, ecx, edx) of the given cpuid leaf and subleaf.
+pub fn cpuid(leaf: u32, subleaf: u32) -> (u32, u32, u32, u32) {
+    let (a, b, c, d): (u32, u32, u32, u32);
+    unsafe {
+        asm!("cpuid"
+             : "={eax}"(a), "={ebx}"(b), "={ecx}"(c), "={edx}"(d)
+             : "{eax}"(leaf), "{ecx}"(subleaf)
+             :: "volatile");
+    }
+    (a, b, c, d)
+}
+
+// Base of the device mapping. DEVBASE unless randomized, see kaslr.rs.
+// Set once before the kernel page table is built.
+pub static mut DEV_BASE: u64 = DEVBASE;
+pub fn dev_base() -> u64 { unsafe { DEV_BASE } }
+
+// Base of a second, randomized mapping of physical memory, see kaslr.rs.
+// 0 if there is none. p2v! hands out addresses in it once physmap_init has
+// mapped it; until then, and without KASLR, physical memory is reached
+// through the kernel window at KERN_BASE.
+pub static mut PHYSMAP: u64 = 0;
+pub static mut PHYSMAP_ON: bool = false;
+
+pub fn phys_base() -> u64 {
+    unsafe { if PHYSMAP_ON { PHYSMAP } else { KERN_BASE.as_u64() } }
+}
+
+// Physical address behind an address of either mapping.
+pub fn virt_to_phys(va: u64) -> u64 {
+    unsafe {
+        if PHYSMAP != 0 && va >= PHYSMAP && va < PHYSMAP + PHYSTOP { va - PHYSMAP } else { va - KERN_BASE.as_u64() }
+    }
+}
+
 // ----------MEM LAYOUT----------
 pub const PGSIZE:u64 = 4096; // 4KB page
 pub const ENTRY_COUNT: usize = 512; // Entries per page
 pub const DEVBASE: u64 = 0xffffffff40000000; // first device virtual address
+pub const DEVWINDOW: u64 = 0x40000000; // 1GB reserved for the device mapping
+pub const DEVEXTRA: u64 = 0x2000000; // 32MB after DEVSPACE's mapping for device memory below DEVSPACE
+pub const KSTACKBASE: u64 = 0xffffffff00000000; // per process kernel stacks, below DEVBASE
+pub const PHYSMAPBASE: u64 = 0xffffc00000000000; // lowest address of the randomized physical memory mapping
+pub const PHYSMAPWINDOW: u64 = 0x10000000000; // 1TB it may be placed in
 pub const DEVSPACE: u64 = 0xfe000000;
 pub const PHYSTOP: u64 = 0x20000000; // 512MB memory
 pub const EXTMEM: u64 = 0x100000;
@@ -99,6 +139,7 @@ pub const MPLINTR   : u8 = 0x04;  // One per system interrupt source
 // --------------LAPIC REGISTERS----------------
 // divided by 4 for use as indices
 // Local APIC registers, divided by 4 for use as uint[] indices.
+pub const LAPIC_ADDR:   u64 = 0xFEE00000;   // Default physical address of the local APIC
 pub const ID       :u32 = (0x0020/4);   // ID
 pub const VER      :u32 = (0x0030/4);   // Version
 pub const TPR      :u32 = (0x0080/4);   // Task Priority
@@ -174,6 +215,9 @@ pub const T_SIMDERR    :u32 =   19;      // SIMD floating point error
 // These are arbitrarily chosen, but with care not to overlap
 // processor defined exceptions or interrupt vectors.
 pub const T_SYSCALL    :u32 =   64;      // system call
+pub const T_TLBFLUSH   :u32 =   65;      // TLB shootdown IPI
+pub const T_DYN0       :u32 =   66;      // First vector handed out by vector_alloc
+pub const T_DYNEND     :u32 =   98;      // One past the last
 pub const T_DEFAULT    :u32 =  500;      // catchall
 
 pub const T_IRQ0       :u32 =   32;      // IRQ 0 corresponds to int T_IRQ
@@ -185,3 +229,7 @@ pub const IRQ_IDE      :u32 =   14;
 pub const IRQ_ERROR    :u32 =   19;
 pub const IRQ_SPURIOUS :u32 =   31;
 
+// ---------------ERRNO----------------------------
+// System calls return the negated value on failure.
+pub const EFAULT       :isize =  14;     // Bad address
+
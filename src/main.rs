@@ -10,8 +10,19 @@ use ros::hlt_loop;
 
 #[no_mangle] // don't mangle the name of this function
 pub unsafe extern "C" fn kmain() -> ! {
+    // Multiboot modules lie after the kernel, keep the allocator off them.
+    let mods_end = ros::kern::multiboot::multiboot_init();
+    let above_mods = |pa: u64| VA::new(p2v!(core::cmp::max(pa, mods_end)));
+    ros::kern::param::param_init();
+
     println!("Early init physical page allocator");
-    ros::kern::kalloc::kinit(*ros::KERN_END, VA::new(p2v!(4*1024*1024 as u64)));
+    ros::kern::kalloc::kinit(core::cmp::max(*ros::KERN_END, above_mods(0)), VA::new(p2v!(4*1024*1024 as u64)));
+
+    ros::kern::kaslr::kaslr_init();
+
+    // Device memory setup_vm has to map besides DEVSPACE.
+    ros::kern::ahci::ahci_reserve();
+    ros::kern::pci::pci_reserve();
 
     println!("Initializing virtual memory");
     ros::kern::vm::kvm_alloc();
@@ -26,6 +37,9 @@ pub unsafe extern "C" fn kmain() -> ! {
     ros::kern::gdt64::gdt_init();
     ros::kern::idt::idt_init();
 
+    println!("Enabling SMEP/SMAP/UMIP");
+    ros::kern::uaccess::uaccess_init();
+
     println!("Initializing IOAPIC");
     ros::kern::ioapic::ioapic_init();
 
@@ -35,12 +49,56 @@ pub unsafe extern "C" fn kmain() -> ! {
     println!("Initializing UART");
     ros::kern::uart::uart_init();
 
+    println!("Enumerating PCI devices");
+    ros::kern::pci::pci_init();
+
+    println!("Initializing IDE disks");
+    ros::kern::ide::ide_init();
+
+    println!("Initializing virtio disks");
+    ros::kern::virtio_blk::virtio_blk_init();
+
+    println!("Initializing AHCI disks");
+    ros::kern::ahci::ahci_init();
+
+    println!("Mounting root file system");
+    if let Err(e) = ros::kern::initrd::initrd_init() {
+        println!("{}", e);
+    }
+    if let Err(e) = ros::kern::fs::fs_init() {
+        println!("{}", e);
+    }
+    if let Err(e) = ros::kern::fat::fat_init() {
+        println!("{}", e);
+    }
+    if let Err(e) = ros::kern::tmpfs::tmpfs_init() {
+        println!("{}", e);
+    }
+    if let Err(e) = ros::kern::procfs::procfs_init() {
+        println!("{}", e);
+    }
+
     println!("Start other APs");
     // This does not work anymore by using #[thread_local]
     // ros::kern::mp::start_others();
 
     println!("Initializing memory");
-    ros::kern::kalloc::kinit(VA::new(p2v!(4*1024*1024 as u64)), VA::new(p2v!(PHYSTOP)));
+    // Only the RAM the memory map reports. The boot block gives no map,
+    // QEMU's -m 512 is taken for granted then.
+    let mut kinit = |st: u64, ed: u64| {
+        let (st, ed) = (above_mods(st), VA::new(p2v!(ed)));
+        if st >= ed { return }
+        if ros::kern::kaslr::enabled() {
+            ros::kern::kalloc::kinit_random(st, ed, ros::kern::kaslr::random_u64());
+        } else {
+            ros::kern::kalloc::kinit(st, ed);
+        }
+    };
+    if !ros::kern::multiboot::available(4*1024*1024, PHYSTOP, &mut kinit) {
+        println!("No memory map, assuming RAM up to {:#x}", PHYSTOP);
+        kinit(4*1024*1024, PHYSTOP);
+    }
+    ros::kern::vm::physmap_init();
 
     println!("User space initialization");
     ros::kern::proc::user_init();
@@ -54,7 +112,7 @@ pub unsafe extern "C" fn kmain() -> ! {
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    println!("{}", info);
+    ros::kern::console::_print_level(ros::kern::console::LOG_ERR, format_args!("{}\n", info));
     hlt_loop()
 }
 
@@ -63,6 +121,7 @@ unsafe fn mp_enter() {
     ros::kern::vm::switch_kvm();
     ros::kern::gdt64::gdt_init();
     ros::kern::lapic::lapic_init();
+    ros::kern::uaccess::uaccess_init();
     mp_main();
 }
 
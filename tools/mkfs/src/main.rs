@@ -0,0 +1,390 @@
+// Host tool building the root file system image, and boot.img, the
+// file system the boot block loads the kernel from.
+//
+// Lays out an empty file system in the on-disk format of kern/fs.rs and
+// copies the given files into the root directory, next to the empty
+// directories the kernel mounts file systems on: fat for a FAT volume,
+// tmp for tmpfs, dev for devfs and proc for procfs. A leading '_' is
+// stripped from the file names, so user programs can be built as _init
+// without clashing with other build products.
+//
+// Usage: mkfs <fs.img> [files...]
+
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process::exit;
+
+// These must match kern/bio.rs, kern/fs.rs and kern/log.rs.
+const BSIZE: usize = 1024;
+const FSMAGIC: u32 = 0x10203040;
+const ROOTINO: u32 = 1;
+const NDIRECT: usize = 11;
+const NINDIRECT: usize = BSIZE / 4;
+const MAXFILE: usize = NDIRECT + NINDIRECT + NINDIRECT * NINDIRECT;
+const DIRSIZ: usize = 14;
+const LOGSIZE: u32 = 30;
+
+const T_DIR: i16 = 1;
+const T_FILE: i16 = 2;
+
+const DINODE_SIZE: usize = 64;
+const DIRENT_SIZE: usize = 16;
+const IPB: u32 = (BSIZE / DINODE_SIZE) as u32;
+const BPB: u32 = (BSIZE * 8) as u32;
+
+const FSSIZE: u32 = 8192;       // Size of file system in blocks, room for a debug kernel
+const NINODES: u32 = 200;
+
+// Disk layout:
+// [ boot block | sb block | log | inode blocks | free bit map | data blocks ]
+const NLOG: u32 = LOGSIZE + 1;  // Header block and LOGSIZE blocks
+const NINODEBLOCKS: u32 = NINODES / IPB + 1;
+const NBITMAP: u32 = FSSIZE / BPB + 1;
+const LOGSTART: u32 = 2;
+const INODESTART: u32 = LOGSTART + NLOG;
+const BMAPSTART: u32 = INODESTART + NINODEBLOCKS;
+const NMETA: u32 = BMAPSTART + NBITMAP;
+
+#[derive(Default)]
+struct DInode {
+    itype: i16,
+    major: i16,
+    minor: i16,
+    nlink: i16,
+    size: u32,
+    addrs: [u32; NDIRECT + 2],
+}
+
+struct Mkfs {
+    img: Vec<u8>,
+    freeinode: u32,
+    freeblock: u32,
+}
+
+fn die(msg: &str) -> ! {
+    eprintln!("mkfs: {}", msg);
+    exit(1);
+}
+
+fn u32_at(b: &[u8], off: usize) -> u32 {
+    let mut x = [0u8; 4];
+    x.copy_from_slice(&b[off..off + 4]);
+    u32::from_le_bytes(x)
+}
+
+fn put(b: &mut [u8], off: usize, v: &[u8]) { b[off..off + v.len()].copy_from_slice(v); }
+
+fn dirent(inum: u32, name: &str) -> [u8; DIRENT_SIZE] {
+    let mut de = [0u8; DIRENT_SIZE];
+    put(&mut de, 0, &(inum as u16).to_le_bytes());
+    put(&mut de, 2, name.as_bytes());
+    de
+}
+
+impl Mkfs {
+    fn new() -> Self {
+        Mkfs {
+            img: vec![0; FSSIZE as usize * BSIZE],
+            freeinode: 1,
+            freeblock: NMETA,       // The first free block that we can allocate
+        }
+    }
+
+    fn block(&mut self, b: u32) -> &mut [u8] {
+        let st = b as usize * BSIZE;
+        &mut self.img[st..st + BSIZE]
+    }
+
+    fn write_sb(&mut self) {
+        let fields = [FSMAGIC, FSSIZE, FSSIZE - NMETA, NINODES, NLOG, LOGSTART, INODESTART, BMAPSTART];
+        let sb = self.block(1);
+        for (i, f) in fields.iter().enumerate() {
+            put(sb, i * 4, &f.to_le_bytes());
+        }
+    }
+
+    fn inode_off(inum: u32) -> usize { (inum % IPB) as usize * DINODE_SIZE }
+
+    fn winode(&mut self, inum: u32, ip: &DInode) {
+        let off = Self::inode_off(inum);
+        let b = self.block(INODESTART + inum / IPB);
+        put(b, off, &ip.itype.to_le_bytes());
+        put(b, off + 2, &ip.major.to_le_bytes());
+        put(b, off + 4, &ip.minor.to_le_bytes());
+        put(b, off + 6, &ip.nlink.to_le_bytes());
+        put(b, off + 8, &ip.size.to_le_bytes());
+        for (i, a) in ip.addrs.iter().enumerate() {
+            put(b, off + 12 + i * 4, &a.to_le_bytes());
+        }
+    }
+
+    fn rinode(&mut self, inum: u32) -> DInode {
+        let off = Self::inode_off(inum);
+        let b = self.block(INODESTART + inum / IPB);
+        let i16_at = |o: usize| i16::from_le_bytes([b[off + o], b[off + o + 1]]);
+        let mut ip = DInode {
+            itype: i16_at(0),
+            major: i16_at(2),
+            minor: i16_at(4),
+            nlink: i16_at(6),
+            size: u32_at(b, off + 8),
+            addrs: [0; NDIRECT + 2],
+        };
+        for i in 0..NDIRECT + 2 {
+            ip.addrs[i] = u32_at(b, off + 12 + i * 4);
+        }
+        ip
+    }
+
+    fn ialloc(&mut self, itype: i16) -> u32 {
+        if self.freeinode >= NINODES { die("out of inodes"); }
+        let inum = self.freeinode;
+        self.freeinode += 1;
+        self.winode(inum, &DInode { itype, nlink: 1, ..Default::default() });
+        inum
+    }
+
+    fn balloc(&mut self) -> u32 {
+        if self.freeblock >= FSSIZE { die("out of blocks"); }
+        let b = self.freeblock;
+        self.freeblock += 1;
+        b
+    }
+
+    // Entry i of indirect block ind, allocating the block it names.
+    fn ind_entry(&mut self, ind: u32, i: usize) -> u32 {
+        let mut x = u32_at(self.block(ind), i * 4);
+        if x == 0 {
+            x = self.balloc();
+            put(self.block(ind), i * 4, &x.to_le_bytes());
+        }
+        x
+    }
+
+    // Append data to the contents of inode inum.
+    fn iappend(&mut self, inum: u32, data: &[u8]) {
+        let mut ip = self.rinode(inum);
+        let mut off = ip.size as usize;
+        let mut data = data;
+        while !data.is_empty() {
+            let fbn = off / BSIZE;
+            if fbn >= MAXFILE { die("file too large"); }
+            let x = if fbn < NDIRECT {
+                if ip.addrs[fbn] == 0 { ip.addrs[fbn] = self.balloc(); }
+                ip.addrs[fbn]
+            } else if fbn < NDIRECT + NINDIRECT {
+                if ip.addrs[NDIRECT] == 0 { ip.addrs[NDIRECT] = self.balloc(); }
+                self.ind_entry(ip.addrs[NDIRECT], fbn - NDIRECT)
+            } else {
+                if ip.addrs[NDIRECT + 1] == 0 { ip.addrs[NDIRECT + 1] = self.balloc(); }
+                let bn = fbn - NDIRECT - NINDIRECT;
+                let ind = self.ind_entry(ip.addrs[NDIRECT + 1], bn / NINDIRECT);
+                self.ind_entry(ind, bn % NINDIRECT)
+            };
+            let n = std::cmp::min(data.len(), (fbn + 1) * BSIZE - off);
+            put(self.block(x), off - fbn * BSIZE, &data[..n]);
+            off += n;
+            data = &data[n..];
+        }
+        ip.size = off as u32;
+        self.winode(inum, &ip);
+    }
+
+    // Mark the blocks in use so far as allocated.
+    fn write_bitmap(&mut self) {
+        let used = self.freeblock;
+        if used >= BPB { die("bitmap spans more than one block"); }
+        let b = self.block(BMAPSTART);
+        for i in 0..used as usize {
+            b[i / 8] |= 1 << (i % 8);
+        }
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 2 { die("usage: mkfs <fs.img> [files...]"); }
+
+    let mut m = Mkfs::new();
+    m.write_sb();
+
+    let rootino = m.ialloc(T_DIR);
+    assert_eq!(rootino, ROOTINO);
+    m.iappend(rootino, &dirent(rootino, "."));
+    m.iappend(rootino, &dirent(rootino, ".."));
+
+    // Mount points.
+    for name in &["fat", "tmp", "dev", "proc"] {
+        let ino = m.ialloc(T_DIR);
+        m.iappend(rootino, &dirent(ino, name));
+        m.iappend(ino, &dirent(ino, "."));
+        m.iappend(ino, &dirent(rootino, ".."));
+        let mut root = m.rinode(rootino);
+        root.nlink += 1;    // for ".." of the new directory
+        m.winode(rootino, &root);
+    }
+
+    for path in &args[2..] {
+        let base = Path::new(path).file_name().and_then(|n| n.to_str())
+            .unwrap_or_else(|| die(&format!("{}: bad file name", path)));
+        // Skip leading _ in name when writing to file system.
+        let name = base.trim_start_matches('_');
+        if name.is_empty() || name.len() > DIRSIZ {
+            die(&format!("{}: name must be 1 to {} characters", path, DIRSIZ));
+        }
+        let data = fs::read(path).unwrap_or_else(|e| die(&format!("{}: {}", path, e)));
+
+        let inum = m.ialloc(T_FILE);
+        m.iappend(rootino, &dirent(inum, name));
+        m.iappend(inum, &data);
+    }
+
+    // Fix size of root inode dir.
+    let mut root = m.rinode(rootino);
+    root.size = ((root.size as usize + BSIZE - 1) / BSIZE * BSIZE) as u32;
+    m.winode(rootino, &root);
+
+    m.write_bitmap();
+
+    let out = &args[1];
+    fs::write(out, &m.img).unwrap_or_else(|e| die(&format!("{}: {}", out, e)));
+    println!("mkfs: {} blocks: {} meta, {} data ({} used), {} inodes ({} used)",
+             FSSIZE, NMETA, FSSIZE - NMETA, m.freeblock - NMETA, NINODES, m.freeinode - 1);
+}
+
+// Check the on-disk format above against the kernel's own definitions.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BIO_RS: &str = include_str!("../../../src/kern/bio.rs");
+    const FS_RS: &str = include_str!("../../../src/kern/fs.rs");
+    const LOG_RS: &str = include_str!("../../../src/kern/log.rs");
+
+    // Value of a constant expression made of literals, constants of src,
+    // + and *.
+    fn eval(src: &str, expr: &str) -> usize {
+        expr.split('+').map(|term| {
+            term.split('*').map(|f| {
+                let f = f.trim();
+                if f.starts_with("0x") {
+                    usize::from_str_radix(&f[2..], 16).unwrap()
+                } else if f.chars().all(|c| c.is_ascii_digit()) {
+                    f.parse().unwrap()
+                } else {
+                    konst(src, f)
+                }
+            }).product::<usize>()
+        }).sum()
+    }
+
+    fn konst(src: &str, name: &str) -> usize {
+        let pat = format!("const {}: ", name);
+        let line = src.lines().find(|l| l.contains(&pat))
+            .unwrap_or_else(|| panic!("no constant {}", name));
+        let expr = &line[line.find('=').unwrap() + 1..line.find(';').unwrap()];
+        if expr.contains("size_of") { panic!("{}: not a literal expression", name) }
+        eval(src, expr)
+    }
+
+    // Names and types of the fields of a struct in src.
+    fn fields(src: &str, name: &str) -> Vec<(String, String)> {
+        let pat = format!("pub struct {} {{", name);
+        let st = src.find(&pat).unwrap_or_else(|| panic!("no struct {}", name)) + pat.len();
+        let body = &src[st..st + src[st..].find("\n}").unwrap()];
+        body.lines().filter_map(|l| {
+            let l = l.split("//").next().unwrap().trim().trim_end_matches(',');
+            let l = l.trim_start_matches("pub ");
+            let colon = l.find(':')?;
+            Some((l[..colon].trim().to_string(), l[colon + 1..].trim().to_string()))
+        }).collect()
+    }
+
+    // Size and alignment of a field type.
+    fn layout(src: &str, ty: &str) -> (usize, usize) {
+        match ty {
+            "u8" | "i8" => (1, 1),
+            "u16" | "i16" => (2, 2),
+            "u32" | "i32" => (4, 4),
+            _ if ty.starts_with('[') => {
+                let semi = ty.find(';').unwrap();
+                let (size, align) = layout(src, ty[1..semi].trim());
+                (size * eval(src, &ty[semi + 1..ty.len() - 1]), align)
+            }
+            _ => panic!("unknown type {}", ty),
+        }
+    }
+
+    // Size of a #[repr(C)] struct of src.
+    fn size_of_struct(src: &str, name: &str) -> usize {
+        let (mut off, mut max) = (0, 1);
+        for (_, ty) in fields(src, name) {
+            let (size, align) = layout(src, &ty);
+            off = (off + align - 1) / align * align + size;
+            if align > max { max = align; }
+        }
+        (off + max - 1) / max * max
+    }
+
+    #[test]
+    fn constants() {
+        assert_eq!(BSIZE, konst(BIO_RS, "BSIZE"));
+        assert_eq!(FSMAGIC as usize, konst(FS_RS, "FSMAGIC"));
+        assert_eq!(ROOTINO as usize, konst(FS_RS, "ROOTINO"));
+        assert_eq!(NDIRECT, konst(FS_RS, "NDIRECT"));
+        assert_eq!(DIRSIZ, konst(FS_RS, "DIRSIZ"));
+        assert_eq!(LOGSIZE as usize, konst(LOG_RS, "LOGSIZE"));
+    }
+
+    #[test]
+    fn superblock() {
+        let names: Vec<String> = fields(FS_RS, "SuperBlock").into_iter().map(|(n, ty)| {
+            assert_eq!(ty, "u32");
+            n
+        }).collect();
+        assert_eq!(names, ["magic", "size", "nblocks", "ninodes", "nlog", "logstart", "inodestart", "bmapstart"]);
+
+        let mut m = Mkfs::new();
+        m.write_sb();
+        let sb = m.block(1);
+        let values: Vec<u32> = (0..names.len()).map(|i| u32_at(sb, i * 4)).collect();
+        assert_eq!(values, [FSMAGIC, FSSIZE, FSSIZE - NMETA, NINODES, NLOG, LOGSTART, INODESTART, BMAPSTART]);
+    }
+
+    // Block bn of inode ip, the way kern/fs.rs and the boot block find it.
+    fn bmap(m: &mut Mkfs, ip: &DInode, bn: usize) -> u32 {
+        if bn < NDIRECT { return ip.addrs[bn] }
+        let bn = bn - NDIRECT;
+        if bn < NINDIRECT { return u32_at(m.block(ip.addrs[NDIRECT]), bn * 4) }
+        let bn = bn - NINDIRECT;
+        let ind = u32_at(m.block(ip.addrs[NDIRECT + 1]), bn / NINDIRECT * 4);
+        u32_at(m.block(ind), bn % NINDIRECT * 4)
+    }
+
+    #[test]
+    fn doubly_indirect() {
+        let data: Vec<u8> = (0..3 * 1024 * 1024 + 100).map(|i: u32| (i * 7 + i / BSIZE as u32) as u8).collect();
+        let mut m = Mkfs::new();
+        let inum = m.ialloc(T_FILE);
+        m.iappend(inum, &data[..1000]);
+        m.iappend(inum, &data[1000..]);
+
+        let ip = m.rinode(inum);
+        assert_eq!(ip.size as usize, data.len());
+        assert_ne!(ip.addrs[NDIRECT + 1], 0);
+        for (bn, chunk) in data.chunks(BSIZE).enumerate() {
+            let b = bmap(&mut m, &ip, bn);
+            assert!((NMETA..FSSIZE).contains(&b));
+            assert_eq!(&m.block(b)[..chunk.len()], chunk);
+        }
+    }
+
+    #[test]
+    fn record_sizes() {
+        let src = format!("{}{}", BIO_RS, FS_RS);
+        assert_eq!(DINODE_SIZE, size_of_struct(&src, "DInode"));
+        assert_eq!(DIRENT_SIZE, size_of_struct(&src, "Dirent"));
+        assert_eq!(BSIZE % DINODE_SIZE, 0);
+    }
+}
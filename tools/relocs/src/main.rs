@@ -0,0 +1,157 @@
+// Host tool for KASLR.
+//
+// The kernel is linked with --emit-relocs, so the relocations the linker
+// resolved are still in the ELF file. This tool collects every absolute
+// reference to a high kernel address and writes the physical addresses of
+// these fields into the .kaslr_relocs section of the image, in place.
+// entry.S adds the random slide to each of them before enabling paging.
+//
+// Usage: relocs <kernel elf>
+
+use std::env;
+use std::fs;
+use std::process::exit;
+
+const KERNEL_BASE: u64 = 0xFFFFFFFF80000000;
+// The window which is slid, see entry.S and kaslr.rs.
+const KERNEL_WINDOW: u64 = 0x40000000;
+
+const SHT_SYMTAB: u32 = 2;
+const SHT_RELA: u32 = 4;
+const SHF_ALLOC: u64 = 2;
+
+const R_X86_64_64: u32 = 1;
+const R_X86_64_PC32: u32 = 2;
+const R_X86_64_PLT32: u32 = 4;
+const R_X86_64_32: u32 = 10;
+const R_X86_64_32S: u32 = 11;
+
+struct Section {
+    name: String,
+    stype: u32,
+    flags: u64,
+    addr: u64,
+    offset: u64,
+    size: u64,
+    link: u32,
+    info: u32,
+}
+
+fn u16_at(b: &[u8], off: usize) -> u16 { u16::from_le_bytes([b[off], b[off + 1]]) }
+fn u32_at(b: &[u8], off: usize) -> u32 {
+    let mut x = [0u8; 4];
+    x.copy_from_slice(&b[off..off + 4]);
+    u32::from_le_bytes(x)
+}
+fn u64_at(b: &[u8], off: usize) -> u64 {
+    let mut x = [0u8; 8];
+    x.copy_from_slice(&b[off..off + 8]);
+    u64::from_le_bytes(x)
+}
+
+fn cstr(b: &[u8], off: usize) -> String {
+    let end = b[off..].iter().position(|&c| c == 0).unwrap_or(0);
+    String::from_utf8_lossy(&b[off..off + end]).into_owned()
+}
+
+fn die(msg: &str) -> ! {
+    eprintln!("relocs: {}", msg);
+    exit(1);
+}
+
+fn sections(elf: &[u8]) -> Vec<Section> {
+    if &elf[0..4] != b"\x7fELF" || elf[4] != 2 { die("not an ELF64 file") }
+    let shoff = u64_at(elf, 0x28) as usize;
+    let shentsize = u16_at(elf, 0x3A) as usize;
+    let shnum = u16_at(elf, 0x3C) as usize;
+    let shstrndx = u16_at(elf, 0x3E) as usize;
+
+    let raw = |i: usize| &elf[shoff + i * shentsize..shoff + (i + 1) * shentsize];
+    let strtab = u64_at(raw(shstrndx), 0x18) as usize;
+    (0..shnum).map(|i| {
+        let s = raw(i);
+        Section {
+            name: cstr(elf, strtab + u32_at(s, 0) as usize),
+            stype: u32_at(s, 4),
+            flags: u64_at(s, 8),
+            addr: u64_at(s, 0x10),
+            offset: u64_at(s, 0x18),
+            size: u64_at(s, 0x20),
+            link: u32_at(s, 0x28),
+            info: u32_at(s, 0x2C),
+        }
+    }).collect()
+}
+
+fn is_high(v: u64) -> bool { (KERNEL_BASE..KERNEL_BASE + KERNEL_WINDOW).contains(&v) }
+
+// Physical address of a linked location.
+fn phys(v: u64) -> u64 { if v >= KERNEL_BASE { v - KERNEL_BASE } else { v } }
+
+fn main() {
+    let path = env::args().nth(1).unwrap_or_else(|| die("usage: relocs <kernel elf>"));
+    let mut elf = fs::read(&path).unwrap_or_else(|e| die(&format!("{}: {}", path, e)));
+    let secs = sections(&elf);
+
+    let table = secs.iter().find(|s| s.name == ".kaslr_relocs")
+        .unwrap_or_else(|| die("no .kaslr_relocs section"));
+    let symidx = secs.iter().position(|s| s.stype == SHT_SYMTAB)
+        .unwrap_or_else(|| die("no symbol table"));
+    let symtab = &secs[symidx];
+
+    let mut r64 = Vec::new();
+    let mut r32 = Vec::new();
+    for rela in secs.iter().filter(|s| s.stype == SHT_RELA) {
+        let target = &secs[rela.info as usize];
+        if target.flags & SHF_ALLOC == 0 { continue }
+        if target.name == ".kaslr_relocs" { continue }
+        if rela.link as usize != symidx {
+            die(&format!("{}: unexpected symbol table", rela.name));
+        }
+
+        for i in 0..(rela.size / 24) as usize {
+            let r = rela.offset as usize + i * 24;
+            let offset = u64_at(&elf, r);
+            let info = u64_at(&elf, r + 8);
+            let addend = u64_at(&elf, r + 16);
+            let rtype = info as u32;
+            let sym = symtab.offset as usize + (info >> 32) as usize * 24;
+            let value = u64_at(&elf, sym + 8).wrapping_add(addend);
+
+            match rtype {
+                R_X86_64_64 if is_high(value) => r64.push(phys(offset) as u32),
+                R_X86_64_32 | R_X86_64_32S if is_high(value) => r32.push(phys(offset) as u32),
+                // Relative references only break when they cross between
+                // the low init code and the high kernel.
+                R_X86_64_PC32 | R_X86_64_PLT32 if is_high(value) != is_high(offset) => {
+                    die(&format!("relative reference from 0x{:x} to 0x{:x} cannot be slid", offset, value));
+                }
+                _ => {}
+            }
+        }
+    }
+    r64.sort();
+    r64.dedup();
+    r32.sort();
+    r32.dedup();
+
+    // The last dword of the section is kaslr_done.
+    let capacity = (table.size as usize - 8 - 4) / 4;
+    if r64.len() + r32.len() > capacity {
+        die(&format!("{} relocations do not fit in .kaslr_relocs ({}), raise KASLR_RELOCS_SIZE",
+                     r64.len() + r32.len(), capacity));
+    }
+
+    let mut out = Vec::new();
+    out.extend_from_slice(&(r64.len() as u32).to_le_bytes());
+    out.extend_from_slice(&(r32.len() as u32).to_le_bytes());
+    for a in r64.iter().chain(r32.iter()) {
+        out.extend_from_slice(&a.to_le_bytes());
+    }
+    let st = table.offset as usize;
+    elf[st..st + out.len()].copy_from_slice(&out);
+
+    fs::write(&path, &elf).unwrap_or_else(|e| die(&format!("{}: {}", path, e)));
+    println!("relocs: {} 64-bit and {} 32-bit relocations at 0x{:x}",
+             r64.len(), r32.len(), table.addr);
+}
@@ -0,0 +1,159 @@
+// The Multiboot 2 information block, as kern/multiboot.rs reads it.
+//
+// A list of 8 byte aligned tags after the total size; only the tags the
+// kernel asks for in entry.S are made. The kernel copies them out of
+// the first 4MB before its page allocator runs, so the block must be
+// there.
+
+use crate::efi::{MemoryDescriptor, LOADER_CODE, LOADER_DATA, BOOT_SERVICES_CODE, BOOT_SERVICES_DATA,
+                 CONVENTIONAL_MEMORY, UNUSABLE_MEMORY, ACPI_RECLAIM_MEMORY, ACPI_MEMORY_NVS};
+
+pub const MULTIBOOT2_BOOTLOADER_MAGIC: u32 = 0x36d76289;
+
+const TAG_END: u32 = 0;
+const TAG_CMDLINE: u32 = 1;
+const TAG_MODULE: u32 = 3;
+const TAG_MMAP: u32 = 6;
+const TAG_FRAMEBUFFER: u32 = 8;
+const TAG_ACPI_OLD: u32 = 14;
+const TAG_ACPI_NEW: u32 = 15;
+
+// Multiboot memory types
+const MEM_AVAILABLE: u32 = 1;
+const MEM_RESERVED: u32 = 2;
+const MEM_ACPI: u32 = 3;
+const MEM_NVS: u32 = 4;
+const MEM_BAD: u32 = 5;
+
+const MMAP_ENTRY_SIZE: usize = 24;
+
+pub struct BootInfo {
+    buf: &'static mut [u8],
+    off: usize,
+    tag: usize,                         // Start of the open tag
+}
+
+// What the kernel may use once boot services are gone is available. As
+// in the BIOS map GRUB passes, that includes the kernel, the modules and
+// this block; the kernel knows where they are.
+fn mem_type(t: u32) -> u32 {
+    match t {
+        LOADER_CODE | LOADER_DATA | BOOT_SERVICES_CODE | BOOT_SERVICES_DATA
+            | CONVENTIONAL_MEMORY => MEM_AVAILABLE,
+        UNUSABLE_MEMORY => MEM_BAD,
+        ACPI_RECLAIM_MEMORY => MEM_ACPI,
+        ACPI_MEMORY_NVS => MEM_NVS,
+        _ => MEM_RESERVED,
+    }
+}
+
+impl BootInfo {
+    // The block in len bytes at physical address pa, identity mapped.
+    pub unsafe fn new(pa: u64, len: usize) -> BootInfo {
+        let buf = core::slice::from_raw_parts_mut(pa as *mut u8, len);
+        for b in buf.iter_mut() { *b = 0; }
+        BootInfo { buf, off: 8, tag: 0 }
+    }
+
+    pub fn addr(&self) -> u64 { self.buf.as_ptr() as u64 }
+
+    // The size of a memory map of n entries.
+    pub fn mmap_size(n: usize) -> usize { 16 + n * MMAP_ENTRY_SIZE }
+
+    fn put(&mut self, b: &[u8]) -> () {
+        self.buf[self.off..self.off + b.len()].copy_from_slice(b);
+        self.off += b.len();
+    }
+
+    fn put32(&mut self, x: u32) -> () { self.put(&x.to_le_bytes()) }
+
+    fn put64(&mut self, x: u64) -> () { self.put(&x.to_le_bytes()) }
+
+    fn begin(&mut self, ttype: u32) -> () {
+        self.tag = self.off;
+        self.put32(ttype);
+        self.put32(0);
+    }
+
+    fn end(&mut self) -> () {
+        let size = (self.off - self.tag) as u32;
+        self.buf[self.tag + 4..self.tag + 8].copy_from_slice(&size.to_le_bytes());
+        self.off = (self.off + 7) & !7;
+    }
+
+    pub fn cmdline(&mut self, s: &[u8]) -> () {
+        self.begin(TAG_CMDLINE);
+        self.put(s);
+        self.put(&[0]);
+        self.end();
+    }
+
+    pub fn module(&mut self, start: u64, end: u64, name: &[u8]) -> () {
+        self.begin(TAG_MODULE);
+        self.put32(start as u32);
+        self.put32(end as u32);
+        self.put(name);
+        self.put(&[0]);
+        self.end();
+    }
+
+    // The UEFI memory map, n descriptors of descsize bytes at map, with
+    // adjacent ranges of the same type merged.
+    pub unsafe fn mmap(&mut self, map: *const u8, n: usize, descsize: usize) -> () {
+        self.begin(TAG_MMAP);
+        self.put32(MMAP_ENTRY_SIZE as u32);
+        self.put32(0);
+        let mut last: Option<(u64, u64, u32)> = None;
+        for i in 0..n {
+            let d = &*(map.add(i * descsize) as *const MemoryDescriptor);
+            let (base, len, mtype) = (d.physical_start, d.number_of_pages * 4096, mem_type(d.mtype));
+            last = match last {
+                Some((b, l, t)) if t == mtype && b + l == base => Some((b, l + len, t)),
+                Some((b, l, t)) => {
+                    self.mmap_entry(b, l, t);
+                    Some((base, len, mtype))
+                }
+                None => Some((base, len, mtype)),
+            };
+        }
+        if let Some((b, l, t)) = last { self.mmap_entry(b, l, t); }
+        self.end();
+    }
+
+    fn mmap_entry(&mut self, base: u64, len: u64, mtype: u32) -> () {
+        self.put64(base);
+        self.put64(len);
+        self.put32(mtype);
+        self.put32(0);
+    }
+
+    // A direct RGB framebuffer, each color given as (position, size) in
+    // bits.
+    pub fn framebuffer(&mut self, addr: u64, pitch: u32, width: u32, height: u32, bpp: u8,
+                       colors: [(u8, u8); 3]) -> () {
+        self.begin(TAG_FRAMEBUFFER);
+        self.put64(addr);
+        self.put32(pitch);
+        self.put32(width);
+        self.put32(height);
+        self.put(&[bpp, 1, 0, 0]);      // Type 1, RGB
+        for &(pos, size) in colors.iter() { self.put(&[pos, size]); }
+        self.end();
+    }
+
+    // A copy of the RSDP; ACPI 2.0 and later have their own tag.
+    pub fn acpi(&mut self, rsdp: &[u8]) -> () {
+        self.begin(if rsdp[15] >= 2 { TAG_ACPI_NEW } else { TAG_ACPI_OLD });
+        self.put(rsdp);
+        self.end();
+    }
+
+    // Close the list, return the total size.
+    pub fn finish(&mut self) -> usize {
+        self.begin(TAG_END);
+        self.end();
+        let total = self.off as u32;
+        self.buf[..4].copy_from_slice(&total.to_le_bytes());
+        self.off
+    }
+}
@@ -0,0 +1,260 @@
+// The parts of the UEFI interface the loader uses.
+//
+// Tables and protocols are declared up to the last member needed; the
+// members before it that are never called are kept as usize to hold
+// their place. All functions use the Microsoft x64 calling convention.
+
+use core::ffi::c_void;
+use core::fmt;
+use core::ptr::null_mut;
+
+pub type Handle = *mut c_void;
+pub type Status = usize;
+
+pub const SUCCESS: Status = 0;
+const ERROR: Status = 1 << 63;
+pub const LOAD_ERROR: Status = ERROR | 1;
+pub const BUFFER_TOO_SMALL: Status = ERROR | 5;
+pub const NOT_FOUND: Status = ERROR | 14;
+
+pub fn error(s: Status) -> bool { s & ERROR != 0 }
+
+#[repr(C)]
+#[derive(Copy, Clone, PartialEq)]
+pub struct Guid(pub u32, pub u16, pub u16, pub [u8; 8]);
+
+pub const LOADED_IMAGE_PROTOCOL: Guid =
+    Guid(0x5b1b31a1, 0x9562, 0x11d2, [0x8e, 0x3f, 0x00, 0xa0, 0xc9, 0x69, 0x72, 0x3b]);
+pub const SIMPLE_FILE_SYSTEM_PROTOCOL: Guid =
+    Guid(0x964e5b22, 0x6459, 0x11d2, [0x8e, 0x39, 0x00, 0xa0, 0xc9, 0x69, 0x72, 0x3b]);
+pub const GRAPHICS_OUTPUT_PROTOCOL: Guid =
+    Guid(0x9042a9de, 0x23dc, 0x4a38, [0x96, 0xfb, 0x7a, 0xde, 0xd0, 0x80, 0x51, 0x6a]);
+pub const ACPI_TABLE: Guid =
+    Guid(0xeb9d2d30, 0x2d88, 0x11d3, [0x9a, 0x16, 0x00, 0x90, 0x27, 0x3f, 0xc1, 0x4d]);
+pub const ACPI_20_TABLE: Guid =
+    Guid(0x8868e871, 0xe4f1, 0x11d3, [0xbc, 0x22, 0x00, 0x80, 0xc7, 0x3c, 0x88, 0x81]);
+
+#[repr(C)]
+pub struct TableHeader {
+    pub signature: u64,
+    pub revision: u32,
+    pub header_size: u32,
+    pub crc32: u32,
+    reserved: u32,
+}
+
+#[repr(C)]
+pub struct SystemTable {
+    pub hdr: TableHeader,
+    pub firmware_vendor: *const u16,
+    pub firmware_revision: u32,
+    pub console_in_handle: Handle,
+    pub con_in: *mut c_void,
+    pub console_out_handle: Handle,
+    pub con_out: *mut SimpleTextOutput,
+    pub standard_error_handle: Handle,
+    pub std_err: *mut SimpleTextOutput,
+    pub runtime_services: *mut c_void,
+    pub boot_services: *mut BootServices,
+    pub number_of_table_entries: usize,
+    pub configuration_table: *const ConfigurationTable,
+}
+
+#[repr(C)]
+pub struct ConfigurationTable {
+    pub vendor_guid: Guid,
+    pub vendor_table: *const c_void,
+}
+
+#[repr(C)]
+pub struct SimpleTextOutput {
+    reset: usize,
+    pub output_string: extern "win64" fn(*mut SimpleTextOutput, *const u16) -> Status,
+}
+
+// AllocatePages types
+pub const ALLOCATE_MAX_ADDRESS: u32 = 1;
+pub const ALLOCATE_ADDRESS: u32 = 2;
+
+// Memory types
+pub const LOADER_CODE: u32 = 1;
+pub const LOADER_DATA: u32 = 2;
+pub const BOOT_SERVICES_CODE: u32 = 3;
+pub const BOOT_SERVICES_DATA: u32 = 4;
+pub const CONVENTIONAL_MEMORY: u32 = 7;
+pub const UNUSABLE_MEMORY: u32 = 8;
+pub const ACPI_RECLAIM_MEMORY: u32 = 9;
+pub const ACPI_MEMORY_NVS: u32 = 10;
+
+#[repr(C)]
+pub struct MemoryDescriptor {
+    pub mtype: u32,
+    pub physical_start: u64,
+    pub virtual_start: u64,
+    pub number_of_pages: u64,
+    pub attribute: u64,
+}
+
+#[repr(C)]
+pub struct BootServices {
+    pub hdr: TableHeader,
+    raise_tpl: usize,
+    restore_tpl: usize,
+    pub allocate_pages: extern "win64" fn(u32, u32, usize, *mut u64) -> Status,
+    pub free_pages: extern "win64" fn(u64, usize) -> Status,
+    pub get_memory_map: extern "win64" fn(*mut usize, *mut MemoryDescriptor, *mut usize, *mut usize, *mut u32) -> Status,
+    pub allocate_pool: extern "win64" fn(u32, usize, *mut *mut u8) -> Status,
+    pub free_pool: extern "win64" fn(*mut u8) -> Status,
+    create_event: usize,
+    set_timer: usize,
+    wait_for_event: usize,
+    signal_event: usize,
+    close_event: usize,
+    check_event: usize,
+    install_protocol_interface: usize,
+    reinstall_protocol_interface: usize,
+    uninstall_protocol_interface: usize,
+    pub handle_protocol: extern "win64" fn(Handle, *const Guid, *mut *mut c_void) -> Status,
+    reserved: usize,
+    register_protocol_notify: usize,
+    locate_handle: usize,
+    locate_device_path: usize,
+    install_configuration_table: usize,
+    load_image: usize,
+    start_image: usize,
+    exit: usize,
+    unload_image: usize,
+    pub exit_boot_services: extern "win64" fn(Handle, usize) -> Status,
+    get_next_monotonic_count: usize,
+    stall: usize,
+    set_watchdog_timer: usize,
+    connect_controller: usize,
+    disconnect_controller: usize,
+    open_protocol: usize,
+    close_protocol: usize,
+    open_protocol_information: usize,
+    protocols_per_handle: usize,
+    locate_handle_buffer: usize,
+    pub locate_protocol: extern "win64" fn(*const Guid, *mut c_void, *mut *mut c_void) -> Status,
+}
+
+#[repr(C)]
+pub struct LoadedImage {
+    pub revision: u32,
+    pub parent_handle: Handle,
+    pub system_table: *mut SystemTable,
+    pub device_handle: Handle,
+    pub file_path: *const c_void,
+    reserved: usize,
+    pub load_options_size: u32,
+    pub load_options: *const c_void,
+    pub image_base: u64,
+    pub image_size: u64,
+}
+
+#[repr(C)]
+pub struct SimpleFileSystem {
+    pub revision: u64,
+    pub open_volume: extern "win64" fn(*mut SimpleFileSystem, *mut *mut File) -> Status,
+}
+
+pub const FILE_MODE_READ: u64 = 1;
+
+#[repr(C)]
+pub struct File {
+    pub revision: u64,
+    pub open: extern "win64" fn(*mut File, *mut *mut File, *const u16, u64, u64) -> Status,
+    pub close: extern "win64" fn(*mut File) -> Status,
+    delete: usize,
+    pub read: extern "win64" fn(*mut File, *mut usize, *mut u8) -> Status,
+    write: usize,
+    pub get_position: extern "win64" fn(*mut File, *mut u64) -> Status,
+    pub set_position: extern "win64" fn(*mut File, u64) -> Status,
+}
+
+// GOP pixel formats
+pub const PIXEL_RGB_RESERVED_8BIT: u32 = 0;
+pub const PIXEL_BGR_RESERVED_8BIT: u32 = 1;
+
+#[repr(C)]
+pub struct GraphicsOutput {
+    query_mode: usize,
+    set_mode: usize,
+    blt: usize,
+    pub mode: *const GraphicsMode,
+}
+
+#[repr(C)]
+pub struct GraphicsMode {
+    pub max_mode: u32,
+    pub mode: u32,
+    pub info: *const GraphicsModeInfo,
+    pub size_of_info: usize,
+    pub frame_buffer_base: u64,
+    pub frame_buffer_size: usize,
+}
+
+#[repr(C)]
+pub struct GraphicsModeInfo {
+    pub version: u32,
+    pub horizontal_resolution: u32,
+    pub vertical_resolution: u32,
+    pub pixel_format: u32,
+    pub pixel_information: [u32; 4],
+    pub pixels_per_scan_line: u32,
+}
+
+static mut ST: *mut SystemTable = null_mut();
+
+pub fn init(st: *mut SystemTable) -> () {
+    unsafe { ST = st; }
+}
+
+pub fn system_table() -> &'static SystemTable {
+    unsafe { &*ST }
+}
+
+pub fn boot_services() -> &'static BootServices {
+    unsafe { &*system_table().boot_services }
+}
+
+// Output through ConOut, usable until ExitBootServices.
+pub struct Console;
+
+impl fmt::Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let out = system_table().con_out;
+        let mut buf = [0u16; 64];
+        let mut n = 0;
+        for c in s.chars() {
+            if c == '\n' {
+                buf[n] = '\r' as u16;
+                n += 1;
+            }
+            buf[n] = if (c as u32) < 0x10000 { c as u16 } else { '?' as u16 };
+            n += 1;
+            if n >= buf.len() - 3 {
+                buf[n] = 0;
+                (unsafe { &*out }.output_string)(out, buf.as_ptr());
+                n = 0;
+            }
+        }
+        buf[n] = 0;
+        (unsafe { &*out }.output_string)(out, buf.as_ptr());
+        Ok(())
+    }
+}
+
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => ({
+        use core::fmt::Write;
+        let _ = write!($crate::efi::Console, $($arg)*);
+    });
+}
+
+#[macro_export]
+macro_rules! println {
+    () => ($crate::print!("\n"));
+    ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
+}
@@ -0,0 +1,404 @@
+// UEFI loader.
+//
+// A PE/COFF application for x86_64-unknown-uefi, installed on the EFI
+// system partition as \EFI\BOOT\BOOTX64.EFI next to the files grub.cfg
+// loads: \boot\ros, \boot\initrd and, in place of the options in
+// grub.cfg, \boot\cmdline. It puts the kernel's segments at the
+// physical addresses they are linked for, the initrd right above, and
+// passes the UEFI memory map, the GOP framebuffer and the ACPI RSDP the
+// way a Multiboot 2 loader would. After ExitBootServices it switches to
+// page tables laid out like init_pml4 and enters the kernel at
+// start64_efi, found through the header in entry.S.
+//
+// Usage: make qemu-efi
+
+#![no_std]
+#![no_main]
+#![feature(asm)]
+
+#[macro_use]
+mod efi;
+mod bootinfo;
+
+use core::ffi::c_void;
+use core::mem::size_of;
+use core::panic::PanicInfo;
+use core::ptr::null_mut;
+use crate::bootinfo::{BootInfo, MULTIBOOT2_BOOTLOADER_MAGIC};
+use crate::efi::*;
+
+const PGSIZE: u64 = 4096;
+const EARLYTOP: u64 = 4 * 1024 * 1024;  // Mapped by the kernel's init_pml4
+const PHYSTOP: u64 = 0x20000000;        // The memory the kernel uses
+const LOWTOP: u64 = 0x100000000;        // Identity mapped for the switch
+
+const KERNEL_BASE: u64 = 0xFFFFFFFF80000000;
+const KERNEL_WINDOW: u64 = 0x40000000;  // At KERNEL_BASE, as init_pd
+
+// The header entry.S has for us in the first 32KB of the image.
+const ROS_EFI_MAGIC: u32 = 0x49464552;
+const HEADER_SEARCH: usize = 32768;
+
+const CMDLINE_MAX: usize = 255;
+const MAXDESC: usize = 16;              // Slack for the map growing
+
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELF_PROG_LOAD: u32 = 1;
+
+#[repr(C)]
+struct ElfHdr {
+    magic: [u8; 4],
+    elf: [u8; 12],
+    etype: u16,
+    machine: u16,
+    version: u32,
+    entry: u64,
+    phoff: u64,
+    shoff: u64,
+    flags: u32,
+    ehsize: u16,
+    phentsize: u16,
+    phnum: u16,
+    shentsize: u16,
+    shnum: u16,
+    shstrndx: u16,
+}
+
+#[repr(C)]
+struct ProgHdr {
+    ptype: u32,
+    flags: u32,
+    off: u64,
+    vaddr: u64,
+    paddr: u64,
+    filesz: u64,
+    memsz: u64,
+    align: u64,
+}
+
+fn pgroundup(a: u64) -> u64 { (a + PGSIZE - 1) & !(PGSIZE - 1) }
+
+fn pages(n: u64) -> usize { (pgroundup(n) / PGSIZE) as usize }
+
+// Allocate the pages holding [pa, pa+n).
+fn alloc_at(pa: u64, n: u64) -> Result<(), &'static str> {
+    let mut a = pa & !(PGSIZE - 1);
+    let np = pages(pa + n - a);
+    if error((boot_services().allocate_pages)(ALLOCATE_ADDRESS, LOADER_DATA, np, &mut a)) {
+        return Err("memory taken by the firmware");
+    }
+    Ok(())
+}
+
+// Allocate zeroed pages for n bytes below top.
+fn alloc_below(top: u64, n: u64) -> Result<u64, &'static str> {
+    let mut a = top - 1;
+    if error((boot_services().allocate_pages)(ALLOCATE_MAX_ADDRESS, LOADER_DATA, pages(n), &mut a)) {
+        return Err("out of memory");
+    }
+    unsafe { core::ptr::write_bytes(a as *mut u8, 0, pgroundup(n) as usize); }
+    Ok(a)
+}
+
+// A UEFI path from an ASCII one.
+fn wide(path: &str) -> [u16; 64] {
+    let mut w = [0u16; 64];
+    for (i, c) in path.bytes().take(w.len() - 1).enumerate() {
+        w[i] = if c == b'/' { b'\\' as u16 } else { c as u16 };
+    }
+    w
+}
+
+// A file on the volume the loader came from.
+struct File(*mut efi::File);
+
+impl File {
+    fn open(root: *mut efi::File, path: &str) -> Result<File, Status> {
+        let mut f = null_mut();
+        let s = unsafe { ((*root).open)(root, &mut f, wide(path).as_ptr(), FILE_MODE_READ, 0) };
+        if error(s) { Err(s) } else { Ok(File(f)) }
+    }
+
+    fn size(&self) -> u64 {
+        let mut n = 0;
+        unsafe {
+            ((*self.0).set_position)(self.0, !0);       // The end of the file
+            ((*self.0).get_position)(self.0, &mut n);
+            ((*self.0).set_position)(self.0, 0);
+        }
+        n
+    }
+
+    fn read(&self, dst: u64, n: u64) -> Result<(), &'static str> {
+        let mut tot = 0;
+        while tot < n {
+            let mut m = (n - tot) as usize;
+            if error(unsafe { ((*self.0).read)(self.0, &mut m, (dst + tot) as *mut u8) }) || m == 0 {
+                return Err("read error");
+            }
+            tot += m as u64;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for File {
+    fn drop(&mut self) {
+        unsafe { ((*self.0).close)(self.0); }
+    }
+}
+
+fn open_volume(image: Handle) -> Result<*mut efi::File, &'static str> {
+    let bs = boot_services();
+    let mut li: *mut c_void = null_mut();
+    if error((bs.handle_protocol)(image, &LOADED_IMAGE_PROTOCOL, &mut li)) {
+        return Err("no loaded image protocol");
+    }
+    let li = unsafe { &*(li as *const LoadedImage) };
+    // Our code must survive the switch to the kernel's page tables.
+    if li.image_base + li.image_size > LOWTOP { return Err("loaded above 4GB") }
+
+    let mut fs: *mut c_void = null_mut();
+    if error((bs.handle_protocol)(li.device_handle, &SIMPLE_FILE_SYSTEM_PROTOCOL, &mut fs)) {
+        return Err("boot device has no file system");
+    }
+    let fs = fs as *mut SimpleFileSystem;
+    let mut root = null_mut();
+    if error(unsafe { ((*fs).open_volume)(fs, &mut root) }) { return Err("cannot open volume") }
+    Ok(root)
+}
+
+// Load the kernel's segments where they are linked, return the end of
+// the memory they take and the entry of the header.
+unsafe fn load_kernel(root: *mut efi::File) -> Result<(u64, u64), &'static str> {
+    let f = File::open(root, "/boot/ros").map_err(|_| "no /boot/ros")?;
+    let size = f.size();
+    let buf = alloc_below(LOWTOP, size)?;
+    f.read(buf, size)?;
+    let image = core::slice::from_raw_parts(buf as *const u8, size as usize);
+
+    let elf = &*(buf as *const ElfHdr);
+    if size < size_of::<ElfHdr>() as u64 || elf.magic != ELF_MAGIC { return Err("/boot/ros is not ELF") }
+    let ph = core::slice::from_raw_parts((buf + elf.phoff) as *const ProgHdr, elf.phnum as usize);
+    let load = || ph.iter().filter(|p| p.ptype == ELF_PROG_LOAD);
+
+    // All at once, segments may share a page.
+    let lo = load().map(|p| p.paddr).min().ok_or("no segments")?;
+    let hi = load().map(|p| p.paddr + p.memsz).max().unwrap_or(lo);
+    if hi > EARLYTOP { return Err("kernel above 4MB") }
+    alloc_at(lo, hi - lo)?;
+    for p in load() {
+        let src = image.get(p.off as usize..(p.off + p.filesz) as usize).ok_or("truncated /boot/ros")?;
+        core::ptr::copy_nonoverlapping(src.as_ptr(), p.paddr as *mut u8, src.len());
+        core::ptr::write_bytes((p.paddr + p.filesz) as *mut u8, 0, (p.memsz - p.filesz) as usize);
+    }
+
+    let mut entry = None;
+    for off in (0..core::cmp::min(HEADER_SEARCH, image.len() - 8)).step_by(8) {
+        let h = (buf + off as u64) as *const u32;
+        if *h == ROS_EFI_MAGIC { entry = Some(*h.add(1) as u64); break }
+    }
+    (boot_services().free_pages)(buf, pages(size));
+    Ok((hi, entry.ok_or("no UEFI header in /boot/ros")?))
+}
+
+// The memory map into buf of cap bytes: the number of descriptors,
+// their size and the map key.
+unsafe fn memory_map(buf: *mut u8, cap: usize) -> Result<(usize, usize, usize), &'static str> {
+    let (mut n, mut key, mut descsize, mut ver) = (cap, 0, 0, 0);
+    if error((boot_services().get_memory_map)(&mut n, buf as *mut MemoryDescriptor, &mut key, &mut descsize, &mut ver)) {
+        return Err("cannot get memory map");
+    }
+    Ok((n / descsize, descsize, key))
+}
+
+// A buffer for the memory map, big enough for the one at exit.
+unsafe fn memory_map_buffer() -> Result<(*mut u8, usize), &'static str> {
+    let (mut n, mut key, mut descsize, mut ver) = (0, 0, 0, 0);
+    let s = (boot_services().get_memory_map)(&mut n, null_mut(), &mut key, &mut descsize, &mut ver);
+    if s != BUFFER_TOO_SMALL { return Err("cannot get memory map") }
+    let cap = n + MAXDESC * descsize;
+    let mut buf = null_mut();
+    if error((boot_services().allocate_pool)(LOADER_DATA, cap, &mut buf)) { return Err("out of memory") }
+    Ok((buf, cap))
+}
+
+// Find len free bytes from lo up, below top.
+unsafe fn find_free(lo: u64, len: u64, top: u64) -> Result<u64, &'static str> {
+    let (buf, cap) = memory_map_buffer()?;
+    let (n, descsize, _) = memory_map(buf, cap)?;
+    let mut found = None;
+    for i in 0..n {
+        let d = &*(buf.add(i * descsize) as *const MemoryDescriptor);
+        let st = core::cmp::max(d.physical_start, pgroundup(lo));
+        let ed = d.physical_start + d.number_of_pages * PGSIZE;
+        if d.mtype == CONVENTIONAL_MEMORY && st + len <= core::cmp::min(ed, top)
+            && found.map_or(true, |f| st < f) {
+            found = Some(st);
+        }
+    }
+    (boot_services().free_pool)(buf);
+    found.ok_or("no room below PHYSTOP")
+}
+
+// Put the initrd right above the kernel, the page allocator starts
+// above it. Returns its range, None if there is none.
+unsafe fn load_initrd(root: *mut efi::File, kend: u64) -> Result<Option<(u64, u64)>, &'static str> {
+    let f = match File::open(root, "/boot/initrd") {
+        Ok(f) => f,
+        Err(NOT_FOUND) => return Ok(None),
+        Err(_) => return Err("cannot open /boot/initrd"),
+    };
+    let size = f.size();
+    if size == 0 { return Ok(None) }
+    let pa = find_free(kend, size, PHYSTOP)?;
+    alloc_at(pa, size)?;
+    f.read(pa, size)?;
+    Ok(Some((pa, pa + size)))
+}
+
+// The options for the kernel, if there is a cmdline file.
+unsafe fn read_cmdline(root: *mut efi::File, buf: &mut [u8; CMDLINE_MAX]) -> Result<usize, &'static str> {
+    let f = match File::open(root, "/boot/cmdline") {
+        Ok(f) => f,
+        Err(_) => return Ok(0),
+    };
+    let n = core::cmp::min(f.size() as usize, buf.len());
+    f.read(buf.as_mut_ptr() as u64, n as u64)?;
+    Ok(buf[..n].iter().rposition(|&c| !(c as char).is_whitespace()).map_or(0, |i| i + 1))
+}
+
+// A copy of the RSDP from the configuration tables, ACPI 2.0 preferred.
+unsafe fn find_rsdp() -> Option<&'static [u8]> {
+    let st = system_table();
+    let tables = core::slice::from_raw_parts(st.configuration_table, st.number_of_table_entries);
+    let find = |g: Guid| tables.iter().find(|t| t.vendor_guid == g).map(|t| t.vendor_table as *const u8);
+    let p = find(ACPI_20_TABLE).or_else(|| find(ACPI_TABLE))?;
+    let len = if *p.add(15) >= 2 { *(p.add(20) as *const u32) as usize } else { 20 };
+    Some(core::slice::from_raw_parts(p, core::cmp::min(len, 36)))
+}
+
+// The framebuffer of GOP, if it is directly writable RGB.
+struct Framebuffer {
+    addr: u64,
+    pitch: u32,
+    width: u32,
+    height: u32,
+    colors: [(u8, u8); 3],
+}
+
+fn find_framebuffer() -> Option<Framebuffer> {
+    let mut gop: *mut c_void = null_mut();
+    if error((boot_services().locate_protocol)(&GRAPHICS_OUTPUT_PROTOCOL, null_mut(), &mut gop)) { return None }
+    let mode = unsafe { &*(*(gop as *const GraphicsOutput)).mode };
+    let mi = unsafe { &*mode.info };
+    let colors = match mi.pixel_format {
+        PIXEL_RGB_RESERVED_8BIT => [(0, 8), (8, 8), (16, 8)],
+        PIXEL_BGR_RESERVED_8BIT => [(16, 8), (8, 8), (0, 8)],
+        _ => return None,               // Bit masks or Blt only
+    };
+    Some(Framebuffer {
+        addr: mode.frame_buffer_base,
+        pitch: mi.pixels_per_scan_line * 4,
+        width: mi.horizontal_resolution,
+        height: mi.vertical_resolution,
+        colors,
+    })
+}
+
+// Page tables like init_pml4 in entry.S: the low memory identity mapped,
+// here all of the first 4GB where this loader and its stack are too, and
+// the kernel window at KERNEL_BASE, with 2MB pages.
+unsafe fn page_tables() -> Result<u64, &'static str> {
+    const PTE_P: u64 = 1;
+    const PTE_W: u64 = 2;
+    const PTE_PS: u64 = 0x80;
+    const HUGE: u64 = 0x200000;
+
+    let pml4 = alloc_below(LOWTOP, PGSIZE)? as *mut u64;
+    let low_pdpt = alloc_below(LOWTOP, PGSIZE)? as *mut u64;
+    let high_pdpt = alloc_below(LOWTOP, PGSIZE)? as *mut u64;
+    let low_pd = alloc_below(LOWTOP, 4 * PGSIZE)? as *mut u64;
+    let high_pd = alloc_below(LOWTOP, PGSIZE)? as *mut u64;
+
+    for i in 0..(LOWTOP / HUGE) as usize {
+        *low_pd.add(i) = i as u64 * HUGE + PTE_PS + PTE_W + PTE_P;
+    }
+    for i in 0..4 {
+        *low_pdpt.add(i) = low_pd.add(i * 512) as u64 + PTE_W + PTE_P;
+    }
+    for i in 0..(KERNEL_WINDOW / HUGE) as usize {
+        *high_pd.add(i) = i as u64 * HUGE + PTE_PS + PTE_W + PTE_P;
+    }
+    *high_pdpt.add((KERNEL_BASE >> 30) as usize & 511) = high_pd as u64 + PTE_W + PTE_P;
+    *pml4 = low_pdpt as u64 + PTE_W + PTE_P;
+    *pml4.add(511) = high_pdpt as u64 + PTE_W + PTE_P;
+    Ok(pml4 as u64)
+}
+
+unsafe fn boot(image: Handle) -> Result<(), &'static str> {
+    let root = open_volume(image)?;
+    let (kend, entry) = load_kernel(root)?;
+    let initrd = load_initrd(root, kend)?;
+    let kend = initrd.map_or(kend, |(_, end)| end);
+    let mut cmdline = [0u8; CMDLINE_MAX];
+    let cmdlen = read_cmdline(root, &mut cmdline)?;
+    ((*root).close)(root);
+    let rsdp = find_rsdp();
+    let fb = find_framebuffer();
+    let pml4 = page_tables()?;
+
+    println!("ros-uefi: kernel to {:#x}, entry {:#x}", kend, entry);
+    if let Some((st, ed)) = initrd { println!("ros-uefi: initrd at {:#x}, {} bytes", st, ed - st); }
+    if rsdp.is_none() { println!("ros-uefi: no ACPI RSDP"); }
+    if let Some(ref f) = fb { println!("ros-uefi: framebuffer {}x{} at {:#x}", f.width, f.height, f.addr); }
+
+    // Nothing may be allocated between getting the map and the exit, so
+    // the buffers for both are made before.
+    let (map, cap) = memory_map_buffer()?;
+    let infolen = 2 * PGSIZE + BootInfo::mmap_size(cap / size_of::<MemoryDescriptor>()) as u64;
+    let infopa = alloc_below(EARLYTOP, infolen)?;
+
+    let mut tries = 0;
+    let info = loop {
+        let (n, descsize, key) = memory_map(map, cap)?;
+        let mut info = BootInfo::new(infopa, infolen as usize);
+        info.cmdline(&cmdline[..cmdlen]);
+        if let Some((st, ed)) = initrd { info.module(st, ed, b"initrd"); }
+        info.mmap(map, n, descsize);
+        if let Some(ref f) = fb { info.framebuffer(f.addr, f.pitch, f.width, f.height, 32, f.colors); }
+        if let Some(r) = rsdp { info.acpi(r); }
+        info.finish();
+
+        // The map changes if the firmware runs an event meanwhile.
+        if !error((boot_services().exit_boot_services)(image, key)) { break info }
+        tries += 1;
+        if tries == 3 { return Err("cannot exit boot services") }
+    };
+
+    // No firmware from here on, see start64_efi in entry.S.
+    asm!("cli
+          mov $0, %cr3
+          jmp *$1"
+         : : "r"(pml4), "r"(entry), "{eax}"(MULTIBOOT2_BOOTLOADER_MAGIC), "{ebx}"(info.addr() as u32)
+         : "memory" : "volatile");
+    unreachable!();
+}
+
+#[no_mangle]
+pub unsafe extern "win64" fn efi_main(image: Handle, st: *mut SystemTable) -> Status {
+    efi::init(st);
+    match boot(image) {
+        Ok(()) => SUCCESS,
+        Err(e) => {
+            println!("ros-uefi: {}", e);
+            LOAD_ERROR
+        }
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    println!("ros-uefi: {}", info);
+    loop {}
+}